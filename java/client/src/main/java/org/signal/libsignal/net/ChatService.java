@@ -20,6 +20,47 @@ import org.signal.libsignal.internal.NativeHandleGuard;
  * String)} method.
  */
 public class ChatService extends NativeHandleGuard.SimpleOwner {
+  /** The transport used to send requests to the Chat Service. */
+  public enum Transport {
+    // Must be kept in sync with the libsignal-bridge Rust enum ChatTransportKind.
+
+    /** A long-lived WebSocket, over which the server can also send requests. */
+    WEB_SOCKET(0),
+    /** One HTTP/2 request per chat request; the server can't send requests. */
+    HTTP2(1);
+
+    private final int value;
+
+    Transport(int value) {
+      this.value = value;
+    }
+  }
+
+  /** The state of a connection kept open by {@link #startSupervised}. */
+  public enum ConnectionState {
+    // Must be kept in sync with connection_state_code in libsignal-bridge.
+
+    DISCONNECTED(0),
+    CONNECTING(1),
+    CONNECTED(2),
+    /** Waiting before trying to connect again. */
+    BACKING_OFF(3);
+
+    private final int value;
+
+    ConnectionState(int value) {
+      this.value = value;
+    }
+
+    private static ConnectionState fromValue(int value) {
+      for (ConnectionState state : values()) {
+        if (state.value == value) {
+          return state;
+        }
+      }
+      throw new IllegalArgumentException("unknown connection state " + value);
+    }
+  }
 
   private final TokioAsyncContext tokioAsyncContext;
 
@@ -27,11 +68,13 @@ public class ChatService extends NativeHandleGuard.SimpleOwner {
       final TokioAsyncContext tokioAsyncContext,
       final Network.ConnectionManager connectionManager,
       final String username,
-      final String password) {
+      final String password,
+      final Transport transport) {
     super(
         connectionManager.guardedMap(
             connectionManagerHandle ->
-                Native.ChatService_new(connectionManagerHandle, username, password)));
+                Native.ChatService_new(
+                    connectionManagerHandle, username, password, transport.value)));
     this.tokioAsyncContext = tokioAsyncContext;
   }
 
@@ -202,6 +245,53 @@ public class ChatService extends NativeHandleGuard.SimpleOwner {
     }
   }
 
+  /**
+   * Keeps a connection to the Chat Service open, reconnecting with backoff whenever it's lost, until
+   * {@link #stopSupervised()} is called.
+   *
+   * <p>Calling this again replaces the previous supervision.
+   *
+   * @return a future that completes once supervision has started.
+   */
+  @SuppressWarnings("unchecked")
+  public CompletableFuture<Void> startSupervised(final boolean authenticated) {
+    return tokioAsyncContext.guardedMap(
+        asyncContextHandle ->
+            guardedMap(
+                chatServiceHandle ->
+                    Native.ChatService_start_supervised(
+                        asyncContextHandle, chatServiceHandle, authenticated)));
+  }
+
+  /** Stops the supervision started by {@link #startSupervised}. */
+  public void stopSupervised() {
+    guardedRun(Native::ChatService_stop_supervised);
+  }
+
+  /**
+   * Tells a supervised connection that the network changed, so that it reconnects right away
+   * instead of waiting out its backoff.
+   */
+  public void networkChanged() {
+    guardedRun(Native::ChatService_network_changed);
+  }
+
+  /**
+   * Waits for the supervised connection's state to be something other than {@code lastState}.
+   *
+   * @return a future that completes with {@link ConnectionState#DISCONNECTED} right away if the
+   *     connection isn't being supervised, and once supervision stops.
+   */
+  public CompletableFuture<ConnectionState> nextConnectionState(final ConnectionState lastState) {
+    return tokioAsyncContext.guardedMap(
+        asyncContextHandle ->
+            guardedMap(
+                chatServiceHandle ->
+                    Native.ChatService_next_connection_state(
+                            asyncContextHandle, chatServiceHandle, lastState.value)
+                        .thenApply(ConnectionState::fromValue)));
+  }
+
   static InternalRequest buildInternalRequest(final Request req) throws MalformedURLException {
     final InternalRequest result =
         new InternalRequest(req.method(), req.pathAndQuery(), req.body());
@@ -38,11 +38,29 @@ public class Network {
   private final Svr3 svr3;
 
   public Network(Environment env, String userAgent) {
+    this(new ConnectionManager(env, userAgent));
+  }
+
+  private Network(ConnectionManager connectionManager) {
     this.tokioAsyncContext = new TokioAsyncContext();
-    this.connectionManager = new ConnectionManager(env, userAgent);
+    this.connectionManager = connectionManager;
     this.svr3 = new Svr3(this);
   }
 
+  /**
+   * Creates a {@code Network} for a deployment other than staging or production.
+   *
+   * @param config the deployment's endpoints, in the JSON format described by libsignal-net's
+   *     {@code EnvConfig}
+   * @param countryCode the ISO 3166-1 alpha-2 code of the client's country, or {@code null} if
+   *     unknown. Decides whether the deployment's domain fronts are used.
+   * @throws IOException if the config is invalid.
+   */
+  public static Network forCustomEnvironment(String config, String countryCode, String userAgent)
+      throws IOException {
+    return new Network(ConnectionManager.forCustomEnvironment(config, userAgent, countryCode));
+  }
+
   /**
    * Sets the proxy host to be used for all new connections (until overridden).
    *
@@ -56,16 +74,75 @@ public class Network {
     this.connectionManager.setProxy(host, port);
   }
 
+  /**
+   * Sets a SOCKS5 proxy to be used for all new connections (until overridden).
+   *
+   * <p>The proxy can be overridden by calling this method or {@link #setProxy} again, or unset by
+   * calling {@link #clearProxy}.
+   *
+   * @param username the username to authenticate with, or {@code null} for none
+   * @param password the password to authenticate with, or {@code null} for none
+   * @throws IOException if the host or port are not (structurally) valid, such as a port that
+   *     doesn't fit in u16.
+   */
+  public void setSocksProxy(String host, int port, String username, String password)
+      throws IOException {
+    this.connectionManager.setSocksProxy(host, port, username, password);
+  }
+
+  /**
+   * Sets an HTTP proxy, used with {@code CONNECT}, for all new connections (until overridden).
+   *
+   * <p>The proxy can be overridden by calling this method or {@link #setProxy} again, or unset by
+   * calling {@link #clearProxy}.
+   *
+   * @param username the username to authenticate with, or {@code null} for none
+   * @param password the password to authenticate with, or {@code null} for none
+   * @throws IOException if the host or port are not (structurally) valid, such as a port that
+   *     doesn't fit in u16.
+   */
+  public void setHttpProxy(String host, int port, String username, String password)
+      throws IOException {
+    this.connectionManager.setHttpProxy(host, port, username, password);
+  }
+
   /**
    * Ensures that future connections will be made directly, not through a proxy.
    *
-   * <p>Clears any proxy configuration set via {@link #setProxy}. If none was set, calling this
-   * method is a no-op.
+   * <p>Clears any proxy configuration set via {@link #setProxy}, {@link #setSocksProxy} or {@link
+   * #setHttpProxy}. If none was set, calling this method is a no-op.
    */
   public void clearProxy() {
     this.connectionManager.clearProxy();
   }
 
+  /**
+   * Remembers DNS results in a file at {@code path}, so that later runs can connect without waiting
+   * on DNS.
+   */
+  public void setDnsCachePath(String path) {
+    this.connectionManager.setDnsCachePath(path);
+  }
+
+  /**
+   * Forgets the DNS results remembered since {@link #setDnsCachePath} was called, including in the
+   * file.
+   */
+  public void clearDnsCache() {
+    this.connectionManager.clearDnsCache();
+  }
+
+  /**
+   * Returns the connection and request metrics recorded since the last call, as JSON, and starts
+   * over from zero.
+   *
+   * <p>The JSON has {@code routes} and {@code endpoints} fields; see libsignal-net's {@code
+   * MetricsSnapshot::to_json}.
+   */
+  public String takeMetricsSnapshot() {
+    return this.connectionManager.takeMetricsSnapshot();
+  }
+
   public Svr3 svr3() {
     return this.svr3;
   }
@@ -117,7 +194,13 @@ public class Network {
   }
 
   public ChatService createChatService(final String username, final String password) {
-    return new ChatService(tokioAsyncContext, connectionManager, username, password);
+    return createChatService(username, password, ChatService.Transport.WEB_SOCKET);
+  }
+
+  /** Like {@link #createChatService(String, String)}, but sends requests over {@code transport}. */
+  public ChatService createChatService(
+      final String username, final String password, final ChatService.Transport transport) {
+    return new ChatService(tokioAsyncContext, connectionManager, username, password, transport);
   }
 
   static class ConnectionManager extends NativeHandleGuard.SimpleOwner {
@@ -125,16 +208,58 @@ public class Network {
       super(Native.ConnectionManager_new(env.value, userAgent));
     }
 
+    private ConnectionManager(long nativeHandle) {
+      super(nativeHandle);
+    }
+
+    private static ConnectionManager forCustomEnvironment(
+        String config, String userAgent, String countryCode) throws IOException {
+      return new ConnectionManager(
+          filterExceptions(
+              IOException.class,
+              () -> Native.ConnectionManager_new_custom(config, userAgent, countryCode)));
+    }
+
     private void setProxy(String host, int port) throws IOException {
       filterExceptions(
           IOException.class,
           () -> guardedRunChecked(h -> Native.ConnectionManager_set_proxy(h, host, port)));
     }
 
+    private void setSocksProxy(String host, int port, String username, String password)
+        throws IOException {
+      filterExceptions(
+          IOException.class,
+          () ->
+              guardedRunChecked(
+                  h -> Native.ConnectionManager_set_socks_proxy(h, host, port, username, password)));
+    }
+
+    private void setHttpProxy(String host, int port, String username, String password)
+        throws IOException {
+      filterExceptions(
+          IOException.class,
+          () ->
+              guardedRunChecked(
+                  h -> Native.ConnectionManager_set_http_proxy(h, host, port, username, password)));
+    }
+
     private void clearProxy() {
       guardedRun(Native::ConnectionManager_clear_proxy);
     }
 
+    private void setDnsCachePath(String path) {
+      guardedRun(h -> Native.ConnectionManager_set_dns_cache_path(h, path));
+    }
+
+    private void clearDnsCache() {
+      guardedRun(Native::ConnectionManager_clear_dns_cache);
+    }
+
+    private String takeMetricsSnapshot() {
+      return guardedMap(Native::ConnectionManager_take_metrics_snapshot);
+    }
+
     @Override
     protected void release(final long nativeHandle) {
       Native.ConnectionManager_Destroy(nativeHandle);
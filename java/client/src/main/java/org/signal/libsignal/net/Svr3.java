@@ -118,7 +118,8 @@ public final class Svr3 {
    * @throws {@link org.signal.libsignal.svr.DataMissingException} when the maximum restore attempts
    *     number has been exceeded or if the value has never been backed up.
    * @throws {@link org.signal.libsignal.svr.RestoreFailedException} when the combination of the
-   *     password and masked share set does not result in successful restoration of the secret.
+   *     password and masked share set does not result in successful restoration of the secret. The
+   *     exception reports how many tries remain.
    * @throws {@link org.signal.libsignal.svr.SvrException} when the de-serialization of a masked
    *     share set fails, or when the server requests fail for reasons other than "maximum attempts
    *     exceeded".
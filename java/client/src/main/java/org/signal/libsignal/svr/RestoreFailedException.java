@@ -6,7 +6,15 @@
 package org.signal.libsignal.svr;
 
 public final class RestoreFailedException extends SvrException {
-  public RestoreFailedException(String message) {
+  private final int triesRemaining;
+
+  public RestoreFailedException(String message, int triesRemaining) {
     super(message);
+    this.triesRemaining = triesRemaining;
+  }
+
+  /** How many more restore attempts can be made before the backed-up secret is deleted. */
+  public int getTriesRemaining() {
+    return this.triesRemaining;
   }
 }
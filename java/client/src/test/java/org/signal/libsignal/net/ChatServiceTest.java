@@ -117,6 +117,37 @@ public class ChatServiceTest {
     chat.disconnect();
   }
 
+  @Test
+  public void testConnectUnauthOverHttp2() throws Exception {
+    final String PROXY_SERVER = TestEnvironment.get("LIBSIGNAL_TESTING_PROXY_SERVER");
+    Assume.assumeNotNull(PROXY_SERVER);
+
+    final Network net = new Network(Network.Environment.STAGING, USER_AGENT);
+    final ChatService chat = net.createChatService("", "", ChatService.Transport.HTTP2);
+    // Just make sure we can connect.
+    chat.connectUnauthenticated().get();
+    chat.disconnect();
+  }
+
+  @Test
+  public void testSupervisedConnection() throws Exception {
+    final String PROXY_SERVER = TestEnvironment.get("LIBSIGNAL_TESTING_PROXY_SERVER");
+    Assume.assumeNotNull(PROXY_SERVER);
+
+    final Network net = new Network(Network.Environment.STAGING, USER_AGENT);
+    final ChatService chat = net.createChatService("", "");
+    chat.startSupervised(false).get();
+
+    ChatService.ConnectionState state = ChatService.ConnectionState.DISCONNECTED;
+    while (state != ChatService.ConnectionState.CONNECTED) {
+      state = chat.nextConnectionState(state).get();
+    }
+    chat.networkChanged();
+
+    chat.stopSupervised();
+    assertEquals(ChatService.ConnectionState.DISCONNECTED, chat.nextConnectionState(state).get());
+  }
+
   @Test
   public void testConnectUnauthThroughProxy() throws Exception {
     final String PROXY_SERVER = TestEnvironment.get("LIBSIGNAL_TESTING_PROXY_SERVER");
@@ -156,4 +187,30 @@ public class ChatServiceTest {
         assertThrows(ExecutionException.class, () -> chat.connectUnauthenticated().get());
     assertTrue(failure.getCause().toString(), failure.getCause() instanceof ChatServiceException);
   }
+
+  @Test
+  public void testConnectFailsWithInvalidSocksOrHttpProxy() throws Exception {
+    final Network net = new Network(Network.Environment.PRODUCTION, USER_AGENT);
+    assertThrows(
+        IOException.class, () -> net.setSocksProxy("signalfoundation.org", 0, null, null));
+    assertThrows(
+        IOException.class,
+        () -> net.setHttpProxy("signalfoundation.org", 100_000, "user", "pass"));
+
+    final ChatService chat = net.createChatService("", "");
+    final ExecutionException failure =
+        assertThrows(ExecutionException.class, () -> chat.connectUnauthenticated().get());
+    assertTrue(failure.getCause().toString(), failure.getCause() instanceof ChatServiceException);
+  }
+
+  @Test
+  public void testNextConnectionStateWithoutSupervision() throws Exception {
+    final Network net = new Network(Network.Environment.STAGING, USER_AGENT);
+    final ChatService chat = net.createChatService("", "");
+    chat.networkChanged();
+    chat.stopSupervised();
+    assertEquals(
+        ChatService.ConnectionState.DISCONNECTED,
+        chat.nextConnectionState(ChatService.ConnectionState.CONNECTED).get());
+  }
 }
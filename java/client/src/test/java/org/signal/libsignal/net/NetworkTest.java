@@ -0,0 +1,72 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+package org.signal.libsignal.net;
+
+import static org.junit.Assert.*;
+
+import java.io.File;
+import java.io.IOException;
+import org.junit.Test;
+
+public class NetworkTest {
+  private static final String USER_AGENT = "test";
+
+  private static final String SGX_MEASUREMENT = "00".repeat(32);
+
+  private static String domain(String hostname) {
+    return "{\"hostname\": \"" + hostname + "\", \"proxy_path\": \"/proxy\", \"cert\": \"signal\"}";
+  }
+
+  private static String enclave(String hostname, String measurement) {
+    return "{\"domain\": " + domain(hostname) + ", \"mr_enclave\": \"" + measurement + "\"}";
+  }
+
+  private static final String CUSTOM_CONFIG =
+      "{"
+          + ("\"chat\": " + domain("chat.example.org") + ",")
+          + ("\"cdsi\": " + enclave("cdsi.example.org", SGX_MEASUREMENT) + ",")
+          + ("\"svr2\": " + enclave("svr2.example.org", SGX_MEASUREMENT) + ",")
+          + "\"svr3\": {"
+          + ("\"sgx\": " + enclave("svr3-sgx.example.org", SGX_MEASUREMENT) + ",")
+          + ("\"nitro\": " + enclave("svr3-nitro.example.org", "1.2.3") + ",")
+          + ("\"tpm2snp\": " + enclave("svr3-tpm2snp.example.org", "1.2.3"))
+          + "},"
+          + "\"domain_fronts\": [{"
+          + "\"sni_list\": [\"front.example.net\"],"
+          + "\"host\": \"signal.cdn.example.net\","
+          + "\"enabled_countries\": [\"AA\"]"
+          + "}]"
+          + "}";
+
+  @Test
+  public void testCustomEnvironment() throws Exception {
+    Network.forCustomEnvironment(CUSTOM_CONFIG, null, USER_AGENT);
+    Network.forCustomEnvironment(CUSTOM_CONFIG, "AA", USER_AGENT);
+  }
+
+  @Test
+  public void testInvalidCustomEnvironment() {
+    assertThrows(IOException.class, () -> Network.forCustomEnvironment("{}", null, USER_AGENT));
+  }
+
+  @Test
+  public void testMetricsStartEmpty() {
+    final Network net = new Network(Network.Environment.STAGING, USER_AGENT);
+    final String snapshot = net.takeMetricsSnapshot();
+    assertTrue(snapshot, snapshot.contains("\"routes\":[]"));
+    assertTrue(snapshot, snapshot.contains("\"endpoints\":{}"));
+  }
+
+  @Test
+  public void testDnsCache() throws Exception {
+    final File cacheFile = File.createTempFile("libsignal-dns", null);
+    cacheFile.deleteOnExit();
+
+    final Network net = new Network(Network.Environment.STAGING, USER_AGENT);
+    net.setDnsCachePath(cacheFile.getPath());
+    net.clearDnsCache();
+  }
+}
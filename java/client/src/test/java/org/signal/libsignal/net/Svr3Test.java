@@ -84,6 +84,7 @@ public class Svr3Test {
     } catch (ExecutionException ex) {
       Throwable cause = ex.getCause();
       assertTrue("Unexpected exception: " + cause, cause instanceof RestoreFailedException);
+      assertEquals(0, ((RestoreFailedException) cause).getTriesRemaining());
     }
   }
 
@@ -169,16 +169,26 @@ public final class Native {
   public static native CompletableFuture<Object> ChatService_connect_auth(long asyncRuntime, long chat);
   public static native CompletableFuture<Object> ChatService_connect_unauth(long asyncRuntime, long chat);
   public static native CompletableFuture ChatService_disconnect(long asyncRuntime, long chat);
-  public static native long ChatService_new(long connectionManager, String username, String password);
+  public static native void ChatService_network_changed(long chat);
+  public static native long ChatService_new(long connectionManager, String username, String password, int transport);
+  public static native CompletableFuture<Integer> ChatService_next_connection_state(long asyncRuntime, long chat, int lastState);
+  public static native CompletableFuture ChatService_start_supervised(long asyncRuntime, long chat, boolean authenticated);
+  public static native void ChatService_stop_supervised(long chat);
   public static native CompletableFuture<Object> ChatService_unauth_send(long asyncRuntime, long chat, long httpRequest, int timeoutMillis);
   public static native CompletableFuture<Object> ChatService_unauth_send_and_debug(long asyncRuntime, long chat, long httpRequest, int timeoutMillis);
 
   public static native void Chat_Destroy(long handle);
 
   public static native void ConnectionManager_Destroy(long handle);
+  public static native void ConnectionManager_clear_dns_cache(long connectionManager);
   public static native void ConnectionManager_clear_proxy(long connectionManager);
   public static native long ConnectionManager_new(int environment, String userAgent);
+  public static native long ConnectionManager_new_custom(String config, String userAgent, String countryCode) throws Exception;
+  public static native void ConnectionManager_set_dns_cache_path(long connectionManager, String path);
+  public static native void ConnectionManager_set_http_proxy(long connectionManager, String host, int port, String username, String password) throws Exception;
   public static native void ConnectionManager_set_proxy(long connectionManager, String host, int port) throws Exception;
+  public static native void ConnectionManager_set_socks_proxy(long connectionManager, String host, int port, String username, String password) throws Exception;
+  public static native String ConnectionManager_take_metrics_snapshot(long connectionManager);
 
   public static native void CreateCallLinkCredentialPresentation_CheckValidContents(byte[] presentationBytes) throws Exception;
   public static native void CreateCallLinkCredentialPresentation_Verify(byte[] presentationBytes, byte[] roomId, long now, byte[] serverParamsBytes, byte[] callLinkParamsBytes) throws Exception;
@@ -173,16 +173,26 @@ export function ChatService_auth_send_and_debug(asyncRuntime: Wrapper<TokioAsync
 export function ChatService_connect_auth(asyncRuntime: Wrapper<TokioAsyncContext>, chat: Wrapper<Chat>): Promise<ChatServiceDebugInfo>;
 export function ChatService_connect_unauth(asyncRuntime: Wrapper<TokioAsyncContext>, chat: Wrapper<Chat>): Promise<ChatServiceDebugInfo>;
 export function ChatService_disconnect(asyncRuntime: Wrapper<TokioAsyncContext>, chat: Wrapper<Chat>): Promise<void>;
-export function ChatService_new(connectionManager: Wrapper<ConnectionManager>, username: string, password: string): Chat;
+export function ChatService_network_changed(chat: Wrapper<Chat>): void;
+export function ChatService_new(connectionManager: Wrapper<ConnectionManager>, username: string, password: string, transport: number): Chat;
+export function ChatService_next_connection_state(asyncRuntime: Wrapper<TokioAsyncContext>, chat: Wrapper<Chat>, lastState: number): Promise<number>;
+export function ChatService_start_supervised(asyncRuntime: Wrapper<TokioAsyncContext>, chat: Wrapper<Chat>, authenticated: boolean): Promise<void>;
+export function ChatService_stop_supervised(chat: Wrapper<Chat>): void;
 export function ChatService_unauth_send(asyncRuntime: Wrapper<TokioAsyncContext>, chat: Wrapper<Chat>, httpRequest: Wrapper<HttpRequest>, timeoutMillis: number): Promise<ChatResponse>;
 export function ChatService_unauth_send_and_debug(asyncRuntime: Wrapper<TokioAsyncContext>, chat: Wrapper<Chat>, httpRequest: Wrapper<HttpRequest>, timeoutMillis: number): Promise<ResponseAndDebugInfo>;
 export function CiphertextMessage_FromPlaintextContent(m: Wrapper<PlaintextContent>): CiphertextMessage;
 export function CiphertextMessage_Serialize(obj: Wrapper<CiphertextMessage>): Buffer;
 export function CiphertextMessage_Type(msg: Wrapper<CiphertextMessage>): number;
+export function ConnectionManager_clear_dns_cache(connectionManager: Wrapper<ConnectionManager>): void;
 export function ConnectionManager_clear_proxy(connectionManager: Wrapper<ConnectionManager>): void;
 export function ConnectionManager_new(environment: number, userAgent: string): ConnectionManager;
+export function ConnectionManager_new_custom(config: string, userAgent: string, countryCode: string | null): ConnectionManager;
+export function ConnectionManager_set_dns_cache_path(connectionManager: Wrapper<ConnectionManager>, path: string): void;
+export function ConnectionManager_set_http_proxy(connectionManager: Wrapper<ConnectionManager>, host: string, port: number, username: string | null, password: string | null): void;
 export function ConnectionManager_set_ipv6_enabled(connectionManager: Wrapper<ConnectionManager>, ipv6Enabled: boolean): void;
 export function ConnectionManager_set_proxy(connectionManager: Wrapper<ConnectionManager>, host: string, port: number): void;
+export function ConnectionManager_set_socks_proxy(connectionManager: Wrapper<ConnectionManager>, host: string, port: number, username: string | null, password: string | null): void;
+export function ConnectionManager_take_metrics_snapshot(connectionManager: Wrapper<ConnectionManager>): string;
 export function CreateCallLinkCredentialPresentation_CheckValidContents(presentationBytes: Buffer): void;
 export function CreateCallLinkCredentialPresentation_Verify(presentationBytes: Buffer, roomId: Buffer, now: Timestamp, serverParamsBytes: Buffer, callLinkParamsBytes: Buffer): void;
 export function CreateCallLinkCredentialRequestContext_CheckValidContents(contextBytes: Buffer): void;
@@ -23,6 +23,37 @@ export enum Environment {
   Production = 1,
 }
 
+/**
+ * A deployment other than {@link Environment.Staging} or {@link Environment.Production}.
+ */
+export type CustomEnvironment = Readonly<{
+  /** The deployment's endpoints, in the JSON format described by libsignal-net's `EnvConfig`. */
+  config: string;
+  /**
+   * The ISO 3166-1 alpha-2 code of the client's country, if known.
+   *
+   * Decides whether the deployment's domain fronts are used.
+   */
+  countryCode?: string;
+}>;
+
+// This must match the libsignal-bridge Rust enum ChatTransportKind.
+export enum ChatTransport {
+  /** A long-lived WebSocket, over which the server can also send requests. */
+  WebSocket = 0,
+  /** One HTTP/2 request per chat request; the server can't send requests. */
+  Http2 = 1,
+}
+
+// This must match connection_state_code in libsignal-bridge.
+export enum ConnectionState {
+  Disconnected = 0,
+  Connecting = 1,
+  Connected = 2,
+  /** Waiting before trying to connect again. */
+  BackingOff = 3,
+}
+
 export type ServiceAuth = {
   username: string;
   password: string;
@@ -78,10 +109,11 @@ export class ChatService {
 
   constructor(
     private readonly asyncContext: TokioAsyncContext,
-    connectionManager: ConnectionManager
+    connectionManager: ConnectionManager,
+    transport: ChatTransport = ChatTransport.WebSocket
   ) {
     this.chatService = newNativeHandle(
-      Native.ChatService_new(connectionManager, '', '')
+      Native.ChatService_new(connectionManager, '', '', transport)
     );
   }
 
@@ -198,6 +230,52 @@ export class ChatService {
       chatRequest.timeoutMillis ?? DEFAULT_CHAT_REQUEST_TIMEOUT_MILLIS
     );
   }
+
+  /**
+   * Keeps a connection to the Chat Service open, reconnecting with backoff whenever it's lost,
+   * until {@link #stopSupervised()} is called.
+   *
+   * Calling this again replaces the previous supervision.
+   */
+  async startSupervised(authenticated: boolean): Promise<void> {
+    await Native.ChatService_start_supervised(
+      this.asyncContext,
+      this.chatService,
+      authenticated
+    );
+  }
+
+  /**
+   * Stops the supervision started by {@link #startSupervised()}.
+   */
+  stopSupervised(): void {
+    Native.ChatService_stop_supervised(this.chatService);
+  }
+
+  /**
+   * Tells a supervised connection that the network changed, so that it reconnects right away
+   * instead of waiting out its backoff.
+   */
+  networkChanged(): void {
+    Native.ChatService_network_changed(this.chatService);
+  }
+
+  /**
+   * Waits for the supervised connection's state to be something other than `lastState`.
+   *
+   * Resolves to {@link ConnectionState.Disconnected} right away if the connection isn't being
+   * supervised, and once supervision stops.
+   */
+  async nextConnectionState(
+    lastState: ConnectionState
+  ): Promise<ConnectionState> {
+    return (await Native.ChatService_next_connection_state(
+      this.asyncContext,
+      this.chatService,
+      lastState
+    )) as ConnectionState;
+  }
+
   static buildHttpRequest(chatRequest: ChatRequest): {
     _nativeHandle: Native.HttpRequest;
   } {
@@ -224,19 +302,32 @@ export class Net {
    */
   svr3: Svr3Client;
 
-  constructor(env: Environment, userAgent: string) {
+  /**
+   * Throws if `env` is a {@link CustomEnvironment} with an invalid config.
+   */
+  constructor(env: Environment | CustomEnvironment, userAgent: string) {
     this.asyncContext = newNativeHandle(Native.TokioAsyncContext_new());
     this.connectionManager = newNativeHandle(
-      Native.ConnectionManager_new(env, userAgent)
+      typeof env === 'object'
+        ? Native.ConnectionManager_new_custom(
+            env.config,
+            userAgent,
+            env.countryCode ?? null
+          )
+        : Native.ConnectionManager_new(env, userAgent)
     );
     this.svr3 = new Svr3ClientImpl(this.asyncContext, this.connectionManager);
   }
 
   /**
    * Creates a new instance of {@link ChatService}.
+   *
+   * Requests are sent over a WebSocket unless another `transport` is given.
    */
-  public newChatService(): ChatService {
-    return new ChatService(this.asyncContext, this.connectionManager);
+  public newChatService(
+    transport: ChatTransport = ChatTransport.WebSocket
+  ): ChatService {
+    return new ChatService(this.asyncContext, this.connectionManager, transport);
   }
 
   /**
@@ -264,16 +355,90 @@ export class Net {
     Native.ConnectionManager_set_proxy(this.connectionManager, host, port);
   }
 
+  /**
+   * Sets a SOCKS5 proxy to be used for all new connections (until overridden).
+   *
+   * The proxy can be overridden by calling this method or {@link #setProxy} again, or unset by
+   * calling {@link #clearProxy}.
+   *
+   * Throws if the host or port is structurally invalid, such as a port that doesn't fit in u16.
+   */
+  setSocksProxy(
+    host: string,
+    port: number,
+    username?: string,
+    password?: string
+  ): void {
+    Native.ConnectionManager_set_socks_proxy(
+      this.connectionManager,
+      host,
+      port,
+      username ?? null,
+      password ?? null
+    );
+  }
+
+  /**
+   * Sets an HTTP proxy, used with `CONNECT`, for all new connections (until overridden).
+   *
+   * The proxy can be overridden by calling this method or {@link #setProxy} again, or unset by
+   * calling {@link #clearProxy}.
+   *
+   * Throws if the host or port is structurally invalid, such as a port that doesn't fit in u16.
+   */
+  setHttpProxy(
+    host: string,
+    port: number,
+    username?: string,
+    password?: string
+  ): void {
+    Native.ConnectionManager_set_http_proxy(
+      this.connectionManager,
+      host,
+      port,
+      username ?? null,
+      password ?? null
+    );
+  }
+
   /**
    * Ensures that future connections will be made directly, not through a proxy.
    *
-   * Clears any proxy configuration set via {@link #setProxy}. If none was set, calling this
-   * method is a no-op.
+   * Clears any proxy configuration set via {@link #setProxy}, {@link #setSocksProxy} or
+   * {@link #setHttpProxy}. If none was set, calling this method is a no-op.
    */
   clearProxy(): void {
     Native.ConnectionManager_clear_proxy(this.connectionManager);
   }
 
+  /**
+   * Remembers DNS results in a file at `path`, so that later runs can connect without waiting on
+   * DNS.
+   */
+  setDnsCachePath(path: string): void {
+    Native.ConnectionManager_set_dns_cache_path(this.connectionManager, path);
+  }
+
+  /**
+   * Forgets the DNS results remembered since {@link #setDnsCachePath} was called, including in
+   * the file.
+   */
+  clearDnsCache(): void {
+    Native.ConnectionManager_clear_dns_cache(this.connectionManager);
+  }
+
+  /**
+   * Returns the connection and request metrics recorded since the last call, as JSON, and starts
+   * over from zero.
+   *
+   * The JSON has `routes` and `endpoints` fields; see libsignal-net's `MetricsSnapshot::to_json`.
+   */
+  takeMetricsSnapshot(): string {
+    return Native.ConnectionManager_take_metrics_snapshot(
+      this.connectionManager
+    );
+  }
+
   async cdsiLookup(
     { username, password }: Readonly<ServiceAuth>,
     {
@@ -407,7 +572,8 @@ export interface Svr3Client {
    * number has been exceeded or if the value has never been backed up.
    * - {@link SvrRestoreFailedError} is returned when the combination of the
    * password and masked share set does not result in successful restoration
-   * of the secret.
+   * of the secret. Its `triesRemaining` property says how many more attempts
+   * can be made.
    * - {@link SvrRequestFailedError} is returned when the de-serialization of a
    * masked share set fails, or when the server requests fail for reasons
    * other than "maximum attempts exceeded".
@@ -9,8 +9,17 @@ import * as util from './util';
 import { Aci, Pni } from '../Address';
 import * as Native from '../../Native';
 import { ErrorCode, LibSignalErrorBase } from '../Errors';
-import { ChatService, Environment, Net, ServiceAuth } from '../net';
+import {
+  ChatService,
+  ChatTransport,
+  ConnectionState,
+  Environment,
+  Net,
+  ServiceAuth,
+} from '../net';
 import { randomBytes } from 'crypto';
+import * as os from 'os';
+import * as path from 'path';
 import { ChatResponse } from '../../Native';
 
 use(chaiAsPromised);
@@ -137,6 +146,30 @@ describe('chat service api', () => {
       await chatService.disconnect();
     }).timeout(10000);
 
+    it('can connect unauthenticated over HTTP/2', async () => {
+      const net = new Net(Environment.Staging, userAgent);
+      const chatService = net.newChatService(ChatTransport.Http2);
+      await chatService.connectUnauthenticated();
+      await chatService.disconnect();
+    }).timeout(10000);
+
+    it('can keep a supervised connection', async () => {
+      const net = new Net(Environment.Staging, userAgent);
+      const chatService = net.newChatService();
+      await chatService.startSupervised(false);
+
+      let state = ConnectionState.Disconnected;
+      while (state !== ConnectionState.Connected) {
+        state = await chatService.nextConnectionState(state);
+      }
+      chatService.networkChanged();
+
+      chatService.stopSupervised();
+      expect(await chatService.nextConnectionState(state)).equals(
+        ConnectionState.Disconnected
+      );
+    }).timeout(20000);
+
     it('can connect through a proxy server', async () => {
       const PROXY_SERVER = process.env.LIBSIGNAL_TESTING_PROXY_SERVER;
       assert(PROXY_SERVER, 'checked above');
@@ -166,6 +199,86 @@ describe('chat service api', () => {
       LibSignalErrorBase
     );
   }).timeout(10000);
+
+  it('cannot connect through an invalid SOCKS or HTTP proxy', async () => {
+    const net = new Net(Environment.Production, userAgent);
+    expect(() => net.setSocksProxy('signalfoundation.org', 0)).throws(Error);
+    expect(() =>
+      net.setHttpProxy('signalfoundation.org', 100_000, 'user', 'pass')
+    ).throws(Error);
+
+    const chatService = net.newChatService();
+    await expect(chatService.connectUnauthenticated()).to.be.rejectedWith(
+      LibSignalErrorBase
+    );
+  }).timeout(10000);
+
+  it('reports disconnected when not supervised', async () => {
+    const net = new Net(Environment.Staging, userAgent);
+    const chatService = net.newChatService();
+    chatService.networkChanged();
+    chatService.stopSupervised();
+    expect(
+      await chatService.nextConnectionState(ConnectionState.Connected)
+    ).equals(ConnectionState.Disconnected);
+  });
+});
+
+describe('connection manager', () => {
+  const sgxMeasurement = '00'.repeat(32);
+  const domain = (hostname: string) => ({
+    hostname,
+    proxy_path: '/proxy',
+    cert: 'signal',
+  });
+  const enclave = (hostname: string, measurement: string) => ({
+    domain: domain(hostname),
+    mr_enclave: measurement,
+  });
+  const customConfig = JSON.stringify({
+    chat: domain('chat.example.org'),
+    cdsi: enclave('cdsi.example.org', sgxMeasurement),
+    svr2: enclave('svr2.example.org', sgxMeasurement),
+    svr3: {
+      sgx: enclave('svr3-sgx.example.org', sgxMeasurement),
+      nitro: enclave('svr3-nitro.example.org', '1.2.3'),
+      tpm2snp: enclave('svr3-tpm2snp.example.org', '1.2.3'),
+    },
+    domain_fronts: [
+      {
+        sni_list: ['front.example.net'],
+        host: 'signal.cdn.example.net',
+        enabled_countries: ['AA'],
+      },
+    ],
+  });
+
+  it('can be created for a custom environment', () => {
+    expect(() => new Net({ config: customConfig }, userAgent)).not.throws();
+    expect(
+      () => new Net({ config: customConfig, countryCode: 'AA' }, userAgent)
+    ).not.throws();
+  });
+
+  it('rejects an invalid custom environment', () => {
+    expect(() => new Net({ config: '{}' }, userAgent)).throws(Error);
+  });
+
+  it('starts with empty metrics', () => {
+    const net = new Net(Environment.Staging, userAgent);
+    expect(JSON.parse(net.takeMetricsSnapshot())).deep.equals({
+      routes: [],
+      endpoints: {},
+    });
+  });
+
+  it('can set and clear the DNS cache', () => {
+    const net = new Net(Environment.Staging, userAgent);
+    net.setDnsCachePath(
+      path.join(os.tmpdir(), `libsignal-dns-${randomBytes(8).toString('hex')}`)
+    );
+    net.clearDnsCache();
+  });
 });
 
 describe('cdsi lookup', () => {
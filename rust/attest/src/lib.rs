@@ -11,6 +11,7 @@ pub mod enclave;
 pub mod hsm_enclave;
 pub mod ias;
 pub mod nitro;
+pub mod proto;
 pub mod sgx_session;
 pub mod svr2;
 pub mod tpm2snp;
@@ -19,6 +20,5 @@ mod cert_chain;
 mod endian;
 mod error;
 mod expireable;
-mod proto;
 mod snow_resolver;
 mod util;
@@ -5,5 +5,5 @@
 
 pub(crate) mod cds2;
 pub(crate) mod svr;
-pub(crate) mod svr2;
+pub mod svr2;
 pub(crate) mod svr3;
@@ -240,7 +240,7 @@ impl From<Svr3Error> for SignalFfiError {
             Svr3Error::ConnectionTimedOut => SignalFfiError::ConnectionTimedOut,
             Svr3Error::AttestationError(inner) => SignalFfiError::Sgx(inner),
             Svr3Error::Protocol(inner) => SignalFfiError::NetworkProtocol(inner.to_string()),
-            Svr3Error::RequestFailed(_) | Svr3Error::RestoreFailed | Svr3Error::DataMissing => {
+            Svr3Error::RequestFailed(_) | Svr3Error::RestoreFailed(_) | Svr3Error::DataMissing => {
                 SignalFfiError::Svr(err)
             }
         }
@@ -253,7 +253,8 @@ impl From<ChatServiceError> for SignalFfiError {
             ChatServiceError::WebSocket(e) => SignalFfiError::WebSocket(e),
             ChatServiceError::AllConnectionRoutesFailed { attempts: _ }
             | ChatServiceError::ServiceUnavailable => SignalFfiError::ConnectionFailed,
-            ChatServiceError::UnexpectedFrameReceived
+            ChatServiceError::Http(_)
+            | ChatServiceError::UnexpectedFrameReceived
             | ChatServiceError::ServerRequestMissingId
             | ChatServiceError::IncomingDataInvalid => {
                 SignalFfiError::NetworkProtocol(err.to_string())
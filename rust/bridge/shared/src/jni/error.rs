@@ -299,7 +299,7 @@ impl From<Svr3Error> for SignalJniError {
             Svr3Error::AttestationError(inner) => inner.into(),
             Svr3Error::Protocol(_)
             | Svr3Error::RequestFailed(_)
-            | Svr3Error::RestoreFailed
+            | Svr3Error::RestoreFailed(_)
             | Svr3Error::DataMissing => SignalJniError::Svr3(err),
         }
     }
@@ -253,6 +253,25 @@ where
             return;
         }
 
+        SignalJniError::Svr3(Svr3Error::RestoreFailed(tries_remaining)) => {
+            let throwable = env
+                .new_string(error.to_string())
+                .map_err(Into::into)
+                .and_then(|message| {
+                    new_instance(
+                        env,
+                        ClassName("org.signal.libsignal.svr.RestoreFailedException"),
+                        jni_args!((
+                            message => java.lang.String,
+                            tries_remaining as jint => int,
+                        ) -> void),
+                    )
+                });
+
+            consume(env, throwable.map(Into::into), &error);
+            return;
+        }
+
         SignalJniError::Bridge(BridgeLayerError::UnexpectedPanic(_))
         | SignalJniError::Bridge(BridgeLayerError::BadJniParameter(_))
         | SignalJniError::Bridge(BridgeLayerError::UnexpectedJniResultType(_, _)) => {
@@ -519,10 +538,6 @@ where
             error,
         ),
 
-        SignalJniError::Svr3(Svr3Error::RestoreFailed) => (
-            ClassName("org.signal.libsignal.svr.RestoreFailedException"),
-            error,
-        ),
         SignalJniError::Svr3(Svr3Error::DataMissing) => (
             ClassName("org.signal.libsignal.svr.DataMissingException"),
             error,
@@ -17,21 +17,25 @@ use http::uri::{InvalidUri, PathAndQuery};
 use http::{HeaderMap, HeaderName, HeaderValue};
 use libsignal_bridge_macros::{bridge_fn, bridge_io};
 use libsignal_net::auth::Auth;
+use libsignal_net::chat::supervisor::{ChatSupervisor, ConnectionState, ReconnectBackoff};
 use libsignal_net::chat::{
-    chat_service, ChatServiceError, ChatServiceWithDebugInfo, DebugInfo as ChatServiceDebugInfo,
-    Request, Response as ChatResponse,
+    chat_service, ChatServiceError, ChatServiceWithDebugInfo, ChatTransport, ChatTransportSelector,
+    DebugInfo as ChatServiceDebugInfo, Request, Response as ChatResponse,
 };
 use libsignal_net::enclave::{
     Cdsi, EnclaveEndpoint, EnclaveEndpointConnection, EnclaveKind, Nitro, PpssSetup, Sgx, Tpm2Snp,
 };
-use libsignal_net::env::{add_user_agent_header, Env, Svr3Env};
-use libsignal_net::infra::connection_manager::MultiRouteConnectionManager;
+use libsignal_net::env::config::EnvConfig;
+use libsignal_net::env::{add_user_agent_header, DomainConfig, Env, Svr3Env};
+use libsignal_net::infra::connection_manager::{MultiRouteConnectionManager, RouteAttemptStrategy};
+use libsignal_net::infra::dns::persistent_cache::{FileDnsCacheStore, PersistentDnsCache};
 use libsignal_net::infra::dns::DnsResolver;
 use libsignal_net::infra::tcp_ssl::{
-    DirectConnector as TcpSslDirectConnector, ProxyConnector as TcpSslProxyConnector,
-    TcpSslConnector, TcpSslConnectorStream,
+    DirectConnector as TcpSslDirectConnector, HttpConnectConnector, ProxyAuth,
+    ProxyConnector as TcpSslProxyConnector, SocksConnector, TcpSslConnector, TcpSslConnectorStream,
 };
-use libsignal_net::infra::{make_ws_config, EndpointConnection};
+use libsignal_net::infra::{make_ws_config, ConnectionParams, EndpointConnection};
+use libsignal_net::metrics::{MeteredConnector, Metrics};
 use libsignal_net::svr::{self, SvrConnection};
 use libsignal_net::svr3::{self, OpaqueMaskedShareSet, PpssOps as _};
 use libsignal_net::{chat, env};
@@ -62,6 +66,24 @@ impl Environment {
     }
 }
 
+/// The transport a [`Chat`] uses to reach the Chat Service.
+#[derive(num_enum::TryFromPrimitive)]
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum ChatTransportKind {
+    WebSocket = 0,
+    Http2 = 1,
+}
+
+impl From<ChatTransportKind> for ChatTransport {
+    fn from(kind: ChatTransportKind) -> Self {
+        match kind {
+            ChatTransportKind::WebSocket => Self::WebSocket,
+            ChatTransportKind::Http2 => Self::Http2,
+        }
+    }
+}
+
 pub struct ConnectionManager {
     chat: EndpointConnection<MultiRouteConnectionManager>,
     cdsi: EnclaveEndpointConnection<Cdsi, MultiRouteConnectionManager>,
@@ -71,49 +93,76 @@ pub struct ConnectionManager {
         EnclaveEndpointConnection<Tpm2Snp, MultiRouteConnectionManager>,
     ),
     transport_connector: std::sync::Mutex<TcpSslConnector>,
+    metrics: Metrics,
 }
 
 impl RefUnwindSafe for ConnectionManager {}
 
 impl ConnectionManager {
     const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+    /// How long to wait on a chat route before also trying the next one.
+    const CHAT_ROUTE_STAGGER_DELAY: Duration = Duration::from_secs(2);
     fn new(environment: Environment, user_agent: String) -> Self {
-        let dns_resolver =
-            DnsResolver::new_with_static_fallback(environment.env().static_fallback());
+        Self::with_env(&environment.env(), user_agent, None)
+    }
+
+    /// `country_code` is the ISO 3166-1 alpha-2 code of the client's country, if known. It
+    /// decides whether the environment's domain fronts are used.
+    fn with_env(
+        net_env: &Env<'static, Svr3Env<'static>>,
+        user_agent: String,
+        country_code: Option<&str>,
+    ) -> Self {
+        let dns_resolver = DnsResolver::new_with_static_fallback(net_env.static_fallback());
         let transport_connector =
             std::sync::Mutex::new(TcpSslDirectConnector::new(dns_resolver).into());
+        let connection_params = |domain_config: &DomainConfig| {
+            add_user_agent_header(
+                domain_config.connection_params_for_country(net_env.domain_fronts, country_code),
+                &user_agent,
+            )
+        };
         let chat_endpoint = PathAndQuery::from_static(env::constants::WEB_SOCKET_PATH);
-        let chat_connection_params = environment
-            .env()
-            .chat_domain_config
-            .connection_params_with_fallback();
-        let chat_connection_params = add_user_agent_header(chat_connection_params, &user_agent);
+        let chat_connection_params = connection_params(&net_env.chat_domain_config);
         let chat_ws_config = make_ws_config(chat_endpoint, Self::DEFAULT_CONNECT_TIMEOUT);
+        let mut chat = EndpointConnection::new_multi(
+            chat_connection_params,
+            Self::DEFAULT_CONNECT_TIMEOUT,
+            chat_ws_config,
+        );
+        chat.manager = chat.manager.with_strategy(RouteAttemptStrategy::Staggered {
+            delay: Self::CHAT_ROUTE_STAGGER_DELAY,
+        });
         Self {
-            chat: EndpointConnection::new_multi(
-                chat_connection_params,
-                Self::DEFAULT_CONNECT_TIMEOUT,
-                chat_ws_config,
-            ),
-            cdsi: Self::endpoint_connection(&environment.env().cdsi, &user_agent),
+            chat,
+            cdsi: Self::endpoint_connection(&net_env.cdsi, &connection_params),
             svr3: (
-                Self::endpoint_connection(environment.env().svr3.sgx(), &user_agent),
-                Self::endpoint_connection(environment.env().svr3.nitro(), &user_agent),
-                Self::endpoint_connection(environment.env().svr3.tpm2snp(), &user_agent),
+                Self::endpoint_connection(net_env.svr3.sgx(), &connection_params),
+                Self::endpoint_connection(net_env.svr3.nitro(), &connection_params),
+                Self::endpoint_connection(net_env.svr3.tpm2snp(), &connection_params),
             ),
             transport_connector,
+            metrics: Metrics::new(),
         }
     }
 
+    /// The current transport connector, recording into this connection manager's metrics.
+    pub(crate) fn metered_transport_connector(&self) -> MeteredConnector<TcpSslConnector> {
+        let transport_connector = self
+            .transport_connector
+            .lock()
+            .expect("not poisoned")
+            .clone();
+        MeteredConnector::new(transport_connector, self.metrics.clone())
+    }
+
     fn endpoint_connection<E: EnclaveKind>(
         endpoint: &EnclaveEndpoint<'static, E>,
-        user_agent: &str,
+        connection_params: impl Fn(&DomainConfig) -> Vec<ConnectionParams>,
     ) -> EnclaveEndpointConnection<E, MultiRouteConnectionManager> {
-        let params = endpoint.domain_config.connection_params_with_fallback();
-        let params = add_user_agent_header(params, user_agent);
         EnclaveEndpointConnection::new_multi(
             endpoint.mr_enclave,
-            params,
+            connection_params(&endpoint.domain_config),
             Self::DEFAULT_CONNECT_TIMEOUT,
         )
     }
@@ -127,16 +176,45 @@ fn ConnectionManager_new(
     ConnectionManager::new(environment.into_inner(), user_agent)
 }
 
+/// Creates a connection manager for a custom deployment, described by `config` in the JSON format
+/// of [EnvConfig].
+///
+/// Each distinct config is kept for the life of the process, so creating many connection managers
+/// for the same deployment doesn't use more memory each time.
+///
+/// If `country_code` is set, connections go through the deployment's domain fronts that are
+/// enabled in that country.
 #[bridge_fn]
-fn ConnectionManager_set_proxy(
+fn ConnectionManager_new_custom(
+    config: String,
+    user_agent: String,
+    country_code: Option<String>,
+) -> Result<ConnectionManager, std::io::Error> {
+    let net_env = EnvConfig::from_json(&config)
+        .and_then(|config| config.build())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    Ok(ConnectionManager::with_env(
+        net_env,
+        user_agent,
+        country_code.as_deref(),
+    ))
+}
+
+/// Replaces the connection manager's transport connector with one built from `port` and the
+/// existing DNS resolver.
+///
+/// If `port` isn't valid, all future connections will fail until a proxy is set successfully or
+/// cleared, so that we never silently fall back to a direct connection.
+fn set_proxy_connector(
     connection_manager: &ConnectionManager,
-    host: String,
     port: i32,
+    make_connector: impl FnOnce(DnsResolver, NonZeroU16) -> TcpSslConnector,
 ) -> Result<(), std::io::Error> {
     let mut guard = connection_manager
         .transport_connector
         .lock()
         .expect("not poisoned");
+    let dns_resolver = guard.dns_resolver().clone();
     // We take port as an i32 because Java 'short' is signed and thus can't represent all port
     // numbers, and we want too-large port numbers to be handled the same way as 0.
     match u16::try_from(port)
@@ -144,42 +222,71 @@ fn ConnectionManager_set_proxy(
         .and_then(|port| NonZeroU16::try_from(port).ok())
     {
         Some(port) => {
-            let proxy_addr = (host.as_str(), port);
-            match &mut *guard {
-                TcpSslConnector::Direct(direct) => *guard = direct.with_proxy(proxy_addr).into(),
-                TcpSslConnector::Proxied(proxied) => proxied.set_proxy(proxy_addr),
-                TcpSslConnector::Invalid(dns_resolver) => {
-                    *guard = TcpSslProxyConnector::new(dns_resolver.clone(), proxy_addr).into()
-                }
-            };
+            *guard = make_connector(dns_resolver, port);
             Ok(())
         }
         None => {
-            match &*guard {
-                TcpSslConnector::Direct(TcpSslDirectConnector { dns_resolver, .. })
-                | TcpSslConnector::Proxied(TcpSslProxyConnector { dns_resolver, .. }) => {
-                    *guard = TcpSslConnector::Invalid(dns_resolver.clone())
-                }
-                TcpSslConnector::Invalid(_dns_resolver) => (),
-            }
+            *guard = TcpSslConnector::Invalid(dns_resolver);
             Err(std::io::ErrorKind::InvalidInput.into())
         }
     }
 }
 
+fn proxy_auth(username: Option<String>, password: Option<String>) -> Option<ProxyAuth> {
+    username.map(|username| ProxyAuth {
+        username,
+        password: password.unwrap_or_default(),
+    })
+}
+
+#[bridge_fn]
+fn ConnectionManager_set_proxy(
+    connection_manager: &ConnectionManager,
+    host: String,
+    port: i32,
+) -> Result<(), std::io::Error> {
+    set_proxy_connector(connection_manager, port, |dns_resolver, port| {
+        TcpSslProxyConnector::new(dns_resolver, (&host, port)).into()
+    })
+}
+
+#[bridge_fn]
+fn ConnectionManager_set_socks_proxy(
+    connection_manager: &ConnectionManager,
+    host: String,
+    port: i32,
+    username: Option<String>,
+    password: Option<String>,
+) -> Result<(), std::io::Error> {
+    let auth = proxy_auth(username, password);
+    set_proxy_connector(connection_manager, port, |dns_resolver, port| {
+        SocksConnector::new(dns_resolver, (&host, port), auth).into()
+    })
+}
+
+#[bridge_fn]
+fn ConnectionManager_set_http_proxy(
+    connection_manager: &ConnectionManager,
+    host: String,
+    port: i32,
+    username: Option<String>,
+    password: Option<String>,
+) -> Result<(), std::io::Error> {
+    let auth = proxy_auth(username, password);
+    set_proxy_connector(connection_manager, port, |dns_resolver, port| {
+        HttpConnectConnector::new(dns_resolver, (&host, port), auth).into()
+    })
+}
+
 #[bridge_fn]
 fn ConnectionManager_clear_proxy(connection_manager: &ConnectionManager) {
     let mut guard = connection_manager
         .transport_connector
         .lock()
         .expect("not poisoned");
-    match &*guard {
-        TcpSslConnector::Direct(_direct) => (),
-        TcpSslConnector::Proxied(TcpSslProxyConnector { dns_resolver, .. })
-        | TcpSslConnector::Invalid(dns_resolver) => {
-            *guard = TcpSslDirectConnector::new(dns_resolver.clone()).into()
-        }
-    };
+    if !matches!(&*guard, TcpSslConnector::Direct(_)) {
+        *guard = TcpSslDirectConnector::new(guard.dns_resolver().clone()).into()
+    }
 }
 
 #[bridge_fn(jni = false, ffi = false)]
@@ -191,6 +298,42 @@ fn ConnectionManager_set_ipv6_enabled(connection_manager: &ConnectionManager, ip
     guard.set_ipv6_enabled(ipv6_enabled);
 }
 
+/// Remembers DNS results in a file at `path`, so that later runs can connect without waiting on
+/// DNS.
+#[bridge_fn]
+fn ConnectionManager_set_dns_cache_path(connection_manager: &ConnectionManager, path: String) {
+    // Read the file before taking any locks, so that connecting isn't held up by the I/O.
+    let cache = PersistentDnsCache::load(FileDnsCacheStore::new(path), Default::default());
+    connection_manager
+        .transport_connector
+        .lock()
+        .expect("not poisoned")
+        .dns_resolver()
+        .set_persistent_cache(Some(cache));
+}
+
+#[bridge_fn]
+fn ConnectionManager_clear_dns_cache(connection_manager: &ConnectionManager) {
+    let cache = connection_manager
+        .transport_connector
+        .lock()
+        .expect("not poisoned")
+        .dns_resolver()
+        .persistent_cache();
+    // The connector's lock has been released; clearing saves the file without holding it.
+    if let Some(cache) = cache {
+        cache.clear();
+    }
+}
+
+/// Returns the connection and request metrics recorded since the last call, as described by
+/// [MetricsSnapshot::to_json](libsignal_net::metrics::MetricsSnapshot::to_json), and starts over
+/// from zero.
+#[bridge_fn]
+fn ConnectionManager_take_metrics_snapshot(connection_manager: &ConnectionManager) -> String {
+    connection_manager.metrics.take_snapshot().to_json()
+}
+
 bridge_handle!(ConnectionManager, clone = false);
 
 #[bridge_fn]
@@ -256,13 +399,8 @@ async fn svr3_connect<'a>(
     password: String,
 ) -> Result<<Svr3Env<'a> as PpssSetup<TcpSslConnectorStream>>::Connections, svr::Error> {
     let auth = Auth { username, password };
-    let ConnectionManager {
-        chat: _chat,
-        cdsi: _cdsi,
-        svr3: (sgx, nitro, tpm2snp),
-        transport_connector,
-    } = connection_manager;
-    let transport_connector = transport_connector.lock().expect("not poisoned").clone();
+    let transport_connector = connection_manager.metered_transport_connector();
+    let (sgx, nitro, tpm2snp) = &connection_manager.svr3;
     let sgx = SvrConnection::connect(auth.clone(), sgx, transport_connector.clone()).await?;
     let nitro = SvrConnection::connect(auth.clone(), nitro, transport_connector.clone()).await?;
     let tpm2snp = SvrConnection::connect(auth, tpm2snp, transport_connector).await?;
@@ -274,6 +412,7 @@ pub struct Chat {
         Arc<dyn ChatServiceWithDebugInfo + Send + Sync>,
         Arc<dyn ChatServiceWithDebugInfo + Send + Sync>,
     >,
+    supervisor: std::sync::Mutex<Option<ChatSupervisor>>,
 }
 
 impl RefUnwindSafe for Chat {}
@@ -362,29 +501,89 @@ fn ChatService_new(
     connection_manager: &ConnectionManager,
     username: String,
     password: String,
+    transport: AsType<ChatTransportKind, u8>,
 ) -> Chat {
     let (incoming_tx, _incoming_rx) = mpsc::channel(1);
     Chat {
         service: chat_service(
             &connection_manager.chat,
-            connection_manager
-                .transport_connector
-                .lock()
-                .expect("not poisoned")
-                .clone(),
+            connection_manager.metered_transport_connector(),
             incoming_tx,
             username,
             password,
+            ChatTransportSelector::new(transport.into_inner().into()),
         )
+        .metered(&connection_manager.metrics)
         .into_dyn(),
+        supervisor: Default::default(),
     }
 }
 
 #[bridge_io(TokioAsyncContext)]
 async fn ChatService_disconnect(chat: &Chat) {
+    // Otherwise the supervisor would reconnect right away.
+    chat.supervisor.lock().expect("not poisoned").take();
     chat.service.disconnect().await
 }
 
+/// Values produced by `ChatService_next_connection_state`.
+fn connection_state_code(state: ConnectionState) -> i32 {
+    match state {
+        ConnectionState::Disconnected => 0,
+        ConnectionState::Connecting => 1,
+        ConnectionState::Connected => 2,
+        ConnectionState::BackingOff { .. } => 3,
+    }
+}
+
+#[bridge_io(TokioAsyncContext)]
+async fn ChatService_start_supervised(chat: &Chat, authenticated: bool) {
+    let backoff = ReconnectBackoff::default();
+    let supervisor = if authenticated {
+        chat.service.supervise_authenticated(backoff)
+    } else {
+        chat.service.supervise_unauthenticated(backoff)
+    };
+    // Replacing a previous supervisor stops it.
+    *chat.supervisor.lock().expect("not poisoned") = Some(supervisor);
+}
+
+#[bridge_fn]
+fn ChatService_stop_supervised(chat: &Chat) {
+    chat.supervisor.lock().expect("not poisoned").take();
+}
+
+#[bridge_fn]
+fn ChatService_network_changed(chat: &Chat) {
+    if let Some(supervisor) = &*chat.supervisor.lock().expect("not poisoned") {
+        supervisor.network_changed();
+    }
+}
+
+/// Waits for the supervised connection's state to be something other than `last_state`.
+///
+/// Returns 0 (disconnected) right away if the connection isn't being supervised, and once
+/// supervision stops.
+#[bridge_io(TokioAsyncContext)]
+async fn ChatService_next_connection_state(chat: &Chat, last_state: i32) -> i32 {
+    let states = chat
+        .supervisor
+        .lock()
+        .expect("not poisoned")
+        .as_ref()
+        .map(ChatSupervisor::subscribe);
+    let Some(mut states) = states else {
+        return connection_state_code(ConnectionState::Disconnected);
+    };
+    match states
+        .wait_for(|state| connection_state_code(*state) != last_state)
+        .await
+    {
+        Ok(state) => connection_state_code(*state),
+        Err(_) => connection_state_code(ConnectionState::Disconnected),
+    }
+}
+
 #[bridge_io(TokioAsyncContext)]
 async fn ChatService_connect_unauth(chat: &Chat) -> Result<ChatServiceDebugInfo, ChatServiceError> {
     chat.service.connect_unauthenticated().await
@@ -112,11 +112,7 @@ async fn CdsiLookup_new(
     let request = std::mem::take(&mut *request.0.lock().expect("not poisoned"));
     let auth = Auth { username, password };
 
-    let transport_connector = connection_manager
-        .transport_connector
-        .lock()
-        .expect("not poisoned")
-        .clone();
+    let transport_connector = connection_manager.metered_transport_connector();
     let connected =
         CdsiConnection::connect(&connection_manager.cdsi, transport_connector, auth).await?;
     let (token, remaining_response) = connected.send_request(request).await?;
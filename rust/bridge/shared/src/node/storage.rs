@@ -467,7 +467,7 @@ impl NodeIdentityKeyStore {
         })
         .then(|cx, result| match result {
             Ok(value) => match value.downcast::<DefaultJsBox<PrivateKey>, _>(cx) {
-                Ok(obj) => Ok(***obj),
+                Ok(obj) => Ok((***obj).clone()),
                 Err(_) => Err("result must be an object".to_owned()),
             },
             Err(error) => Err(error
@@ -249,7 +249,8 @@ fn KyberKeyPair_GetSecretKey(key_pair: &KyberKeyPair) -> KyberSecretKey {
 
 #[bridge_fn(ffi = "identitykeypair_serialize")]
 fn IdentityKeyPair_Serialize(public_key: &PublicKey, private_key: &PrivateKey) -> Vec<u8> {
-    let identity_key_pair = IdentityKeyPair::new(IdentityKey::new(*public_key), *private_key);
+    let identity_key_pair =
+        IdentityKeyPair::new(IdentityKey::new(*public_key), private_key.clone());
     identity_key_pair.serialize().into_vec()
 }
 
@@ -260,7 +261,8 @@ fn IdentityKeyPair_SignAlternateIdentity(
     other_identity: &PublicKey,
 ) -> Result<Vec<u8>> {
     let mut rng = rand::rngs::OsRng;
-    let identity_key_pair = IdentityKeyPair::new(IdentityKey::new(*public_key), *private_key);
+    let identity_key_pair =
+        IdentityKeyPair::new(IdentityKey::new(*public_key), private_key.clone());
     let other_identity = IdentityKey::new(*other_identity);
     Ok(identity_key_pair
         .sign_alternate_identity(&other_identity, &mut rng)?
@@ -673,7 +675,7 @@ fn SignedPreKeyRecord_New(
     priv_key: &PrivateKey,
     signature: &[u8],
 ) -> SignedPreKeyRecord {
-    let keypair = KeyPair::new(*pub_key, *priv_key);
+    let keypair = KeyPair::new(*pub_key, priv_key.clone());
     SignedPreKeyRecord::new(id.into(), timestamp, &keypair, signature)
 }
 
@@ -698,7 +700,7 @@ bridge_get!(PreKeyRecord::private_key -> PrivateKey);
 
 #[bridge_fn]
 fn PreKeyRecord_New(id: u32, pub_key: &PublicKey, priv_key: &PrivateKey) -> PreKeyRecord {
-    let keypair = KeyPair::new(*pub_key, *priv_key);
+    let keypair = KeyPair::new(*pub_key, priv_key.clone());
     PreKeyRecord::new(id.into(), &keypair)
 }
 
@@ -1008,10 +1010,10 @@ fn SessionRecord_InitializeAliceSession(
 ) -> Result<SessionRecord> {
     let our_identity_key_pair = IdentityKeyPair::new(
         IdentityKey::new(*identity_key_public),
-        *identity_key_private,
+        identity_key_private.clone(),
     );
 
-    let our_base_key_pair = KeyPair::new(*base_public, *base_private);
+    let our_base_key_pair = KeyPair::new(*base_public, base_private.clone());
 
     let their_identity_key = IdentityKey::new(*their_identity_key);
 
@@ -1041,12 +1043,13 @@ fn SessionRecord_InitializeBobSession(
 ) -> Result<SessionRecord> {
     let our_identity_key_pair = IdentityKeyPair::new(
         IdentityKey::new(*identity_key_public),
-        *identity_key_private,
+        identity_key_private.clone(),
     );
 
-    let our_signed_pre_key_pair = KeyPair::new(*signed_prekey_public, *signed_prekey_private);
+    let our_signed_pre_key_pair =
+        KeyPair::new(*signed_prekey_public, signed_prekey_private.clone());
 
-    let our_ratchet_key_pair = KeyPair::new(*eph_public, *eph_private);
+    let our_ratchet_key_pair = KeyPair::new(*eph_public, eph_private.clone());
 
     let their_identity_key = IdentityKey::new(*their_identity_key);
 
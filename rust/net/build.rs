@@ -4,7 +4,11 @@
 //
 
 fn main() {
-    let protos = ["src/proto/chat_websocket.proto", "src/proto/cds2.proto"];
+    let protos = [
+        "src/proto/chat_websocket.proto",
+        "src/proto/chat_envelope.proto",
+        "src/proto/cds2.proto",
+    ];
     prost_build::compile_protos(&protos, &["src"]).expect("Protobufs in src are valid");
     for proto in &protos {
         println!("cargo:rerun-if-changed={}", proto);
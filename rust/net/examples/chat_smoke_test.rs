@@ -8,7 +8,7 @@ use std::time::Duration;
 
 use clap::{Args, Parser, ValueEnum};
 use http::uri::PathAndQuery;
-use libsignal_net::chat::{chat_service, ChatServiceError};
+use libsignal_net::chat::{chat_service, ChatServiceError, ChatTransportSelector};
 use libsignal_net::env::constants::WEB_SOCKET_PATH;
 use libsignal_net::env::Svr3Env;
 use libsignal_net::infra::dns::DnsResolver;
@@ -113,6 +113,7 @@ async fn test_connection(
         incoming_tx,
         "".to_owned(),
         "".to_owned(),
+        ChatTransportSelector::default(),
     );
 
     chat.connect_unauthenticated().await?;
@@ -45,6 +45,7 @@ const TEST_SERVER_DOMAIN_CONFIG: DomainConfig = DomainConfig {
     ip_v4: &[],
     ip_v6: &[],
     cert: TEST_SERVER_CERT,
+    pins: None,
     proxy_path: "/svr3-test",
 };
 
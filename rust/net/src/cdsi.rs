@@ -29,6 +29,11 @@ use crate::infra::ws::{
 use crate::infra::{AsyncDuplexStream, TransportConnector};
 use crate::proto::cds2::{ClientRequest, ClientResponse};
 
+pub mod discovery;
+#[cfg(any(test, feature = "fake-cdsi-server"))]
+pub mod fake;
+pub mod scheduler;
+
 trait FixedLengthSerializable {
     const SERIALIZED_LEN: usize;
 
@@ -52,7 +57,9 @@ impl<It: ExactSizeIterator<Item = T>, T: FixedLengthSerializable> CollectSeriali
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
 pub struct E164(NonZeroU64);
 
 impl E164 {
@@ -100,6 +107,7 @@ impl FixedLengthSerializable for Uuid {
     }
 }
 
+#[derive(Clone)]
 pub struct AciAndAccessKey {
     pub aci: Aci,
     pub access_key: [u8; 16],
@@ -120,6 +128,8 @@ impl FixedLengthSerializable for AciAndAccessKey {
 pub struct LookupRequest {
     pub new_e164s: Vec<E164>,
     pub prev_e164s: Vec<E164>,
+    /// Numbers covered by `token` that are no longer being looked up.
+    pub discard_e164s: Vec<E164>,
     pub acis_and_access_keys: Vec<AciAndAccessKey>,
     pub return_acis_without_uaks: bool,
     pub token: Box<[u8]>,
@@ -130,6 +140,7 @@ impl LookupRequest {
         let Self {
             new_e164s,
             prev_e164s,
+            discard_e164s,
             acis_and_access_keys,
             return_acis_without_uaks,
             token,
@@ -138,6 +149,7 @@ impl LookupRequest {
         let aci_uak_pairs = acis_and_access_keys.into_iter().collect_serialized();
         let new_e164s = new_e164s.into_iter().collect_serialized();
         let prev_e164s = prev_e164s.into_iter().collect_serialized();
+        let discard_e164s = discard_e164s.into_iter().collect_serialized();
 
         ClientRequest {
             aci_uak_pairs,
@@ -145,9 +157,9 @@ impl LookupRequest {
             prev_e164s,
             return_acis_without_uaks,
             token: token.into_vec(),
+            // The token is acknowledged in a second message; see ClientResponseCollector::collect.
             token_ack: false,
-            // TODO: use these for supporting non-desktop client requirements.
-            discard_e164s: Vec::new(),
+            discard_e164s,
         }
     }
 }
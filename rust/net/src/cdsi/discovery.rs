@@ -0,0 +1,304 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Incremental contact discovery.
+//!
+//! Every CDSI lookup returns a token that covers the numbers that were looked up. Presenting it on
+//! the next lookup lets the server charge rate-limit permits only for numbers it hasn't seen from
+//! this client before. [DiscoverySession] keeps that token together with the set of numbers it
+//! covers, and turns the current address book into the right `new`/`prev`/`discard` split.
+
+use std::collections::BTreeSet;
+
+use bincode::Options as _;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::cdsi::{
+    AciAndAccessKey, CdsiConnection, LookupError, LookupRequest, LookupResponse, Token, E164,
+};
+use crate::infra::AsyncDuplexStream;
+
+const DISCOVERY_STATE_FORMAT: u8 = 1;
+
+/// The result of a previous successful lookup, to be saved between runs.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiscoveryState {
+    token: Box<[u8]>,
+    e164s: BTreeSet<E164>,
+}
+
+#[derive(Debug, Eq, PartialEq, displaydoc::Display, Error)]
+pub enum DeserializeError {
+    /// Unexpected DiscoveryState serialization format version {0}
+    BadVersion(u8),
+    /// Unsupported DiscoveryState serialization format
+    BadFormat,
+}
+
+impl DiscoveryState {
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    /// The numbers covered by [`Self::token`].
+    pub fn e164s(&self) -> &BTreeSet<E164> {
+        &self.e164s
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = vec![DISCOVERY_STATE_FORMAT];
+        Self::bincode_options()
+            .serialize_into(&mut buf, self)
+            .expect("can serialize to a Vec");
+        buf
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        match bytes {
+            [] => Err(DeserializeError::BadFormat),
+            [DISCOVERY_STATE_FORMAT, data @ ..] => Self::bincode_options()
+                .deserialize(data)
+                .map_err(|_| DeserializeError::BadFormat),
+            [v, ..] => Err(DeserializeError::BadVersion(*v)),
+        }
+    }
+
+    fn bincode_options() -> impl bincode::Options {
+        bincode::config::DefaultOptions::new()
+            .reject_trailing_bytes()
+            .with_fixint_encoding()
+    }
+}
+
+/// How the numbers in an address book are split up for a lookup.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DiscoveryDelta {
+    /// Numbers not covered by the saved token; these are charged against the rate limit.
+    pub new_e164s: Vec<E164>,
+    /// Numbers covered by the saved token.
+    pub prev_e164s: Vec<E164>,
+    /// Numbers covered by the saved token that are no longer in the address book.
+    pub discard_e164s: Vec<E164>,
+}
+
+/// Runs lookups for an address book that changes over time, spending rate-limit permits only on
+/// numbers that weren't part of the previous lookup.
+#[derive(Debug, Default)]
+pub struct DiscoverySession {
+    state: Option<DiscoveryState>,
+}
+
+impl DiscoverySession {
+    /// Resumes from the state saved after a previous lookup, if any.
+    pub fn new(state: Option<DiscoveryState>) -> Self {
+        Self { state }
+    }
+
+    /// The state to save once a lookup has finished.
+    pub fn state(&self) -> Option<&DiscoveryState> {
+        self.state.as_ref()
+    }
+
+    /// Splits `e164s` according to the saved token.
+    pub fn delta(&self, e164s: &BTreeSet<E164>) -> DiscoveryDelta {
+        let Some(DiscoveryState {
+            e164s: previous, ..
+        }) = &self.state
+        else {
+            return DiscoveryDelta {
+                new_e164s: e164s.iter().copied().collect(),
+                ..Default::default()
+            };
+        };
+
+        DiscoveryDelta {
+            new_e164s: e164s.difference(previous).copied().collect(),
+            prev_e164s: e164s.intersection(previous).copied().collect(),
+            discard_e164s: previous.difference(e164s).copied().collect(),
+        }
+    }
+
+    /// Looks up every number in `e164s`, using and then replacing the saved token.
+    ///
+    /// The saved state is only updated once the server has accepted the new token. If the server
+    /// rejects the saved token, the state is cleared so that the next attempt starts over.
+    pub async fn lookup<S: AsyncDuplexStream>(
+        &mut self,
+        connection: CdsiConnection<S>,
+        e164s: BTreeSet<E164>,
+        acis_and_access_keys: Vec<AciAndAccessKey>,
+        return_acis_without_uaks: bool,
+    ) -> Result<LookupResponse, LookupError> {
+        let DiscoveryDelta {
+            new_e164s,
+            prev_e164s,
+            discard_e164s,
+        } = self.delta(&e164s);
+        let token = self
+            .state
+            .as_ref()
+            .map(|state| state.token.clone())
+            .unwrap_or_default();
+
+        let request = LookupRequest {
+            new_e164s,
+            prev_e164s,
+            discard_e164s,
+            acis_and_access_keys,
+            return_acis_without_uaks,
+            token,
+        };
+
+        let (Token(token), collector) = match connection.send_request(request).await {
+            Ok(sent) => sent,
+            Err(LookupError::InvalidToken) => {
+                log::info!("CDSI token was rejected; discarding saved discovery state");
+                self.state = None;
+                return Err(LookupError::InvalidToken);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let response = collector.collect().await?;
+        self.state = Some(DiscoveryState { token, e164s });
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::num::NonZeroU64;
+
+    use assert_matches::assert_matches;
+    use nonzero_ext::nonzero;
+
+    use super::*;
+    use crate::cdsi::fake::FakeCdsiServer;
+
+    const FIRST: E164 = E164::new(nonzero!(18005550101u64));
+    const SECOND: E164 = E164::new(nonzero!(18005550102u64));
+    const THIRD: E164 = E164::new(nonzero!(18005550103u64));
+
+    fn session_with(token: &[u8], e164s: impl IntoIterator<Item = E164>) -> DiscoverySession {
+        DiscoverySession::new(Some(DiscoveryState {
+            token: token.into(),
+            e164s: e164s.into_iter().collect(),
+        }))
+    }
+
+    #[test]
+    fn delta_without_state_is_all_new() {
+        let session = DiscoverySession::default();
+        assert_eq!(
+            session.delta(&[FIRST, SECOND].into()),
+            DiscoveryDelta {
+                new_e164s: vec![FIRST, SECOND],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn delta_with_state() {
+        let session = session_with(b"token", [FIRST, SECOND]);
+        assert_eq!(
+            session.delta(&[SECOND, THIRD].into()),
+            DiscoveryDelta {
+                new_e164s: vec![THIRD],
+                prev_e164s: vec![SECOND],
+                discard_e164s: vec![FIRST],
+            }
+        );
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let state = session_with(b"token", [FIRST, THIRD]).state.unwrap();
+        let serialized = state.serialize();
+        assert_eq!(DiscoveryState::deserialize(&serialized), Ok(state));
+
+        assert_eq!(
+            DiscoveryState::deserialize(&[]),
+            Err(DeserializeError::BadFormat)
+        );
+        assert_eq!(
+            DiscoveryState::deserialize(&[0xFF, 0]),
+            Err(DeserializeError::BadVersion(0xFF))
+        );
+    }
+
+    fn as_bytes(e164s: &[E164]) -> Vec<u8> {
+        e164s
+            .iter()
+            .flat_map(|e164| u64::from(NonZeroU64::from(*e164)).to_be_bytes())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn lookup_sends_delta_and_saves_new_token() {
+        let server = FakeCdsiServer::new();
+        server.add_token(b"old token", [FIRST, SECOND]);
+        let connection = server.connect().await.expect("connected");
+        let mut session = session_with(b"old token", [FIRST, SECOND]);
+
+        let response = session
+            .lookup(connection, [SECOND, THIRD].into(), vec![], false)
+            .await
+            .expect("lookup succeeded");
+        assert_eq!(response.debug_permits_used, 1);
+
+        let [request] = <[_; 1]>::try_from(server.received_requests()).expect("one request");
+        assert_eq!(request.token, b"old token");
+        assert_eq!(request.new_e164s, as_bytes(&[THIRD]));
+        assert_eq!(request.prev_e164s, as_bytes(&[SECOND]));
+        assert_eq!(request.discard_e164s, as_bytes(&[FIRST]));
+
+        let state = session.state().expect("has state");
+        assert_ne!(&*state.token, b"old token");
+        assert_eq!(state.e164s, [SECOND, THIRD].into());
+
+        // The server accepts the new token, so looking up the same numbers again is free.
+        let connection = server.connect().await.expect("connected");
+        let response = session
+            .lookup(connection, [SECOND, THIRD].into(), vec![], false)
+            .await
+            .expect("lookup succeeded");
+        assert_eq!(response.debug_permits_used, 0);
+    }
+
+    #[tokio::test]
+    async fn lookup_clears_state_on_invalid_token() {
+        let server = FakeCdsiServer::new();
+        let connection = server.connect().await.expect("connected");
+        let mut session = session_with(b"stale token", [FIRST]);
+
+        let result = session
+            .lookup(connection, [FIRST].into(), vec![], false)
+            .await;
+        assert_matches!(result, Err(LookupError::InvalidToken));
+        assert_eq!(session.state(), None);
+    }
+
+    #[tokio::test]
+    async fn lookup_keeps_state_on_rate_limit() {
+        let server = FakeCdsiServer::new();
+        server.add_token(b"token", [FIRST]);
+        server.set_rate_limit(0, 10);
+        let connection = server.connect().await.expect("connected");
+        let mut session = session_with(b"token", [FIRST]);
+
+        let result = session
+            .lookup(connection, [FIRST, SECOND].into(), vec![], false)
+            .await;
+        assert_matches!(
+            result,
+            Err(LookupError::RateLimited {
+                retry_after_seconds: 10
+            })
+        );
+        assert_eq!(session.state(), session_with(b"token", [FIRST]).state());
+    }
+}
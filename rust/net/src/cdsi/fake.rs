@@ -0,0 +1,455 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! An in-process stand-in for the CDSI enclave, for exercising [CdsiConnection] lookups offline.
+//!
+//! [FakeCdsiServer::connect] runs both ends of an attested connection over an in-memory stream.
+//! Instead of a live attestation, the server presents the SGX test evidence from
+//! [attest::sgx_session::testutil], which the client checks as of the time it was issued. Lookups
+//! are answered from the accounts registered with [FakeCdsiServer::add_account], and tokens and
+//! rate-limit permits are tracked the way the real service tracks them, so clients can be tested
+//! against invalid tokens and rate limiting as well as successful lookups.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use libsignal_core::{Aci, Pni};
+use prost::Message as _;
+use tokio::io::DuplexStream;
+use tokio::time::Instant;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+
+use crate::cdsi::{
+    CdsiConnection, FixedLengthSerializable as _, LookupError, LookupResponseEntry, E164,
+};
+use crate::enclave::fake::{self, AttestedServer};
+use crate::proto::cds2::{ClientRequest, ClientResponse};
+
+/// A registered account, as found by [FakeCdsiServer].
+#[derive(Clone, Copy, Debug)]
+pub struct FakeAccount {
+    pub aci: Aci,
+    pub pni: Pni,
+    /// The ACI is only returned to clients that present this access key, unless they ask for
+    /// ACIs without access keys.
+    pub access_key: [u8; 16],
+}
+
+struct RateLimit {
+    permits: u32,
+    permits_remaining: u32,
+    retry_after_seconds: u32,
+    /// Set when a lookup is rejected; once it passes, the full allowance is available again.
+    refill_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct State {
+    directory: HashMap<E164, FakeAccount>,
+    /// The numbers covered by each acknowledged token.
+    tokens: HashMap<Vec<u8>, BTreeSet<E164>>,
+    next_token: u64,
+    rate_limit: Option<RateLimit>,
+    permits_used: u32,
+    received: Vec<ClientRequest>,
+}
+
+/// A lookup whose token has been sent but not yet acknowledged.
+struct PendingLookup {
+    token: Vec<u8>,
+    e164s: BTreeSet<E164>,
+    permits: u32,
+    acis_and_access_keys: Vec<(Aci, [u8; 16])>,
+    return_acis_without_uaks: bool,
+}
+
+/// What to do in reply to a client message.
+enum Reply {
+    Message(ClientResponse),
+    MessageAndClose(ClientResponse),
+    Close(CloseFrame<'static>),
+}
+
+/// An in-process fake of the CDSI service; see the [module docs](self).
+///
+/// Cloning produces another handle to the same server.
+#[derive(Clone, Default)]
+pub struct FakeCdsiServer {
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeCdsiServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `e164` resolve to `account` in future lookups.
+    pub fn add_account(&self, e164: E164, account: FakeAccount) {
+        self.state().directory.insert(e164, account);
+    }
+
+    /// Accepts `token` as if this server had issued it for a lookup of `e164s`.
+    pub fn add_token(&self, token: &[u8], e164s: impl IntoIterator<Item = E164>) {
+        self.state()
+            .tokens
+            .insert(token.to_vec(), e164s.into_iter().collect());
+    }
+
+    /// Allows only `permits` more permits to be spent; lookups that would need more are rejected
+    /// with [LookupError::RateLimited] carrying `retry_after_seconds`. Once that long has passed
+    /// after a rejection, another `permits` can be spent.
+    ///
+    /// By default, lookups are not rate limited.
+    pub fn set_rate_limit(&self, permits: u32, retry_after_seconds: u32) {
+        self.state().rate_limit = Some(RateLimit {
+            permits,
+            permits_remaining: permits,
+            retry_after_seconds,
+            refill_at: None,
+        });
+    }
+
+    /// The number of permits spent across all completed lookups.
+    pub fn permits_used(&self) -> u32 {
+        self.state().permits_used
+    }
+
+    /// Every lookup request received so far, including ones that were rejected.
+    pub fn received_requests(&self) -> Vec<ClientRequest> {
+        self.state().received.clone()
+    }
+
+    /// Opens an attested connection to this server.
+    pub async fn connect(&self) -> Result<CdsiConnection<DuplexStream>, LookupError> {
+        let server = self.clone();
+        let connection = fake::connect("cdsi.local", |session| server.serve(session)).await?;
+        Ok(CdsiConnection(connection))
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("not poisoned")
+    }
+
+    async fn serve(self, mut session: AttestedServer) {
+        let mut pending = None;
+        while let Some(plaintext) = session.receive().await {
+            let reply = match (ClientRequest::decode(plaintext.as_slice()), pending.take()) {
+                (Ok(request), None) if !request.token_ack => match self.start_lookup(&request) {
+                    Ok(lookup) => {
+                        let response = ClientResponse {
+                            token: lookup.token.clone(),
+                            ..Default::default()
+                        };
+                        pending = Some(lookup);
+                        Reply::Message(response)
+                    }
+                    Err(close) => Reply::Close(close),
+                },
+                (Ok(request), Some(lookup)) if request.token_ack => self.finish_lookup(lookup),
+                _ => Reply::Close(close_frame(4003, "unexpected message")),
+            };
+
+            match reply {
+                Reply::Message(response) => {
+                    if session.send(&response.encode_to_vec()).await.is_err() {
+                        return;
+                    }
+                }
+                Reply::MessageAndClose(response) => {
+                    if session.send(&response.encode_to_vec()).await.is_ok() {
+                        session.close(None).await;
+                    }
+                    return;
+                }
+                Reply::Close(frame) => {
+                    session.close(Some(frame)).await;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn start_lookup(&self, request: &ClientRequest) -> Result<PendingLookup, CloseFrame<'static>> {
+        let invalid_argument = || close_frame(4003, "invalid request");
+        let prev_e164s = parse_e164s(&request.prev_e164s).ok_or_else(invalid_argument)?;
+        let new_e164s = parse_e164s(&request.new_e164s).ok_or_else(invalid_argument)?;
+        parse_e164s(&request.discard_e164s).ok_or_else(invalid_argument)?;
+        if request.aci_uak_pairs.len() % 32 != 0 {
+            return Err(invalid_argument());
+        }
+        let acis_and_access_keys: Vec<(Aci, [u8; 16])> = request
+            .aci_uak_pairs
+            .chunks(32)
+            .map(|pair| {
+                let (aci, access_key) = pair.split_at(16);
+                (
+                    Aci::from_uuid_bytes(aci.try_into().expect("split at len")),
+                    access_key.try_into().expect("split at len"),
+                )
+            })
+            .collect();
+
+        let mut state = self.state();
+        state.received.push(request.clone());
+        let covered = if request.token.is_empty() {
+            BTreeSet::new()
+        } else {
+            state
+                .tokens
+                .get(&request.token)
+                .cloned()
+                .ok_or_else(|| close_frame(4101, "invalid token"))?
+        };
+
+        let e164s: BTreeSet<E164> = prev_e164s.iter().chain(&new_e164s).copied().collect();
+        // Numbers the token doesn't cover are charged even if the client listed them as
+        // previously looked up.
+        let permits = e164s.difference(&covered).count() as u32;
+
+        let token = format!("fake token {}", state.next_token).into_bytes();
+        state.next_token += 1;
+
+        Ok(PendingLookup {
+            token,
+            e164s,
+            permits,
+            acis_and_access_keys,
+            return_acis_without_uaks: request.return_acis_without_uaks,
+        })
+    }
+
+    fn finish_lookup(&self, lookup: PendingLookup) -> Reply {
+        let PendingLookup {
+            token,
+            e164s,
+            permits,
+            acis_and_access_keys,
+            return_acis_without_uaks,
+        } = lookup;
+        let mut state = self.state();
+
+        if let Some(rate_limit) = &mut state.rate_limit {
+            if rate_limit
+                .refill_at
+                .is_some_and(|refill_at| refill_at <= Instant::now())
+            {
+                rate_limit.permits_remaining = rate_limit.permits;
+                rate_limit.refill_at = None;
+            }
+            if permits > rate_limit.permits_remaining {
+                let retry_after = Duration::from_secs(rate_limit.retry_after_seconds.into());
+                rate_limit
+                    .refill_at
+                    .get_or_insert(Instant::now() + retry_after);
+                let reason = serde_json::json!({
+                    "retry_after_seconds": rate_limit.retry_after_seconds,
+                });
+                return Reply::Close(close_frame(4008, reason.to_string()));
+            }
+            rate_limit.permits_remaining -= permits;
+        }
+        state.permits_used += permits;
+
+        let mut e164_pni_aci_triples = vec![0; e164s.len() * LookupResponseEntry::SERIALIZED_LEN];
+        for (e164, target) in e164s
+            .iter()
+            .zip(e164_pni_aci_triples.chunks_mut(LookupResponseEntry::SERIALIZED_LEN))
+        {
+            let account = state.directory.get(e164);
+            LookupResponseEntry {
+                e164: *e164,
+                pni: account.map(|account| account.pni),
+                aci: account
+                    .filter(|account| {
+                        return_acis_without_uaks
+                            || acis_and_access_keys.contains(&(account.aci, account.access_key))
+                    })
+                    .map(|account| account.aci),
+            }
+            .serialize_into(target);
+        }
+
+        state.tokens.insert(token, e164s);
+
+        Reply::MessageAndClose(ClientResponse {
+            e164_pni_aci_triples,
+            debug_permits_used: permits.try_into().unwrap_or(i32::MAX),
+            ..Default::default()
+        })
+    }
+}
+
+fn parse_e164s(bytes: &[u8]) -> Option<Vec<E164>> {
+    if bytes.len() % E164::SERIALIZED_LEN != 0 {
+        return None;
+    }
+    bytes
+        .chunks(E164::SERIALIZED_LEN)
+        .map(|chunk| E164::from_serialized(chunk.try_into().expect("chunk size is correct")))
+        .collect()
+}
+
+fn close_frame(code: u16, reason: impl Into<String>) -> CloseFrame<'static> {
+    CloseFrame {
+        code: CloseCode::Bad(code),
+        reason: reason.into().into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use nonzero_ext::nonzero;
+
+    use super::*;
+    use crate::cdsi::{AciAndAccessKey, LookupRequest, LookupResponse, Token};
+
+    const REGISTERED: E164 = E164::new(nonzero!(18005550101u64));
+    const UNREGISTERED: E164 = E164::new(nonzero!(18005550102u64));
+    const ACCOUNT: FakeAccount = FakeAccount {
+        aci: Aci::from_uuid_bytes([b'a'; 16]),
+        pni: Pni::from_uuid_bytes([b'p'; 16]),
+        access_key: [b'k'; 16],
+    };
+
+    fn server() -> FakeCdsiServer {
+        let server = FakeCdsiServer::new();
+        server.add_account(REGISTERED, ACCOUNT);
+        server
+    }
+
+    async fn lookup(
+        server: &FakeCdsiServer,
+        request: LookupRequest,
+    ) -> Result<(Token, LookupResponse), LookupError> {
+        let connection = server.connect().await?;
+        let (token, collector) = connection.send_request(request).await?;
+        Ok((token, collector.collect().await?))
+    }
+
+    #[tokio::test]
+    async fn lookup_returns_directory_entries() {
+        let server = server();
+        let (_token, response) = lookup(
+            &server,
+            LookupRequest {
+                new_e164s: vec![REGISTERED, UNREGISTERED],
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("lookup succeeded");
+
+        assert_eq!(
+            response,
+            LookupResponse {
+                records: vec![
+                    LookupResponseEntry {
+                        e164: REGISTERED,
+                        aci: None,
+                        pni: Some(ACCOUNT.pni),
+                    },
+                    LookupResponseEntry {
+                        e164: UNREGISTERED,
+                        aci: None,
+                        pni: None,
+                    },
+                ],
+                debug_permits_used: 2,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn lookup_returns_aci_for_matching_access_key() {
+        let server = server();
+        let (_token, response) = lookup(
+            &server,
+            LookupRequest {
+                new_e164s: vec![REGISTERED],
+                acis_and_access_keys: vec![AciAndAccessKey {
+                    aci: ACCOUNT.aci,
+                    access_key: ACCOUNT.access_key,
+                }],
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("lookup succeeded");
+
+        assert_eq!(response.records[0].aci, Some(ACCOUNT.aci));
+    }
+
+    #[tokio::test]
+    async fn token_discounts_previous_numbers() {
+        let server = server();
+        let (Token(token), _response) = lookup(
+            &server,
+            LookupRequest {
+                new_e164s: vec![REGISTERED],
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("first lookup succeeded");
+
+        let (_token, response) = lookup(
+            &server,
+            LookupRequest {
+                prev_e164s: vec![REGISTERED],
+                new_e164s: vec![UNREGISTERED],
+                token,
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("second lookup succeeded");
+
+        assert_eq!(response.records.len(), 2);
+        assert_eq!(response.debug_permits_used, 1);
+        assert_eq!(server.permits_used(), 2);
+    }
+
+    #[tokio::test]
+    async fn unknown_token_is_rejected() {
+        let server = server();
+        let result = lookup(
+            &server,
+            LookupRequest {
+                prev_e164s: vec![REGISTERED],
+                token: b"never issued".as_slice().into(),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        assert_matches!(result, Err(LookupError::InvalidToken));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_is_enforced() {
+        let server = server();
+        server.set_rate_limit(1, 30);
+
+        let result = lookup(
+            &server,
+            LookupRequest {
+                new_e164s: vec![REGISTERED, UNREGISTERED],
+                ..Default::default()
+            },
+        )
+        .await;
+
+        assert_matches!(
+            result,
+            Err(LookupError::RateLimited {
+                retry_after_seconds: 30
+            })
+        );
+        assert_eq!(server.permits_used(), 0);
+    }
+}
@@ -0,0 +1,385 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Rate-limit aware scheduling for large lookups.
+//!
+//! Looking up a whole address book at once can exceed the account's rate limit, in which case
+//! the server rejects the entire request. [LookupScheduler] instead adds new numbers a batch at a
+//! time, building on the token from the previous batch so that numbers that were already looked
+//! up aren't charged again. When the server says to back off, the deadline is kept so that it can
+//! be saved and honored across restarts, and the batch is halved before it is retried, so that it
+//! eventually fits in the permits the server allows at once.
+
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::time::{Duration, SystemTime};
+
+use crate::cdsi::discovery::DiscoverySession;
+use crate::cdsi::{AciAndAccessKey, CdsiConnection, LookupError, LookupResponse, E164};
+use crate::infra::AsyncDuplexStream;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LookupSchedulerConfig {
+    /// The most numbers not covered by the current token to send in a single lookup.
+    ///
+    /// Each rate-limited batch halves this for the rest of the lookup, down to a single number.
+    pub max_new_e164s_per_batch: NonZeroUsize,
+    /// How long to wait in place for a rate limit to expire before giving up with
+    /// [LookupError::RateLimited].
+    pub max_wait: Duration,
+}
+
+impl Default for LookupSchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_new_e164s_per_batch: nonzero_ext::nonzero!(1000usize),
+            max_wait: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Reported after each batch completes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LookupProgress {
+    /// How many numbers of the address book have been looked up so far.
+    pub looked_up: usize,
+    pub total: usize,
+    /// Rate-limit permits spent so far, as reported by the server.
+    pub permits_used: i32,
+}
+
+/// Splits lookups into batches and waits out rate limits; see the [module docs](self).
+#[derive(Debug, Default)]
+pub struct LookupScheduler {
+    config: LookupSchedulerConfig,
+    retry_at: Option<SystemTime>,
+}
+
+impl LookupScheduler {
+    /// The shortest wait after being rate limited, even if the server says to retry right away.
+    const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+    /// Creates a scheduler, honoring a `retry_at` deadline saved from a previous run.
+    pub fn new(config: LookupSchedulerConfig, retry_at: Option<SystemTime>) -> Self {
+        Self { config, retry_at }
+    }
+
+    /// The time before which the server shouldn't be contacted again, to be saved between runs.
+    pub fn retry_at(&self) -> Option<SystemTime> {
+        self.retry_at
+    }
+
+    /// Looks up every number in `e164s`, opening a new connection with `connect` for each batch.
+    ///
+    /// The returned response covers all of `e164s`, with `debug_permits_used` summed over all
+    /// batches. If a rate limit would take longer than [LookupSchedulerConfig::max_wait] to
+    /// expire, returns [LookupError::RateLimited]; `session` keeps the progress made so far, so
+    /// calling this again later picks up where it left off.
+    pub async fn lookup<S, F, Fut>(
+        &mut self,
+        session: &mut DiscoverySession,
+        e164s: BTreeSet<E164>,
+        acis_and_access_keys: Vec<AciAndAccessKey>,
+        return_acis_without_uaks: bool,
+        mut connect: F,
+        mut on_progress: impl FnMut(LookupProgress),
+    ) -> Result<LookupResponse, LookupError>
+    where
+        S: AsyncDuplexStream,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<CdsiConnection<S>, LookupError>>,
+    {
+        let total = e164s.len();
+        let delta = session.delta(&e164s);
+        let mut pending = delta.new_e164s;
+        let mut covered: BTreeSet<E164> = delta.prev_e164s.into_iter().collect();
+        let mut permits_used = 0;
+        let mut token_rejected = false;
+        let mut max_batch_len = self.config.max_new_e164s_per_batch.get();
+
+        loop {
+            self.wait_for_retry_deadline().await?;
+
+            let batch_len = pending.len().min(max_batch_len);
+            let mut batch = covered.clone();
+            batch.extend(&pending[..batch_len]);
+
+            let result = match connect().await {
+                Ok(connection) => {
+                    session
+                        .lookup(
+                            connection,
+                            batch.clone(),
+                            acis_and_access_keys.clone(),
+                            return_acis_without_uaks,
+                        )
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(response) => {
+                    pending.drain(..batch_len);
+                    covered = batch;
+                    permits_used += response.debug_permits_used;
+                    on_progress(LookupProgress {
+                        looked_up: covered.len(),
+                        total,
+                        permits_used,
+                    });
+
+                    if pending.is_empty() {
+                        return Ok(LookupResponse {
+                            records: response.records,
+                            debug_permits_used: permits_used,
+                        });
+                    }
+                }
+                Err(LookupError::RateLimited {
+                    retry_after_seconds,
+                }) => {
+                    log::info!("CDSI lookup rate limited for {retry_after_seconds}s");
+                    let delay =
+                        Duration::from_secs(retry_after_seconds.into()).max(Self::MIN_RETRY_DELAY);
+                    self.retry_at = Some(SystemTime::now() + delay);
+                    // The batch may need more permits than the server ever allows at once.
+                    max_batch_len = (batch_len / 2).max(1);
+                }
+                Err(LookupError::InvalidToken) if !token_rejected => {
+                    // The session has dropped the token, so everything counts as new again.
+                    token_rejected = true;
+                    pending = session.delta(&e164s).new_e164s;
+                    covered.clear();
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn wait_for_retry_deadline(&mut self) -> Result<(), LookupError> {
+        let Some(retry_at) = self.retry_at else {
+            return Ok(());
+        };
+
+        if let Ok(remaining) = retry_at.duration_since(SystemTime::now()) {
+            if remaining > self.config.max_wait {
+                let retry_after_seconds = remaining
+                    .as_secs()
+                    .saturating_add(u64::from(remaining.subsec_nanos() > 0))
+                    .try_into()
+                    .unwrap_or(u32::MAX);
+                return Err(LookupError::RateLimited {
+                    retry_after_seconds,
+                });
+            }
+            tokio::time::sleep(remaining).await;
+        }
+
+        self.retry_at = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::num::NonZeroU64;
+
+    use assert_matches::assert_matches;
+    use nonzero_ext::nonzero;
+
+    use super::*;
+    use crate::cdsi::fake::FakeCdsiServer;
+
+    fn numbers(count: usize) -> BTreeSet<E164> {
+        (18005550101..)
+            .take(count)
+            .map(|n| E164::new(NonZeroU64::new(n).unwrap()))
+            .collect()
+    }
+
+    fn count_e164s(bytes: &[u8]) -> usize {
+        bytes.len() / 8
+    }
+
+    #[tokio::test]
+    async fn lookup_in_batches() {
+        let server = FakeCdsiServer::new();
+        let mut scheduler = LookupScheduler::new(
+            LookupSchedulerConfig {
+                max_new_e164s_per_batch: nonzero!(2usize),
+                ..Default::default()
+            },
+            None,
+        );
+        let mut session = DiscoverySession::default();
+        let mut progress = vec![];
+
+        let response = scheduler
+            .lookup(
+                &mut session,
+                numbers(5),
+                vec![],
+                false,
+                || server.connect(),
+                |p| progress.push(p.looked_up),
+            )
+            .await
+            .expect("lookup succeeded");
+
+        assert_eq!(response.records.len(), 5);
+        assert_eq!(response.debug_permits_used, 5);
+        assert_eq!(progress, [2, 4, 5]);
+        assert_eq!(session.state().map(|s| s.e164s()), Some(&numbers(5)));
+
+        let sizes: Vec<_> = server
+            .received_requests()
+            .iter()
+            .map(|r| (count_e164s(&r.prev_e164s), count_e164s(&r.new_e164s)))
+            .collect();
+        assert_eq!(sizes, [(0, 2), (2, 2), (4, 1)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lookup_waits_out_short_rate_limit() {
+        let server = FakeCdsiServer::new();
+        // The second batch runs out of permits and has to wait for more.
+        server.set_rate_limit(2, 5);
+        let mut scheduler = LookupScheduler::new(
+            LookupSchedulerConfig {
+                max_new_e164s_per_batch: nonzero!(2usize),
+                ..Default::default()
+            },
+            None,
+        );
+        let mut session = DiscoverySession::default();
+
+        let response = scheduler
+            .lookup(
+                &mut session,
+                numbers(3),
+                vec![],
+                false,
+                || server.connect(),
+                |_| {},
+            )
+            .await
+            .expect("lookup succeeded");
+
+        assert_eq!(response.records.len(), 3);
+        assert_eq!(server.received_requests().len(), 3);
+        assert_eq!(server.permits_used(), 3);
+        assert_eq!(scheduler.retry_at(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lookup_shrinks_batches_that_exceed_rate_limit() {
+        let server = FakeCdsiServer::new();
+        // A batch of two can never be afforded.
+        server.set_rate_limit(1, 5);
+        let mut scheduler = LookupScheduler::new(
+            LookupSchedulerConfig {
+                max_new_e164s_per_batch: nonzero!(2usize),
+                ..Default::default()
+            },
+            None,
+        );
+        let mut session = DiscoverySession::default();
+
+        let response = scheduler
+            .lookup(
+                &mut session,
+                numbers(3),
+                vec![],
+                false,
+                || server.connect(),
+                |_| {},
+            )
+            .await
+            .expect("lookup succeeded");
+
+        assert_eq!(response.records.len(), 3);
+        assert_eq!(server.permits_used(), 3);
+        let sizes: Vec<_> = server
+            .received_requests()
+            .iter()
+            .map(|r| (count_e164s(&r.prev_e164s), count_e164s(&r.new_e164s)))
+            .collect();
+        assert_eq!(sizes, [(0, 2), (0, 1), (1, 1), (1, 1), (2, 1), (2, 1)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lookup_waits_at_least_a_second_after_rate_limit() {
+        let server = FakeCdsiServer::new();
+        server.set_rate_limit(0, 0);
+        let mut scheduler = LookupScheduler::default();
+        let mut session = DiscoverySession::default();
+        let mut attempts = vec![];
+
+        let result = scheduler
+            .lookup(
+                &mut session,
+                numbers(1),
+                vec![],
+                false,
+                || {
+                    attempts.push(tokio::time::Instant::now());
+                    if attempts.len() == 3 {
+                        server.set_rate_limit(1, 0);
+                    }
+                    server.connect()
+                },
+                |_| {},
+            )
+            .await;
+
+        assert_matches!(result, Ok(_));
+        assert_eq!(attempts.len(), 3);
+        for pair in attempts.windows(2) {
+            assert!(pair[1] - pair[0] >= LookupScheduler::MIN_RETRY_DELAY);
+        }
+    }
+
+    #[tokio::test]
+    async fn lookup_gives_up_on_long_rate_limit() {
+        let server = FakeCdsiServer::new();
+        server.set_rate_limit(0, 3600);
+        let mut scheduler = LookupScheduler::default();
+        let mut session = DiscoverySession::default();
+
+        let result = scheduler
+            .lookup(
+                &mut session,
+                numbers(3),
+                vec![],
+                false,
+                || server.connect(),
+                |_| {},
+            )
+            .await;
+        assert_matches!(result, Err(LookupError::RateLimited { retry_after_seconds }) if retry_after_seconds <= 3600);
+        let retry_at = scheduler.retry_at().expect("deadline recorded");
+
+        // A scheduler restored from the saved deadline doesn't contact the server at all.
+        let mut restored = LookupScheduler::new(Default::default(), Some(retry_at));
+        let result = restored
+            .lookup(
+                &mut session,
+                numbers(3),
+                vec![],
+                false,
+                || server.connect(),
+                |_| {},
+            )
+            .await;
+        assert_matches!(result, Err(LookupError::RateLimited { .. }));
+        assert_eq!(
+            server.received_requests().len(),
+            1,
+            "no new lookup was sent"
+        );
+    }
+}
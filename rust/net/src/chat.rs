@@ -9,6 +9,9 @@ use ::http::uri::PathAndQuery;
 use ::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
 use async_trait::async_trait;
 
+use crate::chat::http::ChatOverHttp2ServiceConnector;
+use crate::chat::supervisor::{ChatSupervisor, ReconnectBackoff};
+use crate::chat::transport::ChatOverAnyTransportServiceConnector;
 use crate::chat::ws::{ChatOverWebSocketServiceConnector, ServerRequest};
 use crate::infra::connection_manager::MultiRouteConnectionManager;
 use crate::infra::reconnect::{ServiceConnectorWithDecorator, ServiceWithReconnect};
@@ -16,13 +19,22 @@ use crate::infra::ws::WebSocketClientConnector;
 use crate::infra::{
     ConnectionInfo, EndpointConnection, HttpRequestDecorator, IpType, TransportConnector,
 };
+use crate::metrics::{MeteredChatService, Metrics};
 use crate::proto;
 use crate::utils::basic_authorization;
 
+pub mod api;
 pub mod chat_reconnect;
 mod error;
 pub use error::ChatServiceError;
+#[cfg(any(test, feature = "fake-chat-server"))]
+pub mod fake;
+pub mod http;
+pub mod receive;
 pub mod server_requests;
+pub mod supervisor;
+mod transport;
+pub use transport::{ChatTransport, ChatTransportSelector};
 pub mod ws;
 
 pub type MessageProto = proto::chat_websocket::WebSocketMessage;
@@ -48,6 +60,11 @@ pub trait ChatService {
     /// Depending on the implementing logic, the connection may be re-established later
     /// with a call to [ChatService::send].
     async fn disconnect(&self);
+
+    /// Resolves once the service no longer has an open connection.
+    ///
+    /// Resolves immediately if there is no open connection to begin with.
+    async fn connection_lost(&self);
 }
 
 #[async_trait]
@@ -196,6 +213,41 @@ where
         self.auth_service.disconnect().await;
     }
 
+    /// Keeps the authenticated connection open until the returned supervisor is stopped.
+    pub fn supervise_authenticated(&self, backoff: ReconnectBackoff) -> ChatSupervisor
+    where
+        AuthService: Clone + 'static,
+    {
+        ChatSupervisor::start(self.auth_service.inner.clone(), backoff)
+    }
+
+    /// Keeps the unauthenticated connection open until the returned supervisor is stopped.
+    pub fn supervise_unauthenticated(&self, backoff: ReconnectBackoff) -> ChatSupervisor
+    where
+        UnauthService: Clone + 'static,
+    {
+        ChatSupervisor::start(self.unauth_service.inner.clone(), backoff)
+    }
+
+    /// Records every request sent through either service in `metrics`.
+    pub fn metered(
+        self,
+        metrics: &Metrics,
+    ) -> Chat<MeteredChatService<AuthService>, MeteredChatService<UnauthService>> {
+        let Self {
+            auth_service,
+            unauth_service,
+        } = self;
+        Chat {
+            auth_service: AuthorizedChatService {
+                inner: MeteredChatService::new(auth_service.inner, metrics.clone()),
+            },
+            unauth_service: AnonymousChatService {
+                inner: MeteredChatService::new(unauth_service.inner, metrics.clone()),
+            },
+        }
+    }
+
     pub fn into_dyn(
         self,
     ) -> Chat<
@@ -249,6 +301,10 @@ where
     async fn disconnect(&self) {
         self.inner.disconnect().await
     }
+
+    async fn connection_lost(&self) {
+        self.inner.connection_lost().await
+    }
 }
 
 #[async_trait]
@@ -297,6 +353,10 @@ where
     async fn disconnect(&self) {
         self.inner.disconnect().await
     }
+
+    async fn connection_lost(&self) {
+        self.inner.connection_lost().await
+    }
 }
 
 #[async_trait]
@@ -312,6 +372,10 @@ impl ChatService for Arc<dyn ChatService + Send + Sync> {
     async fn disconnect(&self) {
         self.as_ref().disconnect().await
     }
+
+    async fn connection_lost(&self) {
+        self.as_ref().connection_lost().await
+    }
 }
 
 #[async_trait]
@@ -372,6 +436,10 @@ where
     async fn disconnect(&self) {
         self.inner.disconnect().await
     }
+
+    async fn connection_lost(&self) {
+        self.inner.connection_lost().await
+    }
 }
 
 #[async_trait]
@@ -405,6 +473,10 @@ impl ChatService for Arc<dyn ChatServiceWithDebugInfo + Send + Sync> {
     async fn disconnect(&self) {
         self.as_ref().disconnect().await
     }
+
+    async fn connection_lost(&self) {
+        self.as_ref().connection_lost().await
+    }
 }
 
 #[async_trait]
@@ -423,70 +495,74 @@ impl ChatServiceWithDebugInfo for Arc<dyn ChatServiceWithDebugInfo + Send + Sync
 }
 
 fn build_authorized_chat_service(
-    connection_manager_ws: &MultiRouteConnectionManager,
-    service_connector_ws: &ChatOverWebSocketServiceConnector<impl TransportConnector + 'static>,
+    connection_manager: &MultiRouteConnectionManager,
+    service_connector: &ChatOverAnyTransportServiceConnector<impl TransportConnector + 'static>,
     username: String,
     password: String,
 ) -> AuthorizedChatService<impl ChatServiceWithDebugInfo> {
     let header_auth_decorator = HttpRequestDecorator::Header(
-        http::header::AUTHORIZATION,
+        ::http::header::AUTHORIZATION,
         basic_authorization(&username, &password),
     );
 
-    // ws authorized
-    let chat_over_ws_auth = ServiceWithReconnect::new(
-        ServiceConnectorWithDecorator::new(
-            service_connector_ws.clone(),
-            header_auth_decorator.clone(),
-        ),
-        connection_manager_ws.clone(),
+    let chat_auth = ServiceWithReconnect::new(
+        ServiceConnectorWithDecorator::new(service_connector.clone(), header_auth_decorator),
+        connection_manager.clone(),
         TOTAL_CONNECTION_TIMEOUT,
     );
 
     AuthorizedChatService {
-        inner: AutoDisconnecting {
-            inner: chat_over_ws_auth,
-        },
+        inner: AutoDisconnecting { inner: chat_auth },
     }
 }
 
 fn build_anonymous_chat_service(
-    connection_manager_ws: &MultiRouteConnectionManager,
-    service_connector_ws: &ChatOverWebSocketServiceConnector<impl TransportConnector + 'static>,
+    connection_manager: &MultiRouteConnectionManager,
+    service_connector: &ChatOverAnyTransportServiceConnector<impl TransportConnector + 'static>,
 ) -> AnonymousChatService<impl ChatServiceWithDebugInfo> {
-    // ws anonymous
-    let chat_over_ws_anonymous = ServiceWithReconnect::new(
-        service_connector_ws.clone(),
-        connection_manager_ws.clone(),
+    let chat_anonymous = ServiceWithReconnect::new(
+        service_connector.clone(),
+        connection_manager.clone(),
         TOTAL_CONNECTION_TIMEOUT,
     );
 
     AnonymousChatService {
         inner: AutoDisconnecting {
-            inner: chat_over_ws_anonymous,
+            inner: chat_anonymous,
         },
     }
 }
 
+/// Creates a [Chat] for the given endpoint.
+///
+/// `transport_selector` decides, per route, whether requests go over a WebSocket or over HTTP2.
+/// Requests initiated by the server are only delivered to `incoming_tx` over WebSocket routes.
 pub fn chat_service<T: TransportConnector + 'static>(
     endpoint: &EndpointConnection<MultiRouteConnectionManager>,
     transport_connector: T,
     incoming_tx: tokio::sync::mpsc::Sender<ServerRequest<T::Stream>>,
     username: String,
     password: String,
+    transport_selector: ChatTransportSelector,
 ) -> Chat<impl ChatServiceWithDebugInfo, impl ChatServiceWithDebugInfo> {
     let ws_service_connector = ChatOverWebSocketServiceConnector::new(
-        WebSocketClientConnector::new(transport_connector, endpoint.config.clone()),
+        WebSocketClientConnector::new(transport_connector.clone(), endpoint.config.clone()),
         incoming_tx,
     );
+    let http2_service_connector = ChatOverHttp2ServiceConnector::new(transport_connector);
+    let service_connector = ChatOverAnyTransportServiceConnector::new(
+        ws_service_connector,
+        http2_service_connector,
+        transport_selector,
+    );
     {
         let auth_service = build_authorized_chat_service(
             &endpoint.manager,
-            &ws_service_connector,
+            &service_connector,
             username,
             password,
         );
-        let unauth_service = build_anonymous_chat_service(&endpoint.manager, &ws_service_connector);
+        let unauth_service = build_anonymous_chat_service(&endpoint.manager, &service_connector);
         Chat {
             auth_service,
             unauth_service,
@@ -547,6 +623,12 @@ pub(crate) mod test {
                     status.stop_service()
                 }
             }
+
+            async fn connection_lost(&self) {
+                if let ServiceState::Active(_, status) = &*self.inner {
+                    status.stopped().await
+                }
+            }
         }
 
         pub fn test_request(method: Method, endpoint: &str) -> Request {
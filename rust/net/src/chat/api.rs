@@ -0,0 +1,829 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Typed wrappers for the Chat Service's REST endpoints.
+//!
+//! Each endpoint is exposed as a method on [Chat] that builds the [Request], sends it over the
+//! authenticated connection, and parses the [Response]. Error statuses with a well-known meaning
+//! are mapped to [ChatApiError] variants.
+
+use std::time::Duration;
+
+use http::header::{CONTENT_TYPE, RETRY_AFTER};
+use http::{HeaderMap, HeaderValue, Method, StatusCode};
+use libsignal_protocol::{
+    kem, Aci, CiphertextMessage, CiphertextMessageType, DeviceId, GenericSignedPreKey, IdentityKey,
+    KyberPreKeyRecord, PreKeyBundle, PreKeyRecord, PublicKey, ServiceId, ServiceIdKind,
+    SignalProtocolError, SignedPreKeyRecord, Timestamp,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::chat::{Chat, ChatServiceError, ChatServiceWithDebugInfo, Request, Response};
+use crate::infra::errors::LogSafeDisplay;
+use crate::proto::chat_envelope::envelope;
+
+/// Errors returned by the typed Chat Service API.
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum ChatApiError {
+    /// {0}
+    Chat(#[from] ChatServiceError),
+    /// the server's device list doesn't match the request (missing: {missing_devices:?}, extra: {extra_devices:?})
+    MismatchedDevices {
+        missing_devices: Vec<DeviceId>,
+        extra_devices: Vec<DeviceId>,
+    },
+    /// sessions with some devices are out of date ({stale_devices:?})
+    StaleDevices { stale_devices: Vec<DeviceId> },
+    /// the server requires a challenge to be completed before continuing
+    ChallengeRequired { token: String, options: Vec<String> },
+    /// rate limited by the server
+    RateLimited { retry_after_seconds: Option<u32> },
+    /// unexpected response status {0}
+    UnexpectedStatus(StatusCode),
+    /// the server's response could not be parsed
+    InvalidResponse,
+    /// {0:?} messages can't be sent to a single recipient
+    UnsupportedMessageType(CiphertextMessageType),
+    /// invalid key material: {0}
+    InvalidKeyMaterial(#[from] SignalProtocolError),
+}
+
+impl LogSafeDisplay for ChatApiError {}
+
+/// Pre-keys to upload for one of the account's identities.
+///
+/// Keys that are left out are kept as they are on the server.
+#[derive(Clone, Default)]
+pub struct PreKeyUpload {
+    pub pre_keys: Vec<PreKeyRecord>,
+    pub signed_pre_key: Option<SignedPreKeyRecord>,
+    pub pq_pre_keys: Vec<KyberPreKeyRecord>,
+    pub pq_last_resort_pre_key: Option<KyberPreKeyRecord>,
+}
+
+/// A message encrypted for one of the destination's devices.
+pub struct OutgoingMessage {
+    pub destination_device_id: DeviceId,
+    pub destination_registration_id: u32,
+    pub message: CiphertextMessage,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SendOptions {
+    pub timestamp: Timestamp,
+    /// Only deliver the messages if the recipient is currently connected.
+    pub online: bool,
+    pub urgent: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SendMessageResponse {
+    /// Whether the sender has other devices that should be sent a sync message.
+    pub needs_sync: bool,
+}
+
+/// The parts of a profile that are returned to any client that can see it.
+///
+/// Encrypted fields are returned as-is and must be decrypted with the profile key.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub identity_key: IdentityKey,
+    pub name: Option<Vec<u8>>,
+    pub about: Option<Vec<u8>>,
+    pub about_emoji: Option<Vec<u8>>,
+    pub avatar: Option<String>,
+    pub unidentified_access: Option<Vec<u8>>,
+    pub unrestricted_unidentified_access: bool,
+}
+
+impl<AuthService, UnauthService> Chat<AuthService, UnauthService>
+where
+    AuthService: ChatServiceWithDebugInfo + Send + Sync,
+    UnauthService: ChatServiceWithDebugInfo + Send + Sync,
+{
+    /// Replaces the pre-keys stored on the server for the account's `identity`.
+    pub async fn upload_pre_keys(
+        &self,
+        identity: ServiceIdKind,
+        keys: &PreKeyUpload,
+        timeout: Duration,
+    ) -> Result<(), ChatApiError> {
+        let request = upload_pre_keys_request(identity, keys)?;
+        check_status(self.send_authenticated(request, timeout).await?)?;
+        Ok(())
+    }
+
+    /// Fetches pre-key bundles for `target`, for a single device or (if `device` is `None`) for
+    /// all of its devices.
+    pub async fn get_pre_keys(
+        &self,
+        target: ServiceId,
+        device: Option<DeviceId>,
+        timeout: Duration,
+    ) -> Result<Vec<PreKeyBundle>, ChatApiError> {
+        let request = get_pre_keys_request(target, device);
+        parse_pre_keys_response(check_status(
+            self.send_authenticated(request, timeout).await?,
+        )?)
+    }
+
+    /// Sends `messages` to `destination`; there should be one message for each of its devices.
+    pub async fn send_messages(
+        &self,
+        destination: ServiceId,
+        messages: &[OutgoingMessage],
+        options: SendOptions,
+        timeout: Duration,
+    ) -> Result<SendMessageResponse, ChatApiError> {
+        let request = send_messages_request(destination, messages, options)?;
+        parse_send_messages_response(check_status(
+            self.send_authenticated(request, timeout).await?,
+        )?)
+    }
+
+    pub async fn get_profile(&self, aci: Aci, timeout: Duration) -> Result<Profile, ChatApiError> {
+        let request = get_request(format!("/v1/profile/{}", aci.service_id_string()));
+        parse_profile_response(check_status(
+            self.send_authenticated(request, timeout).await?,
+        )?)
+    }
+
+    /// Tells the server that the message it delivered with `server_guid` can be deleted.
+    pub async fn acknowledge_message(
+        &self,
+        server_guid: Uuid,
+        timeout: Duration,
+    ) -> Result<(), ChatApiError> {
+        let request = Request {
+            method: Method::DELETE,
+            path: format!("/v1/messages/uuid/{server_guid}")
+                .parse()
+                .expect("valid path"),
+            headers: HeaderMap::new(),
+            body: None,
+        };
+        check_status(self.send_authenticated(request, timeout).await?)?;
+        Ok(())
+    }
+}
+
+mod base64_bytes {
+    use base64::prelude::{Engine as _, BASE64_STANDARD};
+    use serde::{Deserialize as _, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64_STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        BASE64_STANDARD
+            .decode(encoded)
+            .map_err(serde::de::Error::custom)
+    }
+
+    pub mod option {
+        use serde::{Deserialize as _, Deserializer};
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Vec<u8>>, D::Error> {
+            #[derive(serde::Deserialize)]
+            struct Wrapper(#[serde(with = "super")] Vec<u8>);
+            Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(bytes)| bytes))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PreKeyEntity {
+    key_id: u32,
+    #[serde(with = "base64_bytes")]
+    public_key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SignedPreKeyEntity {
+    key_id: u32,
+    #[serde(with = "base64_bytes")]
+    public_key: Vec<u8>,
+    #[serde(with = "base64_bytes")]
+    signature: Vec<u8>,
+}
+
+impl SignedPreKeyEntity {
+    fn from_record<R: GenericSignedPreKey>(
+        record: &R,
+        public_key: &[u8],
+    ) -> Result<Self, SignalProtocolError> {
+        Ok(Self {
+            key_id: record.id()?.into(),
+            public_key: public_key.to_vec(),
+            signature: record.signature()?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SetKeysRequest {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pre_keys: Vec<PreKeyEntity>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signed_pre_key: Option<SignedPreKeyEntity>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pq_pre_keys: Vec<SignedPreKeyEntity>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pq_last_resort_pre_key: Option<SignedPreKeyEntity>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PreKeyResponse {
+    #[serde(with = "base64_bytes")]
+    identity_key: Vec<u8>,
+    devices: Vec<PreKeyResponseItem>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PreKeyResponseItem {
+    device_id: u32,
+    registration_id: u32,
+    pre_key: Option<PreKeyEntity>,
+    signed_pre_key: SignedPreKeyEntity,
+    pq_pre_key: Option<SignedPreKeyEntity>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutgoingMessageEntity {
+    r#type: i32,
+    destination_device_id: u32,
+    destination_registration_id: u32,
+    #[serde(with = "base64_bytes")]
+    content: Vec<u8>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutgoingMessageList {
+    messages: Vec<OutgoingMessageEntity>,
+    online: bool,
+    urgent: bool,
+    timestamp: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SendMessageResponseEntity {
+    #[serde(default)]
+    needs_sync: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MismatchedDevicesEntity {
+    #[serde(default)]
+    missing_devices: Vec<u32>,
+    #[serde(default)]
+    extra_devices: Vec<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StaleDevicesEntity {
+    #[serde(default)]
+    stale_devices: Vec<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChallengeEntity {
+    token: String,
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProfileEntity {
+    #[serde(with = "base64_bytes")]
+    identity_key: Vec<u8>,
+    #[serde(default, with = "base64_bytes::option")]
+    name: Option<Vec<u8>>,
+    #[serde(default, with = "base64_bytes::option")]
+    about: Option<Vec<u8>>,
+    #[serde(default, with = "base64_bytes::option")]
+    about_emoji: Option<Vec<u8>>,
+    #[serde(default)]
+    avatar: Option<String>,
+    #[serde(default, with = "base64_bytes::option")]
+    unidentified_access: Option<Vec<u8>>,
+    #[serde(default)]
+    unrestricted_unidentified_access: bool,
+}
+
+/// Envelope types the server expects for messages sent to a single recipient.
+///
+/// These are not always the same values as [CiphertextMessageType].
+fn envelope_type(message_type: CiphertextMessageType) -> Result<i32, ChatApiError> {
+    let envelope_type = match message_type {
+        CiphertextMessageType::Whisper => envelope::Type::Ciphertext,
+        CiphertextMessageType::PreKey => envelope::Type::PrekeyBundle,
+        CiphertextMessageType::Plaintext => envelope::Type::PlaintextContent,
+        CiphertextMessageType::SenderKey => {
+            return Err(ChatApiError::UnsupportedMessageType(message_type))
+        }
+    };
+    Ok(envelope_type.into())
+}
+
+fn get_request(path: String) -> Request {
+    Request {
+        method: Method::GET,
+        path: path.parse().expect("valid path"),
+        headers: HeaderMap::new(),
+        body: None,
+    }
+}
+
+fn json_request(method: Method, path: String, body: &impl Serialize) -> Request {
+    Request {
+        method,
+        path: path.parse().expect("valid path"),
+        headers: HeaderMap::from_iter([(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        )]),
+        body: Some(
+            serde_json::to_vec(body)
+                .expect("can JSON-encode")
+                .into_boxed_slice(),
+        ),
+    }
+}
+
+fn upload_pre_keys_request(
+    identity: ServiceIdKind,
+    keys: &PreKeyUpload,
+) -> Result<Request, SignalProtocolError> {
+    let PreKeyUpload {
+        pre_keys,
+        signed_pre_key,
+        pq_pre_keys,
+        pq_last_resort_pre_key,
+    } = keys;
+    let kyber_entity = |record: &KyberPreKeyRecord| {
+        SignedPreKeyEntity::from_record(record, &record.public_key()?.serialize())
+    };
+    let body = SetKeysRequest {
+        pre_keys: pre_keys
+            .iter()
+            .map(|record| {
+                Ok(PreKeyEntity {
+                    key_id: record.id()?.into(),
+                    public_key: record.public_key()?.serialize().into(),
+                })
+            })
+            .collect::<Result<_, SignalProtocolError>>()?,
+        signed_pre_key: signed_pre_key
+            .as_ref()
+            .map(|record| {
+                SignedPreKeyEntity::from_record(record, &record.public_key()?.serialize())
+            })
+            .transpose()?,
+        pq_pre_keys: pq_pre_keys
+            .iter()
+            .map(kyber_entity)
+            .collect::<Result<_, _>>()?,
+        pq_last_resort_pre_key: pq_last_resort_pre_key
+            .as_ref()
+            .map(kyber_entity)
+            .transpose()?,
+    };
+    let identity = match identity {
+        ServiceIdKind::Aci => "aci",
+        ServiceIdKind::Pni => "pni",
+    };
+    Ok(json_request(
+        Method::PUT,
+        format!("/v2/keys?identity={identity}"),
+        &body,
+    ))
+}
+
+fn get_pre_keys_request(target: ServiceId, device: Option<DeviceId>) -> Request {
+    let device = device.map_or_else(|| "*".to_owned(), |device| device.to_string());
+    get_request(format!("/v2/keys/{}/{device}", target.service_id_string()))
+}
+
+fn send_messages_request(
+    destination: ServiceId,
+    messages: &[OutgoingMessage],
+    options: SendOptions,
+) -> Result<Request, ChatApiError> {
+    let SendOptions {
+        timestamp,
+        online,
+        urgent,
+    } = options;
+    let body = OutgoingMessageList {
+        messages: messages
+            .iter()
+            .map(|message| {
+                Ok(OutgoingMessageEntity {
+                    r#type: envelope_type(message.message.message_type())?,
+                    destination_device_id: message.destination_device_id.into(),
+                    destination_registration_id: message.destination_registration_id,
+                    content: message.message.serialize().to_vec(),
+                })
+            })
+            .collect::<Result<_, ChatApiError>>()?,
+        online,
+        urgent,
+        timestamp: timestamp.epoch_millis(),
+    };
+    Ok(json_request(
+        Method::PUT,
+        format!("/v1/messages/{}", destination.service_id_string()),
+        &body,
+    ))
+}
+
+fn parse_json<'a, T: Deserialize<'a>>(response: &'a Response) -> Result<T, ChatApiError> {
+    serde_json::from_slice(response.body.as_deref().unwrap_or_default()).map_err(|e| {
+        log::warn!("failed to parse {} response: {e}", response.status);
+        ChatApiError::InvalidResponse
+    })
+}
+
+/// Passes successful responses through, and maps error statuses to [ChatApiError]s.
+fn check_status(response: Response) -> Result<Response, ChatApiError> {
+    let device_ids = |ids: Vec<u32>| ids.into_iter().map(DeviceId::from).collect();
+    match response.status {
+        status if status.is_success() => Ok(response),
+        StatusCode::CONFLICT => {
+            let MismatchedDevicesEntity {
+                missing_devices,
+                extra_devices,
+            } = parse_json(&response)?;
+            Err(ChatApiError::MismatchedDevices {
+                missing_devices: device_ids(missing_devices),
+                extra_devices: device_ids(extra_devices),
+            })
+        }
+        StatusCode::GONE => {
+            let StaleDevicesEntity { stale_devices } = parse_json(&response)?;
+            Err(ChatApiError::StaleDevices {
+                stale_devices: device_ids(stale_devices),
+            })
+        }
+        StatusCode::PRECONDITION_REQUIRED => {
+            let ChallengeEntity { token, options } = parse_json(&response)?;
+            Err(ChatApiError::ChallengeRequired { token, options })
+        }
+        StatusCode::TOO_MANY_REQUESTS => Err(ChatApiError::RateLimited {
+            retry_after_seconds: response
+                .headers
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok()?.parse().ok()),
+        }),
+        status => Err(ChatApiError::UnexpectedStatus(status)),
+    }
+}
+
+fn parse_pre_keys_response(response: Response) -> Result<Vec<PreKeyBundle>, ChatApiError> {
+    let PreKeyResponse {
+        identity_key,
+        devices,
+    } = parse_json(&response)?;
+    let invalid = |_| ChatApiError::InvalidResponse;
+    let identity_key = IdentityKey::decode(&identity_key).map_err(invalid)?;
+    devices
+        .into_iter()
+        .map(|device| {
+            let PreKeyResponseItem {
+                device_id,
+                registration_id,
+                pre_key,
+                signed_pre_key,
+                pq_pre_key,
+            } = device;
+            let pre_key = pre_key
+                .map(|PreKeyEntity { key_id, public_key }| {
+                    Ok((key_id.into(), PublicKey::deserialize(&public_key)?))
+                })
+                .transpose()
+                .map_err(invalid)?;
+            let bundle = PreKeyBundle::new(
+                registration_id,
+                device_id.into(),
+                pre_key,
+                signed_pre_key.key_id.into(),
+                PublicKey::deserialize(&signed_pre_key.public_key).map_err(invalid)?,
+                signed_pre_key.signature,
+                identity_key,
+            )
+            .map_err(invalid)?;
+            Ok(match pq_pre_key {
+                Some(SignedPreKeyEntity {
+                    key_id,
+                    public_key,
+                    signature,
+                }) => bundle.with_kyber_pre_key(
+                    key_id.into(),
+                    kem::PublicKey::deserialize(&public_key).map_err(invalid)?,
+                    signature,
+                ),
+                None => bundle,
+            })
+        })
+        .collect()
+}
+
+fn parse_send_messages_response(response: Response) -> Result<SendMessageResponse, ChatApiError> {
+    // Older servers don't include a body.
+    if response.body.is_none() {
+        return Ok(SendMessageResponse { needs_sync: false });
+    }
+    let SendMessageResponseEntity { needs_sync } = parse_json(&response)?;
+    Ok(SendMessageResponse { needs_sync })
+}
+
+fn parse_profile_response(response: Response) -> Result<Profile, ChatApiError> {
+    let ProfileEntity {
+        identity_key,
+        name,
+        about,
+        about_emoji,
+        avatar,
+        unidentified_access,
+        unrestricted_unidentified_access,
+    } = parse_json(&response)?;
+    Ok(Profile {
+        identity_key: IdentityKey::decode(&identity_key)
+            .map_err(|_| ChatApiError::InvalidResponse)?,
+        name,
+        about,
+        about_emoji,
+        avatar,
+        unidentified_access,
+        unrestricted_unidentified_access,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use base64::prelude::{Engine as _, BASE64_STANDARD};
+    use libsignal_protocol::{KeyPair, PlaintextContent};
+    use rand::rngs::OsRng;
+    use serde_json::json;
+
+    use super::*;
+
+    const ACI_UUID: &str = "9d0652a3-dcc3-4d11-975f-74d61598733f";
+
+    fn response(status: u16, body: Option<serde_json::Value>) -> Response {
+        Response {
+            status: StatusCode::from_u16(status).expect("valid"),
+            message: None,
+            body: body.map(|body| serde_json::to_vec(&body).unwrap().into_boxed_slice()),
+            headers: HeaderMap::new(),
+        }
+    }
+
+    fn request_json(request: &Request) -> serde_json::Value {
+        serde_json::from_slice(request.body.as_deref().expect("has body")).expect("valid JSON")
+    }
+
+    #[test]
+    fn upload_pre_keys_request_omits_missing_keys() {
+        let identity = KeyPair::generate(&mut OsRng);
+        let pre_key = KeyPair::generate(&mut OsRng);
+        let signed_pre_key = KeyPair::generate(&mut OsRng);
+        let signature = identity
+            .private_key
+            .calculate_signature(&signed_pre_key.public_key.serialize(), &mut OsRng)
+            .unwrap();
+        let keys = PreKeyUpload {
+            pre_keys: vec![PreKeyRecord::new(7.into(), &pre_key)],
+            signed_pre_key: Some(SignedPreKeyRecord::new(
+                9.into(),
+                Timestamp::from_epoch_millis(1000),
+                &signed_pre_key,
+                &signature,
+            )),
+            ..Default::default()
+        };
+
+        let request = upload_pre_keys_request(ServiceIdKind::Pni, &keys).unwrap();
+        assert_eq!(request.method, Method::PUT);
+        assert_eq!(request.path, "/v2/keys?identity=pni");
+        assert_eq!(
+            request_json(&request),
+            json!({
+                "preKeys": [{
+                    "keyId": 7,
+                    "publicKey": BASE64_STANDARD.encode(pre_key.public_key.serialize()),
+                }],
+                "signedPreKey": {
+                    "keyId": 9,
+                    "publicKey": BASE64_STANDARD.encode(signed_pre_key.public_key.serialize()),
+                    "signature": BASE64_STANDARD.encode(&signature),
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn get_pre_keys_request_path() {
+        let target = ServiceId::parse_from_service_id_string(ACI_UUID).unwrap();
+        assert_eq!(
+            get_pre_keys_request(target, None).path,
+            format!("/v2/keys/{ACI_UUID}/*").as_str()
+        );
+        assert_eq!(
+            get_pre_keys_request(target, Some(3.into())).path,
+            format!("/v2/keys/{ACI_UUID}/3").as_str()
+        );
+    }
+
+    #[test]
+    fn pre_keys_response_produces_bundles() {
+        let identity = KeyPair::generate(&mut OsRng);
+        let signed_pre_key = KeyPair::generate(&mut OsRng);
+        let pre_key = KeyPair::generate(&mut OsRng);
+        let encode = |key: &PublicKey| BASE64_STANDARD.encode(key.serialize());
+
+        let bundles = parse_pre_keys_response(response(
+            200,
+            Some(json!({
+                "identityKey": encode(&identity.public_key),
+                "devices": [
+                    {
+                        "deviceId": 1,
+                        "registrationId": 1234,
+                        "preKey": { "keyId": 5, "publicKey": encode(&pre_key.public_key) },
+                        "signedPreKey": {
+                            "keyId": 6,
+                            "publicKey": encode(&signed_pre_key.public_key),
+                            "signature": BASE64_STANDARD.encode([1; 64]),
+                        },
+                    },
+                    {
+                        "deviceId": 2,
+                        "registrationId": 5678,
+                        "signedPreKey": {
+                            "keyId": 8,
+                            "publicKey": encode(&signed_pre_key.public_key),
+                            "signature": BASE64_STANDARD.encode([2; 64]),
+                        },
+                    },
+                ],
+            })),
+        ))
+        .expect("valid");
+
+        let [first, second] = &bundles[..] else {
+            panic!("expected two bundles, got {}", bundles.len());
+        };
+        assert_eq!(first.device_id().unwrap(), 1.into());
+        assert_eq!(first.registration_id().unwrap(), 1234);
+        assert_eq!(first.pre_key_id().unwrap(), Some(5.into()));
+        assert_eq!(first.pre_key_public().unwrap(), Some(pre_key.public_key));
+        assert_eq!(first.signed_pre_key_id().unwrap(), 6.into());
+        assert_eq!(
+            first.identity_key().unwrap().public_key(),
+            &identity.public_key
+        );
+
+        assert_eq!(second.device_id().unwrap(), 2.into());
+        assert_eq!(second.pre_key_id().unwrap(), None);
+        assert_eq!(second.signed_pre_key_signature().unwrap(), &[2; 64]);
+    }
+
+    #[test]
+    fn pre_keys_response_rejects_bad_keys() {
+        assert_matches!(
+            parse_pre_keys_response(response(
+                200,
+                Some(json!({
+                    "identityKey": BASE64_STANDARD.encode([5; 10]),
+                    "devices": [],
+                })),
+            ))
+            .map(|bundles| bundles.len()),
+            Err(ChatApiError::InvalidResponse)
+        );
+    }
+
+    #[test]
+    fn send_messages_request_uses_envelope_types() {
+        let destination = ServiceId::parse_from_service_id_string(ACI_UUID).unwrap();
+        let content = PlaintextContent::try_from(&[0xC0, 1, 2, 3][..]).unwrap();
+        let serialized = content.serialized().to_vec();
+        let request = send_messages_request(
+            destination,
+            &[OutgoingMessage {
+                destination_device_id: 2.into(),
+                destination_registration_id: 42,
+                message: CiphertextMessage::PlaintextContent(content),
+            }],
+            SendOptions {
+                timestamp: Timestamp::from_epoch_millis(1700000000000),
+                online: false,
+                urgent: true,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(request.method, Method::PUT);
+        assert_eq!(request.path, format!("/v1/messages/{ACI_UUID}").as_str());
+        assert_eq!(
+            request_json(&request),
+            json!({
+                "messages": [{
+                    "type": 8,
+                    "destinationDeviceId": 2,
+                    "destinationRegistrationId": 42,
+                    "content": BASE64_STANDARD.encode(serialized),
+                }],
+                "online": false,
+                "urgent": true,
+                "timestamp": 1700000000000u64,
+            })
+        );
+    }
+
+    #[test]
+    fn send_messages_response_without_body() {
+        assert_eq!(
+            parse_send_messages_response(response(200, None)).unwrap(),
+            SendMessageResponse { needs_sync: false }
+        );
+        assert_eq!(
+            parse_send_messages_response(response(200, Some(json!({ "needsSync": true }))))
+                .unwrap(),
+            SendMessageResponse { needs_sync: true }
+        );
+    }
+
+    #[test]
+    fn error_statuses_are_mapped() {
+        assert_matches!(
+            check_status(response(
+                409,
+                Some(json!({ "missingDevices": [2, 3], "extraDevices": [4] })),
+            )),
+            Err(ChatApiError::MismatchedDevices { missing_devices, extra_devices }) => {
+                assert_eq!(missing_devices, [2.into(), 3.into()]);
+                assert_eq!(extra_devices, [4.into()]);
+            }
+        );
+        assert_matches!(
+            check_status(response(410, Some(json!({ "staleDevices": [5] })))),
+            Err(ChatApiError::StaleDevices { stale_devices }) => {
+                assert_eq!(stale_devices, [5.into()]);
+            }
+        );
+        assert_matches!(
+            check_status(response(
+                428,
+                Some(json!({ "token": "abc", "options": ["recaptcha", "pushChallenge"] })),
+            )),
+            Err(ChatApiError::ChallengeRequired { token, options }) => {
+                assert_eq!(token, "abc");
+                assert_eq!(options, ["recaptcha", "pushChallenge"]);
+            }
+        );
+
+        let mut rate_limited = response(429, None);
+        rate_limited
+            .headers
+            .insert(RETRY_AFTER, HeaderValue::from_static("30"));
+        assert_matches!(
+            check_status(rate_limited),
+            Err(ChatApiError::RateLimited {
+                retry_after_seconds: Some(30)
+            })
+        );
+
+        assert_matches!(
+            check_status(response(409, None)),
+            Err(ChatApiError::InvalidResponse)
+        );
+        assert_matches!(
+            check_status(response(500, None)),
+            Err(ChatApiError::UnexpectedStatus(
+                StatusCode::INTERNAL_SERVER_ERROR
+            ))
+        );
+    }
+}
@@ -38,6 +38,10 @@ where
     async fn disconnect(&self) {
         self.disconnect().await;
     }
+
+    async fn connection_lost(&self) {
+        self.service_stopped().await
+    }
 }
 
 #[async_trait]
@@ -4,6 +4,7 @@
 //
 
 use crate::infra::errors::LogSafeDisplay;
+use crate::infra::http_client::HttpError;
 use crate::infra::reconnect;
 use crate::infra::ws::WebSocketServiceError;
 
@@ -11,6 +12,8 @@ use crate::infra::ws::WebSocketServiceError;
 pub enum ChatServiceError {
     /// websocket error: {0}
     WebSocket(#[from] WebSocketServiceError),
+    /// HTTP error: {0}
+    Http(#[from] HttpError),
     /// Unexpected text frame received
     UnexpectedFrameReceived,
     /// Request message from the server is missing the `id` field
@@ -0,0 +1,658 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! An in-process stand-in for the Chat Service, for writing deterministic tests against
+//! [Chat](crate::chat::Chat) and [ChatService] implementations.
+//!
+//! [FakeChatServer::connector] produces a [TransportConnector] that can be passed to
+//! [chat_service](crate::chat::chat_service) in place of a real one. Connections negotiated for
+//! HTTP/1.1 are served as `chat_websocket` WebSockets, and connections negotiated for HTTP2 are
+//! served as plain HTTP2, matching [ChatTransport](crate::chat::ChatTransport).
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{SinkExt as _, StreamExt as _};
+use http::uri::PathAndQuery;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use http_body_util::{BodyExt as _, Full};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use libsignal_protocol::Timestamp;
+use prost::Message as _;
+use tokio::io::DuplexStream;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+
+use crate::chat::{ChatMessageType, MessageProto, Request, RequestProto, ResponseProto};
+use crate::infra::errors::TransportConnectError;
+use crate::infra::{
+    Alpn, ConnectionInfo, ConnectionParams, DnsSource, RouteType, StreamAndInfo, TransportConnector,
+};
+
+/// Size of the in-memory buffer between a client and the fake server, in each direction.
+const CONNECTION_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A canned response returned by [FakeChatServer] for a matching request.
+#[derive(Clone, Debug)]
+pub struct FakeResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Option<Vec<u8>>,
+}
+
+impl FakeResponse {
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            status,
+            headers: HeaderMap::new(),
+            body: None,
+        }
+    }
+
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn with_body(self, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            body: Some(body.into()),
+            ..self
+        }
+    }
+
+    fn into_proto(self, id: Option<u64>) -> ResponseProto {
+        ResponseProto {
+            id,
+            status: Some(self.status.as_u16().into()),
+            message: self.status.canonical_reason().map(ToOwned::to_owned),
+            headers: header_lines(&self.headers),
+            body: self.body,
+        }
+    }
+
+    fn into_http(self) -> http::Response<Full<Bytes>> {
+        let mut response = http::Response::new(Full::new(self.body.unwrap_or_default().into()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+    }
+}
+
+/// no WebSocket connection to push to
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub struct NotConnected;
+
+/// Resolves to the status the client acknowledged a pushed request with, or to `None` if the
+/// connection was closed first.
+#[derive(Debug)]
+pub struct PushAck(oneshot::Receiver<StatusCode>);
+
+impl Future for PushAck {
+    type Output = Option<StatusCode>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx).map(Result::ok)
+    }
+}
+
+struct Push {
+    request: RequestProto,
+    ack: oneshot::Sender<StatusCode>,
+}
+
+#[derive(Default)]
+struct State {
+    responses: HashMap<(Method, String), FakeResponse>,
+    one_time_responses: HashMap<(Method, String), VecDeque<FakeResponse>>,
+    received: Vec<Request>,
+    websockets: Vec<mpsc::UnboundedSender<Push>>,
+    next_push_id: u64,
+    refuse_connections: bool,
+    disconnect: CancellationToken,
+}
+
+/// An in-process fake of the Chat Service.
+///
+/// Requests are answered with the responses registered via [FakeChatServer::respond] and
+/// [FakeChatServer::respond_once], or with `404 Not Found` if nothing matches. Every request
+/// is recorded and can be inspected with [FakeChatServer::received_requests].
+///
+/// Cloning produces another handle to the same server.
+#[derive(Clone)]
+pub struct FakeChatServer {
+    state: Arc<Mutex<State>>,
+    unresponsive: Arc<watch::Sender<bool>>,
+}
+
+impl Default for FakeChatServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FakeChatServer {
+    pub fn new() -> Self {
+        Self {
+            state: Default::default(),
+            unresponsive: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// Produces a [TransportConnector] whose connections are served by this server.
+    pub fn connector(&self) -> FakeChatConnector {
+        FakeChatConnector {
+            server: self.clone(),
+        }
+    }
+
+    /// Answers every `method` request for `path` (ignoring any query) with `response`.
+    pub fn respond(&self, method: Method, path: &str, response: FakeResponse) {
+        self.state()
+            .responses
+            .insert((method, path.to_owned()), response);
+    }
+
+    /// Answers the next `method` request for `path` with `response`.
+    ///
+    /// One-time responses are used in the order they were added, and take precedence over the
+    /// one registered with [FakeChatServer::respond].
+    pub fn respond_once(&self, method: Method, path: &str, response: FakeResponse) {
+        self.state()
+            .one_time_responses
+            .entry((method, path.to_owned()))
+            .or_default()
+            .push_back(response);
+    }
+
+    /// All requests received so far, over any transport, in the order they arrived.
+    pub fn received_requests(&self) -> Vec<Request> {
+        self.state().received.clone()
+    }
+
+    /// Sends a request to the most recently connected WebSocket client.
+    pub fn push_request(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &HeaderMap,
+        body: Option<Vec<u8>>,
+    ) -> Result<PushAck, NotConnected> {
+        let mut state = self.state();
+        state.websockets.retain(|ws| !ws.is_closed());
+        let id = state.next_push_id;
+        let websocket = state.websockets.last().ok_or(NotConnected)?;
+
+        let (ack_tx, ack_rx) = oneshot::channel();
+        let push = Push {
+            request: RequestProto {
+                verb: Some(method.to_string()),
+                path: Some(path.to_owned()),
+                body,
+                headers: header_lines(headers),
+                id: Some(id),
+            },
+            ack: ack_tx,
+        };
+        websocket.send(push).map_err(|_| NotConnected)?;
+        state.next_push_id += 1;
+        Ok(PushAck(ack_rx))
+    }
+
+    /// Delivers an envelope the way the Chat Service does, as a `PUT /api/v1/message`.
+    pub fn push_envelope(
+        &self,
+        envelope: Vec<u8>,
+        server_delivery_timestamp: Timestamp,
+    ) -> Result<PushAck, NotConnected> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-signal-timestamp"),
+            server_delivery_timestamp.epoch_millis().into(),
+        );
+        self.push_request(Method::PUT, "/api/v1/message", &headers, Some(envelope))
+    }
+
+    /// Signals that all queued envelopes have been delivered.
+    pub fn push_queue_empty(&self) -> Result<PushAck, NotConnected> {
+        self.push_request(Method::PUT, "/api/v1/queue/empty", &HeaderMap::new(), None)
+    }
+
+    /// While set, the server stops reading from its connections.
+    ///
+    /// Requests go unanswered and WebSocket pings go unanswered too, so clients see the
+    /// connection as idle and eventually time it out.
+    pub fn set_unresponsive(&self, unresponsive: bool) {
+        self.unresponsive.send_replace(unresponsive);
+    }
+
+    /// While set, new connection attempts fail as if the server couldn't be reached.
+    pub fn set_refuse_connections(&self, refuse: bool) {
+        self.state().refuse_connections = refuse;
+    }
+
+    /// Closes all open connections; WebSockets are closed with a `Close` frame.
+    ///
+    /// New connections are still accepted afterwards.
+    pub fn disconnect_all(&self) {
+        let token = std::mem::take(&mut self.state().disconnect);
+        token.cancel();
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("not poisoned")
+    }
+
+    fn handle(&self, request: Request) -> FakeResponse {
+        let mut state = self.state();
+        let key = (request.method.clone(), request.path.path().to_owned());
+        state.received.push(request);
+        state
+            .one_time_responses
+            .get_mut(&key)
+            .and_then(VecDeque::pop_front)
+            .or_else(|| state.responses.get(&key).cloned())
+            .unwrap_or_else(|| FakeResponse::new(StatusCode::NOT_FOUND))
+    }
+
+    /// Completes once the server is responsive.
+    async fn responsive(&self) {
+        let mut unresponsive = self.unresponsive.subscribe();
+        // The sender is owned by `self`, so this can't fail.
+        let _ = unresponsive.wait_for(|unresponsive| !unresponsive).await;
+    }
+
+    async fn serve_websocket(self, stream: DuplexStream, disconnect: CancellationToken) {
+        let websocket = match tokio_tungstenite::accept_async(stream).await {
+            Ok(websocket) => websocket,
+            Err(e) => {
+                log::warn!("fake chat server: WebSocket handshake failed: {e}");
+                return;
+            }
+        };
+        let (mut sink, mut source) = websocket.split();
+        let (push_tx, mut push_rx) = mpsc::unbounded_channel();
+        self.state().websockets.push(push_tx);
+        let mut pending_acks = HashMap::<u64, oneshot::Sender<StatusCode>>::new();
+        let mut unresponsive = self.unresponsive.subscribe();
+
+        loop {
+            if *unresponsive.borrow_and_update() {
+                tokio::select! {
+                    // An unresponsive server doesn't send a close frame either.
+                    _ = disconnect.cancelled() => break,
+                    _ = unresponsive.changed() => continue,
+                }
+            }
+
+            let outgoing = tokio::select! {
+                // Check for disconnects and changes in responsiveness before anything else.
+                biased;
+                _ = disconnect.cancelled() => {
+                    let _ignore_failed_close = sink.send(Message::Close(None)).await;
+                    break;
+                }
+                _ = unresponsive.changed() => continue,
+                Some(Push { request, ack }) = push_rx.recv() => {
+                    pending_acks.insert(request.id(), ack);
+                    MessageProto {
+                        r#type: Some(ChatMessageType::Request.into()),
+                        request: Some(request),
+                        response: None,
+                    }
+                }
+                incoming = source.next() => {
+                    let data = match incoming {
+                        Some(Ok(Message::Binary(data))) => data,
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        // Pings are answered by tungstenite itself.
+                        Some(Ok(_)) => continue,
+                    };
+                    let Ok(message) = MessageProto::decode(data.as_slice()) else {
+                        log::warn!("fake chat server: received invalid message");
+                        break;
+                    };
+                    match (message.request, message.response) {
+                        (Some(request), None) => {
+                            let id = request.id;
+                            let response = match request_from_proto(request) {
+                                Some(request) => self.handle(request),
+                                None => FakeResponse::new(StatusCode::BAD_REQUEST),
+                            };
+                            MessageProto {
+                                r#type: Some(ChatMessageType::Response.into()),
+                                request: None,
+                                response: Some(response.into_proto(id)),
+                            }
+                        }
+                        (None, Some(response)) => {
+                            let status = response
+                                .status
+                                .and_then(|status| u16::try_from(status).ok())
+                                .and_then(|status| StatusCode::from_u16(status).ok());
+                            if let (Some(ack), Some(status)) =
+                                (pending_acks.remove(&response.id()), status)
+                            {
+                                let _ignore_dropped_ack = ack.send(status);
+                            }
+                            continue;
+                        }
+                        _ => {
+                            log::warn!("fake chat server: received invalid message");
+                            break;
+                        }
+                    }
+                }
+            };
+
+            if sink
+                .send(Message::Binary(outgoing.encode_to_vec()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    async fn serve_http2(self, stream: DuplexStream, disconnect: CancellationToken) {
+        let server = self.clone();
+        let service =
+            hyper::service::service_fn(move |request: http::Request<hyper::body::Incoming>| {
+                let server = server.clone();
+                async move {
+                    server.responsive().await;
+                    let (parts, body) = http::Request::into_parts(request);
+                    let body = match body.collect().await {
+                        Ok(body) => body.to_bytes(),
+                        Err(_) => return Ok(FakeResponse::new(StatusCode::BAD_REQUEST).into_http()),
+                    };
+                    let request = Request {
+                        method: parts.method,
+                        path: parts
+                            .uri
+                            .path_and_query()
+                            .cloned()
+                            .unwrap_or_else(|| PathAndQuery::from_static("/")),
+                        headers: parts.headers,
+                        body: (!body.is_empty()).then(|| body.to_vec().into_boxed_slice()),
+                    };
+                    Ok::<_, Infallible>(server.handle(request).into_http())
+                }
+            });
+        let connection = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+            .serve_connection(TokioIo::new(stream), service);
+        tokio::select! {
+            result = connection => {
+                if let Err(e) = result {
+                    log::warn!("fake chat server: HTTP2 connection failed: {e}");
+                }
+            }
+            // Dropping the connection future closes the connection.
+            _ = disconnect.cancelled() => {}
+        }
+    }
+}
+
+/// A [TransportConnector] that connects to a [FakeChatServer] in memory.
+#[derive(Clone)]
+pub struct FakeChatConnector {
+    server: FakeChatServer,
+}
+
+#[async_trait]
+impl TransportConnector for FakeChatConnector {
+    type Stream = DuplexStream;
+
+    async fn connect(
+        &self,
+        connection_params: &ConnectionParams,
+        alpn: Alpn,
+    ) -> Result<StreamAndInfo<Self::Stream>, TransportConnectError> {
+        let disconnect = {
+            let state = self.server.state();
+            if state.refuse_connections {
+                return Err(TransportConnectError::TcpConnectionFailed);
+            }
+            state.disconnect.clone()
+        };
+
+        let (client, server_stream) = tokio::io::duplex(CONNECTION_BUFFER_SIZE);
+        let server = self.server.clone();
+        match alpn {
+            Alpn::Http1_1 => tokio::spawn(server.serve_websocket(server_stream, disconnect)),
+            Alpn::Http2 => tokio::spawn(server.serve_http2(server_stream, disconnect)),
+            Alpn::Dot => return Err(TransportConnectError::InvalidConfiguration),
+        };
+
+        Ok(StreamAndInfo(
+            client,
+            ConnectionInfo {
+                route_type: RouteType::Direct,
+                dns_source: DnsSource::Static,
+                address: url::Host::Domain(connection_params.host.to_string()),
+            },
+        ))
+    }
+}
+
+fn header_lines(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some(format!("{name}: {}", value.to_str().ok()?)))
+        .collect()
+}
+
+fn request_from_proto(proto: RequestProto) -> Option<Request> {
+    let method = Method::from_bytes(proto.verb.as_deref()?.as_bytes()).ok()?;
+    let path = proto.path.as_deref()?.parse().ok()?;
+    let headers = proto
+        .headers
+        .iter()
+        .map(|header| {
+            let (name, value) = header.split_once(':')?;
+            Some((
+                HeaderName::try_from(name.trim()).ok()?,
+                HeaderValue::from_str(value.trim()).ok()?,
+            ))
+        })
+        .collect::<Option<HeaderMap>>()?;
+    Some(Request {
+        method,
+        path,
+        headers,
+        body: proto.body.map(Vec::into_boxed_slice),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
+    use futures_util::StreamExt as _;
+    use nonzero_ext::nonzero;
+
+    use super::*;
+    use crate::chat::server_requests::{stream_incoming_messages, ServerMessage};
+    use crate::chat::test::shared::test_request;
+    use crate::chat::{
+        chat_service, ChatServiceError, ChatServiceWithDebugInfo, ChatTransport,
+        ChatTransportSelector,
+    };
+    use crate::infra::certs::RootCertificates;
+    use crate::infra::connection_manager::MAX_COOLDOWN_INTERVAL;
+    use crate::infra::test::shared::{TIMEOUT_DURATION, TIME_ADVANCE_VALUE};
+    use crate::infra::{make_ws_config, EndpointConnection};
+
+    fn chat_with_fake_server(
+        server: &FakeChatServer,
+        transport: ChatTransport,
+    ) -> (
+        crate::chat::Chat<impl ChatServiceWithDebugInfo, impl ChatServiceWithDebugInfo>,
+        mpsc::Receiver<crate::chat::ws::ServerRequest<DuplexStream>>,
+    ) {
+        let connection_params = ConnectionParams::new(
+            RouteType::Test,
+            "chat.signal.org.local",
+            "chat.signal.org.local",
+            nonzero!(443u16),
+            Default::default(),
+            RootCertificates::Signal,
+        );
+        let endpoint = EndpointConnection::new_multi(
+            [connection_params],
+            TIMEOUT_DURATION,
+            make_ws_config(
+                PathAndQuery::from_static("/v1/websocket/"),
+                TIMEOUT_DURATION,
+            ),
+        );
+        let (incoming_tx, incoming_rx) = mpsc::channel(1);
+        let chat = chat_service(
+            &endpoint,
+            server.connector(),
+            incoming_tx,
+            "user".to_owned(),
+            "password".to_owned(),
+            ChatTransportSelector::new(transport),
+        );
+        (chat, incoming_rx)
+    }
+
+    #[tokio::test]
+    async fn fake_server_answers_with_scripted_responses() {
+        for transport in [ChatTransport::WebSocket, ChatTransport::Http2] {
+            let server = FakeChatServer::new();
+            server.respond(
+                Method::GET,
+                "/v1/config",
+                FakeResponse::new(StatusCode::OK).with_body(*b"config"),
+            );
+            server.respond_once(
+                Method::GET,
+                "/v1/config",
+                FakeResponse::new(StatusCode::SERVICE_UNAVAILABLE),
+            );
+            let (chat, _incoming_rx) = chat_with_fake_server(&server, transport);
+            chat.connect_unauthenticated().await.expect("connected");
+
+            let statuses = [
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::OK,
+                StatusCode::OK,
+            ];
+            for expected in statuses {
+                let response = chat
+                    .send_unauthenticated(test_request(Method::GET, "/v1/config"), TIMEOUT_DURATION)
+                    .await
+                    .expect("response");
+                assert_eq!(response.status, expected, "{transport:?}");
+            }
+
+            let response = chat
+                .send_unauthenticated(test_request(Method::GET, "/v1/other"), TIMEOUT_DURATION)
+                .await
+                .expect("response");
+            assert_eq!(response.status, StatusCode::NOT_FOUND);
+            assert_eq!(server.received_requests().len(), 4);
+        }
+    }
+
+    #[tokio::test]
+    async fn fake_server_pushes_envelopes_and_queue_empty() {
+        let server = FakeChatServer::new();
+        let (chat, incoming_rx) = chat_with_fake_server(&server, ChatTransport::WebSocket);
+        assert_matches!(server.push_queue_empty(), Err(NotConnected));
+
+        chat.connect_unauthenticated().await.expect("connected");
+        let envelope_ack = server
+            .push_envelope(b"envelope".to_vec(), Timestamp::from_epoch_millis(1000))
+            .expect("connected");
+        let queue_empty_ack = server.push_queue_empty().expect("connected");
+
+        let mut messages = stream_incoming_messages(incoming_rx);
+        match messages.next().await.expect("message") {
+            ServerMessage::IncomingMessage {
+                envelope,
+                server_delivery_timestamp,
+                send_ack,
+                ..
+            } => {
+                assert_eq!(envelope, b"envelope");
+                assert_eq!(server_delivery_timestamp.epoch_millis(), 1000);
+                send_ack.await.expect("can ack");
+            }
+            message => panic!("unexpected message {message:?}"),
+        }
+        assert_eq!(envelope_ack.await, Some(StatusCode::OK));
+
+        assert_matches!(messages.next().await, Some(ServerMessage::QueueEmpty));
+        // The stream doesn't ack queue-empty signals.
+        chat.disconnect().await;
+        assert_eq!(queue_empty_ack.await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fake_server_forced_disconnect() {
+        let server = FakeChatServer::new();
+        let (chat, _incoming_rx) = chat_with_fake_server(&server, ChatTransport::WebSocket);
+        chat.connect_unauthenticated().await.expect("connected");
+        // Make sure reconnect attempts don't start at the same instant as the first connection.
+        tokio::time::advance(TIME_ADVANCE_VALUE).await;
+
+        server.set_refuse_connections(true);
+        server.disconnect_all();
+        // Give the client a chance to notice the close frame.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_matches!(
+            chat.send_unauthenticated(test_request(Method::GET, "/"), TIMEOUT_DURATION)
+                .await,
+            Err(ChatServiceError::ServiceUnavailable)
+        );
+        assert_matches!(server.push_queue_empty(), Err(NotConnected));
+
+        // The client keeps trying to reconnect in the background.
+        server.set_refuse_connections(false);
+        tokio::time::sleep(MAX_COOLDOWN_INTERVAL).await;
+        let response = chat
+            .send_unauthenticated(test_request(Method::GET, "/"), TIMEOUT_DURATION)
+            .await
+            .expect("reconnected");
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert!(server.push_queue_empty().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fake_server_stops_answering_when_unresponsive() {
+        let server = FakeChatServer::new();
+        let (chat, _incoming_rx) = chat_with_fake_server(&server, ChatTransport::WebSocket);
+        chat.connect_unauthenticated().await.expect("connected");
+
+        server.set_unresponsive(true);
+        assert_matches!(
+            chat.send_unauthenticated(test_request(Method::GET, "/"), TIMEOUT_DURATION)
+                .await,
+            Err(ChatServiceError::Timeout)
+        );
+        assert!(server.received_requests().is_empty());
+
+        server.set_unresponsive(false);
+        let response = chat
+            .send_unauthenticated(test_request(Method::GET, "/"), TIMEOUT_DURATION)
+            .await
+            .expect("response");
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        // The request that timed out is answered (and recorded) late.
+        assert_eq!(server.received_requests().len(), 2);
+    }
+}
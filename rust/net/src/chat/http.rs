@@ -0,0 +1,278 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use derive_where::derive_where;
+
+use crate::chat::{ChatService, ChatServiceError, RemoteAddressInfo, Request, Response};
+use crate::infra::http_client::{
+    http2_channel, AggregatingHttp2Client, Http2Channel, Http2Connection, HttpError,
+};
+use crate::infra::reconnect::{ServiceConnector, ServiceStatus};
+use crate::infra::{AsyncDuplexStream, ConnectionInfo, ConnectionParams, TransportConnector};
+
+/// Largest response body the chat client will accept over HTTP2.
+const MAX_RESPONSE_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive_where(Clone)]
+pub(super) struct ChatOverHttp2ServiceConnector<T: TransportConnector> {
+    transport_connector: T,
+}
+
+impl<T: TransportConnector> ChatOverHttp2ServiceConnector<T> {
+    pub fn new(transport_connector: T) -> Self {
+        Self {
+            transport_connector,
+        }
+    }
+}
+
+#[async_trait]
+impl<T: TransportConnector> ServiceConnector for ChatOverHttp2ServiceConnector<T> {
+    type Service = ChatOverHttp2;
+    type Channel = Http2Channel<T::Stream>;
+    type ConnectError = HttpError;
+    type StartError = ChatServiceError;
+
+    async fn connect_channel(
+        &self,
+        connection_params: &ConnectionParams,
+    ) -> Result<Self::Channel, Self::ConnectError> {
+        http2_channel(
+            &self.transport_connector,
+            connection_params.clone(),
+            MAX_RESPONSE_SIZE,
+        )
+        .await
+    }
+
+    fn start_service(
+        &self,
+        channel: Self::Channel,
+    ) -> (Self::Service, ServiceStatus<Self::StartError>) {
+        let Http2Channel {
+            client,
+            connection,
+            connection_info,
+        } = channel;
+        let service_status = ServiceStatus::default();
+        tokio::spawn(connection_task(
+            connection,
+            service_status.clone(),
+            connection_info.clone(),
+        ));
+        (
+            ChatOverHttp2 {
+                http_client: client,
+                service_status: service_status.clone(),
+                connection_info,
+            },
+            service_status,
+        )
+    }
+}
+
+async fn connection_task<S: AsyncDuplexStream + 'static>(
+    connection: Http2Connection<S>,
+    service_status: ServiceStatus<ChatServiceError>,
+    connection_info: ConnectionInfo,
+) {
+    tokio::select! {
+        result = connection => {
+            match result {
+                Ok(()) => {
+                    log::info!("HTTP2 connection [{}] closed", connection_info.description());
+                }
+                Err(err) => {
+                    log::warn!(
+                        "HTTP2 connection [{}] failed: {}",
+                        connection_info.description(),
+                        err
+                    );
+                }
+            }
+            service_status.stop_service_with_error(HttpError::ConnectionClosed.into());
+        }
+        // Dropping the connection future closes the connection.
+        _ = service_status.stopped() => {}
+    }
+}
+
+/// Sends each [Request] to the Chat Service as a separate HTTP2 request.
+///
+/// Unlike [ChatOverWebSocket](crate::chat::ws::ChatOverWebSocket), this transport has no way to
+/// receive requests initiated by the server.
+#[derive(Clone, Debug)]
+pub struct ChatOverHttp2 {
+    http_client: AggregatingHttp2Client,
+    service_status: ServiceStatus<ChatServiceError>,
+    connection_info: ConnectionInfo,
+}
+
+impl RemoteAddressInfo for ChatOverHttp2 {
+    fn connection_info(&self) -> ConnectionInfo {
+        self.connection_info.clone()
+    }
+}
+
+#[async_trait]
+impl ChatService for ChatOverHttp2 {
+    async fn send(&self, msg: Request, timeout: Duration) -> Result<Response, ChatServiceError> {
+        // checking if the connection has been closed
+        if self.service_status.is_stopped() {
+            return Err(HttpError::ConnectionClosed.into());
+        }
+
+        let Request {
+            method,
+            body,
+            headers,
+            path,
+        } = msg;
+        let mut request_builder = http::Request::builder().method(method);
+        request_builder
+            .headers_mut()
+            .expect("builder has no errors yet")
+            .extend(headers);
+        let body = body.map(Bytes::from).unwrap_or_default();
+
+        let (parts, body) = tokio::select! {
+            result = self.http_client.send_request_aggregate_response(path, request_builder, body) => result?,
+            _ = tokio::time::sleep(timeout) => return Err(ChatServiceError::Timeout),
+            _ = self.service_status.stopped() => return Err(HttpError::ConnectionClosed.into()),
+        };
+
+        Ok(Response {
+            status: parts.status,
+            message: parts.status.canonical_reason().map(ToOwned::to_owned),
+            body: (!body.is_empty()).then(|| body.to_vec().into_boxed_slice()),
+            headers: parts.headers,
+        })
+    }
+
+    async fn connect(&self) -> Result<(), ChatServiceError> {
+        // ChatOverHttp2 is created connected
+        Ok(())
+    }
+
+    async fn disconnect(&self) {
+        self.service_status.stop_service()
+    }
+
+    async fn connection_lost(&self) {
+        self.service_status.stopped().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use http::{HeaderValue, Method, StatusCode};
+    use warp::{Filter, Reply};
+
+    use crate::chat::http::ChatOverHttp2ServiceConnector;
+    use crate::chat::test::shared::{connection_manager, test_request};
+    use crate::chat::{ChatService, ChatServiceError};
+    use crate::infra::http_client::HttpError;
+    use crate::infra::reconnect::ServiceState;
+    use crate::infra::test::shared::{InMemoryWarpConnector, NoReconnectService, TIMEOUT_DURATION};
+
+    async fn create_http2_chat_service<F>(
+        server: F,
+    ) -> NoReconnectService<ChatOverHttp2ServiceConnector<InMemoryWarpConnector<F>>>
+    where
+        F: Filter + Clone + Send + Sync + 'static,
+        F::Extract: Reply,
+    {
+        let connector = ChatOverHttp2ServiceConnector::new(InMemoryWarpConnector::new(server));
+        NoReconnectService::start(connector, connection_manager()).await
+    }
+
+    #[tokio::test]
+    async fn http2_service_sends_request_receives_response() {
+        let server = warp::post()
+            .and(warp::path!("v1" / "echo"))
+            .and(warp::header::<String>("x-test"))
+            .and(warp::body::bytes())
+            .map(|header: String, body: warp::hyper::body::Bytes| {
+                warp::reply::with_header(body.to_vec(), "x-test-reply", header)
+            });
+
+        let chat = create_http2_chat_service(server).await;
+        assert!(!chat.service_status().unwrap().is_stopped());
+
+        let mut request = test_request(Method::POST, "/v1/echo");
+        request
+            .headers
+            .insert("x-test", HeaderValue::from_static("header value"));
+        request.body = Some(b"request body".to_vec().into_boxed_slice());
+
+        let response = chat
+            .send(request, TIMEOUT_DURATION)
+            .await
+            .expect("response");
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.message.as_deref(), Some("OK"));
+        assert_eq!(response.body.as_deref(), Some(&b"request body"[..]));
+        assert_eq!(
+            response.headers.get("x-test-reply"),
+            Some(&HeaderValue::from_static("header value"))
+        );
+    }
+
+    #[tokio::test]
+    async fn http2_service_passes_error_statuses_through() {
+        let server = warp::any()
+            .map(|| warp::reply::with_status(warp::reply(), warp::http::StatusCode::UNAUTHORIZED));
+
+        let chat = create_http2_chat_service(server).await;
+        let response = chat
+            .send(test_request(Method::GET, "/v1/keepalive"), TIMEOUT_DURATION)
+            .await
+            .expect("response");
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response.body, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn http2_service_times_out_on_late_response_from_server() {
+        let server = warp::any().then(|| async {
+            tokio::time::sleep(TIMEOUT_DURATION * 2).await;
+            warp::reply()
+        });
+
+        let chat = create_http2_chat_service(server).await;
+        let response = chat
+            .send(test_request(Method::GET, "/"), TIMEOUT_DURATION)
+            .await;
+        assert_matches!(response, Err(ChatServiceError::Timeout));
+        // a single slow request doesn't take the connection down
+        assert!(!chat.service_status().unwrap().is_stopped());
+    }
+
+    #[tokio::test]
+    async fn http2_service_fails_requests_after_disconnect() {
+        let server = warp::any().map(warp::reply);
+
+        let chat = create_http2_chat_service(server).await;
+        let service = match &*chat.inner {
+            ServiceState::Active(service, _) => service.clone(),
+            _ => panic!("service is not active"),
+        };
+        service.disconnect().await;
+        assert!(chat.service_status().unwrap().is_stopped());
+
+        let response = service
+            .send(test_request(Method::GET, "/"), TIMEOUT_DURATION)
+            .await;
+        assert_matches!(
+            response,
+            Err(ChatServiceError::Http(HttpError::ConnectionClosed))
+        );
+    }
+}
@@ -0,0 +1,1166 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Turns the messages produced by [stream_incoming_messages] into decrypted content.
+//!
+//! A [ReceivePipeline] parses each envelope, decrypts it against a [ProtocolStore], and hands the
+//! result to a [CommitHook]. The envelope is only acknowledged to the server once the hook has
+//! durably recorded it, and the changes that decryption makes to the store are held back until then
+//! as well. Envelopes that fail to decrypt are committed and acknowledged too, so that the caller
+//! can request a resend instead of having the same envelope delivered forever.
+//!
+//! [stream_incoming_messages]: crate::chat::server_requests::stream_incoming_messages
+
+use std::fmt::{Debug, Display};
+
+use async_trait::async_trait;
+use futures_util::{Stream, StreamExt as _};
+use libsignal_protocol::{
+    group_decrypt, message_decrypt, sealed_sender_decrypt_to_usmc, Aci, CiphertextMessage,
+    CiphertextMessageType, ContentHint, DeviceId, Direction, IdentityKey, IdentityKeyPair,
+    IdentityKeyStore, KyberPreKeyId, KyberPreKeyRecord, KyberPreKeyStore, PlaintextContent,
+    PreKeyId, PreKeyRecord, PreKeySignalMessage, PreKeyStore, ProtocolAddress, ProtocolStore,
+    PublicKey, SenderKeyRecord, SenderKeyStore, ServiceId, SessionRecord, SessionStore,
+    SignalMessage, SignalProtocolError, SignedPreKeyId, SignedPreKeyRecord, SignedPreKeyStore,
+    Timestamp,
+};
+use prost::Message as _;
+use rand::rngs::OsRng;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::chat::server_requests::ServerMessage;
+use crate::chat::ChatServiceError;
+use crate::proto::chat_envelope::{envelope, Envelope};
+
+/// Information about the local device needed to process sealed sender messages.
+#[derive(Clone, Debug)]
+pub struct ReceiveConfig {
+    pub local_aci: Aci,
+    pub local_e164: Option<String>,
+    pub local_device_id: DeviceId,
+    /// The key that sender certificates must chain up to.
+    pub trust_root: PublicKey,
+}
+
+#[derive(Debug)]
+pub enum ReceivedItem {
+    Envelope(ReceivedEnvelope),
+    /// The server has delivered every message that was queued when the connection was opened.
+    QueueEmpty,
+}
+
+#[derive(Debug)]
+pub struct ReceivedEnvelope {
+    /// Identifies the envelope on the server; the same envelope may be delivered more than once if
+    /// it wasn't acknowledged.
+    pub server_guid: Option<Uuid>,
+    pub server_timestamp: Timestamp,
+    /// The timestamp chosen by the sender, used to refer to the message in receipts.
+    pub timestamp: Timestamp,
+    pub destination: Option<ServiceId>,
+    pub urgent: bool,
+    pub story: bool,
+    pub content: ReceivedContent,
+}
+
+#[derive(Debug)]
+pub enum ReceivedContent {
+    Message(DecryptedMessage),
+    /// The server is reporting that the message sent at [ReceivedEnvelope::timestamp] was
+    /// delivered to `sender`.
+    DeliveryReceipt {
+        sender: ProtocolAddress,
+    },
+    Failed(DecryptionFailure),
+}
+
+#[derive(Debug)]
+pub struct DecryptedMessage {
+    pub sender: ProtocolAddress,
+    /// Only available for sealed sender messages whose certificate includes it.
+    pub sender_e164: Option<String>,
+    pub sealed_sender: bool,
+    pub message_type: CiphertextMessageType,
+    pub content_hint: ContentHint,
+    pub group_id: Option<Vec<u8>>,
+    /// The serialized `Content`, with padding removed.
+    pub content: Vec<u8>,
+}
+
+/// An envelope that could not be decrypted.
+///
+/// The fields other than `error` describe how far processing got, and are enough to build a
+/// `DecryptionErrorMessage` asking the sender to try again.
+#[derive(Debug)]
+pub struct DecryptionFailure {
+    pub sender: Option<ProtocolAddress>,
+    pub message_type: Option<CiphertextMessageType>,
+    pub content_hint: ContentHint,
+    pub group_id: Option<Vec<u8>>,
+    /// The ciphertext that failed to decrypt, with any sealed sender wrapping removed.
+    pub ciphertext: Vec<u8>,
+    pub error: DecryptionError,
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum DecryptionError {
+    /// invalid envelope: {0}
+    InvalidEnvelope(&'static str),
+    /// envelope type {0} is not supported
+    UnsupportedType(i32),
+    /// {0}
+    Protocol(#[from] SignalProtocolError),
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum ReceiveError<E: Debug + Display> {
+    /// failed to commit received envelope: {0}
+    Commit(E),
+    /// failed to update the protocol store: {0}
+    Store(SignalProtocolError),
+    /// failed to acknowledge envelope: {0}
+    Ack(ChatServiceError),
+}
+
+/// Records received envelopes before they are acknowledged.
+#[async_trait(?Send)]
+pub trait CommitHook {
+    type Error: Debug + Display;
+
+    /// Durably records `envelope`, including envelopes that failed to decrypt.
+    ///
+    /// If this fails, the envelope is not acknowledged and the server will deliver it again. The
+    /// protocol store is only updated after this succeeds, so the redelivered envelope can still
+    /// be decrypted.
+    async fn commit(&mut self, envelope: &ReceivedEnvelope) -> Result<(), Self::Error>;
+}
+
+pub struct ReceivePipeline<S, H> {
+    store: S,
+    hook: H,
+    config: ReceiveConfig,
+}
+
+impl<S, H> ReceivePipeline<S, H>
+where
+    S: ProtocolStore + SenderKeyStore,
+    H: CommitHook,
+{
+    pub fn new(store: S, hook: H, config: ReceiveConfig) -> Self {
+        Self {
+            store,
+            hook,
+            config,
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_parts(self) -> (S, H) {
+        (self.store, self.hook)
+    }
+
+    /// Decrypts and commits a single message, acknowledging it if it was an envelope.
+    pub async fn process(
+        &mut self,
+        message: ServerMessage,
+    ) -> Result<ReceivedItem, ReceiveError<H::Error>> {
+        let (envelope, server_delivery_timestamp, send_ack) = match message {
+            ServerMessage::QueueEmpty => return Ok(ReceivedItem::QueueEmpty),
+            ServerMessage::IncomingMessage {
+                request_id: _,
+                envelope,
+                server_delivery_timestamp,
+                send_ack,
+            } => (envelope, server_delivery_timestamp, send_ack),
+        };
+
+        let store = SharedStore::new(&mut self.store);
+        let received =
+            decrypt_envelope(&store, &self.config, &envelope, server_delivery_timestamp).await;
+        let writes = store.into_pending_writes();
+        self.hook
+            .commit(&received)
+            .await
+            .map_err(ReceiveError::Commit)?;
+        writes
+            .apply(&mut self.store)
+            .await
+            .map_err(ReceiveError::Store)?;
+        send_ack.await.map_err(ReceiveError::Ack)?;
+        Ok(ReceivedItem::Envelope(received))
+    }
+
+    /// Processes each of `messages` in turn.
+    pub fn process_stream(
+        self,
+        messages: impl Stream<Item = ServerMessage>,
+    ) -> impl Stream<Item = Result<ReceivedItem, ReceiveError<H::Error>>> {
+        futures_util::stream::unfold(
+            (self, Box::pin(messages)),
+            |(mut pipeline, mut messages)| async move {
+                let message = messages.next().await?;
+                let result = pipeline.process(message).await;
+                Some((result, (pipeline, messages)))
+            },
+        )
+    }
+}
+
+async fn decrypt_envelope<S: ProtocolStore + SenderKeyStore>(
+    store: &SharedStore<'_, S>,
+    config: &ReceiveConfig,
+    bytes: &[u8],
+    server_delivery_timestamp: Timestamp,
+) -> ReceivedEnvelope {
+    let envelope = match Envelope::decode(bytes) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            log::warn!("failed to decode envelope: {e}");
+            return ReceivedEnvelope {
+                server_guid: None,
+                server_timestamp: server_delivery_timestamp,
+                timestamp: Timestamp::from_epoch_millis(0),
+                destination: None,
+                urgent: true,
+                story: false,
+                content: ReceivedContent::Failed(DecryptionFailure::new(
+                    None,
+                    None,
+                    bytes,
+                    DecryptionError::InvalidEnvelope("could not be decoded"),
+                )),
+            };
+        }
+    };
+
+    let server_timestamp = envelope
+        .server_timestamp
+        .map_or(server_delivery_timestamp, Timestamp::from_epoch_millis);
+    let content = decrypt_content(store, config, &envelope, server_timestamp).await;
+    ReceivedEnvelope {
+        server_guid: envelope
+            .server_guid
+            .as_deref()
+            .and_then(|guid| Uuid::try_parse(guid).ok()),
+        server_timestamp,
+        timestamp: Timestamp::from_epoch_millis(envelope.timestamp()),
+        destination: envelope
+            .destination_service_id
+            .as_deref()
+            .and_then(ServiceId::parse_from_service_id_string),
+        urgent: envelope.urgent(),
+        story: envelope.story(),
+        content,
+    }
+}
+
+async fn decrypt_content<S: ProtocolStore + SenderKeyStore>(
+    store: &SharedStore<'_, S>,
+    config: &ReceiveConfig,
+    envelope: &Envelope,
+    server_timestamp: Timestamp,
+) -> ReceivedContent {
+    let ciphertext = envelope.content.as_deref().unwrap_or_default();
+    let message_type = match envelope.r#type() {
+        envelope::Type::Ciphertext => CiphertextMessageType::Whisper,
+        envelope::Type::PrekeyBundle => CiphertextMessageType::PreKey,
+        envelope::Type::SenderkeyMessage => CiphertextMessageType::SenderKey,
+        envelope::Type::PlaintextContent => CiphertextMessageType::Plaintext,
+        envelope::Type::UnidentifiedSender => {
+            return decrypt_sealed_sender(store, config, ciphertext, server_timestamp).await
+        }
+        envelope::Type::ServerDeliveryReceipt => {
+            return match envelope_source(envelope) {
+                Ok(sender) => ReceivedContent::DeliveryReceipt { sender },
+                Err(e) => {
+                    ReceivedContent::Failed(DecryptionFailure::new(None, None, ciphertext, e))
+                }
+            }
+        }
+        envelope::Type::Unknown | envelope::Type::KeyExchange => {
+            return ReceivedContent::Failed(DecryptionFailure::new(
+                None,
+                None,
+                ciphertext,
+                DecryptionError::UnsupportedType(envelope.r#type.unwrap_or_default()),
+            ))
+        }
+    };
+
+    let sender = match envelope_source(envelope) {
+        Ok(sender) => sender,
+        Err(e) => {
+            return ReceivedContent::Failed(DecryptionFailure::new(
+                None,
+                Some(message_type),
+                ciphertext,
+                e,
+            ))
+        }
+    };
+    match decrypt_by_type(store, &sender, message_type, ciphertext).await {
+        Ok(content) => ReceivedContent::Message(DecryptedMessage {
+            sender,
+            sender_e164: None,
+            sealed_sender: false,
+            message_type,
+            content_hint: ContentHint::Default,
+            group_id: None,
+            content,
+        }),
+        Err(e) => ReceivedContent::Failed(DecryptionFailure::new(
+            Some(sender),
+            Some(message_type),
+            ciphertext,
+            e.into(),
+        )),
+    }
+}
+
+async fn decrypt_sealed_sender<S: ProtocolStore + SenderKeyStore>(
+    store: &SharedStore<'_, S>,
+    config: &ReceiveConfig,
+    ciphertext: &[u8],
+    server_timestamp: Timestamp,
+) -> ReceivedContent {
+    let usmc = match sealed_sender_decrypt_to_usmc(ciphertext, &store).await {
+        Ok(usmc) => usmc,
+        Err(e) => {
+            return ReceivedContent::Failed(DecryptionFailure::new(
+                None,
+                None,
+                ciphertext,
+                e.into(),
+            ))
+        }
+    };
+
+    // Everything below reads from an already-parsed message, so these accessors can't fail.
+    let certificate = usmc.sender().expect("parsed");
+    let message_type = usmc.msg_type().expect("parsed");
+    let contents = usmc.contents().expect("parsed");
+    let content_hint = usmc.content_hint().expect("parsed");
+    let group_id = usmc.group_id().expect("parsed").map(<[u8]>::to_vec);
+    let sender = ProtocolAddress::new(
+        certificate.sender_uuid().expect("parsed").to_owned(),
+        certificate.sender_device_id().expect("parsed"),
+    );
+    let failed = |error: SignalProtocolError| {
+        ReceivedContent::Failed(DecryptionFailure {
+            sender: Some(sender.clone()),
+            message_type: Some(message_type),
+            content_hint,
+            group_id: group_id.clone(),
+            ciphertext: contents.to_vec(),
+            error: error.into(),
+        })
+    };
+
+    match certificate.validate(&config.trust_root, server_timestamp) {
+        Ok(true) => {}
+        Ok(false) => {
+            return failed(SignalProtocolError::InvalidSealedSenderMessage(
+                "trust root validation failed".to_owned(),
+            ))
+        }
+        Err(e) => return failed(e),
+    }
+    let is_local_account = sender.name() == config.local_aci.service_id_string()
+        || matches!(
+            (&config.local_e164, certificate.sender_e164()),
+            (Some(local), Ok(Some(sender))) if local == sender
+        );
+    if is_local_account && sender.device_id() == config.local_device_id {
+        return failed(SignalProtocolError::SealedSenderSelfSend);
+    }
+
+    match decrypt_by_type(store, &sender, message_type, contents).await {
+        Ok(content) => ReceivedContent::Message(DecryptedMessage {
+            sender_e164: certificate
+                .sender_e164()
+                .expect("parsed")
+                .map(str::to_owned),
+            sender,
+            sealed_sender: true,
+            message_type,
+            content_hint,
+            group_id,
+            content,
+        }),
+        Err(e) => failed(e),
+    }
+}
+
+fn envelope_source(envelope: &Envelope) -> Result<ProtocolAddress, DecryptionError> {
+    let service_id = envelope
+        .source_service_id
+        .as_deref()
+        .and_then(ServiceId::parse_from_service_id_string)
+        .ok_or(DecryptionError::InvalidEnvelope(
+            "missing or invalid source",
+        ))?;
+    let device_id = envelope
+        .source_device
+        .ok_or(DecryptionError::InvalidEnvelope("missing source device"))?;
+    Ok(ProtocolAddress::new(
+        service_id.service_id_string(),
+        device_id.into(),
+    ))
+}
+
+async fn decrypt_by_type<S: ProtocolStore + SenderKeyStore>(
+    store: &SharedStore<'_, S>,
+    sender: &ProtocolAddress,
+    message_type: CiphertextMessageType,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, SignalProtocolError> {
+    let message = match message_type {
+        CiphertextMessageType::Whisper => {
+            CiphertextMessage::SignalMessage(SignalMessage::try_from(ciphertext)?)
+        }
+        CiphertextMessageType::PreKey => {
+            CiphertextMessage::PreKeySignalMessage(PreKeySignalMessage::try_from(ciphertext)?)
+        }
+        CiphertextMessageType::SenderKey => {
+            let padded = group_decrypt(ciphertext, &mut &*store, sender).await?;
+            return Ok(strip_padding(padded));
+        }
+        CiphertextMessageType::Plaintext => {
+            let content = PlaintextContent::try_from(ciphertext)?;
+            return Ok(strip_padding(content.body().to_vec()));
+        }
+    };
+    let padded = message_decrypt(
+        &message,
+        sender,
+        &mut &*store,
+        &mut &*store,
+        &mut &*store,
+        &store,
+        &mut &*store,
+        &mut OsRng,
+    )
+    .await?;
+    Ok(strip_padding(padded))
+}
+
+/// Removes the padding that senders add after the content: a single `0x80` followed by zeros.
+///
+/// Content without a padding boundary is returned unchanged.
+fn strip_padding(mut content: Vec<u8>) -> Vec<u8> {
+    let Some(boundary) = content.iter().rposition(|b| *b != 0) else {
+        return content;
+    };
+    if content[boundary] == 0x80 {
+        content.truncate(boundary);
+    }
+    content
+}
+
+impl DecryptionFailure {
+    fn new(
+        sender: Option<ProtocolAddress>,
+        message_type: Option<CiphertextMessageType>,
+        ciphertext: &[u8],
+        error: DecryptionError,
+    ) -> Self {
+        Self {
+            sender,
+            message_type,
+            content_hint: ContentHint::Default,
+            group_id: None,
+            ciphertext: ciphertext.to_vec(),
+            error,
+        }
+    }
+}
+
+/// Lets a single [ProtocolStore] be passed as each of the separate stores that decryption needs.
+///
+/// Writes are held back in `writes` rather than applied, so that nothing changes in the real store
+/// until the envelope has been committed; reads see the held-back writes first. Only one store
+/// operation is in progress at any time, so neither lock is ever contended.
+struct SharedStore<'a, S> {
+    store: Mutex<&'a mut S>,
+    writes: std::sync::Mutex<Vec<StoreWrite>>,
+}
+
+/// A write made during decryption, applied to the real store by [PendingWrites::apply].
+enum StoreWrite {
+    Identity(ProtocolAddress, IdentityKey),
+    Session(ProtocolAddress, SessionRecord),
+    PreKey(PreKeyId, PreKeyRecord),
+    RemovePreKey(PreKeyId),
+    SignedPreKey(SignedPreKeyId, SignedPreKeyRecord),
+    KyberPreKey(KyberPreKeyId, KyberPreKeyRecord),
+    MarkKyberPreKeyUsed(KyberPreKeyId),
+    SenderKey(ProtocolAddress, Uuid, SenderKeyRecord),
+}
+
+/// The writes made while decrypting one envelope, in the order they were made.
+struct PendingWrites(Vec<StoreWrite>);
+
+impl<'a, S> SharedStore<'a, S> {
+    fn new(store: &'a mut S) -> Self {
+        Self {
+            store: Mutex::new(store),
+            writes: Default::default(),
+        }
+    }
+
+    fn into_pending_writes(self) -> PendingWrites {
+        PendingWrites(self.writes.into_inner().expect("not poisoned"))
+    }
+
+    fn push(&self, write: StoreWrite) {
+        self.writes.lock().expect("not poisoned").push(write)
+    }
+
+    /// Returns the result of `f` for the most recent held-back write it matches.
+    fn find<T>(&self, f: impl FnMut(&StoreWrite) -> Option<T>) -> Option<T> {
+        self.writes
+            .lock()
+            .expect("not poisoned")
+            .iter()
+            .rev()
+            .find_map(f)
+    }
+}
+
+impl PendingWrites {
+    async fn apply<S: ProtocolStore + SenderKeyStore>(
+        self,
+        store: &mut S,
+    ) -> Result<(), SignalProtocolError> {
+        for write in self.0 {
+            match write {
+                StoreWrite::Identity(address, identity) => {
+                    store.save_identity(&address, &identity).await?;
+                }
+                StoreWrite::Session(address, record) => {
+                    store.store_session(&address, &record).await?
+                }
+                StoreWrite::PreKey(id, record) => store.save_pre_key(id, &record).await?,
+                StoreWrite::RemovePreKey(id) => store.remove_pre_key(id).await?,
+                StoreWrite::SignedPreKey(id, record) => {
+                    store.save_signed_pre_key(id, &record).await?
+                }
+                StoreWrite::KyberPreKey(id, record) => {
+                    store.save_kyber_pre_key(id, &record).await?
+                }
+                StoreWrite::MarkKyberPreKeyUsed(id) => store.mark_kyber_pre_key_used(id).await?,
+                StoreWrite::SenderKey(sender, distribution_id, record) => {
+                    store
+                        .store_sender_key(&sender, distribution_id, &record)
+                        .await?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: ProtocolStore> IdentityKeyStore for &SharedStore<'_, S> {
+    async fn get_identity_key_pair(&self) -> Result<IdentityKeyPair, SignalProtocolError> {
+        self.store.lock().await.get_identity_key_pair().await
+    }
+
+    async fn get_local_registration_id(&self) -> Result<u32, SignalProtocolError> {
+        self.store.lock().await.get_local_registration_id().await
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+    ) -> Result<bool, SignalProtocolError> {
+        let replaced = self
+            .get_identity(address)
+            .await?
+            .is_some_and(|existing| existing != *identity);
+        self.push(StoreWrite::Identity(address.clone(), *identity));
+        Ok(replaced)
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: Direction,
+    ) -> Result<bool, SignalProtocolError> {
+        // An identity saved during this decryption is trusted, just as it would be once saved.
+        if self.find(|write| match write {
+            StoreWrite::Identity(saved_address, saved) if saved_address == address => {
+                Some(saved == identity)
+            }
+            _ => None,
+        }) == Some(true)
+        {
+            return Ok(true);
+        }
+        self.store
+            .lock()
+            .await
+            .is_trusted_identity(address, identity, direction)
+            .await
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+    ) -> Result<Option<IdentityKey>, SignalProtocolError> {
+        if let Some(identity) = self.find(|write| match write {
+            StoreWrite::Identity(saved_address, identity) if saved_address == address => {
+                Some(*identity)
+            }
+            _ => None,
+        }) {
+            return Ok(Some(identity));
+        }
+        self.store.lock().await.get_identity(address).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: ProtocolStore> SessionStore for &SharedStore<'_, S> {
+    async fn load_session(
+        &self,
+        address: &ProtocolAddress,
+    ) -> Result<Option<SessionRecord>, SignalProtocolError> {
+        if let Some(record) = self.find(|write| match write {
+            StoreWrite::Session(saved_address, record) if saved_address == address => {
+                Some(record.clone())
+            }
+            _ => None,
+        }) {
+            return Ok(Some(record));
+        }
+        self.store.lock().await.load_session(address).await
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+    ) -> Result<(), SignalProtocolError> {
+        self.push(StoreWrite::Session(address.clone(), record.clone()));
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: ProtocolStore> PreKeyStore for &SharedStore<'_, S> {
+    async fn get_pre_key(&self, prekey_id: PreKeyId) -> Result<PreKeyRecord, SignalProtocolError> {
+        match self.find(|write| match write {
+            StoreWrite::PreKey(id, record) if *id == prekey_id => Some(Some(record.clone())),
+            StoreWrite::RemovePreKey(id) if *id == prekey_id => Some(None),
+            _ => None,
+        }) {
+            Some(record) => record.ok_or(SignalProtocolError::InvalidPreKeyId),
+            None => self.store.lock().await.get_pre_key(prekey_id).await,
+        }
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        prekey_id: PreKeyId,
+        record: &PreKeyRecord,
+    ) -> Result<(), SignalProtocolError> {
+        self.push(StoreWrite::PreKey(prekey_id, record.clone()));
+        Ok(())
+    }
+
+    async fn remove_pre_key(&mut self, prekey_id: PreKeyId) -> Result<(), SignalProtocolError> {
+        self.push(StoreWrite::RemovePreKey(prekey_id));
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: ProtocolStore> SignedPreKeyStore for &SharedStore<'_, S> {
+    async fn get_signed_pre_key(
+        &self,
+        signed_prekey_id: SignedPreKeyId,
+    ) -> Result<SignedPreKeyRecord, SignalProtocolError> {
+        if let Some(record) = self.find(|write| match write {
+            StoreWrite::SignedPreKey(id, record) if *id == signed_prekey_id => Some(record.clone()),
+            _ => None,
+        }) {
+            return Ok(record);
+        }
+        self.store
+            .lock()
+            .await
+            .get_signed_pre_key(signed_prekey_id)
+            .await
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+    ) -> Result<(), SignalProtocolError> {
+        self.push(StoreWrite::SignedPreKey(signed_prekey_id, record.clone()));
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: ProtocolStore> KyberPreKeyStore for &SharedStore<'_, S> {
+    async fn get_kyber_pre_key(
+        &self,
+        kyber_prekey_id: KyberPreKeyId,
+    ) -> Result<KyberPreKeyRecord, SignalProtocolError> {
+        if let Some(record) = self.find(|write| match write {
+            StoreWrite::KyberPreKey(id, record) if *id == kyber_prekey_id => Some(record.clone()),
+            _ => None,
+        }) {
+            return Ok(record);
+        }
+        self.store
+            .lock()
+            .await
+            .get_kyber_pre_key(kyber_prekey_id)
+            .await
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+    ) -> Result<(), SignalProtocolError> {
+        self.push(StoreWrite::KyberPreKey(kyber_prekey_id, record.clone()));
+        Ok(())
+    }
+
+    async fn mark_kyber_pre_key_used(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+    ) -> Result<(), SignalProtocolError> {
+        self.push(StoreWrite::MarkKyberPreKeyUsed(kyber_prekey_id));
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: SenderKeyStore> SenderKeyStore for &SharedStore<'_, S> {
+    async fn store_sender_key(
+        &mut self,
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+        record: &SenderKeyRecord,
+    ) -> Result<(), SignalProtocolError> {
+        self.push(StoreWrite::SenderKey(
+            sender.clone(),
+            distribution_id,
+            record.clone(),
+        ));
+        Ok(())
+    }
+
+    async fn load_sender_key(
+        &mut self,
+        sender: &ProtocolAddress,
+        distribution_id: Uuid,
+    ) -> Result<Option<SenderKeyRecord>, SignalProtocolError> {
+        if let Some(record) = self.find(|write| match write {
+            StoreWrite::SenderKey(saved_sender, id, record)
+                if saved_sender == sender && *id == distribution_id =>
+            {
+                Some(record.clone())
+            }
+            _ => None,
+        }) {
+            return Ok(Some(record));
+        }
+        self.store
+            .lock()
+            .await
+            .load_sender_key(sender, distribution_id)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use assert_matches::assert_matches;
+    use futures_util::stream;
+    use libsignal_protocol::{
+        message_encrypt, process_prekey_bundle, sealed_sender_encrypt, GenericSignedPreKey,
+        IdentityKeyPair, InMemSignalProtocolStore, KeyPair, PreKeyBundle, SenderCertificate,
+        ServerCertificate,
+    };
+
+    use super::*;
+
+    const ALICE_UUID: &str = "9d0652a3-dcc3-4d11-975f-74d61598733f";
+    const BOB_UUID: &str = "796abedb-ca4e-4f18-8803-1fde5b921f9f";
+    const SERVER_GUID: &str = "e4e3fa4a-2d30-4a4d-8f5f-bd5ba4a5fa2b";
+
+    #[derive(Default)]
+    struct RecordingHook {
+        committed: Vec<Option<Uuid>>,
+        fail: bool,
+    }
+
+    #[async_trait(?Send)]
+    impl CommitHook for RecordingHook {
+        type Error = &'static str;
+
+        async fn commit(&mut self, envelope: &ReceivedEnvelope) -> Result<(), Self::Error> {
+            if self.fail {
+                return Err("storage is full");
+            }
+            self.committed.push(envelope.server_guid);
+            Ok(())
+        }
+    }
+
+    fn new_store() -> InMemSignalProtocolStore {
+        InMemSignalProtocolStore::new(IdentityKeyPair::generate(&mut OsRng), 5).expect("valid")
+    }
+
+    fn bob_address() -> ProtocolAddress {
+        ProtocolAddress::new(BOB_UUID.to_owned(), 1.into())
+    }
+
+    fn config(trust_root: PublicKey) -> ReceiveConfig {
+        ReceiveConfig {
+            local_aci: Aci::parse_from_service_id_string(BOB_UUID).expect("valid"),
+            local_e164: None,
+            local_device_id: 1.into(),
+            trust_root,
+        }
+    }
+
+    /// Sets up a session from `alice` to `bob`, as if Alice had fetched Bob's pre-keys.
+    async fn start_session(
+        alice: &mut InMemSignalProtocolStore,
+        bob: &mut InMemSignalProtocolStore,
+    ) {
+        let pre_key = KeyPair::generate(&mut OsRng);
+        let signed_pre_key = KeyPair::generate(&mut OsRng);
+        let identity = bob.get_identity_key_pair().await.unwrap();
+        let signature = identity
+            .private_key()
+            .calculate_signature(&signed_pre_key.public_key.serialize(), &mut OsRng)
+            .unwrap();
+        bob.save_pre_key(1.into(), &PreKeyRecord::new(1.into(), &pre_key))
+            .await
+            .unwrap();
+        bob.save_signed_pre_key(
+            2.into(),
+            &SignedPreKeyRecord::new(
+                2.into(),
+                Timestamp::from_epoch_millis(1000),
+                &signed_pre_key,
+                &signature,
+            ),
+        )
+        .await
+        .unwrap();
+
+        let bundle = PreKeyBundle::new(
+            bob.get_local_registration_id().await.unwrap(),
+            1.into(),
+            Some((1.into(), pre_key.public_key)),
+            2.into(),
+            signed_pre_key.public_key,
+            signature.to_vec(),
+            *identity.identity_key(),
+        )
+        .unwrap();
+        process_prekey_bundle(
+            &bob_address(),
+            &mut alice.session_store,
+            &mut alice.identity_store,
+            &bundle,
+            SystemTime::now(),
+            &mut OsRng,
+        )
+        .await
+        .unwrap();
+    }
+
+    fn padded(content: &[u8]) -> Vec<u8> {
+        let mut padded = content.to_vec();
+        padded.push(0x80);
+        padded.resize(160, 0);
+        padded
+    }
+
+    fn incoming(envelope: Envelope) -> (ServerMessage, Arc<AtomicBool>) {
+        incoming_bytes(envelope.encode_to_vec())
+    }
+
+    fn incoming_bytes(envelope: Vec<u8>) -> (ServerMessage, Arc<AtomicBool>) {
+        let acked = Arc::new(AtomicBool::new(false));
+        let message = ServerMessage::IncomingMessage {
+            request_id: 1,
+            envelope,
+            server_delivery_timestamp: Timestamp::from_epoch_millis(2000),
+            send_ack: Box::pin({
+                let acked = acked.clone();
+                async move {
+                    acked.store(true, Ordering::SeqCst);
+                    Ok(())
+                }
+            }),
+        };
+        (message, acked)
+    }
+
+    async fn prekey_envelope(alice: &mut InMemSignalProtocolStore) -> Envelope {
+        let message = message_encrypt(
+            &padded(b"hello"),
+            &bob_address(),
+            &mut alice.session_store,
+            &mut alice.identity_store,
+            SystemTime::now(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(message.message_type(), CiphertextMessageType::PreKey);
+        Envelope {
+            r#type: Some(envelope::Type::PrekeyBundle.into()),
+            source_service_id: Some(ALICE_UUID.to_owned()),
+            source_device: Some(3),
+            timestamp: Some(1500),
+            content: Some(message.serialize().to_vec()),
+            server_guid: Some(SERVER_GUID.to_owned()),
+            server_timestamp: Some(1700),
+            destination_service_id: Some(BOB_UUID.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn prekey_message_is_decrypted_and_acked_after_commit() {
+        let mut alice = new_store();
+        let mut bob = new_store();
+        start_session(&mut alice, &mut bob).await;
+        let (message, acked) = incoming(prekey_envelope(&mut alice).await);
+
+        let trust_root = KeyPair::generate(&mut OsRng).public_key;
+        let mut pipeline = ReceivePipeline::new(bob, RecordingHook::default(), config(trust_root));
+        let received = assert_matches!(
+            pipeline.process(message).await,
+            Ok(ReceivedItem::Envelope(received)) => received
+        );
+
+        assert_eq!(received.server_guid, Some(SERVER_GUID.parse().unwrap()));
+        assert_eq!(received.timestamp, Timestamp::from_epoch_millis(1500));
+        assert_eq!(
+            received.server_timestamp,
+            Timestamp::from_epoch_millis(1700)
+        );
+        assert!(received.urgent);
+        let message = assert_matches!(received.content, ReceivedContent::Message(m) => m);
+        assert_eq!(
+            message.sender,
+            ProtocolAddress::new(ALICE_UUID.to_owned(), 3.into())
+        );
+        assert!(!message.sealed_sender);
+        assert_eq!(message.message_type, CiphertextMessageType::PreKey);
+        assert_eq!(message.content, b"hello");
+
+        assert!(acked.load(Ordering::SeqCst));
+        let (_store, hook) = pipeline.into_parts();
+        assert_eq!(hook.committed, [Some(SERVER_GUID.parse().unwrap())]);
+    }
+
+    #[tokio::test]
+    async fn commit_failure_prevents_ack() {
+        let mut alice = new_store();
+        let mut bob = new_store();
+        start_session(&mut alice, &mut bob).await;
+        let envelope = prekey_envelope(&mut alice).await;
+        let (message, acked) = incoming(envelope.clone());
+
+        let trust_root = KeyPair::generate(&mut OsRng).public_key;
+        let hook = RecordingHook {
+            fail: true,
+            ..Default::default()
+        };
+        let mut pipeline = ReceivePipeline::new(bob, hook, config(trust_root));
+        assert_matches!(
+            pipeline.process(message).await,
+            Err(ReceiveError::Commit("storage is full"))
+        );
+        assert!(!acked.load(Ordering::SeqCst));
+
+        // The server delivers the envelope again, and this time it can be committed.
+        pipeline.hook.fail = false;
+        let (redelivered, acked) = incoming(envelope);
+        let received = assert_matches!(
+            pipeline.process(redelivered).await,
+            Ok(ReceivedItem::Envelope(received)) => received
+        );
+        let message = assert_matches!(received.content, ReceivedContent::Message(m) => m);
+        assert_eq!(message.content, b"hello");
+        assert!(acked.load(Ordering::SeqCst));
+        assert_eq!(
+            pipeline.hook.committed,
+            vec![Some(SERVER_GUID.parse().unwrap())]
+        );
+    }
+
+    #[tokio::test]
+    async fn failures_are_committed_and_acked() {
+        let (undecodable, undecodable_acked) = incoming_bytes(vec![0xff; 3]);
+        let (garbage, garbage_acked) = incoming(Envelope {
+            r#type: Some(envelope::Type::Ciphertext.into()),
+            source_service_id: Some(ALICE_UUID.to_owned()),
+            source_device: Some(3),
+            content: Some(vec![0x33; 40]),
+            ..Default::default()
+        });
+        let (unsupported, unsupported_acked) = incoming(Envelope {
+            r#type: Some(envelope::Type::KeyExchange.into()),
+            ..Default::default()
+        });
+
+        let trust_root = KeyPair::generate(&mut OsRng).public_key;
+        let pipeline =
+            ReceivePipeline::new(new_store(), RecordingHook::default(), config(trust_root));
+        let results: Vec<_> = pipeline
+            .process_stream(stream::iter([
+                undecodable,
+                garbage,
+                unsupported,
+                ServerMessage::QueueEmpty,
+            ]))
+            .collect()
+            .await;
+
+        let failure = |result: &Result<ReceivedItem, ReceiveError<&'static str>>| match result {
+            Ok(ReceivedItem::Envelope(ReceivedEnvelope {
+                content: ReceivedContent::Failed(failure),
+                ..
+            })) => Some((failure.sender.clone(), failure.error.to_string())),
+            _ => None,
+        };
+        assert_matches!(&results[..], [undecodable, garbage, unsupported, Ok(ReceivedItem::QueueEmpty)] => {
+            assert_matches!(
+                failure(undecodable),
+                Some((None, e)) if e == "invalid envelope: could not be decoded"
+            );
+            assert_matches!(failure(garbage), Some((Some(sender), _)) if sender.name() == ALICE_UUID);
+            assert_matches!(
+                failure(unsupported),
+                Some((None, e)) if e == "envelope type 2 is not supported"
+            );
+        });
+        assert!(undecodable_acked.load(Ordering::SeqCst));
+        assert!(garbage_acked.load(Ordering::SeqCst));
+        assert!(unsupported_acked.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn sealed_sender_message_is_decrypted() {
+        let mut alice = new_store();
+        let mut bob = new_store();
+        start_session(&mut alice, &mut bob).await;
+
+        let trust_root = KeyPair::generate(&mut OsRng);
+        let server_key = KeyPair::generate(&mut OsRng);
+        let server_cert = ServerCertificate::new(
+            1,
+            server_key.public_key,
+            &trust_root.private_key,
+            &mut OsRng,
+        )
+        .unwrap();
+        let sender_cert = SenderCertificate::new(
+            ALICE_UUID.to_owned(),
+            Some("+14155550101".to_owned()),
+            *alice
+                .get_identity_key_pair()
+                .await
+                .unwrap()
+                .identity_key()
+                .public_key(),
+            3.into(),
+            Timestamp::from_epoch_millis(10000),
+            server_cert,
+            &server_key.private_key,
+            &mut OsRng,
+        )
+        .unwrap();
+        let ciphertext = sealed_sender_encrypt(
+            &bob_address(),
+            &sender_cert,
+            &padded(b"sealed"),
+            &mut alice.session_store,
+            &mut alice.identity_store,
+            SystemTime::now(),
+            &mut OsRng,
+        )
+        .await
+        .unwrap();
+        let sealed = Envelope {
+            r#type: Some(envelope::Type::UnidentifiedSender.into()),
+            timestamp: Some(1500),
+            content: Some(ciphertext),
+            server_timestamp: Some(1700),
+            ..Default::default()
+        };
+
+        // Validation uses the server timestamp, so a certificate that expired since is still
+        // accepted.
+        let mut pipeline =
+            ReceivePipeline::new(bob, RecordingHook::default(), config(trust_root.public_key));
+        let (message, acked) = incoming(sealed.clone());
+        let received = assert_matches!(
+            pipeline.process(message).await,
+            Ok(ReceivedItem::Envelope(received)) => received
+        );
+        let message = assert_matches!(received.content, ReceivedContent::Message(m) => m);
+        assert_eq!(
+            message.sender,
+            ProtocolAddress::new(ALICE_UUID.to_owned(), 3.into())
+        );
+        assert_eq!(message.sender_e164.as_deref(), Some("+14155550101"));
+        assert!(message.sealed_sender);
+        assert_eq!(message.content, b"sealed");
+        assert!(acked.load(Ordering::SeqCst));
+
+        // With a different trust root, the sender is known but the message is rejected.
+        let (bob, _) = pipeline.into_parts();
+        let other_root = KeyPair::generate(&mut OsRng).public_key;
+        let mut pipeline = ReceivePipeline::new(bob, RecordingHook::default(), config(other_root));
+        let (message, _acked) = incoming(sealed);
+        let received = assert_matches!(
+            pipeline.process(message).await,
+            Ok(ReceivedItem::Envelope(received)) => received
+        );
+        let failure = assert_matches!(received.content, ReceivedContent::Failed(f) => f);
+        assert_eq!(
+            failure.sender,
+            Some(ProtocolAddress::new(ALICE_UUID.to_owned(), 3.into()))
+        );
+        assert_eq!(failure.message_type, Some(CiphertextMessageType::PreKey));
+        assert_matches!(
+            failure.error,
+            DecryptionError::Protocol(SignalProtocolError::InvalidSealedSenderMessage(_))
+        );
+    }
+
+    #[test]
+    fn strip_padding_leaves_unpadded_content() {
+        assert_eq!(strip_padding(padded(b"abc")), b"abc");
+        assert_eq!(strip_padding(b"abc".to_vec()), b"abc");
+        assert_eq!(strip_padding(vec![0; 4]), [0; 4]);
+    }
+}
@@ -0,0 +1,464 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Keeps a chat connection open for as long as the caller wants it.
+//!
+//! [ServiceWithReconnect](crate::infra::reconnect::ServiceWithReconnect) only connects when asked
+//! to. A [ChatSupervisor] asks on the caller's behalf: it connects right away, reconnects whenever
+//! the connection is lost, and backs off (with jitter) while connection attempts keep failing.
+//! Callers that learn about a network change from the OS can report it with
+//! [ChatSupervisor::network_changed] to skip the remaining backoff.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng as _;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::chat::ChatService;
+
+/// The state of a supervised connection, as reported by [ChatSupervisor::subscribe].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Supervision has not started yet, or has been stopped.
+    Disconnected,
+    Connecting,
+    Connected,
+    /// The last connection attempt failed; the next one will start after `delay`.
+    BackingOff {
+        delay: Duration,
+    },
+}
+
+/// How long to wait between failed connection attempts.
+///
+/// The `n`th consecutive failure waits somewhere between half and all of
+/// `min(initial * 2^n, max)`, so that many clients that lost their connections at the same time
+/// don't all come back at once.
+///
+/// A connection that is lost before it has been up for `stable_after` counts as another failure,
+/// so that a server that accepts connections and then drops them right away isn't hammered.
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectBackoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub stable_after: Duration,
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(64),
+            stable_after: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectBackoff {
+    fn delay(&self, consecutive_failures: u32) -> Duration {
+        let ceiling = self
+            .initial
+            .checked_mul(1 << consecutive_failures.min(16))
+            .map_or(self.max, |delay| delay.min(self.max));
+        rand::thread_rng().gen_range(ceiling / 2..=ceiling)
+    }
+}
+
+/// Keeps a [ChatService] connected until stopped or dropped.
+///
+/// Stopping the supervisor does not close the connection; use [ChatService::disconnect] for that.
+pub struct ChatSupervisor {
+    state: watch::Receiver<ConnectionState>,
+    network_changed: Arc<Notify>,
+    stop: CancellationToken,
+}
+
+impl ChatSupervisor {
+    /// Starts supervising `service` on the current tokio runtime.
+    pub fn start<S>(service: S, backoff: ReconnectBackoff) -> Self
+    where
+        S: ChatService + Send + Sync + 'static,
+    {
+        let (state_tx, state) = watch::channel(ConnectionState::Disconnected);
+        let network_changed = Arc::new(Notify::new());
+        let stop = CancellationToken::new();
+        tokio::spawn(supervise(
+            service,
+            backoff,
+            state_tx,
+            network_changed.clone(),
+            stop.clone(),
+        ));
+        Self {
+            state,
+            network_changed,
+            stop,
+        }
+    }
+
+    /// Returns a receiver that observes every state change.
+    ///
+    /// Like any [watch::Receiver], it only sees the latest state; quick successive changes may be
+    /// coalesced. The receiver reports the sender closed after the final
+    /// [ConnectionState::Disconnected] once supervision stops.
+    pub fn subscribe(&self) -> watch::Receiver<ConnectionState> {
+        self.state.clone()
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        *self.state.borrow()
+    }
+
+    /// Tells the supervisor that the device's network connectivity changed.
+    ///
+    /// An open connection is dropped and re-established, since it may be bound to an interface
+    /// that's no longer usable; if the supervisor is backing off, it tries again immediately.
+    /// A change reported while a connection attempt is under way takes effect once that attempt
+    /// finishes, since the connection it produces may be bound to the old interface too.
+    pub fn network_changed(&self) {
+        // Stores a permit if the supervisor isn't currently waiting, so the change isn't lost.
+        self.network_changed.notify_one();
+    }
+
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+}
+
+impl Drop for ChatSupervisor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+async fn supervise(
+    service: impl ChatService,
+    backoff: ReconnectBackoff,
+    state: watch::Sender<ConnectionState>,
+    network_changed: Arc<Notify>,
+    stop: CancellationToken,
+) {
+    let mut consecutive_failures = 0;
+    loop {
+        state.send_replace(ConnectionState::Connecting);
+        let result = tokio::select! {
+            result = service.connect() => result,
+            _ = stop.cancelled() => break,
+        };
+
+        match result {
+            Ok(()) => {
+                log::info!("supervised chat connection established");
+                let connected_at = Instant::now();
+                state.send_replace(ConnectionState::Connected);
+                tokio::select! {
+                    _ = service.connection_lost() => {}
+                    _ = network_changed.notified() => {
+                        log::info!("network changed; re-establishing supervised chat connection");
+                        service.disconnect().await;
+                        consecutive_failures = 0;
+                        continue;
+                    }
+                    _ = stop.cancelled() => break,
+                }
+                let uptime = connected_at.elapsed();
+                if uptime >= backoff.stable_after {
+                    log::info!("supervised chat connection lost; reconnecting");
+                    consecutive_failures = 0;
+                    continue;
+                }
+                log::info!("supervised chat connection lost after only {:.1?}", uptime);
+            }
+            Err(e) => {
+                log::info!("supervised chat connection attempt failed ({e})");
+            }
+        }
+
+        let delay = backoff.delay(consecutive_failures);
+        consecutive_failures = consecutive_failures.saturating_add(1);
+        log::info!("retrying supervised chat connection in {:.1?}", delay);
+        state.send_replace(ConnectionState::BackingOff { delay });
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = network_changed.notified() => {
+                log::info!("network changed; retrying supervised chat connection now");
+                consecutive_failures = 0;
+            }
+            _ = stop.cancelled() => break,
+        }
+    }
+    state.send_replace(ConnectionState::Disconnected);
+    log::info!("stopped supervising chat connection");
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use assert_matches::assert_matches;
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::chat::{ChatServiceError, Request, Response};
+
+    #[derive(Default)]
+    struct FakeServiceState {
+        connect_count: u32,
+        refuse_connections: bool,
+        connect_delay: Duration,
+        connection: Option<CancellationToken>,
+    }
+
+    #[derive(Clone, Default)]
+    struct FakeService(Arc<Mutex<FakeServiceState>>);
+
+    impl FakeService {
+        fn state(&self) -> std::sync::MutexGuard<'_, FakeServiceState> {
+            self.0.lock().expect("not poisoned")
+        }
+
+        fn drop_connection(&self) {
+            if let Some(connection) = self.state().connection.take() {
+                connection.cancel();
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ChatService for FakeService {
+        async fn send(
+            &self,
+            _msg: Request,
+            _timeout: Duration,
+        ) -> Result<Response, ChatServiceError> {
+            Err(ChatServiceError::ServiceUnavailable)
+        }
+
+        async fn connect(&self) -> Result<(), ChatServiceError> {
+            let delay = self.state().connect_delay;
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            let mut state = self.state();
+            state.connect_count += 1;
+            if state.refuse_connections {
+                return Err(ChatServiceError::AllConnectionRoutesFailed { attempts: 1 });
+            }
+            state.connection.get_or_insert_with(CancellationToken::new);
+            Ok(())
+        }
+
+        async fn disconnect(&self) {
+            self.drop_connection()
+        }
+
+        async fn connection_lost(&self) {
+            let connection = self.state().connection.clone();
+            if let Some(connection) = connection {
+                connection.cancelled().await
+            }
+        }
+    }
+
+    async fn wait_for_state(
+        states: &mut watch::Receiver<ConnectionState>,
+        predicate: impl FnMut(&ConnectionState) -> bool,
+    ) -> ConnectionState {
+        *states.wait_for(predicate).await.expect("still supervising")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn supervisor_reconnects_after_connection_is_lost() {
+        let service = FakeService::default();
+        let supervisor = ChatSupervisor::start(service.clone(), ReconnectBackoff::default());
+        let mut states = supervisor.subscribe();
+        wait_for_state(&mut states, |s| *s == ConnectionState::Connected).await;
+        assert_eq!(service.state().connect_count, 1);
+
+        tokio::time::sleep(ReconnectBackoff::default().stable_after).await;
+        let lost_at = Instant::now();
+        service.drop_connection();
+        states.changed().await.expect("still supervising");
+        wait_for_state(&mut states, |s| *s == ConnectionState::Connected).await;
+        assert_eq!(service.state().connect_count, 2);
+        assert_eq!(
+            lost_at.elapsed(),
+            Duration::ZERO,
+            "reconnected without backing off"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn supervisor_backs_off_while_connections_are_dropped_right_away() {
+        let service = FakeService::default();
+        let backoff = ReconnectBackoff {
+            initial: Duration::from_secs(2),
+            max: Duration::from_secs(5),
+            stable_after: Duration::from_secs(30),
+        };
+        let supervisor = ChatSupervisor::start(service.clone(), backoff);
+        let mut states = supervisor.subscribe();
+
+        for (min, max) in [(1, 2), (2, 4), (2, 5)] {
+            wait_for_state(&mut states, |s| *s == ConnectionState::Connected).await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            service.drop_connection();
+            let state = wait_for_state(&mut states, |s| {
+                matches!(s, ConnectionState::BackingOff { .. })
+            })
+            .await;
+            let delay = assert_matches!(state, ConnectionState::BackingOff { delay } => delay);
+            assert!(
+                (Duration::from_secs(min)..=Duration::from_secs(max)).contains(&delay),
+                "{delay:?} not in {min}..={max}s"
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn supervisor_backs_off_while_connections_fail() {
+        let service = FakeService::default();
+        service.state().refuse_connections = true;
+        let backoff = ReconnectBackoff {
+            initial: Duration::from_secs(2),
+            max: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let supervisor = ChatSupervisor::start(service.clone(), backoff);
+        let mut states = supervisor.subscribe();
+
+        for (min, max) in [(1, 2), (2, 4), (2, 5), (2, 5)] {
+            // Each failed attempt publishes a new state, even if the intermediate "connecting"
+            // state is coalesced away.
+            states.changed().await.expect("still supervising");
+            let state = wait_for_state(&mut states, |s| {
+                matches!(s, ConnectionState::BackingOff { .. })
+            })
+            .await;
+            let delay = assert_matches!(state, ConnectionState::BackingOff { delay } => delay);
+            assert!(
+                (Duration::from_secs(min)..=Duration::from_secs(max)).contains(&delay),
+                "{delay:?} not in {min}..={max}s"
+            );
+        }
+
+        service.state().refuse_connections = false;
+        wait_for_state(&mut states, |s| *s == ConnectionState::Connected).await;
+        assert_eq!(service.state().connect_count, 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn network_change_skips_backoff() {
+        let service = FakeService::default();
+        service.state().refuse_connections = true;
+        let backoff = ReconnectBackoff {
+            initial: Duration::from_secs(60),
+            max: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let supervisor = ChatSupervisor::start(service.clone(), backoff);
+        let mut states = supervisor.subscribe();
+        wait_for_state(&mut states, |s| {
+            matches!(s, ConnectionState::BackingOff { .. })
+        })
+        .await;
+
+        let start = Instant::now();
+        service.state().refuse_connections = false;
+        supervisor.network_changed();
+        wait_for_state(&mut states, |s| *s == ConnectionState::Connected).await;
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn network_change_replaces_open_connection() {
+        let service = FakeService::default();
+        let supervisor = ChatSupervisor::start(service.clone(), ReconnectBackoff::default());
+        let mut states = supervisor.subscribe();
+        wait_for_state(&mut states, |s| *s == ConnectionState::Connected).await;
+        let first_connection = service.state().connection.clone().expect("connected");
+
+        supervisor.network_changed();
+        first_connection.cancelled().await;
+        states.changed().await.expect("still supervising");
+        wait_for_state(&mut states, |s| *s == ConnectionState::Connected).await;
+        assert_eq!(service.state().connect_count, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn network_change_while_connecting_replaces_new_connection() {
+        let service = FakeService::default();
+        service.state().connect_delay = Duration::from_secs(5);
+        let supervisor = ChatSupervisor::start(service.clone(), ReconnectBackoff::default());
+        let mut states = supervisor.subscribe();
+        wait_for_state(&mut states, |s| *s == ConnectionState::Connecting).await;
+
+        supervisor.network_changed();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(supervisor.state(), ConnectionState::Connected);
+        assert_eq!(service.state().connect_count, 2);
+
+        // The permit was used up; the second connection stays.
+        let connection = service.state().connection.clone().expect("connected");
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(!connection.is_cancelled());
+        assert_eq!(service.state().connect_count, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn network_change_while_connecting_skips_backoff_after_failure() {
+        let service = FakeService::default();
+        service.state().connect_delay = Duration::from_secs(5);
+        service.state().refuse_connections = true;
+        let backoff = ReconnectBackoff {
+            initial: Duration::from_secs(60),
+            max: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let supervisor = ChatSupervisor::start(service.clone(), backoff);
+        let mut states = supervisor.subscribe();
+        wait_for_state(&mut states, |s| *s == ConnectionState::Connecting).await;
+
+        let start = Instant::now();
+        supervisor.network_changed();
+        // Let the first attempt fail before the second one is allowed to succeed.
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        service.state().refuse_connections = false;
+        wait_for_state(&mut states, |s| *s == ConnectionState::Connected).await;
+        assert!(start.elapsed() < Duration::from_secs(30));
+        assert_eq!(service.state().connect_count, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stopping_leaves_the_connection_open() {
+        let service = FakeService::default();
+        let supervisor = ChatSupervisor::start(service.clone(), ReconnectBackoff::default());
+        let mut states = supervisor.subscribe();
+        wait_for_state(&mut states, |s| *s == ConnectionState::Connected).await;
+
+        drop(supervisor);
+        assert_matches!(
+            states
+                .wait_for(|s| *s == ConnectionState::Disconnected)
+                .await,
+            Ok(_)
+        );
+        assert!(states.changed().await.is_err(), "supervisor task finished");
+        assert!(service.state().connection.is_some());
+    }
+
+    #[test]
+    fn backoff_delay_is_capped() {
+        let backoff = ReconnectBackoff::default();
+        for failures in [0, 5, 31, u32::MAX] {
+            let delay = backoff.delay(failures);
+            assert!(delay >= backoff.initial / 2, "{delay:?}");
+            assert!(delay <= backoff.max, "{delay:?}");
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use derive_where::derive_where;
+
+use crate::chat::http::{ChatOverHttp2, ChatOverHttp2ServiceConnector};
+use crate::chat::ws::{ChatOverWebSocket, ChatOverWebSocketServiceConnector};
+use crate::chat::{ChatService, ChatServiceError, RemoteAddressInfo, Request, Response};
+use crate::infra::errors::LogSafeDisplay;
+use crate::infra::http_client::HttpError;
+use crate::infra::reconnect::{ServiceConnector, ServiceStatus};
+use crate::infra::ws::WebSocketConnectError;
+use crate::infra::{
+    AsyncDuplexStream, ConnectionInfo, ConnectionParams, RouteType, TransportConnector,
+};
+
+/// Transport used to carry [Request]s to the Chat Service.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub enum ChatTransport {
+    /// A long-lived WebSocket; also used by the server to deliver its own requests.
+    #[default]
+    WebSocket,
+    /// One HTTP2 request per [Request]; the server can't initiate requests over this transport.
+    Http2,
+}
+
+/// Picks the [ChatTransport] to use for each [RouteType].
+///
+/// Routes without an explicit choice use the default transport, which is
+/// [ChatTransport::WebSocket] unless configured otherwise.
+#[derive(Clone, Debug, Default)]
+pub struct ChatTransportSelector {
+    default: ChatTransport,
+    per_route: HashMap<RouteType, ChatTransport>,
+}
+
+impl ChatTransportSelector {
+    pub fn new(default: ChatTransport) -> Self {
+        Self {
+            default,
+            per_route: HashMap::new(),
+        }
+    }
+
+    pub fn with_route(mut self, route_type: RouteType, transport: ChatTransport) -> Self {
+        self.per_route.insert(route_type, transport);
+        self
+    }
+
+    pub fn transport_for(&self, route_type: RouteType) -> ChatTransport {
+        self.per_route
+            .get(&route_type)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Connects to the Chat Service using whichever transport the selector picks for the route.
+#[derive_where(Clone)]
+pub(super) struct ChatOverAnyTransportServiceConnector<T: TransportConnector> {
+    ws: ChatOverWebSocketServiceConnector<T>,
+    http2: ChatOverHttp2ServiceConnector<T>,
+    selector: ChatTransportSelector,
+}
+
+impl<T: TransportConnector> ChatOverAnyTransportServiceConnector<T> {
+    pub fn new(
+        ws: ChatOverWebSocketServiceConnector<T>,
+        http2: ChatOverHttp2ServiceConnector<T>,
+        selector: ChatTransportSelector,
+    ) -> Self {
+        Self {
+            ws,
+            http2,
+            selector,
+        }
+    }
+}
+
+pub(super) enum AnyTransportChannel<T: TransportConnector> {
+    WebSocket(<ChatOverWebSocketServiceConnector<T> as ServiceConnector>::Channel),
+    Http2(Box<<ChatOverHttp2ServiceConnector<T> as ServiceConnector>::Channel>),
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub(super) enum AnyTransportConnectError {
+    /// websocket: {0}
+    WebSocket(#[from] WebSocketConnectError),
+    /// HTTP2: {0}
+    Http2(#[from] HttpError),
+}
+
+impl LogSafeDisplay for AnyTransportConnectError {}
+
+#[async_trait]
+impl<T: TransportConnector> ServiceConnector for ChatOverAnyTransportServiceConnector<T> {
+    type Service = ChatOverAnyTransport<T::Stream>;
+    type Channel = AnyTransportChannel<T>;
+    type ConnectError = AnyTransportConnectError;
+    type StartError = ChatServiceError;
+
+    async fn connect_channel(
+        &self,
+        connection_params: &ConnectionParams,
+    ) -> Result<Self::Channel, Self::ConnectError> {
+        match self.selector.transport_for(connection_params.route_type) {
+            ChatTransport::WebSocket => Ok(AnyTransportChannel::WebSocket(
+                self.ws.connect_channel(connection_params).await?,
+            )),
+            ChatTransport::Http2 => Ok(AnyTransportChannel::Http2(Box::new(
+                self.http2.connect_channel(connection_params).await?,
+            ))),
+        }
+    }
+
+    fn start_service(
+        &self,
+        channel: Self::Channel,
+    ) -> (Self::Service, ServiceStatus<Self::StartError>) {
+        match channel {
+            AnyTransportChannel::WebSocket(channel) => {
+                let (service, status) = self.ws.start_service(channel);
+                (ChatOverAnyTransport::WebSocket(service), status)
+            }
+            AnyTransportChannel::Http2(channel) => {
+                let (service, status) = self.http2.start_service(*channel);
+                (ChatOverAnyTransport::Http2(service), status)
+            }
+        }
+    }
+}
+
+#[derive_where(Clone)]
+#[derive(Debug)]
+pub enum ChatOverAnyTransport<S> {
+    WebSocket(ChatOverWebSocket<S>),
+    Http2(ChatOverHttp2),
+}
+
+impl<S> RemoteAddressInfo for ChatOverAnyTransport<S> {
+    fn connection_info(&self) -> ConnectionInfo {
+        match self {
+            Self::WebSocket(service) => service.connection_info(),
+            Self::Http2(service) => service.connection_info(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> ChatService for ChatOverAnyTransport<S>
+where
+    S: AsyncDuplexStream,
+{
+    async fn send(&self, msg: Request, timeout: Duration) -> Result<Response, ChatServiceError> {
+        match self {
+            Self::WebSocket(service) => service.send(msg, timeout).await,
+            Self::Http2(service) => service.send(msg, timeout).await,
+        }
+    }
+
+    async fn connect(&self) -> Result<(), ChatServiceError> {
+        match self {
+            Self::WebSocket(service) => service.connect().await,
+            Self::Http2(service) => service.connect().await,
+        }
+    }
+
+    async fn disconnect(&self) {
+        match self {
+            Self::WebSocket(service) => service.disconnect().await,
+            Self::Http2(service) => service.disconnect().await,
+        }
+    }
+
+    async fn connection_lost(&self) {
+        match self {
+            Self::WebSocket(service) => service.connection_lost().await,
+            Self::Http2(service) => service.connection_lost().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use http::Method;
+    use tokio::sync::mpsc;
+    use warp::Filter;
+
+    use super::*;
+    use crate::chat::test::shared::{connection_manager, test_request};
+    use crate::infra::reconnect::ServiceState;
+    use crate::infra::test::shared::{InMemoryWarpConnector, NoReconnectService, TIMEOUT_DURATION};
+    use crate::infra::ws::{WebSocketClientConnector, WebSocketConfig};
+
+    #[test]
+    fn selector_uses_default_for_unlisted_routes() {
+        let selector = ChatTransportSelector::default();
+        assert_eq!(
+            selector.transport_for(RouteType::Direct),
+            ChatTransport::WebSocket
+        );
+
+        let selector = ChatTransportSelector::new(ChatTransport::Http2)
+            .with_route(RouteType::Direct, ChatTransport::WebSocket);
+        assert_eq!(
+            selector.transport_for(RouteType::Direct),
+            ChatTransport::WebSocket
+        );
+        assert_eq!(
+            selector.transport_for(RouteType::ProxyF),
+            ChatTransport::Http2
+        );
+    }
+
+    #[tokio::test]
+    async fn connector_picks_transport_for_route() {
+        // Plain HTTP handler that would reject a WebSocket upgrade.
+        let server = warp::any().map(warp::reply);
+        let transport_connector = InMemoryWarpConnector::new(server);
+        let (incoming_tx, _incoming_rx) = mpsc::channel(1);
+        let ws_config = WebSocketConfig {
+            ws_config: Default::default(),
+            endpoint: http::uri::PathAndQuery::from_static("/test"),
+            max_connection_time: TIMEOUT_DURATION,
+            keep_alive_interval: TIMEOUT_DURATION,
+            max_idle_time: TIMEOUT_DURATION,
+        };
+        let connector = ChatOverAnyTransportServiceConnector::new(
+            ChatOverWebSocketServiceConnector::new(
+                WebSocketClientConnector::new(transport_connector.clone(), ws_config),
+                incoming_tx,
+            ),
+            ChatOverHttp2ServiceConnector::new(transport_connector),
+            ChatTransportSelector::default().with_route(RouteType::Test, ChatTransport::Http2),
+        );
+
+        let chat = NoReconnectService::start(connector, connection_manager()).await;
+        let service = match &*chat.inner {
+            ServiceState::Active(service, _) => service.clone(),
+            _ => panic!("service is not active"),
+        };
+        assert_matches!(service, ChatOverAnyTransport::Http2(_));
+        service
+            .send(test_request(Method::GET, "/"), TIMEOUT_DURATION)
+            .await
+            .expect("response");
+    }
+}
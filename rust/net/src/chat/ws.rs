@@ -287,6 +287,10 @@ where
     async fn disconnect(&self) {
         self.service_status.stop_service()
     }
+
+    async fn connection_lost(&self) {
+        self.service_status.stopped().await
+    }
 }
 
 fn decode_and_validate(data: &[u8]) -> Result<ChatMessage, ChatServiceError> {
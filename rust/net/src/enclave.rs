@@ -27,6 +27,9 @@ use crate::infra::{
 };
 use crate::svr::SvrConnection;
 
+#[cfg(any(test, feature = "fake-cdsi-server", feature = "fake-svr3-server"))]
+pub(crate) mod fake;
+
 pub trait EnclaveKind {
     fn url_path(enclave: &[u8]) -> PathAndQuery;
 }
@@ -304,6 +307,16 @@ impl<E: EnclaveKind> EnclaveEndpointConnection<E, SingleRouteThrottlingConnectio
     }
 }
 
+impl<C> EnclaveEndpointConnection<Sgx, C> {
+    /// The Raft group ID of the enclave, used to salt PINs for SVR2.
+    pub fn group_id(&self) -> Option<u64> {
+        self.params
+            .raft_config_override
+            .map(|config| config.group_id)
+            .or_else(|| attest::svr2::lookup_groupid(self.params.mr_enclave.as_ref()))
+    }
+}
+
 impl<E: EnclaveKind> EnclaveEndpointConnection<E, MultiRouteConnectionManager> {
     pub fn new_multi(
         mr_enclave: MrEnclave<&'static [u8], E>,
@@ -0,0 +1,152 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! The server end of an attested connection, shared by the in-process enclave fakes.
+//!
+//! The server presents the SGX test evidence from [attest::sgx_session::testutil] and completes
+//! the Noise handshake with the matching private key. Clients accept it by checking that evidence
+//! as of the time it was issued, whatever kind of enclave they think they are talking to.
+
+use std::future::Future;
+
+use futures_util::{SinkExt as _, StreamExt as _};
+use tokio::io::DuplexStream;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::Message;
+
+use crate::infra::ws::{AttestedConnection, AttestedConnectionError, WebSocketClient};
+use crate::infra::{ConnectionInfo, DnsSource, RouteType};
+
+/// Size of the in-memory buffer between a client and a fake server, in each direction.
+const CONNECTION_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Largest Noise transport message, including its authentication tag.
+const NOISE_PACKET_MAX: usize = 65535;
+const NOISE_TAG_LEN: usize = 16;
+
+/// An established server session, after the handshake.
+pub(crate) struct AttestedServer {
+    websocket: WebSocketStream<DuplexStream>,
+    transport: snow::TransportState,
+}
+
+impl AttestedServer {
+    /// Waits for the next message from the client.
+    ///
+    /// Returns `None` once the client has closed the connection or sent something that can't be
+    /// decrypted.
+    pub(crate) async fn receive(&mut self) -> Option<Vec<u8>> {
+        loop {
+            match self.websocket.next().await?.ok()? {
+                Message::Binary(ciphertext) => return self.decrypt(&ciphertext),
+                Message::Close(_) => return None,
+                _ => continue,
+            }
+        }
+    }
+
+    pub(crate) async fn send(&mut self, plaintext: &[u8]) -> Result<(), tungstenite::Error> {
+        let mut ciphertext = Vec::with_capacity(plaintext.len() + NOISE_TAG_LEN);
+        for chunk in plaintext.chunks(NOISE_PACKET_MAX - NOISE_TAG_LEN) {
+            let mut buffer = vec![0; chunk.len() + NOISE_TAG_LEN];
+            let written = self
+                .transport
+                .write_message(chunk, &mut buffer)
+                .expect("buffer is large enough");
+            ciphertext.extend_from_slice(&buffer[..written]);
+        }
+        self.websocket.send(Message::Binary(ciphertext)).await
+    }
+
+    pub(crate) async fn close(mut self, frame: Option<CloseFrame<'static>>) {
+        _ = self.websocket.close(frame).await;
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let mut plaintext = Vec::with_capacity(ciphertext.len());
+        for chunk in ciphertext.chunks(NOISE_PACKET_MAX) {
+            let mut buffer = vec![0; chunk.len()];
+            let read = self.transport.read_message(chunk, &mut buffer).ok()?;
+            plaintext.extend_from_slice(&buffer[..read]);
+        }
+        Some(plaintext)
+    }
+}
+
+/// Opens an attested connection to a fake server at `host`, which is run by `serve` on a new
+/// task once the handshake has completed.
+pub(crate) async fn connect<F, Fut>(
+    host: &str,
+    serve: F,
+) -> Result<AttestedConnection<DuplexStream>, AttestedConnectionError>
+where
+    F: FnOnce(AttestedServer) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (client, server) = tokio::io::duplex(CONNECTION_BUFFER_SIZE);
+    let url = url::Url::parse(&format!("ws://{host}/")).expect("valid URL");
+    let (client, server) = tokio::join!(
+        tokio_tungstenite::client_async(url, client),
+        tokio_tungstenite::accept_async(server)
+    );
+    let (client, _response) = client.map_err(|e| AttestedConnectionError::WebSocket(e.into()))?;
+    let server = server.expect("client completed the websocket handshake");
+    let log_host = host.to_owned();
+    tokio::spawn(async move {
+        match server_handshake(server).await {
+            Some(server) => serve(server).await,
+            None => log::debug!("fake {log_host} client went away during the handshake"),
+        }
+    });
+
+    let websocket = WebSocketClient::new_fake(
+        client,
+        ConnectionInfo {
+            route_type: RouteType::Direct,
+            dns_source: DnsSource::Static,
+            address: url::Host::Domain(host.to_owned()),
+        },
+    );
+    AttestedConnection::connect(websocket, |_attestation| {
+        attest::sgx_session::testutil::handshake_from_tests_data()
+    })
+    .await
+}
+
+/// Performs the server side of the Noise handshake, presenting the SGX test evidence.
+async fn server_handshake(mut websocket: WebSocketStream<DuplexStream>) -> Option<AttestedServer> {
+    let private_key = attest::sgx_session::testutil::private_key();
+    let mut handshake = snow::Builder::new(
+        attest::client_connection::NOISE_PATTERN
+            .parse()
+            .expect("valid pattern"),
+    )
+    .local_private_key(&private_key)
+    .build_responder()
+    .expect("valid parameters");
+
+    websocket
+        .send(Message::Binary(
+            attest::sgx_session::testutil::EVIDENCE_BYTES.to_vec(),
+        ))
+        .await
+        .ok()?;
+
+    let Message::Binary(incoming) = websocket.next().await?.ok()? else {
+        return None;
+    };
+    handshake.read_message(&incoming, &mut []).ok()?;
+
+    let mut outgoing = vec![0; NOISE_PACKET_MAX];
+    let written = handshake.write_message(&[], &mut outgoing).ok()?;
+    outgoing.truncate(written);
+    websocket.send(Message::Binary(outgoing)).await.ok()?;
+
+    Some(AttestedServer {
+        websocket,
+        transport: handshake.into_transport_mode().ok()?,
+    })
+}
@@ -15,12 +15,14 @@ use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
 
 use crate::enclave::{Cdsi, EnclaveEndpoint, MrEnclave, Nitro, Sgx, Tpm2Snp};
-use crate::infra::certs::RootCertificates;
+use crate::infra::certs::{PinningPolicy, RootCertificates};
 use crate::infra::dns::lookup_result::LookupResult;
 use crate::infra::{
     ConnectionParams, DnsSource, HttpRequestDecorator, HttpRequestDecoratorSeq, RouteType,
 };
 
+pub mod config;
+
 pub(crate) const WS_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
 pub(crate) const WS_MAX_IDLE_TIME: Duration = Duration::from_secs(15);
 const DEFAULT_HTTPS_PORT: NonZeroU16 = nonzero!(443_u16);
@@ -37,6 +39,7 @@ const DOMAIN_CONFIG_CHAT: DomainConfig = DomainConfig {
         ip_addr!(v6, "2600:9000:a61f:527c:d5eb:a431:5239:3232"),
     ],
     cert: RootCertificates::Signal,
+    pins: None,
     proxy_path: "/service",
 };
 
@@ -52,6 +55,7 @@ const DOMAIN_CONFIG_CHAT_STAGING: DomainConfig = DomainConfig {
         ip_addr!(v6, "2600:9000:a61f:527c:2215:cd9:bac6:a2f8"),
     ],
     cert: RootCertificates::Signal,
+    pins: None,
     proxy_path: "/service-staging",
 };
 
@@ -61,6 +65,7 @@ const DOMAIN_CONFIG_CDSI: DomainConfig = DomainConfig {
     ip_v4: &[ip_addr!(v4, "40.122.45.194")],
     ip_v6: &[ip_addr!(v6, "2603:1030:7::1")],
     cert: RootCertificates::Signal,
+    pins: None,
     proxy_path: "/cdsi",
 };
 
@@ -70,6 +75,7 @@ const DOMAIN_CONFIG_CDSI_STAGING: DomainConfig = DomainConfig {
     ip_v4: &[ip_addr!(v4, "104.43.162.137")],
     ip_v6: &[ip_addr!(v6, "2603:1030:7::732")],
     cert: RootCertificates::Signal,
+    pins: None,
     proxy_path: "/cdsi-staging",
 };
 
@@ -79,6 +85,7 @@ const DOMAIN_CONFIG_SVR2: DomainConfig = DomainConfig {
     ip_v4: &[ip_addr!(v4, "20.66.40.69")],
     ip_v6: &[],
     cert: RootCertificates::Signal,
+    pins: None,
     proxy_path: "/svr2",
 };
 
@@ -88,6 +95,7 @@ const DOMAIN_CONFIG_SVR2_STAGING: DomainConfig = DomainConfig {
     ip_v4: &[ip_addr!(v4, "20.253.229.239")],
     ip_v6: &[],
     cert: RootCertificates::Signal,
+    pins: None,
     proxy_path: "/svr2-staging",
 };
 
@@ -97,6 +105,7 @@ const DOMAIN_CONFIG_SVR3_SGX: DomainConfig = DomainConfig {
     ip_v4: &[ip_addr!(v4, "143.244.220.150")],
     ip_v6: &[],
     cert: RootCertificates::Signal,
+    pins: None,
     proxy_path: "/svr3-sgx",
 };
 
@@ -106,6 +115,7 @@ const DOMAIN_CONFIG_SVR3_SGX_STAGING: DomainConfig = DomainConfig {
     ip_v4: &[ip_addr!(v4, "13.88.63.29")],
     ip_v6: &[],
     cert: RootCertificates::Signal,
+    pins: None,
     proxy_path: "/svr3-sgx-staging",
 };
 
@@ -115,6 +125,7 @@ const DOMAIN_CONFIG_SVR3_NITRO: DomainConfig = DomainConfig {
     ip_v4: &[],
     ip_v6: &[],
     cert: RootCertificates::Signal,
+    pins: None,
     proxy_path: "/svr3-nitro",
 };
 
@@ -124,6 +135,7 @@ const DOMAIN_CONFIG_SVR3_NITRO_STAGING: DomainConfig = DomainConfig {
     ip_v4: &[ip_addr!(v4, "75.2.86.85"), ip_addr!(v4, "99.83.239.137")],
     ip_v6: &[],
     cert: RootCertificates::Signal,
+    pins: None,
     proxy_path: "/svr3-nitro-staging",
 };
 
@@ -133,6 +145,7 @@ pub const DOMAIN_CONFIG_SVR3_TPM2SNP: DomainConfig = DomainConfig {
     ip_v4: &[],
     ip_v6: &[],
     cert: RootCertificates::Signal,
+    pins: None,
     proxy_path: "/svr3-tpm2snp",
 };
 
@@ -142,6 +155,7 @@ pub const DOMAIN_CONFIG_SVR3_TPM2SNP_STAGING: DomainConfig = DomainConfig {
     ip_v4: &[ip_addr!(v4, "13.88.30.76")],
     ip_v6: &[],
     cert: RootCertificates::Signal,
+    pins: None,
     proxy_path: "/svr3-tpm2snp-staging",
 };
 
@@ -175,6 +189,10 @@ pub struct DomainConfig {
     pub ip_v4: &'static [Ipv4Addr],
     pub ip_v6: &'static [Ipv6Addr],
     pub cert: RootCertificates,
+    /// Keys the server must present on direct connections, on top of a valid chain.
+    ///
+    /// Not applied to connections through a proxy, which present the proxy's certificate.
+    pub pins: Option<PinningPolicy>,
 }
 
 impl DomainConfig {
@@ -194,6 +212,7 @@ impl DomainConfig {
             HttpRequestDecoratorSeq::default(),
             self.cert.clone(),
         )
+        .with_pins(self.pins.clone())
     }
 
     pub fn connection_params_with_fallback(&self) -> Vec<ConnectionParams> {
@@ -205,6 +224,28 @@ impl DomainConfig {
         let proxy_params = itertools::interleave(shuffled_g_params, shuffled_f_params);
         iter::once(direct).chain(proxy_params).collect()
     }
+
+    /// Returns the routes to try for a client in the country with the given ISO 3166-1 alpha-2
+    /// code, if known.
+    ///
+    /// Routes through each of `fronts` that is enabled in that country come first, in the order
+    /// the fronts are listed, followed by the routes from [Self::connection_params_with_fallback].
+    /// Outside those countries, or if the country isn't known, only the latter are returned.
+    pub fn connection_params_for_country(
+        &self,
+        fronts: &[DomainFrontConfig],
+        country_code: Option<&str>,
+    ) -> Vec<ConnectionParams> {
+        let rng = thread_rng();
+        fronts
+            .iter()
+            .filter(|front| {
+                country_code.is_some_and(|country_code| front.is_enabled_in(country_code))
+            })
+            .flat_map(|front| front.shuffled_connection_params(self.proxy_path, rng.clone()))
+            .chain(self.connection_params_with_fallback())
+            .collect()
+    }
 }
 
 pub fn add_user_agent_header(
@@ -248,11 +289,61 @@ impl ProxyConfig {
     }
 }
 
+/// A CDN that forwards requests to the services by `Host` header, so that they can be reached
+/// without their hostnames appearing in DNS lookups or in the TLS handshake.
+#[derive(Clone, Debug)]
+pub struct DomainFrontConfig {
+    /// Domains served by the CDN, used for DNS resolution and as the SNI.
+    pub sni_list: &'static [&'static str],
+    /// The host the CDN forwards to the services.
+    ///
+    /// Only sent inside the encrypted connection, as the `Host` header and request authority.
+    pub host: &'static str,
+    /// Prepended to the [DomainConfig::proxy_path] of each service.
+    pub path_prefix: &'static str,
+    /// ISO 3166-1 alpha-2 codes of the countries where the front is used.
+    pub enabled_countries: &'static [&'static str],
+}
+
+impl DomainFrontConfig {
+    pub fn is_enabled_in(&self, country_code: &str) -> bool {
+        self.enabled_countries
+            .iter()
+            .any(|country| country.eq_ignore_ascii_case(country_code))
+    }
+
+    fn shuffled_connection_params<'a>(
+        &'a self,
+        proxy_path: &'static str,
+        mut rng: impl Rng,
+    ) -> impl Iterator<Item = ConnectionParams> + 'a {
+        let mut sni_list = self.sni_list.to_vec();
+        sni_list.shuffle(&mut rng);
+        sni_list.into_iter().map(move |sni| {
+            // Decorators are applied in order, so the front's prefix ends up outermost.
+            let mut decorators =
+                HttpRequestDecoratorSeq::from(HttpRequestDecorator::Host(self.host));
+            decorators.add(HttpRequestDecorator::PathPrefix(proxy_path));
+            decorators.add(HttpRequestDecorator::PathPrefix(self.path_prefix));
+            ConnectionParams::new(
+                RouteType::DomainFront,
+                sni,
+                sni,
+                DEFAULT_HTTPS_PORT,
+                decorators,
+                RootCertificates::Native,
+            )
+        })
+    }
+}
+
 pub struct Env<'a, Svr3> {
     pub cdsi: EnclaveEndpoint<'a, Cdsi>,
     pub svr2: EnclaveEndpoint<'a, Sgx>,
     pub svr3: Svr3,
     pub chat_domain_config: DomainConfig,
+    /// Fronts available to all services; see [DomainConfig::connection_params_for_country].
+    pub domain_fronts: &'a [DomainFrontConfig],
 }
 
 impl<'a> Env<'a, Svr3Env<'a>> {
@@ -263,6 +354,7 @@ impl<'a> Env<'a, Svr3Env<'a>> {
             svr2,
             svr3,
             chat_domain_config,
+            domain_fronts: _,
         } = self;
         HashMap::from([
             cdsi.domain_config.static_fallback(),
@@ -300,6 +392,7 @@ impl<'a> Svr3Env<'a> {
 
 pub const STAGING: Env<'static, Svr3Env> = Env {
     chat_domain_config: DOMAIN_CONFIG_CHAT_STAGING,
+    domain_fronts: &[],
     cdsi: EnclaveEndpoint {
         domain_config: DOMAIN_CONFIG_CDSI_STAGING,
         mr_enclave: MrEnclave::new(attest::constants::ENCLAVE_ID_CDSI_STAGING),
@@ -326,6 +419,7 @@ pub const STAGING: Env<'static, Svr3Env> = Env {
 
 pub const PROD: Env<'static, Svr3Env> = Env {
     chat_domain_config: DOMAIN_CONFIG_CHAT,
+    domain_fronts: &[],
     cdsi: EnclaveEndpoint {
         domain_config: DOMAIN_CONFIG_CDSI,
         mr_enclave: MrEnclave::new(attest::constants::ENCLAVE_ID_CDSI_PROD),
@@ -353,3 +447,63 @@ pub const PROD: Env<'static, Svr3Env> = Env {
 pub mod constants {
     pub const WEB_SOCKET_PATH: &str = "/v1/websocket/";
 }
+
+#[cfg(test)]
+mod test {
+    use http::Request;
+
+    use super::*;
+
+    const FRONT: DomainFrontConfig = DomainFrontConfig {
+        sni_list: &["front-a.example", "front-b.example"],
+        host: "signal.cdn.example",
+        path_prefix: "/signal",
+        enabled_countries: &["AA", "BB"],
+    };
+
+    fn route_types(params: &[ConnectionParams]) -> Vec<RouteType> {
+        params.iter().map(|p| p.route_type).collect()
+    }
+
+    #[test]
+    fn fronts_are_used_only_where_enabled() {
+        let fallback = route_types(&DOMAIN_CONFIG_CHAT.connection_params_with_fallback());
+        assert_eq!(fallback[0], RouteType::Direct);
+
+        let params = DOMAIN_CONFIG_CHAT.connection_params_for_country(&[FRONT], Some("bb"));
+        assert_eq!(
+            route_types(&params[..2]),
+            [RouteType::DomainFront, RouteType::DomainFront]
+        );
+        // The usual routes, including the proxy fallbacks, are still tried after the fronts.
+        assert_eq!(route_types(&params[2..]), fallback);
+        for fronted in &params[..2] {
+            assert!(FRONT.sni_list.contains(&&*fronted.sni));
+            // The connection itself goes to the front; only the requests name the real host.
+            assert_eq!(fronted.host, fronted.sni);
+        }
+
+        for country_code in [Some("CC"), None] {
+            let params = DOMAIN_CONFIG_CHAT.connection_params_for_country(&[FRONT], country_code);
+            assert_eq!(route_types(&params), fallback);
+        }
+    }
+
+    #[test]
+    fn fronted_requests_are_rewritten() {
+        let params = DOMAIN_CONFIG_CHAT.connection_params_for_country(&[FRONT], Some("AA"));
+        let builder = Request::get("https://front-a.example/v1/websocket/")
+            .header(http::header::HOST, "front-a.example");
+        let (parts, _) = params[0]
+            .http_request_decorator
+            .decorate_request(builder)
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert_eq!(
+            parts.uri.to_string(),
+            "https://signal.cdn.example/signal/service/v1/websocket/"
+        );
+        assert_eq!(parts.headers[http::header::HOST], "signal.cdn.example");
+    }
+}
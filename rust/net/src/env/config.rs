@@ -0,0 +1,564 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Environments loaded at runtime, for deployments other than [PROD](super::PROD) and
+//! [STAGING](super::STAGING).
+//!
+//! An [EnvConfig] is read from JSON of the following form, where `ip_v4`, `ip_v6`, `pins`,
+//! `port` (443) and `domain_fronts` are optional:
+//!
+//! ```json
+//! {
+//!   "chat": {
+//!     "hostname": "chat.example.org",
+//!     "proxy_path": "/service",
+//!     "ip_v4": ["192.0.2.1"],
+//!     "cert": "native",
+//!     "pins": { "pins": ["sha256/..."], "backup_pins": [], "expires_unix_secs": 1767225600 }
+//!   },
+//!   "cdsi": { "domain": { ... }, "mr_enclave": "<64 hex digits>" },
+//!   "svr2": { "domain": { ... }, "mr_enclave": "<64 hex digits>" },
+//!   "svr3": {
+//!     "sgx": { "domain": { ... }, "mr_enclave": "<64 hex digits>" },
+//!     "nitro": { "domain": { ... }, "mr_enclave": "<version>" },
+//!     "tpm2snp": { "domain": { ... }, "mr_enclave": "<version>" }
+//!   },
+//!   "domain_fronts": [
+//!     { "sni_list": ["..."], "host": "...", "path_prefix": "", "enabled_countries": ["..."] }
+//!   ]
+//! }
+//! ```
+//!
+//! `cert` is `"native"`, `"signal"` or `{ "der": "<base64>" }`. Pins are always enforced; use
+//! [PinningMode::ReportOnly] by setting [DomainConfig::pins] in code instead.
+//!
+//! [EnvConfig::build] checks everything it can before any connection is made: hostnames, paths,
+//! certificates, pins and the format of the enclave measurements.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::num::NonZeroU16;
+use std::str::FromStr as _;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use base64::Engine as _;
+use boring::x509::X509;
+use http::uri::PathAndQuery;
+use serde::Deserialize;
+
+use super::{DomainConfig, DomainFrontConfig, Env, Svr3Env, DEFAULT_HTTPS_PORT};
+use crate::enclave::{Cdsi, EnclaveEndpoint, EnclaveKind, MrEnclave, Nitro, Sgx, Tpm2Snp};
+use crate::infra::certs::{PinningMode, PinningPolicy, RootCertificates, SpkiHash};
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum Error {
+    /// Invalid config: {0}
+    Parse(#[from] serde_json::Error),
+    /// Invalid hostname for {0}
+    InvalidHostname(String),
+    /// Invalid path for {0}
+    InvalidPath(String),
+    /// Invalid certificate for {0}
+    InvalidCertificate(String),
+    /// Invalid pin for {0}
+    InvalidPin(String),
+    /// Invalid enclave measurement for {0}
+    InvalidMeasurement(String),
+    /// Invalid country code for {0}
+    InvalidCountryCode(String),
+}
+
+/// A deployment's endpoints, as read from JSON; see the [module docs](self).
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvConfig {
+    chat: DomainSpec,
+    cdsi: EnclaveSpec,
+    svr2: EnclaveSpec,
+    svr3: Svr3Spec,
+    #[serde(default)]
+    domain_fronts: Vec<DomainFrontSpec>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Svr3Spec {
+    sgx: EnclaveSpec,
+    nitro: EnclaveSpec,
+    tpm2snp: EnclaveSpec,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnclaveSpec {
+    domain: DomainSpec,
+    mr_enclave: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DomainSpec {
+    hostname: String,
+    #[serde(default = "default_port")]
+    port: NonZeroU16,
+    proxy_path: String,
+    #[serde(default)]
+    ip_v4: Vec<Ipv4Addr>,
+    #[serde(default)]
+    ip_v6: Vec<Ipv6Addr>,
+    cert: CertSpec,
+    #[serde(default)]
+    pins: Option<PinsSpec>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum CertSpec {
+    Native,
+    Signal,
+    /// Base64-encoded DER.
+    Der(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PinsSpec {
+    pins: Vec<String>,
+    #[serde(default)]
+    backup_pins: Vec<String>,
+    #[serde(default)]
+    expires_unix_secs: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DomainFrontSpec {
+    sni_list: Vec<String>,
+    host: String,
+    #[serde(default)]
+    path_prefix: String,
+    #[serde(default)]
+    enabled_countries: Vec<String>,
+}
+
+fn default_port() -> NonZeroU16 {
+    DEFAULT_HTTPS_PORT
+}
+
+impl EnvConfig {
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Validates the config and produces the [Env] it describes.
+    ///
+    /// The rest of the crate expects endpoints to live for the whole process, like the built-in
+    /// ones, so each distinct config is built only once and then kept. Building an equal config
+    /// again returns the same [Env].
+    pub fn build(&self) -> Result<&'static Env<'static, Svr3Env<'static>>, Error> {
+        type Built = HashMap<EnvConfig, &'static Env<'static, Svr3Env<'static>>>;
+        static BUILT: OnceLock<Mutex<Built>> = OnceLock::new();
+
+        let mut built = BUILT
+            .get_or_init(Default::default)
+            .lock()
+            .expect("not poisoned");
+        if let Some(env) = built.get(self) {
+            return Ok(env);
+        }
+        let env: &'static Env<_> = Box::leak(Box::new(self.build_uncached()?));
+        built.insert(self.clone(), env);
+        Ok(env)
+    }
+
+    fn build_uncached(&self) -> Result<Env<'static, Svr3Env<'static>>, Error> {
+        let Self {
+            chat,
+            cdsi,
+            svr2,
+            svr3,
+            domain_fronts,
+        } = self;
+        // Check everything before building anything, so that a rejected config leaks nothing.
+        chat.check("chat")?;
+        cdsi.check::<Cdsi>("cdsi")?;
+        svr2.check::<Sgx>("svr2")?;
+        svr3.sgx.check::<Sgx>("svr3.sgx")?;
+        svr3.nitro.check::<Nitro>("svr3.nitro")?;
+        svr3.tpm2snp.check::<Tpm2Snp>("svr3.tpm2snp")?;
+        for (i, front) in domain_fronts.iter().enumerate() {
+            front.check(&format!("domain_fronts[{i}]"))?;
+        }
+
+        Ok(Env {
+            chat_domain_config: chat.build("chat")?,
+            cdsi: cdsi.build::<Cdsi>("cdsi")?,
+            svr2: svr2.build::<Sgx>("svr2")?,
+            svr3: Svr3Env(
+                svr3.sgx.build::<Sgx>("svr3.sgx")?,
+                svr3.nitro.build::<Nitro>("svr3.nitro")?,
+                svr3.tpm2snp.build::<Tpm2Snp>("svr3.tpm2snp")?,
+            ),
+            domain_fronts: leak(
+                domain_fronts
+                    .iter()
+                    .enumerate()
+                    .map(|(i, front)| front.build(&format!("domain_fronts[{i}]")))
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+}
+
+impl EnclaveSpec {
+    fn check<E: Measurement>(&self, field: &str) -> Result<(), Error> {
+        E::parse(&self.mr_enclave)
+            .ok_or_else(|| Error::InvalidMeasurement(format!("{field}.mr_enclave")))?;
+        self.domain.check(&format!("{field}.domain"))
+    }
+
+    fn build<E: Measurement>(&self, field: &str) -> Result<EnclaveEndpoint<'static, E>, Error> {
+        let mr_enclave = E::parse(&self.mr_enclave)
+            .ok_or_else(|| Error::InvalidMeasurement(format!("{field}.mr_enclave")))?;
+        let domain_config = self.domain.build(&format!("{field}.domain"))?;
+        Ok(EnclaveEndpoint {
+            domain_config,
+            mr_enclave: MrEnclave::new(leak(mr_enclave)),
+        })
+    }
+}
+
+impl DomainSpec {
+    fn check(&self, field: &str) -> Result<(), Error> {
+        self.build_parts(field).map(|_| ())
+    }
+
+    /// Checks the fields and builds the parts that don't need to be leaked.
+    fn build_parts(&self, field: &str) -> Result<(RootCertificates, Option<PinningPolicy>), Error> {
+        check_hostname(&self.hostname, || format!("{field}.hostname"))?;
+        check_path(&self.proxy_path, || format!("{field}.proxy_path"))?;
+        let cert = self.cert.build(|| format!("{field}.cert"))?;
+        let pins = self
+            .pins
+            .as_ref()
+            .map(|pins| pins.build(|| format!("{field}.pins")))
+            .transpose()?;
+        Ok((cert, pins))
+    }
+
+    fn build(&self, field: &str) -> Result<DomainConfig, Error> {
+        let Self {
+            hostname,
+            port,
+            proxy_path,
+            ip_v4,
+            ip_v6,
+            cert: _,
+            pins: _,
+        } = self;
+        let (cert, pins) = self.build_parts(field)?;
+
+        Ok(DomainConfig {
+            hostname: leak_str(hostname),
+            port: *port,
+            proxy_path: leak_str(proxy_path),
+            ip_v4: leak(ip_v4.clone()),
+            ip_v6: leak(ip_v6.clone()),
+            cert,
+            pins,
+        })
+    }
+}
+
+impl CertSpec {
+    fn build(&self, field: impl FnOnce() -> String) -> Result<RootCertificates, Error> {
+        match self {
+            Self::Native => Ok(RootCertificates::Native),
+            Self::Signal => Ok(RootCertificates::Signal),
+            Self::Der(encoded) => base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .ok()
+                .filter(|der| X509::from_der(der).is_ok())
+                .map(|der| RootCertificates::FromDer(Cow::Owned(der)))
+                .ok_or_else(|| Error::InvalidCertificate(field())),
+        }
+    }
+}
+
+impl PinsSpec {
+    fn build(&self, field: impl Fn() -> String) -> Result<PinningPolicy, Error> {
+        let parse_all = |pins: &[String]| {
+            pins.iter()
+                .map(|pin| parse_pin(pin).ok_or_else(|| Error::InvalidPin(field())))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let pins = parse_all(&self.pins)?;
+        if pins.is_empty() {
+            return Err(Error::InvalidPin(field()));
+        }
+        Ok(PinningPolicy {
+            pins: pins.into(),
+            backup_pins: parse_all(&self.backup_pins)?.into(),
+            expires: self
+                .expires_unix_secs
+                .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+            mode: PinningMode::Enforce,
+        })
+    }
+}
+
+impl DomainFrontSpec {
+    fn check(&self, field: &str) -> Result<(), Error> {
+        let Self {
+            sni_list,
+            host,
+            path_prefix,
+            enabled_countries,
+        } = self;
+        for sni in sni_list {
+            check_hostname(sni, || format!("{field}.sni_list"))?;
+        }
+        check_hostname(host, || format!("{field}.host"))?;
+        if !path_prefix.is_empty() {
+            check_path(path_prefix, || format!("{field}.path_prefix"))?;
+        }
+        if let Some(country) = enabled_countries
+            .iter()
+            .find(|country| country.len() != 2 || !country.bytes().all(|b| b.is_ascii_alphabetic()))
+        {
+            return Err(Error::InvalidCountryCode(format!(
+                "{field}.enabled_countries: {country}"
+            )));
+        }
+        Ok(())
+    }
+
+    fn build(&self, field: &str) -> Result<DomainFrontConfig, Error> {
+        self.check(field)?;
+        let Self {
+            sni_list,
+            host,
+            path_prefix,
+            enabled_countries,
+        } = self;
+        Ok(DomainFrontConfig {
+            sni_list: leak(sni_list.iter().map(|sni| leak_str(sni)).collect()),
+            host: leak_str(host),
+            path_prefix: leak_str(path_prefix),
+            enabled_countries: leak(
+                enabled_countries
+                    .iter()
+                    .map(|country| leak_str(country))
+                    .collect(),
+            ),
+        })
+    }
+}
+
+/// How the measurement of an enclave is written in a config.
+trait Measurement: EnclaveKind {
+    fn parse(value: &str) -> Option<Vec<u8>>;
+}
+
+fn parse_sgx_measurement(value: &str) -> Option<Vec<u8>> {
+    hex::decode(value).ok().filter(|bytes| bytes.len() == 32)
+}
+
+/// Versions, such as `0.20240411.210730`, go in the request path as-is.
+fn parse_version_measurement(value: &str) -> Option<Vec<u8>> {
+    let valid = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'.');
+    valid.then(|| value.as_bytes().to_vec())
+}
+
+impl Measurement for Cdsi {
+    fn parse(value: &str) -> Option<Vec<u8>> {
+        parse_sgx_measurement(value)
+    }
+}
+
+impl Measurement for Sgx {
+    fn parse(value: &str) -> Option<Vec<u8>> {
+        parse_sgx_measurement(value)
+    }
+}
+
+impl Measurement for Nitro {
+    fn parse(value: &str) -> Option<Vec<u8>> {
+        parse_version_measurement(value)
+    }
+}
+
+impl Measurement for Tpm2Snp {
+    fn parse(value: &str) -> Option<Vec<u8>> {
+        parse_version_measurement(value)
+    }
+}
+
+/// Only accepts domain names already in the canonical form used for DNS, SNI and `Host`.
+fn check_hostname(hostname: &str, field: impl FnOnce() -> String) -> Result<(), Error> {
+    match url::Host::parse(hostname) {
+        Ok(url::Host::Domain(domain)) if domain == hostname => Ok(()),
+        _ => Err(Error::InvalidHostname(field())),
+    }
+}
+
+fn check_path(path: &str, field: impl FnOnce() -> String) -> Result<(), Error> {
+    let valid = path.starts_with('/')
+        && PathAndQuery::from_str(path).is_ok_and(|parsed| parsed.query().is_none());
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidPath(field()))
+    }
+}
+
+fn parse_pin(pin: &str) -> Option<SpkiHash> {
+    let encoded = pin.strip_prefix("sha256/")?;
+    let hash = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .ok()?;
+    Some(SpkiHash(hash.try_into().ok()?))
+}
+
+fn leak<T>(values: Vec<T>) -> &'static [T] {
+    Box::leak(values.into_boxed_slice())
+}
+
+fn leak_str(value: &str) -> &'static str {
+    Box::leak(value.to_owned().into_boxed_str())
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::infra::RouteType;
+
+    const SGX_MEASUREMENT: &str =
+        "acb1973aa0bbbd14b3b4e06f145497d948fd4a98efc500fcce363b3b743ec482";
+
+    fn domain(hostname: &str) -> Value {
+        json!({ "hostname": hostname, "proxy_path": "/proxy", "cert": "signal" })
+    }
+
+    fn enclave(hostname: &str, mr_enclave: &str) -> Value {
+        json!({ "domain": domain(hostname), "mr_enclave": mr_enclave })
+    }
+
+    fn valid_config() -> Value {
+        let pin = format!(
+            "sha256/{}",
+            base64::engine::general_purpose::STANDARD.encode([1; 32])
+        );
+        json!({
+            "chat": {
+                "hostname": "chat.example.org",
+                "port": 8443,
+                "proxy_path": "/service",
+                "ip_v4": ["192.0.2.1"],
+                "ip_v6": ["2001:db8::1"],
+                "cert": "native",
+                "pins": { "pins": [pin] },
+            },
+            "cdsi": enclave("cdsi.example.org", SGX_MEASUREMENT),
+            "svr2": enclave("svr2.example.org", SGX_MEASUREMENT),
+            "svr3": {
+                "sgx": enclave("svr3-sgx.example.org", SGX_MEASUREMENT),
+                "nitro": enclave("svr3-nitro.example.org", "24e56baa.52b91975.ec540f3f"),
+                "tpm2snp": enclave("svr3-tpm2snp.example.org", "0.20240411.210730"),
+            },
+            "domain_fronts": [{
+                "sni_list": ["front.example.net"],
+                "host": "signal.cdn.example.net",
+                "enabled_countries": ["AA"],
+            }],
+        })
+    }
+
+    fn build(config: Value) -> Result<&'static Env<'static, Svr3Env<'static>>, Error> {
+        EnvConfig::from_json(&config.to_string())?.build()
+    }
+
+    fn rejection(config: Value) -> Error {
+        match build(config) {
+            Ok(_) => panic!("invalid config was accepted"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn builds_env() {
+        let env = build(valid_config()).expect("valid config");
+
+        let chat = &env.chat_domain_config;
+        assert_eq!(chat.hostname, "chat.example.org");
+        assert_eq!(chat.port.get(), 8443);
+        assert_eq!(chat.ip_v4, [Ipv4Addr::new(192, 0, 2, 1)]);
+        assert_matches!(chat.cert, RootCertificates::Native);
+        assert_matches!(&chat.pins, Some(pins) if pins.pins[..] == [SpkiHash([1; 32])]);
+
+        assert_eq!(env.cdsi.domain_config.port, DEFAULT_HTTPS_PORT);
+        assert_eq!(hex::encode(env.svr2.mr_enclave.as_ref()), SGX_MEASUREMENT);
+        assert_eq!(env.svr3.tpm2snp().mr_enclave.as_ref(), b"0.20240411.210730");
+
+        let params = chat.connection_params_for_country(env.domain_fronts, Some("AA"));
+        assert_eq!(params[0].route_type, RouteType::DomainFront);
+    }
+
+    #[test]
+    fn equal_configs_share_one_env() {
+        let env = build(valid_config()).expect("valid config");
+        assert!(std::ptr::eq(
+            env,
+            build(valid_config()).expect("valid config")
+        ));
+
+        let mut config = valid_config();
+        config["chat"]["hostname"] = json!("other-chat.example.org");
+        assert!(!std::ptr::eq(env, build(config).expect("valid config")));
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        let mut config = valid_config();
+        config["cdsi"]["domain"]["hostname"] = json!("not a hostname");
+        assert_matches!(rejection(config), Error::InvalidHostname(field) if field == "cdsi.domain.hostname");
+
+        let mut config = valid_config();
+        config["chat"]["proxy_path"] = json!("service");
+        assert_matches!(rejection(config), Error::InvalidPath(_));
+
+        let mut config = valid_config();
+        config["chat"]["cert"] = json!({ "der": "AAAA" });
+        assert_matches!(rejection(config), Error::InvalidCertificate(_));
+
+        let mut config = valid_config();
+        config["chat"]["pins"]["pins"] = json!(["sha256/AAAA"]);
+        assert_matches!(rejection(config), Error::InvalidPin(_));
+
+        let mut config = valid_config();
+        config["svr3"]["sgx"]["mr_enclave"] = json!("0.20240411.210730");
+        assert_matches!(rejection(config), Error::InvalidMeasurement(field) if field == "svr3.sgx.mr_enclave");
+
+        let mut config = valid_config();
+        config["svr3"]["nitro"]["mr_enclave"] = json!("../v2");
+        assert_matches!(rejection(config), Error::InvalidMeasurement(_));
+
+        let mut config = valid_config();
+        config["domain_fronts"][0]["enabled_countries"] = json!(["AAA"]);
+        assert_matches!(rejection(config), Error::InvalidCountryCode(_));
+
+        let mut config = valid_config();
+        config["chat"]["hostnmae"] = json!("typo.example.org");
+        assert_matches!(rejection(config), Error::Parse(_));
+    }
+}
@@ -10,13 +10,13 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use crate::env::{WS_KEEP_ALIVE_INTERVAL, WS_MAX_IDLE_TIME};
-use ::http::uri::PathAndQuery;
+use ::http::uri::{Authority, PathAndQuery};
 use ::http::Uri;
 use async_trait::async_trait;
 use tokio::io::{AsyncRead, AsyncWrite};
 use url::Host;
 
-use crate::infra::certs::RootCertificates;
+use crate::infra::certs::{PinningPolicy, RootCertificates};
 use crate::infra::connection_manager::{
     MultiRouteConnectionManager, SingleRouteThrottlingConnectionManager,
 };
@@ -27,7 +27,7 @@ pub mod certs;
 pub mod connection_manager;
 pub mod dns;
 pub mod errors;
-mod http_client;
+pub mod http_client;
 pub(crate) mod reconnect;
 pub mod tcp_ssl;
 pub mod ws;
@@ -57,6 +57,10 @@ pub enum HttpRequestDecorator {
     Header(http::header::HeaderName, http::header::HeaderValue),
     /// Prefixes the path portion of the request with the given string.
     PathPrefix(&'static str),
+    /// Replaces the host in the request URI and the `Host` header, if any, keeping the port.
+    ///
+    /// Used when the server that terminates TLS routes requests by host, as a CDN does.
+    Host(&'static str),
     /// Applies generic decoration logic.
     Generic(fn(http::request::Builder) -> http::request::Builder),
 }
@@ -82,6 +86,7 @@ impl HttpRequestDecoratorSeq {
 /// - `port` to connect to,
 /// - `http_request_decorator`, a [HttpRequestDecorator] to apply to all HTTP requests,
 /// - `certs`, [RootCertificates] representing trusted certificates,
+/// - `pins`, an optional [PinningPolicy] for the keys the server may present,
 /// - `dns_resolver`, a [DnsResolver] to use when resolving DNS.
 /// This is also applicable to WebSocket connections (in this case, `http_request_decorator` will
 /// only be applied to the initial connection upgrade request).
@@ -93,6 +98,7 @@ pub struct ConnectionParams {
     pub port: NonZeroU16,
     pub http_request_decorator: HttpRequestDecoratorSeq,
     pub certs: RootCertificates,
+    pub pins: Option<PinningPolicy>,
 }
 
 impl ConnectionParams {
@@ -111,6 +117,7 @@ impl ConnectionParams {
             port,
             http_request_decorator,
             certs,
+            pins: None,
         }
     }
 
@@ -124,6 +131,11 @@ impl ConnectionParams {
         self.certs = certs;
         self
     }
+
+    pub fn with_pins(mut self, pins: Option<PinningPolicy>) -> Self {
+        self.pins = pins;
+        self
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -143,8 +155,11 @@ pub struct ConnectionInfo {
 }
 
 /// Source for the result of a hostname lookup.
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, strum::Display)]
+#[derive(
+    Copy, Clone, Debug, Eq, PartialEq, Hash, strum::Display, serde::Serialize, serde::Deserialize,
+)]
 #[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "snake_case")]
 pub enum DnsSource {
     /// The result was returned from the cache
     Cache,
@@ -152,6 +167,8 @@ pub enum DnsSource {
     UdpLookup,
     /// The result came from performing a DNS-over-HTTPS query.
     DnsOverHttpsLookup,
+    /// The result came from performing a DNS-over-TLS query.
+    DnsOverTlsLookup,
     /// The result came from performing a DNS query using a system resolver.
     SystemLookup,
     /// The result was resolved from a preconfigured static entry.
@@ -173,6 +190,12 @@ pub enum RouteType {
     ProxyG,
     /// Connection over a custom TLS proxy
     TlsProxy,
+    /// Connection over a SOCKS5 proxy
+    SocksProxy,
+    /// Connection over an HTTP proxy using `CONNECT`
+    HttpProxy,
+    /// Connection through a CDN that forwards requests to the service by `Host` header
+    DomainFront,
     /// Test-only value
     #[cfg(test)]
     Test,
@@ -217,11 +240,32 @@ impl HttpRequestDecorator {
                 );
                 request_builder.uri(Uri::from_parts(parts).expect("valid uri"))
             }
+            Self::Host(host) => {
+                let uri = request_builder.uri_ref().expect("request has URI set");
+                let mut parts = (*uri).clone().into_parts();
+                if let Some(authority) = &parts.authority {
+                    let rewritten = match authority.port_u16() {
+                        Some(port) => format!("{host}:{port}"),
+                        None => host.to_string(),
+                    };
+                    parts.authority =
+                        Some(Authority::from_str(&rewritten).expect("valid authority"));
+                }
+                let mut request_builder =
+                    request_builder.uri(Uri::from_parts(parts).expect("valid uri"));
+                if let Some(value) = request_builder
+                    .headers_mut()
+                    .and_then(|headers| headers.get_mut(http::header::HOST))
+                {
+                    *value = http::HeaderValue::from_static(host);
+                }
+                request_builder
+            }
         }
     }
 }
 
-pub struct StreamAndInfo<T>(T, ConnectionInfo);
+pub struct StreamAndInfo<T>(pub(crate) T, pub(crate) ConnectionInfo);
 
 impl<T> StreamAndInfo<T> {
     fn map_stream<U>(self, f: impl FnOnce(T) -> U) -> StreamAndInfo<U> {
@@ -251,6 +295,8 @@ pub trait TransportConnector: Clone + Send + Sync {
 pub enum Alpn {
     Http1_1,
     Http2,
+    /// DNS-over-TLS, as registered in RFC 7858.
+    Dot,
 }
 
 impl AsRef<[u8]> for Alpn {
@@ -258,6 +304,7 @@ impl AsRef<[u8]> for Alpn {
         match self {
             Alpn::Http1_1 => b"\x08http/1.1",
             Alpn::Http2 => b"\x02h2",
+            Alpn::Dot => b"\x03dot",
         }
     }
 }
@@ -462,6 +509,27 @@ pub(crate) mod test {
         }
     }
 
+    #[test]
+    fn test_host_decorator() {
+        let cases = vec![
+            (
+                "https://chat.signal.org/v1?a=b",
+                "https://front.example/v1?a=b",
+            ),
+            (
+                "wss://chat.signal.org:8443/v1",
+                "wss://front.example:8443/v1",
+            ),
+        ];
+        for (input, expected_uri) in cases.into_iter() {
+            let builder = Request::get(input).header(http::header::HOST, "chat.signal.org");
+            let builder = HttpRequestDecorator::Host("front.example").decorate_request(builder);
+            let (parts, _) = builder.body(()).unwrap().into_parts();
+            assert_eq!(expected_uri, parts.uri.to_string(), "for input [{}]", input);
+            assert_eq!("front.example", parts.headers[http::header::HOST]);
+        }
+    }
+
     #[test]
     fn test_header_auth_decorator() {
         let expected = "Basic dXNybm06cHNzd2Q=";
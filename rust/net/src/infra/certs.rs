@@ -4,12 +4,19 @@
 //
 
 use std::borrow::Cow;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
 
+use base64::Engine as _;
 use boring::error::ErrorStack;
 use boring::ssl::{SslAlert, SslConnectorBuilder, SslVerifyError, SslVerifyMode};
+use boring::stack::StackRef;
 use boring::x509::store::X509StoreBuilder;
-use boring::x509::X509;
+use boring::x509::{X509Ref, X509VerifyResult, X509};
 use rustls::client::danger::ServerCertVerifier;
+use sha2::{Digest as _, Sha256};
 
 const SIGNAL_ROOT_CERT_DER: &[u8] = include_bytes!("../../res/signal.cer");
 
@@ -19,6 +26,8 @@ pub enum Error {
     BadCertificate,
     /// Bad hostname
     BadHostname,
+    /// Server key did not match any pinned key
+    PinMismatch,
 }
 
 impl From<ErrorStack> for Error {
@@ -35,10 +44,13 @@ pub enum RootCertificates {
 }
 
 impl RootCertificates {
+    /// Configures `connector` to verify certificates against these roots, and against `pins` if
+    /// given.
     pub fn apply_to_connector(
         &self,
         connector: &mut SslConnectorBuilder,
         host_name: &str,
+        pins: Option<PinVerifier>,
     ) -> Result<(), Error> {
         let der = match self {
             RootCertificates::Native => {
@@ -52,7 +64,7 @@ impl RootCertificates {
                     // dependency on ring.
                     verifier.set_provider(rustls::crypto::ring::default_provider().into())
                 }
-                return set_up_platform_verifier(connector, host_name, verifier);
+                return set_up_platform_verifier(connector, host_name, verifier, pins);
             }
             RootCertificates::Signal => SIGNAL_ROOT_CERT_DER,
             RootCertificates::FromDer(der) => der,
@@ -60,16 +72,216 @@ impl RootCertificates {
         let mut store_builder = X509StoreBuilder::new()?;
         store_builder.add_cert(X509::from_der(der)?)?;
         connector.set_verify_cert_store(store_builder.build())?;
+        if let Some(pins) = pins {
+            connector.set_verify_callback(SslVerifyMode::PEER, move |preverify_ok, context| {
+                // The callback runs for each certificate from the root down, so by the time it
+                // gets to the leaf the whole chain has been verified.
+                if !preverify_ok || context.error_depth() != 0 {
+                    return preverify_ok;
+                }
+                pins.verify(context.chain().into_iter().flatten())
+            });
+        }
         Ok(())
     }
 }
 
+/// SHA-256 hash of a DER-encoded SubjectPublicKeyInfo, the usual form of a public key pin.
+///
+/// Formatted as `sha256/<base64>`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpkiHash(pub [u8; 32]);
+
+impl SpkiHash {
+    pub fn of_certificate(cert: &X509Ref) -> Result<Self, Error> {
+        let spki = cert.public_key()?.public_key_to_der()?;
+        Ok(Self(Sha256::digest(spki).into()))
+    }
+}
+
+impl fmt::Display for SpkiHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(self.0);
+        write!(f, "sha256/{encoded}")
+    }
+}
+
+impl fmt::Debug for SpkiHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// A server whose certificate chain didn't contain any pinned key.
+#[derive(Clone, Debug)]
+pub struct PinMismatch {
+    pub host_name: String,
+    /// The keys in the verified chain, leaf first.
+    pub verified_chain: Vec<SpkiHash>,
+}
+
+/// Called for every [PinMismatch] in [PinningMode::ReportOnly].
+#[derive(Clone)]
+pub struct PinMismatchReporter(Arc<dyn Fn(&PinMismatch) + Send + Sync>);
+
+impl PinMismatchReporter {
+    pub fn new(report: impl Fn(&PinMismatch) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(report))
+    }
+}
+
+impl fmt::Debug for PinMismatchReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PinMismatchReporter").finish_non_exhaustive()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum PinningMode {
+    /// Reject connections to servers that don't present a pinned key.
+    Enforce,
+    /// Allow such connections, but report them.
+    ///
+    /// Useful to find out whether pins would break connections, for example behind a gateway
+    /// that inspects TLS traffic, before enforcing them.
+    ReportOnly(PinMismatchReporter),
+}
+
+/// The public keys a server may present, in addition to having a valid certificate chain.
+///
+/// A connection is accepted if any certificate in the verified chain has one of the pinned keys.
+/// Other certificates the server sends along are ignored, since anyone can include them.
+#[derive(Clone, Debug)]
+pub struct PinningPolicy {
+    /// Keys currently in use.
+    pub pins: Cow<'static, [SpkiHash]>,
+    /// Keys not yet in use, so that the server can move to them without breaking existing
+    /// clients.
+    pub backup_pins: Cow<'static, [SpkiHash]>,
+    /// After this time pins are no longer checked, so that old clients can't be locked out by key
+    /// changes they don't know about.
+    pub expires: Option<SystemTime>,
+    pub mode: PinningMode,
+}
+
+impl PinningPolicy {
+    /// Checks the verified certificate chain of `host_name`.
+    pub(crate) fn check<'a>(
+        &self,
+        host_name: &str,
+        verified_chain: impl IntoIterator<Item = &'a X509Ref>,
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        if self.expires.is_some_and(|expires| expires <= now) {
+            log::debug!("pins for {host_name} have expired, not checking them");
+            return Ok(());
+        }
+
+        let verified_chain = verified_chain
+            .into_iter()
+            .map(SpkiHash::of_certificate)
+            .collect::<Result<Vec<_>, _>>()?;
+        if verified_chain.iter().any(|hash| self.pins.contains(hash)) {
+            return Ok(());
+        }
+        if verified_chain
+            .iter()
+            .any(|hash| self.backup_pins.contains(hash))
+        {
+            log::info!("TLS certificate for {host_name} matched a backup pin");
+            return Ok(());
+        }
+
+        match &self.mode {
+            PinningMode::Enforce => {
+                log::warn!("TLS certificate for {host_name} did not match any pinned key");
+                Err(Error::PinMismatch)
+            }
+            PinningMode::ReportOnly(PinMismatchReporter(report)) => {
+                log::warn!(
+                    "TLS certificate for {host_name} did not match any pinned key (report only)"
+                );
+                report(&PinMismatch {
+                    host_name: host_name.to_owned(),
+                    verified_chain,
+                });
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Checks a [PinningPolicy] while the server's certificate chain is verified.
+///
+/// Remembers whether the pins didn't match, so that the failed handshake can be reported as a pin
+/// mismatch rather than as an invalid certificate.
+#[derive(Clone, Debug)]
+pub struct PinVerifier {
+    policy: PinningPolicy,
+    host_name: Arc<str>,
+    mismatched: Arc<AtomicBool>,
+}
+
+impl PinVerifier {
+    pub fn new(policy: PinningPolicy, host_name: &str) -> Self {
+        Self {
+            policy,
+            host_name: host_name.into(),
+            mismatched: Default::default(),
+        }
+    }
+
+    /// Whether a handshake was rejected because of the pins.
+    pub fn mismatched(&self) -> bool {
+        self.mismatched.load(Ordering::Relaxed)
+    }
+
+    fn verify<'a>(&self, verified_chain: impl IntoIterator<Item = &'a X509Ref>) -> bool {
+        match self
+            .policy
+            .check(&self.host_name, verified_chain, SystemTime::now())
+        {
+            Ok(()) => true,
+            Err(Error::PinMismatch) => {
+                self.mismatched.store(true, Ordering::Relaxed);
+                false
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// The certificates in `presented` that the leaf depends on: the leaf, the certificate that
+/// signed it, the one that signed that, and so on.
+///
+/// Stands in for the verified chain where the verifier doesn't expose the one it built. Any other
+/// certificates the server sent along are left out.
+fn issuance_path(presented: &StackRef<X509>) -> Vec<&X509Ref> {
+    let mut path: Vec<&X509Ref> = presented.iter().take(1).collect();
+    while let Some(&subject) = path.last() {
+        let issuer = presented.iter().find(|candidate| {
+            !path.iter().any(|cert| std::ptr::eq(*cert, *candidate))
+                && candidate.issued(subject) == X509VerifyResult::OK
+                && candidate
+                    .public_key()
+                    .and_then(|key| subject.verify(&key))
+                    .unwrap_or(false)
+        });
+        match issuer {
+            Some(issuer) => path.push(issuer),
+            None => break,
+        }
+    }
+    path
+}
+
 /// Configures [rustls_platform_verifier] as a BoringSSL [custom verify
 /// callback](boring::ssl::SslContextBuilder::set_custom_verify_callback).
 fn set_up_platform_verifier(
     connector: &mut SslConnectorBuilder,
     host_name: &str,
     verifier: impl ServerCertVerifier + 'static,
+    pins: Option<PinVerifier>,
 ) -> Result<(), Error> {
     let host_as_server_name = rustls::pki_types::ServerName::try_from(host_name)
         .map_err(|_| Error::BadHostname)?
@@ -157,6 +369,15 @@ fn set_up_platform_verifier(
                 })
             })?;
 
+        if let Some(pins) = &pins {
+            let presented = ssl
+                .peer_cert_chain()
+                .ok_or(SslVerifyError::Invalid(SslAlert::NO_CERTIFICATE))?;
+            if !pins.verify(issuance_path(presented)) {
+                return Err(SslVerifyError::Invalid(SslAlert::BAD_CERTIFICATE));
+            }
+        }
+
         Ok(())
     });
 
@@ -165,10 +386,12 @@ fn set_up_platform_verifier(
 
 #[cfg(test)]
 mod test {
-    use std::sync::Arc;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     use assert_matches::assert_matches;
     use boring::ssl::{ErrorCode, SslConnector, SslMethod};
+    use boring::stack::Stack;
     use rustls::RootCertStore;
     use tokio::net::TcpStream;
 
@@ -197,6 +420,7 @@ mod test {
             &mut ssl,
             SERVER_HOSTNAME,
             Arc::into_inner(verifier).expect("only one referent"),
+            None,
         )
         .expect("valid");
 
@@ -231,6 +455,7 @@ mod test {
             &mut ssl,
             SERVER_HOSTNAME,
             Arc::into_inner(verifier).expect("only one referent"),
+            None,
         )
         .expect("valid");
 
@@ -245,4 +470,111 @@ mod test {
             Err(e) if e.code() == Some(ErrorCode::SSL)
         );
     }
+
+    fn chain_of(cert: &rcgen::CertifiedKey) -> Stack<X509> {
+        let mut chain = Stack::new().expect("can allocate");
+        chain
+            .push(X509::from_der(cert.cert.der()).expect("valid"))
+            .expect("can push");
+        chain
+    }
+
+    fn hash_of(cert: &rcgen::CertifiedKey) -> SpkiHash {
+        SpkiHash::of_certificate(&X509::from_der(cert.cert.der()).expect("valid")).expect("has key")
+    }
+
+    fn policy(pins: Vec<SpkiHash>, backup_pins: Vec<SpkiHash>, mode: PinningMode) -> PinningPolicy {
+        PinningPolicy {
+            pins: pins.into(),
+            backup_pins: backup_pins.into(),
+            expires: None,
+            mode,
+        }
+    }
+
+    #[test]
+    fn pinned_key_is_accepted() {
+        let chain = chain_of(&SERVER_CERTIFICATE);
+        let server = hash_of(&SERVER_CERTIFICATE);
+        let other = hash_of(&PROXY_CERTIFICATE);
+        let now = SystemTime::now();
+
+        policy(vec![server], vec![], PinningMode::Enforce)
+            .check(SERVER_HOSTNAME, &*chain, now)
+            .expect("primary pin matches");
+        policy(vec![other], vec![server], PinningMode::Enforce)
+            .check(SERVER_HOSTNAME, &*chain, now)
+            .expect("backup pin matches");
+    }
+
+    #[test]
+    fn mismatch_is_rejected_when_enforced() {
+        let chain = chain_of(&SERVER_CERTIFICATE);
+        let other = hash_of(&PROXY_CERTIFICATE);
+
+        assert_matches!(
+            policy(vec![other], vec![other], PinningMode::Enforce).check(
+                SERVER_HOSTNAME,
+                &*chain,
+                SystemTime::now()
+            ),
+            Err(Error::PinMismatch)
+        );
+    }
+
+    #[test]
+    fn mismatch_is_reported_in_report_only_mode() {
+        let chain = chain_of(&SERVER_CERTIFICATE);
+        let reported = Arc::new(Mutex::new(vec![]));
+        let mode = PinningMode::ReportOnly(PinMismatchReporter::new({
+            let reported = reported.clone();
+            move |mismatch| reported.lock().unwrap().push(mismatch.clone())
+        }));
+
+        policy(vec![hash_of(&PROXY_CERTIFICATE)], vec![], mode)
+            .check(SERVER_HOSTNAME, &*chain, SystemTime::now())
+            .expect("not enforced");
+
+        let reported = reported.lock().unwrap();
+        assert_matches!(reported.as_slice(), [mismatch] => {
+            assert_eq!(mismatch.host_name, SERVER_HOSTNAME);
+            assert_eq!(mismatch.verified_chain, [hash_of(&SERVER_CERTIFICATE)]);
+        });
+    }
+
+    #[test]
+    fn expired_pins_are_not_checked() {
+        let chain = chain_of(&SERVER_CERTIFICATE);
+        let expires = SystemTime::now();
+        let policy = PinningPolicy {
+            expires: Some(expires),
+            ..policy(
+                vec![hash_of(&PROXY_CERTIFICATE)],
+                vec![],
+                PinningMode::Enforce,
+            )
+        };
+
+        assert_matches!(
+            policy.check(SERVER_HOSTNAME, &*chain, expires - Duration::from_secs(1)),
+            Err(Error::PinMismatch)
+        );
+        policy
+            .check(SERVER_HOSTNAME, &*chain, expires)
+            .expect("expired");
+    }
+
+    #[test]
+    fn issuance_path_leaves_out_unrelated_certificates() {
+        let mut chain = chain_of(&SERVER_CERTIFICATE);
+        chain
+            .push(X509::from_der(PROXY_CERTIFICATE.cert.der()).expect("valid"))
+            .expect("can push");
+
+        let path = issuance_path(&chain)
+            .into_iter()
+            .map(|cert| SpkiHash::of_certificate(cert).expect("has key"))
+            .collect::<Vec<_>>();
+        assert_eq!(path, [hash_of(&SERVER_CERTIFICATE)]);
+    }
 }
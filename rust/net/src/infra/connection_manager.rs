@@ -8,10 +8,12 @@ use std::fmt::Debug;
 use std::future::Future;
 use std::ops::Add;
 use std::panic::RefUnwindSafe;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use itertools::Itertools;
 use tokio::sync::Mutex;
 use tokio::time::{timeout_at, Instant};
@@ -138,21 +140,247 @@ pub struct SingleRouteThrottlingConnectionManager {
     connection_timeout: Duration,
 }
 
+/// Decides how [MultiRouteConnectionManager] moves from one route to the next.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RouteAttemptStrategy {
+    /// Routes are tried one at a time, in the configured order.
+    #[default]
+    Sequential,
+    /// Routes are raced: the next route is started if the previous one hasn't succeeded
+    /// within `delay` (or right away, if it has failed), and the attempts that are still
+    /// in flight once one of them succeeds are cancelled.
+    ///
+    /// Routes that keep losing races are moved behind the ones that win them.
+    Staggered { delay: Duration },
+}
+
+/// Outcomes of past attempts for a single route, used to order routes for
+/// [RouteAttemptStrategy::Staggered].
+#[derive(Debug, Default)]
+struct RouteStats {
+    successes: AtomicU32,
+    consecutive_failures: AtomicU32,
+}
+
+impl RouteStats {
+    fn record_success(&self) {
+        self.successes.fetch_add(1, Ordering::Relaxed);
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+    }
+
+    fn record_failure(&self) {
+        // Can't overflow in practice, but saturate anyway to keep the ordering sane.
+        let _ = self
+            .consecutive_failures
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                Some(n.saturating_add(1))
+            });
+    }
+}
+
 /// A connection manager that holds a list of [SingleRouteThrottlingConnectionManager] instances
 /// and iterates over them until it can find one that results in a successful connection attempt.
 /// If none did, it will return [ConnectionAttemptOutcome::WaitUntil] with the minimum possible
 /// cooldown time (based on cooldown times returned by all throttling connection managers).
+///
+/// Whether routes are tried one by one or raced against each other is controlled by
+/// [RouteAttemptStrategy].
 #[derive(Clone)]
 pub struct MultiRouteConnectionManager<M = SingleRouteThrottlingConnectionManager> {
     route_managers: Vec<M>,
     connection_timeout: Duration,
+    strategy: RouteAttemptStrategy,
+    route_stats: Arc<[RouteStats]>,
 }
 
 impl<M> MultiRouteConnectionManager<M> {
     pub fn new(route_managers: Vec<M>, connection_timeout: Duration) -> Self {
+        let route_stats = route_managers
+            .iter()
+            .map(|_| RouteStats::default())
+            .collect();
         Self {
             route_managers,
             connection_timeout,
+            strategy: RouteAttemptStrategy::default(),
+            route_stats,
+        }
+    }
+
+    pub fn with_strategy(self, strategy: RouteAttemptStrategy) -> Self {
+        Self { strategy, ..self }
+    }
+
+    /// Indices of route managers in the order they should be attempted in a race.
+    ///
+    /// Routes with fewer consecutive failures go first; ties keep the configured order.
+    fn attempt_order(&self) -> Vec<usize> {
+        (0..self.route_managers.len())
+            .sorted_by_key(|&i| {
+                self.route_stats[i]
+                    .consecutive_failures
+                    .load(Ordering::Relaxed)
+            })
+            .collect()
+    }
+}
+
+impl<M> MultiRouteConnectionManager<M>
+where
+    M: ConnectionManager,
+{
+    async fn connect_sequentially<'a, T, E, Fun, Fut>(
+        &'a self,
+        connection_fn: Fun,
+    ) -> ConnectionAttemptOutcome<T, E>
+    where
+        T: Send,
+        E: Send + Debug + LogSafeDisplay,
+        Fun: Fn(&'a ConnectionParams) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<T, E>> + Send,
+    {
+        let now = Instant::now();
+        let deadline = now + self.connection_timeout;
+        let mut earliest_retry = now + MAX_COOLDOWN_INTERVAL;
+        for route_manager in self.route_managers.iter() {
+            match timeout_at(deadline, connect_route(route_manager, &connection_fn)).await {
+                Err(_) => return ConnectionAttemptOutcome::TimedOut,
+                Ok(Ok(r)) => return ConnectionAttemptOutcome::Attempted(Ok(r)),
+                Ok(Err(i)) => earliest_retry = min(earliest_retry, i),
+            }
+        }
+        ConnectionAttemptOutcome::WaitUntil(earliest_retry)
+    }
+
+    async fn connect_staggered<'a, T, E, Fun, Fut>(
+        &'a self,
+        connection_fn: Fun,
+        delay: Duration,
+    ) -> ConnectionAttemptOutcome<T, E>
+    where
+        T: Send,
+        E: Send + Debug + LogSafeDisplay,
+        Fun: Fn(&'a ConnectionParams) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<T, E>> + Send,
+    {
+        let now = Instant::now();
+        let deadline = now + self.connection_timeout;
+        let connection_fn = &connection_fn;
+        let mut pending = self.attempt_order().into_iter();
+        // Routes in the order they were started, kept outside of the race
+        // so that they can be accounted for even if it times out.
+        let mut started = Vec::with_capacity(pending.len());
+
+        let attempt = |index: usize| async move {
+            let result = connect_route(&self.route_managers[index], connection_fn).await;
+            (index, result)
+        };
+
+        let race = async {
+            let mut earliest_retry = now + MAX_COOLDOWN_INTERVAL;
+            let mut in_flight = FuturesUnordered::new();
+            let stagger = tokio::time::sleep(delay);
+            tokio::pin!(stagger);
+
+            let Some(first) = pending.next() else {
+                return Err(earliest_retry);
+            };
+            started.push(first);
+            in_flight.push(attempt(first));
+
+            loop {
+                tokio::select! {
+                    Some((index, result)) = in_flight.next() => match result {
+                        Ok(r) => return Ok((index, r)),
+                        Err(i) => {
+                            earliest_retry = min(earliest_retry, i);
+                            // Don't wait for the stagger delay to try the next route.
+                            if let Some(next) = pending.next() {
+                                started.push(next);
+                                in_flight.push(attempt(next));
+                                stagger.as_mut().reset(Instant::now() + delay);
+                            } else if in_flight.is_empty() {
+                                return Err(earliest_retry);
+                            }
+                        }
+                    },
+                    () = &mut stagger, if pending.len() > 0 => {
+                        let next = pending.next().expect("checked above");
+                        log::info!(
+                            "Starting a connection attempt via {} while waiting on earlier routes",
+                            self.route_managers[next].describe_for_logging()
+                        );
+                        started.push(next);
+                        in_flight.push(attempt(next));
+                        stagger.as_mut().reset(Instant::now() + delay);
+                    }
+                }
+            }
+        };
+
+        let outcome = timeout_at(deadline, race).await;
+        // Any attempts still in flight were cancelled when the race was dropped.
+        match outcome {
+            Ok(Ok((winner, r))) => {
+                // Routes that were started before the winner lost the race to it.
+                for &index in started.iter().take_while(|&&index| index != winner) {
+                    self.route_stats[index].record_failure();
+                }
+                self.route_stats[winner].record_success();
+                log::debug!(
+                    "Connected via {} ({} successful races)",
+                    self.route_managers[winner].describe_for_logging(),
+                    self.route_stats[winner].successes.load(Ordering::Relaxed)
+                );
+                ConnectionAttemptOutcome::Attempted(Ok(r))
+            }
+            Ok(Err(earliest_retry)) => {
+                for &index in &started {
+                    self.route_stats[index].record_failure();
+                }
+                ConnectionAttemptOutcome::WaitUntil(earliest_retry)
+            }
+            Err(_) => {
+                for &index in &started {
+                    self.route_stats[index].record_failure();
+                }
+                ConnectionAttemptOutcome::TimedOut
+            }
+        }
+    }
+}
+
+/// Keeps attempting to connect via `route_manager` until it succeeds or enters a cooldown,
+/// in which case the time of the next possible attempt is returned.
+async fn connect_route<'a, M, T, E, Fun, Fut>(
+    route_manager: &'a M,
+    connection_fn: &Fun,
+) -> Result<T, Instant>
+where
+    M: ConnectionManager,
+    T: Send,
+    E: Send + Debug + LogSafeDisplay,
+    Fun: Fn(&'a ConnectionParams) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<T, E>> + Send,
+{
+    loop {
+        match route_manager.connect_or_wait(connection_fn).await {
+            ConnectionAttemptOutcome::Attempted(Ok(r)) => return Ok(r),
+            ConnectionAttemptOutcome::Attempted(Err(e)) => {
+                log::debug!("Connection attempt failed with an error: {:?}", e);
+                log::info!(
+                    "Connection attempt failed with an error: {} ({})",
+                    e,
+                    route_manager.describe_for_logging(),
+                );
+            }
+            ConnectionAttemptOutcome::TimedOut => {
+                log::info!(
+                    "Connection attempt timed out ({:?})",
+                    route_manager.describe_for_logging()
+                );
+            }
+            ConnectionAttemptOutcome::WaitUntil(i) => return Err(i),
         }
     }
 }
@@ -170,6 +398,9 @@ where
     /// limitations it will soon reach the "cooldown" state and no time will be wasted
     /// on trying it. As a result, it's unlikely that we will be waiting on more than one
     /// connection attempt, except maybe the case of the few first requests.
+    ///
+    /// With [RouteAttemptStrategy::Staggered], a route that hangs (rather than failing
+    /// quickly) only delays the next route by the stagger delay instead of the whole timeout.
     async fn connect_or_wait<'a, T, E, Fun, Fut>(
         &'a self,
         connection_fn: Fun,
@@ -180,47 +411,12 @@ where
         Fun: Fn(&'a ConnectionParams) -> Fut + Send + Sync,
         Fut: Future<Output = Result<T, E>> + Send,
     {
-        let now = Instant::now();
-        let deadline = now + self.connection_timeout;
-        let mut earliest_retry = now + MAX_COOLDOWN_INTERVAL;
-        for route_manager in self.route_managers.iter() {
-            loop {
-                let result_or_timeout =
-                    timeout_at(deadline, route_manager.connect_or_wait(&connection_fn)).await;
-                let result = match result_or_timeout {
-                    Ok(r) => r,
-                    Err(_) => return ConnectionAttemptOutcome::TimedOut,
-                };
-                match result {
-                    ConnectionAttemptOutcome::Attempted(Ok(r)) => {
-                        return ConnectionAttemptOutcome::Attempted(Ok(r));
-                    }
-                    ConnectionAttemptOutcome::Attempted(Err(e)) => {
-                        log::debug!("Connection attempt failed with an error: {:?}", e);
-                        log::info!(
-                            "Connection attempt failed with an error: {} ({})",
-                            e,
-                            route_manager.describe_for_logging(),
-                        );
-                        continue;
-                    }
-                    ConnectionAttemptOutcome::TimedOut => {
-                        log::info!(
-                            "Connection attempt timed out ({:?})",
-                            route_manager.describe_for_logging()
-                        );
-                        continue;
-                    }
-                    ConnectionAttemptOutcome::WaitUntil(i) => {
-                        if i < earliest_retry {
-                            earliest_retry = i
-                        }
-                        break;
-                    }
-                }
+        match self.strategy {
+            RouteAttemptStrategy::Sequential => self.connect_sequentially(connection_fn).await,
+            RouteAttemptStrategy::Staggered { delay } => {
+                self.connect_staggered(connection_fn, delay).await
             }
         }
-        ConnectionAttemptOutcome::WaitUntil(earliest_retry)
     }
 
     fn describe_for_logging(&self) -> String {
@@ -301,7 +497,7 @@ impl ConnectionManager for SingleRouteThrottlingConnectionManager {
 mod test {
     use std::borrow::Borrow;
     use std::future;
-    use std::sync::atomic::{AtomicU16, Ordering};
+    use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 
     use assert_matches::assert_matches;
     use nonzero_ext::nonzero;
@@ -322,6 +518,8 @@ mod test {
 
     const ROUTE_2: &str = "route2.signal.org";
 
+    const STAGGER_DELAY: Duration = Duration::from_millis(100);
+
     #[tokio::test]
     async fn single_route_successfull_attempts() {
         let manager = SingleRouteThrottlingConnectionManager::new(
@@ -595,6 +793,113 @@ mod test {
         );
     }
 
+    fn staggered(
+        route_managers: Vec<SingleRouteThrottlingConnectionManager>,
+    ) -> MultiRouteConnectionManager {
+        MultiRouteConnectionManager::new(route_managers, TIMEOUT_DURATION * 2).with_strategy(
+            RouteAttemptStrategy::Staggered {
+                delay: STAGGER_DELAY,
+            },
+        )
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn staggered_manager_races_past_hanging_route() {
+        let multi_route_manager = staggered(vec![
+            SingleRouteThrottlingConnectionManager::new(
+                example_connection_params(ROUTE_THAT_TIMES_OUT),
+                TIMEOUT_DURATION,
+            ),
+            SingleRouteThrottlingConnectionManager::new(
+                example_connection_params(ROUTE_1),
+                TIMEOUT_DURATION,
+            ),
+        ]);
+
+        let hanging_route_finished = AtomicBool::new(false);
+        let start = Instant::now();
+        let attempt_outcome: ConnectionAttemptOutcome<&str, TestError> = multi_route_manager
+            .connect_or_wait(|connection_params| async {
+                let result = simulate_connect(connection_params, true).await;
+                if matches!(result, Ok(ROUTE_THAT_TIMES_OUT)) {
+                    hanging_route_finished.store(true, Ordering::Relaxed);
+                }
+                result
+            })
+            .await;
+        assert_matches!(
+            attempt_outcome,
+            ConnectionAttemptOutcome::Attempted(Ok(ROUTE_1))
+        );
+        assert_eq!(start.elapsed(), STAGGER_DELAY);
+
+        // The losing attempt was cancelled rather than left running.
+        time::advance(LONG_CONNECTION_TIME).await;
+        assert!(!hanging_route_finished.load(Ordering::Relaxed));
+
+        // The winning route is now tried first.
+        assert_eq!(multi_route_manager.attempt_order(), [1, 0]);
+        let start = Instant::now();
+        let attempt_outcome: ConnectionAttemptOutcome<&str, TestError> = multi_route_manager
+            .connect_or_wait(|connection_params| simulate_connect(connection_params, true))
+            .await;
+        assert_matches!(
+            attempt_outcome,
+            ConnectionAttemptOutcome::Attempted(Ok(ROUTE_1))
+        );
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn staggered_manager_moves_on_immediately_after_failure() {
+        let multi_route_manager = staggered(vec![
+            SingleRouteThrottlingConnectionManager::new(
+                example_connection_params(ROUTE_1),
+                TIMEOUT_DURATION,
+            ),
+            SingleRouteThrottlingConnectionManager::new(
+                example_connection_params(ROUTE_2),
+                TIMEOUT_DURATION,
+            ),
+        ]);
+
+        time::advance(TIME_ADVANCE_VALUE).await;
+        let start = Instant::now();
+        validate_expected_route(&multi_route_manager, false, ROUTE_2).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(multi_route_manager.attempt_order(), [1, 0]);
+
+        // Once the preferred route fails again, the winner is still ahead of it.
+        time::advance(MAX_COOLDOWN_INTERVAL).await;
+        validate_expected_route(&multi_route_manager, false, ROUTE_2).await;
+        assert_eq!(multi_route_manager.attempt_order(), [1, 0]);
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn staggered_manager_times_out() {
+        let multi_route_manager = staggered(vec![
+            SingleRouteThrottlingConnectionManager::new(
+                example_connection_params(ROUTE_THAT_TIMES_OUT),
+                TIMEOUT_DURATION,
+            ),
+            SingleRouteThrottlingConnectionManager::new(
+                example_connection_params(ROUTE_THAT_TIMES_OUT),
+                TIMEOUT_DURATION,
+            ),
+        ]);
+
+        time::advance(TIME_ADVANCE_VALUE).await;
+        let attempt_outcome: ConnectionAttemptOutcome<&str, TestError> = multi_route_manager
+            .connect_or_wait(|connection_params| simulate_connect(connection_params, true))
+            .await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::TimedOut);
+        // Both routes were raced and lost.
+        assert!(multi_route_manager
+            .route_stats
+            .iter()
+            .all(|stats| stats.consecutive_failures.load(Ordering::Relaxed) == 1));
+    }
+
     async fn validate_expected_route(
         multi_route_manager: &MultiRouteConnectionManager,
         route1_healthy: bool,
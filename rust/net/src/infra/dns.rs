@@ -16,12 +16,16 @@ use tokio::time::Instant;
 use crate::infra::certs::RootCertificates;
 use crate::infra::dns::custom_resolver::CustomDnsResolver;
 use crate::infra::dns::dns_errors::Error;
-use crate::infra::dns::dns_lookup::{DnsLookup, DnsLookupRequest, StaticDnsMap, SystemDnsLookup};
+use crate::infra::dns::dns_lookup::{
+    DnsLookup, DnsLookupRequest, StaggeredDnsLookup, StaticDnsMap, SystemDnsLookup,
+};
 use crate::infra::dns::dns_transport_doh::{DohTransport, CLOUDFLARE_NS};
+use crate::infra::dns::dns_transport_dot::{DotConnectionParams, DotTransport, DOT_PORT};
 use crate::infra::dns::dns_types::ResourceType;
 use crate::infra::dns::dns_utils::oneshot_broadcast::Receiver;
 use crate::infra::dns::dns_utils::{log_safe_domain, oneshot_broadcast};
 use crate::infra::dns::lookup_result::LookupResult;
+use crate::infra::dns::persistent_cache::{CachedLookup, PersistentDnsCache};
 use crate::infra::{ConnectionParams, HttpRequestDecoratorSeq, RouteType};
 use crate::utils;
 
@@ -30,10 +34,12 @@ mod dns_errors;
 pub mod dns_lookup;
 mod dns_message;
 pub mod dns_transport_doh;
+pub mod dns_transport_dot;
 pub mod dns_transport_udp;
 mod dns_types;
 mod dns_utils;
 pub mod lookup_result;
+pub mod persistent_cache;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -41,6 +47,7 @@ struct DnsResolverState {
     /// Controls if lookup results will contain IPv6 entries.
     ipv6_enabled: bool,
     in_flight_lookups: HashMap<String, Receiver<Result<LookupResult>>>,
+    persistent_cache: Option<PersistentDnsCache>,
 }
 
 impl Default for DnsResolverState {
@@ -48,6 +55,7 @@ impl Default for DnsResolverState {
         Self {
             ipv6_enabled: true,
             in_flight_lookups: Default::default(),
+            persistent_cache: None,
         }
     }
 }
@@ -85,6 +93,9 @@ impl DnsResolver {
         }
     }
 
+    /// How long a DoH lookup gets before a DoT lookup is started alongside it.
+    const DOT_STAGGER_DELAY: Duration = Duration::from_secs(1);
+
     /// Creates a DNS resolver with a default resolution strategy
     /// to be used for most of the external use cases
     pub fn new_with_static_fallback(static_map: HashMap<&'static str, LookupResult>) -> Self {
@@ -96,13 +107,32 @@ impl DnsResolver {
             HttpRequestDecoratorSeq::default(),
             RootCertificates::Native,
         );
-        let custom_resolver = Box::new(CustomDnsResolver::<DohTransport>::new(connection_params));
+        let doh_resolver: Arc<dyn DnsLookup> =
+            Arc::new(CustomDnsResolver::<DohTransport>::new(connection_params));
+        // Used where DoH is blocked, e.g. by filtering on the `/dns-query` path.
+        let dot_resolver: Arc<dyn DnsLookup> = Arc::new(CustomDnsResolver::<DotTransport>::new(
+            DotConnectionParams::new(ConnectionParams::new(
+                RouteType::Direct,
+                CLOUDFLARE_NS,
+                CLOUDFLARE_NS,
+                DOT_PORT,
+                HttpRequestDecoratorSeq::default(),
+                RootCertificates::Native,
+            )),
+        ));
+        let doh_or_dot = || -> Box<dyn DnsLookup> {
+            Box::new(StaggeredDnsLookup::new(
+                doh_resolver.clone(),
+                dot_resolver.clone(),
+                Self::DOT_STAGGER_DELAY,
+            ))
+        };
         DnsResolver {
             lookup_options: Arc::new(vec![
                 (Box::new(SystemDnsLookup), Duration::from_secs(2)),
-                (custom_resolver.clone(), Duration::from_secs(5)),
-                (custom_resolver.clone(), Duration::from_secs(10)),
-                (custom_resolver.clone(), Duration::from_secs(15)),
+                (doh_or_dot(), Duration::from_secs(5)),
+                (doh_or_dot(), Duration::from_secs(10)),
+                (doh_or_dot(), Duration::from_secs(15)),
                 (Box::new(StaticDnsMap(static_map)), Duration::from_secs(1)),
             ]),
             state: Default::default(),
@@ -117,6 +147,23 @@ impl DnsResolver {
         }
     }
 
+    /// Consults `cache` before any other lookup option, and saves successful lookups to it.
+    ///
+    /// Passing `None` stops using the previously set cache.
+    pub fn set_persistent_cache(&self, cache: Option<PersistentDnsCache>) {
+        let mut guard = self.state.lock().expect("not poisoned");
+        guard.persistent_cache = cache;
+        guard.in_flight_lookups.clear();
+    }
+
+    pub fn persistent_cache(&self) -> Option<PersistentDnsCache> {
+        self.state
+            .lock()
+            .expect("not poisoned")
+            .persistent_cache
+            .clone()
+    }
+
     pub async fn lookup_ip(&self, hostname: &str) -> Result<LookupResult> {
         match self.start_or_join_lookup(hostname).val().await {
             Ok(r) => r,
@@ -135,7 +182,12 @@ impl DnsResolver {
                 guard
                     .in_flight_lookups
                     .insert(hostname.to_string(), rx.clone());
-                self.spawn_lookup(hostname.to_string(), tx, guard.ipv6_enabled);
+                self.spawn_lookup(
+                    hostname.to_string(),
+                    tx,
+                    guard.ipv6_enabled,
+                    guard.persistent_cache.clone(),
+                );
                 rx
             }
             Some(r) => r.clone(),
@@ -147,9 +199,32 @@ impl DnsResolver {
         hostname: String,
         result_sender: Sender<Result<LookupResult>>,
         ipv6_enabled: bool,
+        persistent_cache: Option<PersistentDnsCache>,
     ) {
         let self_clone = self.clone();
         tokio::spawn(async move {
+            let mut result_sender = Some(result_sender);
+            match persistent_cache
+                .as_ref()
+                .and_then(|cache| cache.get(&hostname, ipv6_enabled))
+            {
+                Some(CachedLookup::Fresh(result)) => {
+                    self_clone.clear_in_flight_map(hostname.as_str());
+                    let _ = result_sender.take().expect("not sent yet").send(Ok(result));
+                    return;
+                }
+                Some(CachedLookup::Stale(result)) => {
+                    // Answer now, but keep going to refresh the cache. Lookups that join in the
+                    // meantime get the same stale answer.
+                    log::info!(
+                        "Using stale cached DNS record for [{}] while refreshing it",
+                        log_safe_domain(hostname.as_str())
+                    );
+                    let _ = result_sender.take().expect("not sent yet").send(Ok(result));
+                }
+                None => {}
+            }
+
             let request = DnsLookupRequest {
                 hostname: Arc::from(hostname.as_str()),
                 ipv6_enabled,
@@ -165,16 +240,25 @@ impl DnsResolver {
                 .filter_map(|result| future::ready(result.ok()))
                 .next()
                 .await
-                .ok_or(Error::LookupFailed)
-                .and_then(|res| match ipv6_enabled {
-                    true => Ok(res),
-                    false if res.ipv4.is_empty() => Err(Error::RequestedIpTypeNotFound),
-                    false => Ok(LookupResult {
-                        ipv6: vec![],
-                        ..res
-                    }),
-                });
+                .ok_or(Error::LookupFailed);
+            if let (Some(cache), Ok(result)) = (persistent_cache, &result) {
+                let hostname = hostname.clone();
+                let result = result.clone();
+                // Saving the cache does blocking I/O.
+                let _ = tokio::task::spawn_blocking(move || cache.insert(&hostname, &result)).await;
+            }
+            let result = result.and_then(|res| match ipv6_enabled {
+                true => Ok(res),
+                false if res.ipv4.is_empty() => Err(Error::RequestedIpTypeNotFound),
+                false => Ok(LookupResult {
+                    ipv6: vec![],
+                    ..res
+                }),
+            });
             self_clone.clear_in_flight_map(hostname.as_str());
+            let Some(result_sender) = result_sender else {
+                return;
+            };
             if result_sender.send(result).is_err() {
                 log::debug!(
                     "No DNS result listeners left for domain [{}]",
@@ -232,6 +316,8 @@ mod test {
     use const_str::ip_addr;
 
     use crate::infra::dns::dns_lookup::DnsLookupRequest;
+    use crate::infra::dns::persistent_cache::test::MemoryStore;
+    use crate::infra::dns::persistent_cache::{DnsCacheStore as _, PersistentDnsCacheConfig};
     use crate::infra::dns::{DnsLookup, DnsResolver, Error, LookupResult, StaticDnsMap};
     use crate::infra::DnsSource;
     use crate::utils::sleep_and_catch_up;
@@ -636,6 +722,78 @@ mod test {
         .await;
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_staggered_lookup() {
+        let stagger = ATTEMPT_TIMEOUT / 4;
+        let slow = ATTEMPT_TIMEOUT * 10;
+        let short_delay = ATTEMPT_TIMEOUT / 10;
+
+        let ip_1 = ip_addr!(v4, "2.2.2.1");
+        let ip_2 = ip_addr!(v4, "2.2.2.2");
+
+        async fn lookup(
+            primary: Box<TestLookup>,
+            secondary: Box<TestLookup>,
+            stagger: Duration,
+        ) -> (Result<LookupResult>, Duration, usize) {
+            let lookup =
+                StaggeredDnsLookup::new(Arc::new(*primary), Arc::new(*secondary.clone()), stagger);
+            let started_at = Instant::now();
+            let request = DnsLookupRequest {
+                hostname: CUSTOM_DOMAIN.into(),
+                ipv6_enabled: true,
+            };
+            let result = lookup.dns_lookup(request).await;
+            (
+                result,
+                started_at.elapsed(),
+                secondary.logged_requests().len(),
+            )
+        }
+
+        // A quick primary answer never starts the secondary lookup.
+        let (result, elapsed, secondary_requests) = lookup(
+            TestLookup::with_custom_response(short_delay, ip_1),
+            TestLookup::with_custom_response(short_delay, ip_2),
+            stagger,
+        )
+        .await;
+        assert_eq!(&[ip_1], result.expect("success").ipv4.as_slice());
+        assert_eq!(elapsed, short_delay);
+        assert_eq!(secondary_requests, 0);
+
+        // A slow primary is raced against the secondary after the stagger delay.
+        let (result, elapsed, secondary_requests) = lookup(
+            TestLookup::with_custom_response(slow, ip_1),
+            TestLookup::with_custom_response(short_delay, ip_2),
+            stagger,
+        )
+        .await;
+        assert_eq!(&[ip_2], result.expect("success").ipv4.as_slice());
+        assert_eq!(elapsed, stagger + short_delay);
+        assert_eq!(secondary_requests, 1);
+
+        // A failing primary starts the secondary right away.
+        let (result, elapsed, _) = lookup(
+            TestLookup::standard_responses(Duration::ZERO),
+            TestLookup::with_custom_response(short_delay, ip_2),
+            stagger,
+        )
+        .await;
+        assert_eq!(&[ip_2], result.expect("success").ipv4.as_slice());
+        assert_eq!(elapsed, short_delay);
+
+        // If the secondary fails, the primary can still answer.
+        let (result, elapsed, _) = lookup(
+            TestLookup::with_custom_response(ATTEMPT_TIMEOUT, ip_1),
+            TestLookup::standard_responses(short_delay),
+            stagger,
+        )
+        .await;
+        assert_eq!(&[ip_1], result.expect("success").ipv4.as_slice());
+        assert_eq!(elapsed, ATTEMPT_TIMEOUT);
+    }
+
     #[tokio::test(start_paused = true)]
     async fn test_request_joins_in_flight_request() {
         let response_delay = ATTEMPT_TIMEOUT / 2;
@@ -678,4 +836,69 @@ mod test {
         // making sure that the `test_lookup` have only seen one request
         assert_matches!(test_lookup.logged_requests().as_slice(), [_, _]);
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn persistent_cache_answers_before_other_lookup_options() {
+        let store = MemoryStore::default();
+        let dns_resolver = DnsResolver::new_custom(vec![(
+            TestLookup::standard_responses(Duration::ZERO),
+            ATTEMPT_TIMEOUT,
+        )]);
+        dns_resolver.set_persistent_cache(Some(PersistentDnsCache::load(
+            store.clone(),
+            Default::default(),
+        )));
+        let _ = dns_resolver.lookup_ip(DUAL_STACK_DOMAIN).await.unwrap();
+
+        // A new resolver, as after a restart, whose lookups would take a long time.
+        let test_lookup = TestLookup::standard_responses(ATTEMPT_TIMEOUT / 2);
+        let dns_resolver = DnsResolver::new_custom(vec![(test_lookup.clone(), ATTEMPT_TIMEOUT)]);
+        dns_resolver
+            .set_persistent_cache(Some(PersistentDnsCache::load(store, Default::default())));
+        let started_at = Instant::now();
+        let result = dns_resolver.lookup_ip(DUAL_STACK_DOMAIN).await.unwrap();
+        assert_eq!(started_at.elapsed(), Duration::ZERO);
+        assert_eq!(result.source(), DnsSource::Cache);
+        assert_eq!(result.ipv4, [IPV4]);
+        assert_eq!(result.ipv6, [IPV6]);
+        assert_matches!(test_lookup.logged_requests().as_slice(), []);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_persistent_cache_entry_is_served_and_refreshed() {
+        let store = MemoryStore::default();
+        store
+            .save(
+                format!(
+                    r#"{{"version":1,"entries":{{"{DUAL_STACK_DOMAIN}":{{"ipv4":["192.0.2.1"],"ipv6":[],"source":"udp_lookup","expires_at":1}}}}}}"#
+                )
+                .as_bytes(),
+            )
+            .expect("can save");
+        let cache = PersistentDnsCache::load(
+            store,
+            PersistentDnsCacheConfig {
+                max_staleness: Duration::MAX,
+                ..Default::default()
+            },
+        );
+
+        let test_lookup = TestLookup::standard_responses(ATTEMPT_TIMEOUT / 2);
+        let dns_resolver = DnsResolver::new_custom(vec![(test_lookup.clone(), ATTEMPT_TIMEOUT)]);
+        dns_resolver.set_persistent_cache(Some(cache.clone()));
+
+        let started_at = Instant::now();
+        let result = dns_resolver.lookup_ip(DUAL_STACK_DOMAIN).await.unwrap();
+        assert_eq!(started_at.elapsed(), Duration::ZERO);
+        assert_eq!(result.ipv4, [ip_addr!(v4, "192.0.2.1")]);
+
+        tokio::time::sleep(ATTEMPT_TIMEOUT).await;
+        assert_matches!(test_lookup.logged_requests().as_slice(), [_]);
+        let refreshed = assert_matches!(
+            cache.get(DUAL_STACK_DOMAIN, true),
+            Some(CachedLookup::Fresh(result)) => result
+        );
+        assert_eq!(refreshed.ipv4, [IPV4]);
+        assert_eq!(refreshed.ipv6, [IPV6]);
+    }
 }
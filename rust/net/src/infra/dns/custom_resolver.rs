@@ -121,9 +121,15 @@ impl<T: DnsTransport + Sync + 'static> CustomDnsResolver<T> {
         let (ipv4_res_rx, ipv6_res_rx) = self.send_dns_queries(transport, request);
         let (maybe_ipv4, maybe_ipv6) =
             results_within_interval(ipv4_res_rx, ipv6_res_rx, RESOLUTION_DELAY).await;
+        let expiration = Iterator::min(
+            maybe_ipv4
+                .iter()
+                .map(|r| r.expiration)
+                .chain(maybe_ipv6.iter().map(|r| r.expiration)),
+        );
         let ipv4s = maybe_ipv4.map_or(vec![], |r| r.data);
         let ipv6s = maybe_ipv6.map_or(vec![], |r| r.data);
-        match LookupResult::new(T::dns_source(), ipv4s, ipv6s) {
+        match LookupResult::new(T::dns_source(), ipv4s, ipv6s).with_expiration(expiration) {
             lookup_result if !lookup_result.is_empty() => Ok(lookup_result),
             _ => Err(Error::LookupFailed),
         }
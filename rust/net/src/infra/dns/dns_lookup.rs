@@ -13,6 +13,7 @@ use itertools::Itertools;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub struct DnsLookupRequest {
@@ -33,6 +34,28 @@ pub struct SystemDnsLookup;
 #[derive(Default)]
 pub struct StaticDnsMap(pub HashMap<&'static str, LookupResult>);
 
+/// Starts a `secondary` lookup if the `primary` one hasn't finished within `stagger` (or as soon
+/// as it fails), and uses whichever result succeeds first.
+pub struct StaggeredDnsLookup {
+    primary: Arc<dyn DnsLookup>,
+    secondary: Arc<dyn DnsLookup>,
+    stagger: Duration,
+}
+
+impl StaggeredDnsLookup {
+    pub fn new(
+        primary: Arc<dyn DnsLookup>,
+        secondary: Arc<dyn DnsLookup>,
+        stagger: Duration,
+    ) -> Self {
+        Self {
+            primary,
+            secondary,
+            stagger,
+        }
+    }
+}
+
 #[async_trait]
 impl DnsLookup for SystemDnsLookup {
     async fn dns_lookup(&self, request: DnsLookupRequest) -> dns::Result<LookupResult> {
@@ -62,6 +85,33 @@ impl DnsLookup for StaticDnsMap {
     }
 }
 
+#[async_trait]
+impl DnsLookup for StaggeredDnsLookup {
+    async fn dns_lookup(&self, request: DnsLookupRequest) -> dns::Result<LookupResult> {
+        let primary = self.primary.dns_lookup(request.clone());
+        tokio::pin!(primary);
+        let primary_failed = tokio::select! {
+            result = &mut primary => match result {
+                Ok(result) => return Ok(result),
+                Err(_) => true,
+            },
+            _ = tokio::time::sleep(self.stagger) => false,
+        };
+
+        let secondary = self.secondary.dns_lookup(request);
+        if primary_failed {
+            return secondary.await;
+        }
+        tokio::pin!(secondary);
+        match futures_util::future::select(primary, secondary).await {
+            futures_util::future::Either::Left((Ok(result), _))
+            | futures_util::future::Either::Right((Ok(result), _)) => Ok(result),
+            futures_util::future::Either::Left((Err(_), other)) => other.await,
+            futures_util::future::Either::Right((Err(_), other)) => other.await,
+        }
+    }
+}
+
 #[async_trait]
 impl<T> DnsLookup for CustomDnsResolver<T>
 where
@@ -38,7 +38,7 @@ pub const KNOWN_NAMESERVERS: &[(&str, Ipv4Addr, Ipv6Addr)] = &[
     ),
 ];
 
-fn dns_resolver_for_known_ns(ipv6_enabled: bool) -> DnsResolver {
+pub(super) fn dns_resolver_for_known_ns(ipv6_enabled: bool) -> DnsResolver {
     let map: HashMap<_, _> = KNOWN_NAMESERVERS
         .iter()
         .map(|(name, ipv4, ipv6)| {
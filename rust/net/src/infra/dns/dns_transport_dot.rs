@@ -0,0 +1,413 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::num::NonZeroU16;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+use futures_util::{stream, FutureExt as _, StreamExt as _};
+use nonzero_ext::nonzero;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::time::Instant;
+
+use crate::infra::dns::custom_resolver::{DnsQueryResult, DnsTransport};
+use crate::infra::dns::dns_errors::Error;
+use crate::infra::dns::dns_lookup::DnsLookupRequest;
+use crate::infra::dns::dns_message;
+use crate::infra::dns::dns_transport_doh::dns_resolver_for_known_ns;
+use crate::infra::dns::dns_types::ResourceType;
+use crate::infra::tcp_ssl::{DirectConnector, TcpSslConnector, TcpSslConnectorStream};
+use crate::infra::{dns, Alpn, ConnectionParams, DnsSource, StreamAndInfo, TransportConnector};
+
+pub const DOT_PORT: NonZeroU16 = nonzero!(853u16);
+
+const A_REQUEST_ID: u16 = 0;
+const AAAA_REQUEST_ID: u16 = 1;
+
+/// How long an idle connection is kept around for the next lookup.
+///
+/// Resolvers are free to close idle connections at any time; this is short enough that they
+/// usually won't have, but a closed connection is detected and replaced anyway.
+const MAX_IDLE_TIME: Duration = Duration::from_secs(10);
+
+struct IdleConnection {
+    stream: TcpSslConnectorStream,
+    idle_since: Instant,
+}
+
+/// Connection parameters for [DotTransport].
+///
+/// Clones share a single idle connection, so lookups made through the same
+/// [CustomDnsResolver](crate::infra::dns::custom_resolver::CustomDnsResolver) reuse one TLS
+/// session instead of doing a handshake every time.
+#[derive(Clone)]
+pub struct DotConnectionParams {
+    connection_params: ConnectionParams,
+    connector: TcpSslConnector,
+    idle_connection: Arc<Mutex<Option<IdleConnection>>>,
+}
+
+impl DotConnectionParams {
+    /// Connects directly to the resolver, looking up its name among [KNOWN_NAMESERVERS].
+    ///
+    /// [KNOWN_NAMESERVERS]: crate::infra::dns::dns_transport_doh::KNOWN_NAMESERVERS
+    pub fn new(connection_params: ConnectionParams) -> Self {
+        let connector = DirectConnector::new(dns_resolver_for_known_ns(true)).into();
+        Self::with_connector(connection_params, connector)
+    }
+
+    /// Connects to the resolver using `connector`, which may go through a proxy.
+    ///
+    /// The connector's DNS resolver must not itself depend on this transport.
+    pub fn with_connector(connection_params: ConnectionParams, connector: TcpSslConnector) -> Self {
+        Self {
+            connection_params,
+            connector,
+            idle_connection: Default::default(),
+        }
+    }
+
+    fn take_idle_connection(&self) -> Option<TcpSslConnectorStream> {
+        let IdleConnection {
+            mut stream,
+            idle_since,
+        } = self.idle_connection.lock().expect("not poisoned").take()?;
+        if idle_since.elapsed() > MAX_IDLE_TIME {
+            return None;
+        }
+        // Nothing should be readable on an idle connection. If a read completes right away, the
+        // server either closed the connection or sent something we can't match to a query.
+        let mut byte = [0];
+        match stream.read(&mut byte).now_or_never() {
+            None => Some(stream),
+            Some(_) => {
+                log::debug!("discarding idle DNS-over-TLS connection closed by the server");
+                None
+            }
+        }
+    }
+}
+
+/// DNS transport that sends queries over TLS, as described in RFC 7858.
+pub struct DotTransport {
+    stream: TcpSslConnectorStream,
+    idle_connection: Arc<Mutex<Option<IdleConnection>>>,
+}
+
+#[async_trait]
+impl DnsTransport for DotTransport {
+    type ConnectionParameters = DotConnectionParams;
+
+    fn dns_source() -> DnsSource {
+        DnsSource::DnsOverTlsLookup
+    }
+
+    async fn connect(
+        connection_params: Self::ConnectionParameters,
+        ipv6_enabled: bool,
+    ) -> dns::Result<Self> {
+        let idle_connection = connection_params.idle_connection.clone();
+        if let Some(stream) = connection_params.take_idle_connection() {
+            log::debug!("reusing DNS-over-TLS connection");
+            return Ok(Self {
+                stream,
+                idle_connection,
+            });
+        }
+
+        let DotConnectionParams {
+            connection_params,
+            mut connector,
+            ..
+        } = connection_params;
+        connector.set_ipv6_enabled(ipv6_enabled);
+        match connector.connect(&connection_params, Alpn::Dot).await {
+            Ok(StreamAndInfo(stream, _)) => Ok(Self {
+                stream,
+                idle_connection,
+            }),
+            Err(error) => {
+                log::error!("Failed to connect to DNS-over-TLS resolver: {}", error);
+                Err(Error::TransportFailure)
+            }
+        }
+    }
+
+    async fn send_queries(
+        self,
+        request: DnsLookupRequest,
+    ) -> dns::Result<BoxStream<'static, dns::Result<DnsQueryResult>>> {
+        let Self {
+            mut stream,
+            idle_connection,
+        } = self;
+
+        // Both queries are pipelined on the same connection; responses may come back in either
+        // order and are told apart by their IDs.
+        // https://datatracker.ietf.org/doc/html/rfc7766#section-6.2.1.1
+        let mut queries = vec![];
+        if request.ipv6_enabled {
+            queries.push(dns_message::create_request_with_id(
+                AAAA_REQUEST_ID,
+                &request.hostname,
+                ResourceType::AAAA,
+            )?);
+        }
+        queries.push(dns_message::create_request_with_id(
+            A_REQUEST_ID,
+            &request.hostname,
+            ResourceType::A,
+        )?);
+
+        let mut framed = vec![];
+        for query in &queries {
+            let len = u16::try_from(query.len()).map_err(|_| Error::MessageTooLong)?;
+            framed.extend_from_slice(&len.to_be_bytes());
+            framed.extend_from_slice(query);
+        }
+        stream.write_all(&framed).await?;
+        stream.flush().await?;
+
+        let state = Some((stream, queries.len(), idle_connection));
+        Ok(stream::unfold(state, |state| async move {
+            let (mut stream, remaining, idle_connection) = state?;
+            let message = match read_message(&mut stream).await {
+                Ok(message) => message,
+                // The connection is in an unknown state, so don't try to use it again.
+                Err(e) => return Some((Err(e), None)),
+            };
+            let next_state = match remaining - 1 {
+                0 => {
+                    *idle_connection.lock().expect("not poisoned") = Some(IdleConnection {
+                        stream,
+                        idle_since: Instant::now(),
+                    });
+                    None
+                }
+                remaining => Some((stream, remaining, idle_connection)),
+            };
+            Some((parse_message(&message), next_state))
+        })
+        .boxed())
+    }
+}
+
+/// Reads one length-prefixed DNS message.
+///
+/// [TCP usage](https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.2)
+async fn read_message(stream: &mut TcpSslConnectorStream) -> dns::Result<Vec<u8>> {
+    let len = stream.read_u16().await?;
+    let mut message = vec![0; len.into()];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+fn parse_message(message: &[u8]) -> dns::Result<DnsQueryResult> {
+    let result = match dns_message::get_id(message)? {
+        A_REQUEST_ID => DnsQueryResult::Left(dns_message::parse_response(message, |bytes_vec| {
+            let octets: [u8; 4] = bytes_vec.try_into().unwrap();
+            Ok(Ipv4Addr::from(octets))
+        })?),
+        AAAA_REQUEST_ID => {
+            DnsQueryResult::Right(dns_message::parse_response(message, |bytes_vec| {
+                let octets: [u8; 16] = bytes_vec.try_into().unwrap();
+                Ok(Ipv6Addr::from(octets))
+            })?)
+        }
+        _ => Err(Error::UnexpectedMessageId)?,
+    };
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use boring::pkey::PKey;
+    use boring::ssl::{SslAcceptor, SslMethod};
+    use boring::x509::X509;
+    use const_str::ip_addr;
+    use hickory_proto::op::{Message, MessageType};
+    use hickory_proto::rr::rdata::{A, AAAA};
+    use hickory_proto::rr::{RData, Record, RecordType};
+    use hickory_proto::serialize::binary::{BinDecodable as _, BinEncodable as _};
+
+    use super::*;
+    use crate::infra::certs::RootCertificates;
+    use crate::infra::dns::custom_resolver::CustomDnsResolver;
+    use crate::infra::dns::lookup_result::LookupResult;
+    use crate::infra::dns::DnsResolver;
+    use crate::infra::tcp_ssl::testutil::{SERVER_CERTIFICATE, SERVER_HOSTNAME};
+    use crate::infra::{HttpRequestDecoratorSeq, RouteType};
+
+    const IPV4: Ipv4Addr = ip_addr!(v4, "192.0.2.1");
+    const IPV6: Ipv6Addr = ip_addr!(v6, "2001:db8::1");
+
+    fn answer(query: &[u8]) -> Vec<u8> {
+        let query = Message::from_bytes(query).expect("valid query");
+        let question = query.queries().first().expect("has a question").clone();
+        let rdata = match question.query_type() {
+            RecordType::A => RData::A(A::from(IPV4)),
+            RecordType::AAAA => RData::AAAA(AAAA::from(IPV6)),
+            other => panic!("unexpected query type {other}"),
+        };
+        let mut response = Message::new();
+        response
+            .set_id(query.id())
+            .set_message_type(MessageType::Response)
+            .add_query(question.clone())
+            .add_answer(Record::from_rdata(question.name().clone(), 300, rdata));
+        response.to_bytes().expect("can encode")
+    }
+
+    /// Starts a local stand-in for a DNS-over-TLS resolver.
+    ///
+    /// If `close_after` is set, each connection is closed after answering that many queries.
+    /// Returns the server address, the number of connections accepted so far, and the server task.
+    fn localhost_dot_server(
+        close_after: Option<usize>,
+    ) -> (
+        SocketAddr,
+        Arc<AtomicU32>,
+        impl std::future::Future<Output = ()>,
+    ) {
+        let ssl_acceptor = (|| {
+            let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+            builder.set_certificate(X509::from_der(SERVER_CERTIFICATE.cert.der())?.as_ref())?;
+            builder.set_private_key(
+                PKey::private_key_from_der(SERVER_CERTIFICATE.key_pair.serialized_der())?.as_ref(),
+            )?;
+            builder.check_private_key().map(|()| builder.build())
+        })()
+        .expect("can configure acceptor");
+
+        let listener = std::net::TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).expect("can bind");
+        listener.set_nonblocking(true).expect("can set nonblocking");
+        let addr = listener.local_addr().expect("is bound to local addr");
+        let listener = tokio::net::TcpListener::from_std(listener).expect("can use std socket");
+        let connections = Arc::new(AtomicU32::new(0));
+
+        let server = {
+            let connections = connections.clone();
+            async move {
+                loop {
+                    let (tcp_stream, _) = listener.accept().await.expect("incoming connection");
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    let mut stream = tokio_boring::accept(&ssl_acceptor, tcp_stream)
+                        .await
+                        .expect("handshake successful");
+                    tokio::spawn(async move {
+                        let mut answered = 0;
+                        while close_after != Some(answered) {
+                            let Ok(len) = stream.read_u16().await else {
+                                return;
+                            };
+                            let mut query = vec![0; len.into()];
+                            stream.read_exact(&mut query).await.expect("full query");
+                            let response = answer(&query);
+                            let len = u16::try_from(response.len()).expect("short response");
+                            stream
+                                .write_all(&len.to_be_bytes())
+                                .await
+                                .expect("can write");
+                            stream.write_all(&response).await.expect("can write");
+                            answered += 1;
+                        }
+                        stream.shutdown().await.expect("can close");
+                    });
+                }
+            }
+        };
+        (addr, connections, server)
+    }
+
+    fn resolver_for(addr: SocketAddr) -> CustomDnsResolver<DotTransport> {
+        let connector = DirectConnector::new(DnsResolver::new_from_static_map(HashMap::from([(
+            SERVER_HOSTNAME,
+            LookupResult::localhost(),
+        )])));
+        let connection_params = ConnectionParams::new(
+            RouteType::Test,
+            SERVER_HOSTNAME,
+            SERVER_HOSTNAME,
+            addr.port().try_into().expect("bound port"),
+            HttpRequestDecoratorSeq::default(),
+            RootCertificates::FromDer(Cow::Borrowed(SERVER_CERTIFICATE.cert.der())),
+        );
+        CustomDnsResolver::new(DotConnectionParams::with_connector(
+            connection_params,
+            connector.into(),
+        ))
+    }
+
+    fn request(hostname: &str, ipv6_enabled: bool) -> DnsLookupRequest {
+        DnsLookupRequest {
+            hostname: Arc::from(hostname),
+            ipv6_enabled,
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_over_tls_and_reuses_the_connection() {
+        let (addr, connections, server) = localhost_dot_server(None);
+        let _server_handle = tokio::spawn(server);
+        let resolver = resolver_for(addr);
+
+        for (hostname, ipv6_enabled) in [("chat.signal.org", true), ("cdsi.signal.org", false)] {
+            let result = resolver
+                .resolve(request(hostname, ipv6_enabled))
+                .await
+                .expect("resolved");
+            assert_eq!(result.source(), DnsSource::DnsOverTlsLookup);
+            assert_eq!(result.ipv4, [IPV4]);
+            let expected_ipv6: &[Ipv6Addr] = if ipv6_enabled { &[IPV6] } else { &[] };
+            assert_eq!(result.ipv6, expected_ipv6);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_the_server_closes_the_connection() {
+        let (addr, connections, server) = localhost_dot_server(Some(2));
+        let _server_handle = tokio::spawn(server);
+        let resolver = resolver_for(addr);
+
+        resolver
+            .resolve(request("chat.signal.org", true))
+            .await
+            .expect("resolved");
+        // Give the server's close a moment to arrive.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let result = resolver
+            .resolve(request("cdsi.signal.org", true))
+            .await
+            .expect("resolved");
+        assert_eq!(
+            result.into_iter().collect::<Vec<_>>(),
+            [IpAddr::V6(IPV6), IpAddr::V4(IPV4)]
+        );
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_resolver_is_unreachable() {
+        // Bind and immediately drop a listener to find a port nobody is listening on.
+        let addr = std::net::TcpListener::bind((Ipv6Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .expect("can bind");
+        let resolver = resolver_for(addr);
+        assert!(matches!(
+            resolver.resolve(request("chat.signal.org", true)).await,
+            Err(Error::TransportFailure)
+        ));
+    }
+}
@@ -7,12 +7,15 @@ use crate::infra::DnsSource;
 use std::iter::Map;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::vec::IntoIter;
+use tokio::time::Instant;
 
 #[derive(Debug, Clone)]
 pub struct LookupResult {
     pub(crate) source: DnsSource,
     pub(crate) ipv4: Vec<Ipv4Addr>,
     pub(crate) ipv6: Vec<Ipv6Addr>,
+    /// When the records expire, if the source reported TTLs.
+    pub(crate) expiration: Option<Instant>,
 }
 
 impl IntoIterator for LookupResult {
@@ -34,7 +37,16 @@ impl IntoIterator for LookupResult {
 
 impl LookupResult {
     pub fn new(source: DnsSource, ipv4: Vec<Ipv4Addr>, ipv6: Vec<Ipv6Addr>) -> Self {
-        Self { source, ipv4, ipv6 }
+        Self {
+            source,
+            ipv4,
+            ipv6,
+            expiration: None,
+        }
+    }
+
+    pub(crate) fn with_expiration(self, expiration: Option<Instant>) -> Self {
+        Self { expiration, ..self }
     }
 
     pub(crate) fn source(&self) -> DnsSource {
@@ -0,0 +1,525 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! A DNS cache that survives restarts.
+//!
+//! On a network that blocks or poisons DNS, a freshly started client can spend a long time going
+//! through every lookup option before it falls back to the static IPs in `env.rs`, which may
+//! themselves be stale. [PersistentDnsCache] remembers the last good answer for each host name so
+//! that the next start can use it right away. Expired answers are still served for a while (see
+//! [PersistentDnsCacheConfig::max_staleness]), with a fresh lookup running in the background.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::infra::dns::dns_utils::log_safe_domain;
+use crate::infra::dns::lookup_result::LookupResult;
+use crate::infra::DnsSource;
+
+/// Where a [PersistentDnsCache] keeps its contents between runs.
+pub trait DnsCacheStore: Send + Sync {
+    /// Returns the previously saved contents, or `None` if nothing has been saved yet.
+    fn load(&self) -> io::Result<Option<Vec<u8>>>;
+
+    /// Replaces the saved contents.
+    fn save(&self, contents: &[u8]) -> io::Result<()>;
+}
+
+/// Saves the cache to a file, replacing it atomically on every update.
+pub struct FileDnsCacheStore {
+    path: PathBuf,
+}
+
+impl FileDnsCacheStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl DnsCacheStore for FileDnsCacheStore {
+    fn load(&self) -> io::Result<Option<Vec<u8>>> {
+        match std::fs::read(&self.path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self, contents: &[u8]) -> io::Result<()> {
+        let temp_path = self.path.with_extension("tmp");
+        std::fs::write(&temp_path, contents)?;
+        std::fs::rename(&temp_path, &self.path)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PersistentDnsCacheConfig {
+    /// Once there are more entries than this, the ones closest to expiring are evicted.
+    pub max_entries: usize,
+    /// How long after its TTL runs out an entry may still be served while a fresh lookup runs.
+    pub max_staleness: Duration,
+    /// TTL to use for results whose source doesn't report one, like the system resolver.
+    pub default_ttl: Duration,
+    /// Longer TTLs, whether reported or default, are cut down to this.
+    pub max_ttl: Duration,
+}
+
+impl Default for PersistentDnsCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 64,
+            max_staleness: Duration::from_secs(7 * 24 * 60 * 60),
+            default_ttl: Duration::from_secs(5 * 60),
+            max_ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// A cached result, as returned by [PersistentDnsCache::get].
+#[derive(Debug)]
+pub(crate) enum CachedLookup {
+    Fresh(LookupResult),
+    /// Past its TTL; usable, but should be refreshed.
+    Stale(LookupResult),
+}
+
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Default, Serialize, Deserialize)]
+struct CacheContents {
+    version: u32,
+    entries: HashMap<String, CacheEntry>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    ipv4: Vec<Ipv4Addr>,
+    ipv6: Vec<Ipv6Addr>,
+    /// Where the result originally came from; cached results are always reported as
+    /// [DnsSource::Cache], but this is kept for debugging.
+    source: DnsSource,
+    /// Seconds since the Unix epoch.
+    expires_at: u64,
+}
+
+struct State {
+    config: PersistentDnsCacheConfig,
+    entries: HashMap<String, CacheEntry>,
+    /// Incremented on every change, so that saves that finish out of order can be detected.
+    generation: u64,
+}
+
+/// The contents of the cache as of some [State::generation], ready to be saved.
+struct Snapshot {
+    generation: u64,
+    contents: Vec<u8>,
+}
+
+struct SavedStore {
+    store: Box<dyn DnsCacheStore>,
+    saved_generation: u64,
+}
+
+/// Remembers lookup results across restarts; see the [module docs](self).
+///
+/// Cloning produces another handle to the same cache.
+#[derive(Clone)]
+pub struct PersistentDnsCache {
+    state: Arc<Mutex<State>>,
+    /// Kept separate from `state` so that saving, which does I/O, doesn't hold up lookups.
+    store: Arc<Mutex<SavedStore>>,
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl PersistentDnsCache {
+    /// Loads whatever was previously saved in `store`.
+    ///
+    /// A missing, unreadable, or corrupt cache is treated as empty, since the cache is only an
+    /// optimization.
+    pub fn load(store: impl DnsCacheStore + 'static, config: PersistentDnsCacheConfig) -> Self {
+        let entries = match store.load() {
+            Ok(None) => HashMap::new(),
+            Ok(Some(contents)) => match serde_json::from_slice::<CacheContents>(&contents) {
+                Ok(contents) if contents.version == FORMAT_VERSION => contents.entries,
+                Ok(contents) => {
+                    log::info!(
+                        "ignoring DNS cache with unknown version {}",
+                        contents.version
+                    );
+                    HashMap::new()
+                }
+                Err(e) => {
+                    log::warn!("ignoring unreadable DNS cache: {e}");
+                    HashMap::new()
+                }
+            },
+            Err(e) => {
+                log::warn!("failed to load DNS cache: {}", e.kind());
+                HashMap::new()
+            }
+        };
+        let cache = Self {
+            state: Arc::new(Mutex::new(State {
+                config,
+                entries,
+                generation: 0,
+            })),
+            store: Arc::new(Mutex::new(SavedStore {
+                store: Box::new(store),
+                saved_generation: 0,
+            })),
+        };
+        cache.evict_stale();
+        cache
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().expect("not poisoned").entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets the entry for `hostname`, e.g. because connecting to its addresses failed.
+    pub fn remove(&self, hostname: &str) {
+        let snapshot = {
+            let mut state = self.state.lock().expect("not poisoned");
+            if state.entries.remove(hostname).is_none() {
+                return;
+            }
+            state.changed()
+        };
+        self.save(snapshot);
+    }
+
+    /// Forgets all entries.
+    pub fn clear(&self) {
+        let snapshot = {
+            let mut state = self.state.lock().expect("not poisoned");
+            state.entries.clear();
+            state.changed()
+        };
+        self.save(snapshot);
+    }
+
+    /// Forgets entries that are too stale to be served.
+    pub fn evict_stale(&self) {
+        self.evict_stale_at(SystemTime::now())
+    }
+
+    fn evict_stale_at(&self, now: SystemTime) {
+        let snapshot = {
+            let mut state = self.state.lock().expect("not poisoned");
+            let cutoff = unix_seconds(now).saturating_sub(state.config.max_staleness.as_secs());
+            let count_before = state.entries.len();
+            state.entries.retain(|_, entry| entry.expires_at >= cutoff);
+            if state.entries.len() == count_before {
+                return;
+            }
+            state.changed()
+        };
+        self.save(snapshot);
+    }
+
+    pub(crate) fn get(&self, hostname: &str, ipv6_enabled: bool) -> Option<CachedLookup> {
+        self.get_at(hostname, ipv6_enabled, SystemTime::now())
+    }
+
+    fn get_at(&self, hostname: &str, ipv6_enabled: bool, now: SystemTime) -> Option<CachedLookup> {
+        let state = self.state.lock().expect("not poisoned");
+        let entry = state.entries.get(hostname)?;
+        let now = unix_seconds(now);
+        if entry
+            .expires_at
+            .saturating_add(state.config.max_staleness.as_secs())
+            < now
+        {
+            return None;
+        }
+        let ipv6 = if ipv6_enabled {
+            entry.ipv6.clone()
+        } else if entry.ipv4.is_empty() {
+            return None;
+        } else {
+            vec![]
+        };
+        let result = LookupResult::new(DnsSource::Cache, entry.ipv4.clone(), ipv6);
+        Some(if entry.expires_at < now {
+            CachedLookup::Stale(result)
+        } else {
+            CachedLookup::Fresh(result)
+        })
+    }
+
+    /// Remembers `result` for `hostname` and saves the cache.
+    ///
+    /// Results that came from the cache or from static configuration aren't worth saving and are
+    /// ignored. This does blocking I/O, so async callers should move it off the runtime's threads.
+    pub(crate) fn insert(&self, hostname: &str, result: &LookupResult) {
+        self.insert_at(hostname, result, SystemTime::now())
+    }
+
+    fn insert_at(&self, hostname: &str, result: &LookupResult, now: SystemTime) {
+        if matches!(result.source, DnsSource::Cache | DnsSource::Static) || result.is_empty() {
+            return;
+        }
+        let mut state = self.state.lock().expect("not poisoned");
+        let ttl = result
+            .expiration
+            .map_or(state.config.default_ttl, |expiration| {
+                expiration.saturating_duration_since(tokio::time::Instant::now())
+            })
+            .min(state.config.max_ttl);
+        log::debug!(
+            "saving DNS result for [{}] from {} to the persistent cache",
+            log_safe_domain(hostname),
+            result.source
+        );
+        state.entries.insert(
+            hostname.to_owned(),
+            CacheEntry {
+                ipv4: result.ipv4.clone(),
+                ipv6: result.ipv6.clone(),
+                source: result.source,
+                expires_at: unix_seconds(now.checked_add(ttl).unwrap_or(now)),
+            },
+        );
+
+        let excess = state.entries.len().saturating_sub(state.config.max_entries);
+        if excess > 0 {
+            let mut by_expiration = state
+                .entries
+                .iter()
+                .map(|(hostname, entry)| (entry.expires_at, hostname.clone()))
+                .collect::<Vec<_>>();
+            by_expiration.sort_unstable();
+            for (_, hostname) in by_expiration.into_iter().take(excess) {
+                state.entries.remove(&hostname);
+            }
+        }
+        let snapshot = state.changed();
+        drop(state);
+        self.save(snapshot);
+    }
+
+    /// Writes `snapshot` to the store, unless a newer one has been written already.
+    fn save(&self, snapshot: Snapshot) {
+        let mut store = self.store.lock().expect("not poisoned");
+        if snapshot.generation <= store.saved_generation {
+            return;
+        }
+        match store.store.save(&snapshot.contents) {
+            Ok(()) => store.saved_generation = snapshot.generation,
+            Err(e) => log::warn!("failed to save DNS cache: {}", e.kind()),
+        }
+    }
+}
+
+impl State {
+    /// Records a change, returning the new contents to be saved once the lock is released.
+    fn changed(&mut self) -> Snapshot {
+        self.generation += 1;
+        let contents = serde_json::to_vec(&CacheContents {
+            version: FORMAT_VERSION,
+            entries: self.entries.clone(),
+        })
+        .expect("can serialize");
+        Snapshot {
+            generation: self.generation,
+            contents,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use assert_matches::assert_matches;
+    use const_str::ip_addr;
+
+    use super::*;
+
+    const HOSTNAME: &str = "chat.signal.org";
+    const IPV4: Ipv4Addr = ip_addr!(v4, "192.0.2.1");
+    const IPV6: Ipv6Addr = ip_addr!(v6, "2001:db8::1");
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    /// Keeps the saved contents in memory, shared between clones.
+    #[derive(Clone, Default)]
+    pub(crate) struct MemoryStore(Arc<Mutex<Option<Vec<u8>>>>);
+
+    impl DnsCacheStore for MemoryStore {
+        fn load(&self) -> io::Result<Option<Vec<u8>>> {
+            Ok(self.0.lock().expect("not poisoned").clone())
+        }
+
+        fn save(&self, contents: &[u8]) -> io::Result<()> {
+            *self.0.lock().expect("not poisoned") = Some(contents.to_vec());
+            Ok(())
+        }
+    }
+
+    fn lookup_result() -> LookupResult {
+        LookupResult::new(DnsSource::UdpLookup, vec![IPV4], vec![IPV6])
+    }
+
+    #[test]
+    fn entries_survive_reload() {
+        let store = MemoryStore::default();
+        let now = SystemTime::now();
+        PersistentDnsCache::load(store.clone(), Default::default()).insert_at(
+            HOSTNAME,
+            &lookup_result(),
+            now,
+        );
+
+        let reloaded = PersistentDnsCache::load(store, Default::default());
+        let result = assert_matches!(
+            reloaded.get_at(HOSTNAME, true, now),
+            Some(CachedLookup::Fresh(result)) => result
+        );
+        assert_eq!(result.source, DnsSource::Cache);
+        assert_eq!(result.ipv4, [IPV4]);
+        assert_eq!(result.ipv6, [IPV6]);
+
+        let result = assert_matches!(
+            reloaded.get_at(HOSTNAME, false, now),
+            Some(CachedLookup::Fresh(result)) => result
+        );
+        assert!(result.ipv6.is_empty());
+    }
+
+    #[test]
+    fn entries_go_stale_then_expire() {
+        let config = PersistentDnsCacheConfig {
+            max_staleness: 24 * HOUR,
+            default_ttl: HOUR,
+            ..Default::default()
+        };
+        let cache = PersistentDnsCache::load(MemoryStore::default(), config);
+        let now = SystemTime::now();
+        cache.insert_at(HOSTNAME, &lookup_result(), now);
+
+        assert_matches!(
+            cache.get_at(HOSTNAME, true, now + HOUR / 2),
+            Some(CachedLookup::Fresh(_))
+        );
+        assert_matches!(
+            cache.get_at(HOSTNAME, true, now + 2 * HOUR),
+            Some(CachedLookup::Stale(_))
+        );
+        assert_matches!(cache.get_at(HOSTNAME, true, now + 26 * HOUR), None);
+
+        cache.evict_stale_at(now + 2 * HOUR);
+        assert_eq!(cache.len(), 1);
+        cache.evict_stale_at(now + 26 * HOUR);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn long_ttls_are_capped() {
+        let config = PersistentDnsCacheConfig {
+            default_ttl: Duration::MAX,
+            max_ttl: HOUR,
+            ..Default::default()
+        };
+        let cache = PersistentDnsCache::load(MemoryStore::default(), config);
+        let now = SystemTime::now();
+        cache.insert_at(HOSTNAME, &lookup_result(), now);
+
+        assert_matches!(
+            cache.get_at(HOSTNAME, true, now + 2 * HOUR),
+            Some(CachedLookup::Stale(_))
+        );
+    }
+
+    #[test]
+    fn lookups_are_not_blocked_while_saving() {
+        let store = MemoryStore::default();
+        let cache = PersistentDnsCache::load(store.clone(), Default::default());
+        let now = SystemTime::now();
+        cache.insert_at(HOSTNAME, &lookup_result(), now);
+
+        // Hold the store's lock so that the next save blocks, as if writing were slow.
+        let saving = store.0.lock().expect("not poisoned");
+        let inserter = std::thread::spawn({
+            let cache = cache.clone();
+            move || cache.insert_at("other.signal.org", &lookup_result(), now)
+        });
+        while cache.state.lock().expect("not poisoned").generation < 2 {
+            std::thread::yield_now();
+        }
+
+        assert_matches!(
+            cache.get_at(HOSTNAME, true, now),
+            Some(CachedLookup::Fresh(_))
+        );
+        drop(saving);
+        inserter.join().expect("no panic");
+        assert_eq!(PersistentDnsCache::load(store, Default::default()).len(), 2);
+    }
+
+    #[test]
+    fn static_and_cached_results_are_not_saved() {
+        let cache = PersistentDnsCache::load(MemoryStore::default(), Default::default());
+        for source in [DnsSource::Static, DnsSource::Cache] {
+            cache.insert(HOSTNAME, &LookupResult::new(source, vec![IPV4], vec![]));
+        }
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn evicts_entries_closest_to_expiring() {
+        let config = PersistentDnsCacheConfig {
+            max_entries: 2,
+            ..Default::default()
+        };
+        let cache = PersistentDnsCache::load(MemoryStore::default(), config);
+        let now = SystemTime::now();
+        cache.insert_at("b.signal.org", &lookup_result(), now + HOUR);
+        cache.insert_at("a.signal.org", &lookup_result(), now);
+        cache.insert_at("c.signal.org", &lookup_result(), now + 2 * HOUR);
+
+        assert_eq!(cache.len(), 2);
+        assert_matches!(cache.get_at("a.signal.org", true, now), None);
+
+        cache.remove("b.signal.org");
+        assert_eq!(cache.len(), 1);
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn corrupt_contents_are_ignored() {
+        let store = MemoryStore::default();
+        store.save(b"not json").expect("can save");
+        let cache = PersistentDnsCache::load(store, Default::default());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn file_store_round_trips() {
+        let dir = std::env::temp_dir().join(format!("dns-cache-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("can create temp dir");
+        let store = FileDnsCacheStore::new(dir.join("dns-cache.json"));
+
+        assert_matches!(store.load(), Ok(None));
+        store.save(b"contents").expect("can save");
+        assert_matches!(store.load(), Ok(Some(contents)) if contents == b"contents");
+
+        std::fs::remove_dir_all(&dir).expect("can clean up");
+    }
+}
@@ -24,8 +24,16 @@ pub enum TransportConnectError {
     SslError(SslErrorReasons),
     /// Failed to load certificates
     CertError,
+    /// Server certificate chain could not be verified: {0}
+    CertificateChainInvalid(&'static str),
+    /// Server certificate did not match any pinned key
+    CertificatePinMismatch,
     /// Failed to establish SSL connection: {0}
     SslFailedHandshake(FailedHandshakeReason),
+    /// Proxy did not establish a connection to the target
+    ProxyProtocol,
+    /// Proxy rejected the provided credentials
+    ProxyAuthenticationFailed,
 }
 
 #[derive(Debug)]
@@ -84,8 +92,11 @@ impl From<boring::error::ErrorStack> for TransportConnectError {
 }
 
 impl From<certs::Error> for TransportConnectError {
-    fn from(_value: certs::Error) -> Self {
-        Self::CertError
+    fn from(value: certs::Error) -> Self {
+        match value {
+            certs::Error::PinMismatch => Self::CertificatePinMismatch,
+            certs::Error::BadCertificate | certs::Error::BadHostname => Self::CertError,
+        }
     }
 }
 
@@ -103,8 +114,12 @@ impl From<TransportConnectError> for std::io::Error {
             TransportConnectError::TcpConnectionFailed => ErrorKind::ConnectionRefused,
             TransportConnectError::SslFailedHandshake(_)
             | TransportConnectError::SslError(_)
-            | TransportConnectError::CertError => ErrorKind::InvalidData,
+            | TransportConnectError::CertError
+            | TransportConnectError::CertificateChainInvalid(_)
+            | TransportConnectError::CertificatePinMismatch => ErrorKind::InvalidData,
             TransportConnectError::DnsError => ErrorKind::NotFound,
+            TransportConnectError::ProxyProtocol => ErrorKind::ConnectionRefused,
+            TransportConnectError::ProxyAuthenticationFailed => ErrorKind::PermissionDenied,
         };
         Self::new(kind, value.to_string())
     }
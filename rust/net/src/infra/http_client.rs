@@ -3,7 +3,10 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use crate::infra::{Alpn, ConnectionParams, StreamAndInfo, TransportConnector};
+use crate::infra::errors::LogSafeDisplay;
+use crate::infra::{
+    Alpn, AsyncDuplexStream, ConnectionInfo, ConnectionParams, StreamAndInfo, TransportConnector,
+};
 use bytes::Bytes;
 use http::request::Builder;
 use http::response::Parts;
@@ -12,7 +15,7 @@ use http_body_util::{BodyExt, Full, Limited};
 use hyper::client::conn::http2;
 use hyper_util::rt::{TokioExecutor, TokioIo};
 
-#[derive(displaydoc::Display, Debug)]
+#[derive(displaydoc::Display, Debug, thiserror::Error)]
 pub enum HttpError {
     /// SSL handshake failed
     SslHandshakeFailed,
@@ -30,6 +33,23 @@ pub enum HttpError {
     FailedToReadContentOfUnknownSize,
     /// Content larger than max size configured for the client
     ResponseTooLarge,
+    /// HTTP2 connection is closed
+    ConnectionClosed,
+}
+
+impl LogSafeDisplay for HttpError {}
+
+/// The driver for an HTTP2 connection.
+///
+/// Must be polled (usually by spawning it as a task) for requests sent over the corresponding
+/// [AggregatingHttp2Client] to make progress; dropping it closes the connection.
+pub(crate) type Http2Connection<S> = http2::Connection<TokioIo<S>, Full<Bytes>, TokioExecutor>;
+
+/// An established HTTP2 connection that has not been started yet.
+pub(crate) struct Http2Channel<S: AsyncDuplexStream + 'static> {
+    pub(crate) client: AggregatingHttp2Client,
+    pub(crate) connection: Http2Connection<S>,
+    pub(crate) connection_info: ConnectionInfo,
 }
 
 #[derive(Debug, Clone)]
@@ -106,7 +126,39 @@ pub(crate) async fn http2_client<C: TransportConnector>(
     connection_params: ConnectionParams,
     max_response_size: usize,
 ) -> Result<AggregatingHttp2Client, HttpError> {
-    let StreamAndInfo(ssl_stream, info) = transport_connector
+    let Http2Channel {
+        client,
+        connection,
+        connection_info,
+    } = http2_channel(transport_connector, connection_params, max_response_size).await?;
+
+    // Starting a thread to drive client connection events.
+    // The task will complete once the connection is closed due to an error
+    // or if all clients are dropped.
+    tokio::spawn(async move {
+        match connection.await {
+            Ok(_) => log::info!(
+                "HTTP2 connection [{}] closed",
+                connection_info.description()
+            ),
+            Err(err) => log::warn!(
+                "HTTP2 connection [{}] failed: {}",
+                connection_info.description(),
+                err
+            ),
+        }
+    });
+
+    Ok(client)
+}
+
+/// Establishes an HTTP2 connection but leaves it to the caller to drive it.
+pub(crate) async fn http2_channel<C: TransportConnector>(
+    transport_connector: &C,
+    connection_params: ConnectionParams,
+    max_response_size: usize,
+) -> Result<Http2Channel<C::Stream>, HttpError> {
+    let StreamAndInfo(ssl_stream, connection_info) = transport_connector
         .connect(&connection_params, Alpn::Http2)
         .await
         .map_err(|e| {
@@ -118,23 +170,17 @@ pub(crate) async fn http2_client<C: TransportConnector>(
         .await
         .map_err(|_| HttpError::Http2HandshakeFailed)?;
 
-    // Starting a thread to drive client connection events.
-    // The task will complete once the connection is closed due to an error
-    // or if all clients are dropped.
-    tokio::spawn(async move {
-        match connection.await {
-            Ok(_) => log::info!("HTTP2 connection [{}] closed", info.description()),
-            Err(err) => log::warn!("HTTP2 connection [{}] failed: {}", info.description(), err),
-        }
-    });
-
     let clone = connection_params.clone();
-    Ok(AggregatingHttp2Client {
-        service: sender,
-        connection_params: ConnectionParams {
-            sni: connection_params.host.clone(),
-            ..clone
+    Ok(Http2Channel {
+        client: AggregatingHttp2Client {
+            service: sender,
+            connection_params: ConnectionParams {
+                sni: connection_params.host.clone(),
+                ..clone
+            },
+            max_response_size,
         },
-        max_response_size,
+        connection,
+        connection_info,
     })
 }
@@ -291,6 +291,15 @@ where
             | ServiceState::Active(_, _) => Err(StateError::ServiceUnavailable),
         }
     }
+
+    /// Resolves once the active service stops, or immediately if there's no active service.
+    pub(crate) async fn service_stopped(&self) {
+        let service_status = match &*self.data.state.lock().await {
+            ServiceState::Active(_, service_status) => service_status.clone(),
+            _ => return,
+        };
+        service_status.stopped().await
+    }
 }
 
 impl<C, M> ServiceWithReconnect<C, M>
@@ -9,14 +9,15 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
-use boring::ssl::{ConnectConfiguration, SslConnector, SslMethod};
+use boring::ssl::{ConnectConfiguration, ErrorCode, SslConnector, SslMethod, SslRef};
+use boring::x509::X509VerifyResult;
 use futures_util::TryFutureExt;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio_boring::SslStream;
 use tokio_util::either::Either;
 
-use crate::infra::certs::RootCertificates;
+use crate::infra::certs::{PinVerifier, RootCertificates};
 use crate::infra::dns::DnsResolver;
 use crate::infra::errors::TransportConnectError;
 use crate::infra::{
@@ -24,28 +25,54 @@ use crate::infra::{
 };
 use crate::utils::first_ok;
 
+mod http_connect;
+pub use http_connect::HttpConnectConnector;
+mod socks;
+pub use socks::SocksConnector;
+
 const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(200);
 
 #[derive(Clone)]
 pub enum TcpSslConnector {
     Direct(DirectConnector),
     Proxied(ProxyConnector),
+    Socks(SocksConnector),
+    HttpConnect(HttpConnectConnector),
     /// Used when configuring one of the other kinds of connector isn't possible, perhaps because
     /// invalid configuration options were provided.
     Invalid(DnsResolver),
 }
 
 impl TcpSslConnector {
+    pub fn dns_resolver(&self) -> &DnsResolver {
+        match self {
+            TcpSslConnector::Direct(c) => &c.dns_resolver,
+            TcpSslConnector::Proxied(c) => &c.dns_resolver,
+            TcpSslConnector::Socks(c) => &c.dns_resolver,
+            TcpSslConnector::HttpConnect(c) => &c.dns_resolver,
+            TcpSslConnector::Invalid(resolver) => resolver,
+        }
+    }
+
     pub fn set_ipv6_enabled(&mut self, ipv6_enabled: bool) {
         let dns_resolver = match self {
             TcpSslConnector::Direct(c) => &mut c.dns_resolver,
             TcpSslConnector::Proxied(c) => &mut c.dns_resolver,
+            TcpSslConnector::Socks(c) => &mut c.dns_resolver,
+            TcpSslConnector::HttpConnect(c) => &mut c.dns_resolver,
             TcpSslConnector::Invalid(resolver) => resolver,
         };
         dns_resolver.set_ipv6_enabled(ipv6_enabled);
     }
 }
 
+/// Credentials for proxies that require authentication.
+#[derive(Clone)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
 pub struct TcpSslConnectorStream(
     Either<
         <DirectConnector as TransportConnector>::Stream,
@@ -131,7 +158,7 @@ impl TransportConnector for ProxyConnector {
                     self.proxy_host,
                     self.proxy_port
                 );
-                let ssl_config = ssl_config(&self.proxy_certs, &self.proxy_host, None)?;
+                let ssl_config = ssl_config(&self.proxy_certs, &self.proxy_host, None, None)?;
                 Either::Left(tokio_boring::connect(ssl_config, &self.proxy_host, tcp_stream).await?)
             }
             ShouldUseTls::No => {
@@ -194,10 +221,11 @@ impl ProxyConnector {
 fn ssl_config(
     certs: &RootCertificates,
     host_name: &str,
+    pins: Option<PinVerifier>,
     alpn: Option<Alpn>,
 ) -> Result<ConnectConfiguration, TransportConnectError> {
     let mut ssl = SslConnector::builder(SslMethod::tls_client())?;
-    certs.apply_to_connector(&mut ssl, host_name)?;
+    certs.apply_to_connector(&mut ssl, host_name, pins)?;
     if let Some(alpn) = alpn {
         ssl.set_alpn_protos(alpn.as_ref())?;
     }
@@ -209,9 +237,36 @@ async fn connect_tls<S: AsyncRead + AsyncWrite + Unpin>(
     connection_params: &ConnectionParams,
     alpn: Alpn,
 ) -> Result<SslStream<S>, TransportConnectError> {
-    let ssl_config = ssl_config(&connection_params.certs, &connection_params.sni, Some(alpn))?;
-
-    Ok(tokio_boring::connect(ssl_config, &connection_params.sni, transport).await?)
+    let pins = connection_params
+        .pins
+        .clone()
+        .map(|policy| PinVerifier::new(policy, &connection_params.sni));
+    let ssl_config = ssl_config(
+        &connection_params.certs,
+        &connection_params.sni,
+        pins.clone(),
+        Some(alpn),
+    )?;
+
+    let start = tokio::time::Instant::now();
+    let stream = tokio_boring::connect(ssl_config, &connection_params.sni, transport)
+        .await
+        .map_err(|e| {
+            if pins.as_ref().is_some_and(PinVerifier::mismatched) {
+                return TransportConnectError::CertificatePinMismatch;
+            }
+            // Tell a certificate that failed verification apart from other handshake failures.
+            match e.ssl().map(SslRef::verify_result) {
+                Some(result)
+                    if e.code() == Some(ErrorCode::SSL) && result != X509VerifyResult::OK =>
+                {
+                    TransportConnectError::CertificateChainInvalid(result.error_string())
+                }
+                _ => e.into(),
+            }
+        })?;
+    crate::metrics::report_tls_handshake(start.elapsed());
+    Ok(stream)
 }
 
 async fn connect_tcp(
@@ -327,6 +382,14 @@ impl TransportConnector for TcpSslConnector {
                 .connect(connection_params, alpn)
                 .await
                 .map(|s| s.map_stream(Either::Right)),
+            Self::Socks(socks) => socks
+                .connect(connection_params, alpn)
+                .await
+                .map(|s| s.map_stream(Either::Left)),
+            Self::HttpConnect(http_connect) => http_connect
+                .connect(connection_params, alpn)
+                .await
+                .map(|s| s.map_stream(Either::Right)),
             Self::Invalid(_) => Err(TransportConnectError::InvalidConfiguration),
         }
         .map(|s| s.map_stream(TcpSslConnectorStream))
@@ -345,6 +408,18 @@ impl From<ProxyConnector> for TcpSslConnector {
     }
 }
 
+impl From<SocksConnector> for TcpSslConnector {
+    fn from(value: SocksConnector) -> Self {
+        Self::Socks(value)
+    }
+}
+
+impl From<HttpConnectConnector> for TcpSslConnector {
+    fn from(value: HttpConnectConnector) -> Self {
+        Self::HttpConnect(value)
+    }
+}
+
 #[cfg(test)]
 pub(crate) mod testutil {
     use std::future::Future;
@@ -377,10 +452,24 @@ pub(crate) mod testutil {
     ///
     /// Returns the address of the server and a [`Future`] that runs it.
     pub(crate) fn localhost_http_server() -> (SocketAddr, impl Future<Output = ()>) {
+        localhost_http_server_with_chain(SERVER_CERTIFICATE.cert.pem())
+    }
+
+    /// Like [localhost_http_server], but sends `extra` after the server's certificate as if it
+    /// were part of the chain.
+    pub(crate) fn localhost_http_server_with_extra_certificate(
+        extra: &CertifiedKey,
+    ) -> (SocketAddr, impl Future<Output = ()>) {
+        localhost_http_server_with_chain(SERVER_CERTIFICATE.cert.pem() + &extra.cert.pem())
+    }
+
+    fn localhost_http_server_with_chain(
+        chain_pem: String,
+    ) -> (SocketAddr, impl Future<Output = ()>) {
         let filter = warp::any().map(|| FAKE_RESPONSE);
         let server = warp::serve(filter)
             .tls()
-            .cert(SERVER_CERTIFICATE.cert.pem())
+            .cert(chain_pem)
             .key(SERVER_CERTIFICATE.key_pair.serialize_pem());
 
         server.bind_ephemeral((Ipv6Addr::LOCALHOST, 0))
@@ -539,6 +628,7 @@ pub(crate) mod testutil {
 mod test {
     use super::testutil::*;
     use super::*;
+    use crate::infra::certs::{PinningMode, PinningPolicy, SpkiHash};
     use crate::infra::dns::lookup_result::LookupResult;
     use crate::infra::HttpRequestDecoratorSeq;
     use assert_matches::assert_matches;
@@ -561,6 +651,7 @@ mod test {
             port: addr.port().try_into().expect("bound port"),
             http_request_decorator: HttpRequestDecoratorSeq::default(),
             certs: RootCertificates::FromDer(Cow::Borrowed(SERVER_CERTIFICATE.cert.der())),
+            pins: None,
         };
 
         let StreamAndInfo(stream, info) = connector
@@ -580,6 +671,92 @@ mod test {
         make_http_request_response_over(stream).await
     }
 
+    async fn connect_directly_with(
+        certs: RootCertificates,
+        pins: Option<PinningPolicy>,
+    ) -> Result<StreamAndInfo<SslStream<TcpStream>>, TransportConnectError> {
+        let (addr, server) = localhost_http_server();
+        let _server_handle = tokio::spawn(server);
+
+        let connector = DirectConnector::new(DnsResolver::new_with_static_fallback(HashMap::from(
+            [(SERVER_HOSTNAME, LookupResult::localhost())],
+        )));
+        let connection_params = ConnectionParams {
+            route_type: RouteType::Test,
+            sni: SERVER_HOSTNAME.into(),
+            host: addr.ip().to_string().into(),
+            port: addr.port().try_into().expect("bound port"),
+            http_request_decorator: HttpRequestDecoratorSeq::default(),
+            certs,
+            pins,
+        };
+        connector.connect(&connection_params, Alpn::Http1_1).await
+    }
+
+    #[tokio::test]
+    async fn connect_with_untrusted_certificate() {
+        let result = connect_directly_with(
+            RootCertificates::FromDer(Cow::Borrowed(PROXY_CERTIFICATE.cert.der())),
+            None,
+        )
+        .await;
+        assert_matches!(
+            result,
+            Err(TransportConnectError::CertificateChainInvalid(_))
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_with_mismatched_pin() {
+        let proxy_key = SpkiHash::of_certificate(
+            &boring::x509::X509::from_der(PROXY_CERTIFICATE.cert.der()).expect("valid"),
+        )
+        .expect("has key");
+        let result = connect_directly_with(
+            RootCertificates::FromDer(Cow::Borrowed(SERVER_CERTIFICATE.cert.der())),
+            Some(PinningPolicy {
+                pins: vec![proxy_key].into(),
+                backup_pins: [].as_slice().into(),
+                expires: None,
+                mode: PinningMode::Enforce,
+            }),
+        )
+        .await;
+        assert_matches!(result, Err(TransportConnectError::CertificatePinMismatch));
+    }
+
+    #[tokio::test]
+    async fn connect_with_pinned_key_outside_verified_chain() {
+        // The server also sends the proxy's certificate, but nothing in the verified chain has the
+        // pinned key.
+        let (addr, server) = localhost_http_server_with_extra_certificate(&PROXY_CERTIFICATE);
+        let _server_handle = tokio::spawn(server);
+
+        let connector = DirectConnector::new(DnsResolver::new_with_static_fallback(HashMap::from(
+            [(SERVER_HOSTNAME, LookupResult::localhost())],
+        )));
+        let connection_params = ConnectionParams {
+            route_type: RouteType::Test,
+            sni: SERVER_HOSTNAME.into(),
+            host: addr.ip().to_string().into(),
+            port: addr.port().try_into().expect("bound port"),
+            http_request_decorator: HttpRequestDecoratorSeq::default(),
+            certs: RootCertificates::FromDer(Cow::Borrowed(SERVER_CERTIFICATE.cert.der())),
+            pins: Some(PinningPolicy {
+                pins: vec![SpkiHash::of_certificate(
+                    &boring::x509::X509::from_der(PROXY_CERTIFICATE.cert.der()).expect("valid"),
+                )
+                .expect("has key")]
+                .into(),
+                backup_pins: [].as_slice().into(),
+                expires: None,
+                mode: PinningMode::Enforce,
+            }),
+        };
+        let result = connector.connect(&connection_params, Alpn::Http1_1).await;
+        assert_matches!(result, Err(TransportConnectError::CertificatePinMismatch));
+    }
+
     #[tokio::test]
     async fn connect_through_proxy() {
         let (addr, server) = localhost_http_server();
@@ -611,6 +788,7 @@ mod test {
             port: addr.port().try_into().expect("bound port"),
             http_request_decorator: HttpRequestDecoratorSeq::default(),
             certs: RootCertificates::FromDer(Cow::Borrowed(SERVER_CERTIFICATE.cert.der())),
+            pins: None,
         };
 
         let StreamAndInfo(stream, info) = connector
@@ -656,6 +834,7 @@ mod test {
             port: addr.port().try_into().expect("bound port"),
             http_request_decorator: HttpRequestDecoratorSeq::default(),
             certs: RootCertificates::FromDer(Cow::Borrowed(SERVER_CERTIFICATE.cert.der())),
+            pins: None,
         };
 
         let StreamAndInfo(stream, info) = connector
@@ -690,6 +869,7 @@ mod test {
             port: addr.port().try_into().expect("bound port"),
             http_request_decorator: HttpRequestDecoratorSeq::default(),
             certs: RootCertificates::FromDer(Cow::Borrowed(SERVER_CERTIFICATE.cert.der())),
+            pins: None,
         };
 
         match connector.connect(&connection_params, Alpn::Http1_1).await {
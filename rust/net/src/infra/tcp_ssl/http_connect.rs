@@ -0,0 +1,349 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! A [TransportConnector] that tunnels connections through an HTTP proxy using the `CONNECT`
+//! method.
+//!
+//! As with SOCKS, the proxy is given the target host name and is responsible for resolving it.
+
+use std::num::NonZeroU16;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_boring::SslStream;
+use tokio_util::either::Either;
+
+use crate::infra::certs::RootCertificates;
+use crate::infra::dns::DnsResolver;
+use crate::infra::errors::TransportConnectError;
+use crate::infra::tcp_ssl::{connect_tcp, connect_tls, ssl_config, ProxyAuth, ShouldUseTls};
+use crate::infra::{
+    Alpn, ConnectionInfo, ConnectionParams, RouteType, StreamAndInfo, TransportConnector,
+};
+use crate::utils::basic_authorization;
+
+/// Upper bound on the size of the proxy's response headers.
+const MAX_RESPONSE_HEADER_LEN: usize = 8 * 1024;
+
+#[derive(Clone)]
+pub struct HttpConnectConnector {
+    pub dns_resolver: DnsResolver,
+    proxy_host: Arc<str>,
+    proxy_port: NonZeroU16,
+    proxy_certs: RootCertificates,
+    use_tls_for_proxy: ShouldUseTls,
+    auth: Option<ProxyAuth>,
+}
+
+impl HttpConnectConnector {
+    /// Creates a connector for a plaintext HTTP proxy.
+    ///
+    /// The tunneled connection is still TLS-encrypted end to end.
+    pub fn new(
+        dns_resolver: DnsResolver,
+        (proxy_host, proxy_port): (&str, NonZeroU16),
+        auth: Option<ProxyAuth>,
+    ) -> Self {
+        Self {
+            dns_resolver,
+            proxy_host: proxy_host.into(),
+            proxy_port,
+            proxy_certs: RootCertificates::Native,
+            use_tls_for_proxy: ShouldUseTls::No,
+            auth,
+        }
+    }
+
+    /// Creates a connector for an HTTP proxy that is itself reached over TLS.
+    pub fn new_with_tls(
+        dns_resolver: DnsResolver,
+        proxy_addr: (&str, NonZeroU16),
+        auth: Option<ProxyAuth>,
+    ) -> Self {
+        Self {
+            use_tls_for_proxy: ShouldUseTls::Yes,
+            ..Self::new(dns_resolver, proxy_addr, auth)
+        }
+    }
+}
+
+#[async_trait]
+impl TransportConnector for HttpConnectConnector {
+    type Stream = SslStream<Either<SslStream<TcpStream>, TcpStream>>;
+
+    async fn connect(
+        &self,
+        connection_params: &ConnectionParams,
+        alpn: Alpn,
+    ) -> Result<StreamAndInfo<Self::Stream>, TransportConnectError> {
+        let StreamAndInfo(tcp_stream, remote_address) = connect_tcp(
+            &self.dns_resolver,
+            connection_params.route_type,
+            &self.proxy_host,
+            self.proxy_port,
+        )
+        .await?;
+
+        log::debug!(
+            "connecting to HTTP proxy {}:{}",
+            self.proxy_host,
+            self.proxy_port
+        );
+        let mut inner_stream = match self.use_tls_for_proxy {
+            ShouldUseTls::Yes => {
+                let ssl_config = ssl_config(&self.proxy_certs, &self.proxy_host, None, None)?;
+                Either::Left(tokio_boring::connect(ssl_config, &self.proxy_host, tcp_stream).await?)
+            }
+            ShouldUseTls::No => Either::Right(tcp_stream),
+        };
+
+        handshake(
+            &mut inner_stream,
+            &connection_params.sni,
+            connection_params.port,
+            self.auth.as_ref(),
+        )
+        .await?;
+
+        let tls_stream = connect_tls(inner_stream, connection_params, alpn).await?;
+
+        Ok(StreamAndInfo(
+            tls_stream,
+            ConnectionInfo {
+                route_type: RouteType::HttpProxy,
+                ..remote_address
+            },
+        ))
+    }
+}
+
+/// Sends a `CONNECT` request and waits for the proxy to accept it.
+///
+/// Reads exactly up to the end of the response headers so that nothing from the tunneled
+/// connection is consumed.
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    target_host: &str,
+    target_port: NonZeroU16,
+    auth: Option<&ProxyAuth>,
+) -> Result<(), TransportConnectError> {
+    let authority = match target_host.parse::<std::net::Ipv6Addr>() {
+        Ok(ip) => format!("[{ip}]:{target_port}"),
+        Err(_) => format!("{target_host}:{target_port}"),
+    };
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(ProxyAuth { username, password }) = auth {
+        let credentials = basic_authorization(username, password);
+        request.push_str("Proxy-Authorization: ");
+        request.push_str(credentials.to_str().expect("base64 is ASCII"));
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(io_error)?;
+
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_HEADER_LEN {
+            log::warn!("HTTP proxy response headers are too long");
+            return Err(TransportConnectError::ProxyProtocol);
+        }
+        response.push(stream.read_u8().await.map_err(io_error)?);
+    }
+
+    // e.g. "HTTP/1.1 200 Connection established"
+    let status = response
+        .split(|b| *b == b' ')
+        .nth(1)
+        .and_then(|code| http::StatusCode::from_bytes(code).ok());
+    match status {
+        Some(status) if status.is_success() => Ok(()),
+        Some(http::StatusCode::PROXY_AUTHENTICATION_REQUIRED) => {
+            Err(TransportConnectError::ProxyAuthenticationFailed)
+        }
+        Some(status) => {
+            log::warn!("HTTP proxy refused to connect: {status}");
+            Err(TransportConnectError::ProxyProtocol)
+        }
+        None => {
+            log::warn!("HTTP proxy sent a malformed response");
+            Err(TransportConnectError::ProxyProtocol)
+        }
+    }
+}
+
+fn io_error(e: std::io::Error) -> TransportConnectError {
+    log::warn!("HTTP proxy connection failed: {}", e.kind());
+    TransportConnectError::ProxyProtocol
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::net::{Ipv6Addr, SocketAddr};
+
+    use assert_matches::assert_matches;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    use super::*;
+    use crate::infra::dns::lookup_result::LookupResult;
+    use crate::infra::tcp_ssl::testutil::{
+        localhost_http_server, make_http_request_response_over, SERVER_CERTIFICATE, SERVER_HOSTNAME,
+    };
+    use crate::infra::{DnsSource, HttpRequestDecoratorSeq};
+
+    const PROXY_HOSTNAME: &str = "test-http-proxy.signal.org.local";
+
+    /// Starts a plaintext HTTP proxy that only allows `CONNECT`s to `upstream_host`.
+    ///
+    /// If `expected_authorization` is set, requests must carry it in `Proxy-Authorization`.
+    fn localhost_http_connect_proxy(
+        upstream_host: &'static str,
+        upstream_addr: SocketAddr,
+        expected_authorization: Option<String>,
+    ) -> (SocketAddr, impl Future<Output = ()>) {
+        let listener = std::net::TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).expect("can bind");
+        listener.set_nonblocking(true).expect("can set nonblocking");
+        let listen_addr = listener.local_addr().expect("is bound to local addr");
+        let tcp_listener = tokio::net::TcpListener::from_std(listener).expect("can use std socket");
+
+        let proxy = async move {
+            loop {
+                let (stream, _) = tcp_listener.accept().await.expect("incoming connection");
+                let mut stream = BufReader::new(stream);
+
+                let mut request_line = String::new();
+                stream.read_line(&mut request_line).await.expect("request");
+                assert_eq!(
+                    request_line,
+                    format!(
+                        "CONNECT {upstream_host}:{} HTTP/1.1\r\n",
+                        upstream_addr.port()
+                    )
+                );
+
+                let mut authorization = None;
+                loop {
+                    let mut header = String::new();
+                    stream.read_line(&mut header).await.expect("header");
+                    if header == "\r\n" {
+                        break;
+                    }
+                    if let Some(value) = header.strip_prefix("Proxy-Authorization: ") {
+                        authorization = Some(value.trim_end().to_owned());
+                    }
+                }
+
+                if expected_authorization.is_some() && authorization != expected_authorization {
+                    stream
+                        .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                        .await
+                        .expect("can reply");
+                    continue;
+                }
+
+                let mut upstream = TcpStream::connect(upstream_addr)
+                    .await
+                    .expect("can connect to upstream");
+                stream
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await
+                    .expect("can reply");
+                tokio::io::copy_bidirectional(&mut stream, &mut upstream)
+                    .await
+                    .expect("can proxy");
+            }
+        };
+
+        (listen_addr, proxy)
+    }
+
+    fn connector(proxy_addr: SocketAddr, auth: Option<ProxyAuth>) -> HttpConnectConnector {
+        HttpConnectConnector::new(
+            DnsResolver::new_with_static_fallback(HashMap::from([(
+                PROXY_HOSTNAME,
+                LookupResult::localhost(),
+            )])),
+            (PROXY_HOSTNAME, proxy_addr.port().try_into().unwrap()),
+            auth,
+        )
+    }
+
+    fn connection_params(server_addr: SocketAddr) -> ConnectionParams {
+        ConnectionParams {
+            route_type: RouteType::Test,
+            sni: SERVER_HOSTNAME.into(),
+            host: SERVER_HOSTNAME.into(),
+            port: server_addr.port().try_into().expect("bound port"),
+            http_request_decorator: HttpRequestDecoratorSeq::default(),
+            certs: RootCertificates::FromDer(Cow::Borrowed(SERVER_CERTIFICATE.cert.der())),
+            pins: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_through_http_proxy() {
+        let (addr, server) = localhost_http_server();
+        let _server_handle = tokio::spawn(server);
+
+        let (proxy_addr, proxy) = localhost_http_connect_proxy(SERVER_HOSTNAME, addr, None);
+        let _proxy_handle = tokio::spawn(proxy);
+
+        let StreamAndInfo(stream, info) = connector(proxy_addr, None)
+            .connect(&connection_params(addr), Alpn::Http1_1)
+            .await
+            .expect("can connect");
+
+        assert_eq!(
+            info,
+            ConnectionInfo {
+                address: url::Host::Ipv6(Ipv6Addr::LOCALHOST),
+                dns_source: DnsSource::Static,
+                route_type: RouteType::HttpProxy,
+            }
+        );
+
+        make_http_request_response_over(stream).await;
+    }
+
+    #[tokio::test]
+    async fn connect_through_http_proxy_with_auth() {
+        let (addr, server) = localhost_http_server();
+        let _server_handle = tokio::spawn(server);
+
+        let expected_authorization = basic_authorization("user", "secret")
+            .to_str()
+            .expect("ASCII")
+            .to_owned();
+        let (proxy_addr, proxy) =
+            localhost_http_connect_proxy(SERVER_HOSTNAME, addr, Some(expected_authorization));
+        let _proxy_handle = tokio::spawn(proxy);
+
+        let auth = ProxyAuth {
+            username: "user".to_owned(),
+            password: "secret".to_owned(),
+        };
+        let StreamAndInfo(stream, _info) = connector(proxy_addr, Some(auth))
+            .connect(&connection_params(addr), Alpn::Http1_1)
+            .await
+            .expect("can connect");
+
+        make_http_request_response_over(stream).await;
+
+        match connector(proxy_addr, None)
+            .connect(&connection_params(addr), Alpn::Http1_1)
+            .await
+        {
+            Ok(_) => panic!("should have failed"),
+            Err(e) => assert_matches!(e, TransportConnectError::ProxyAuthenticationFailed),
+        }
+    }
+}
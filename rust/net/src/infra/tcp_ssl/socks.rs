@@ -0,0 +1,428 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! A [TransportConnector] that tunnels connections through a SOCKS5 proxy ([RFC 1928]).
+//!
+//! The target host name is passed to the proxy as-is so that the proxy resolves it, which keeps
+//! DNS lookups for Signal's servers off the local network.
+//!
+//! [RFC 1928]: https://www.rfc-editor.org/rfc/rfc1928
+
+use std::net::IpAddr;
+use std::num::NonZeroU16;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_boring::SslStream;
+
+use crate::infra::dns::DnsResolver;
+use crate::infra::errors::TransportConnectError;
+use crate::infra::tcp_ssl::{connect_tcp, connect_tls, ProxyAuth};
+use crate::infra::{
+    Alpn, ConnectionInfo, ConnectionParams, RouteType, StreamAndInfo, TransportConnector,
+};
+
+const SOCKS_VERSION: u8 = 0x05;
+const USERNAME_PASSWORD_AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+
+const COMMAND_CONNECT: u8 = 0x01;
+
+const ADDRESS_TYPE_IPV4: u8 = 0x01;
+const ADDRESS_TYPE_DOMAIN: u8 = 0x03;
+const ADDRESS_TYPE_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+
+#[derive(Clone)]
+pub struct SocksConnector {
+    pub dns_resolver: DnsResolver,
+    proxy_host: Arc<str>,
+    proxy_port: NonZeroU16,
+    auth: Option<ProxyAuth>,
+}
+
+impl SocksConnector {
+    pub fn new(
+        dns_resolver: DnsResolver,
+        (proxy_host, proxy_port): (&str, NonZeroU16),
+        auth: Option<ProxyAuth>,
+    ) -> Self {
+        Self {
+            dns_resolver,
+            proxy_host: proxy_host.into(),
+            proxy_port,
+            auth,
+        }
+    }
+}
+
+#[async_trait]
+impl TransportConnector for SocksConnector {
+    type Stream = SslStream<TcpStream>;
+
+    async fn connect(
+        &self,
+        connection_params: &ConnectionParams,
+        alpn: Alpn,
+    ) -> Result<StreamAndInfo<Self::Stream>, TransportConnectError> {
+        let StreamAndInfo(mut tcp_stream, remote_address) = connect_tcp(
+            &self.dns_resolver,
+            connection_params.route_type,
+            &self.proxy_host,
+            self.proxy_port,
+        )
+        .await?;
+
+        log::debug!(
+            "connecting to SOCKS proxy {}:{}",
+            self.proxy_host,
+            self.proxy_port
+        );
+        handshake(
+            &mut tcp_stream,
+            &connection_params.sni,
+            connection_params.port,
+            self.auth.as_ref(),
+        )
+        .await?;
+
+        let tls_stream = connect_tls(tcp_stream, connection_params, alpn).await?;
+
+        Ok(StreamAndInfo(
+            tls_stream,
+            ConnectionInfo {
+                route_type: RouteType::SocksProxy,
+                ..remote_address
+            },
+        ))
+    }
+}
+
+/// Negotiates a `CONNECT` to `target_host:target_port` on a freshly opened proxy connection.
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    target_host: &str,
+    target_port: NonZeroU16,
+    auth: Option<&ProxyAuth>,
+) -> Result<(), TransportConnectError> {
+    let methods: &[u8] = match auth {
+        Some(_) => &[METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD],
+        None => &[METHOD_NO_AUTH],
+    };
+    let mut greeting = vec![SOCKS_VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await.map_err(io_error)?;
+
+    let [version, method] = read_array(stream).await?;
+    if version != SOCKS_VERSION {
+        log::warn!("SOCKS proxy replied with version {version}");
+        return Err(TransportConnectError::ProxyProtocol);
+    }
+    match (method, auth) {
+        (METHOD_NO_AUTH, _) => {}
+        (METHOD_USERNAME_PASSWORD, Some(auth)) => authenticate(stream, auth).await?,
+        (METHOD_NONE_ACCEPTABLE, _) => {
+            log::warn!("SOCKS proxy requires an authentication method we don't support");
+            return Err(TransportConnectError::ProxyAuthenticationFailed);
+        }
+        (method, _) => {
+            log::warn!("SOCKS proxy picked unoffered authentication method {method}");
+            return Err(TransportConnectError::ProxyProtocol);
+        }
+    }
+
+    let mut request = vec![SOCKS_VERSION, COMMAND_CONNECT, 0x00];
+    match target_host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ADDRESS_TYPE_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ADDRESS_TYPE_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let host_len = u8::try_from(target_host.len())
+                .map_err(|_| TransportConnectError::InvalidConfiguration)?;
+            request.push(ADDRESS_TYPE_DOMAIN);
+            request.push(host_len);
+            request.extend_from_slice(target_host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&target_port.get().to_be_bytes());
+    stream.write_all(&request).await.map_err(io_error)?;
+
+    let [version, reply, _reserved, address_type] = read_array(stream).await?;
+    if version != SOCKS_VERSION {
+        log::warn!("SOCKS proxy replied with version {version}");
+        return Err(TransportConnectError::ProxyProtocol);
+    }
+    if reply != REPLY_SUCCEEDED {
+        log::warn!("SOCKS proxy failed to connect to the target: reply code {reply}");
+        return Err(TransportConnectError::ProxyProtocol);
+    }
+
+    // The bound address isn't useful to us, but it has to be consumed before the tunnel starts.
+    let address_len = match address_type {
+        ADDRESS_TYPE_IPV4 => 4,
+        ADDRESS_TYPE_IPV6 => 16,
+        ADDRESS_TYPE_DOMAIN => {
+            let [len] = read_array(stream).await?;
+            len.into()
+        }
+        _ => {
+            log::warn!("SOCKS proxy replied with address type {address_type}");
+            return Err(TransportConnectError::ProxyProtocol);
+        }
+    };
+    let mut bound_address_and_port = vec![0; address_len + 2];
+    stream
+        .read_exact(&mut bound_address_and_port)
+        .await
+        .map_err(io_error)?;
+
+    Ok(())
+}
+
+/// Username/password authentication as described in [RFC 1929].
+///
+/// [RFC 1929]: https://www.rfc-editor.org/rfc/rfc1929
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    ProxyAuth { username, password }: &ProxyAuth,
+) -> Result<(), TransportConnectError> {
+    let username_len =
+        u8::try_from(username.len()).map_err(|_| TransportConnectError::InvalidConfiguration)?;
+    let password_len =
+        u8::try_from(password.len()).map_err(|_| TransportConnectError::InvalidConfiguration)?;
+
+    let mut request = vec![USERNAME_PASSWORD_AUTH_VERSION, username_len];
+    request.extend_from_slice(username.as_bytes());
+    request.push(password_len);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request).await.map_err(io_error)?;
+
+    let [_version, status] = read_array(stream).await?;
+    if status != 0 {
+        return Err(TransportConnectError::ProxyAuthenticationFailed);
+    }
+    Ok(())
+}
+
+async fn read_array<const N: usize, S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<[u8; N], TransportConnectError> {
+    let mut buf = [0; N];
+    stream.read_exact(&mut buf).await.map_err(io_error)?;
+    Ok(buf)
+}
+
+fn io_error(e: std::io::Error) -> TransportConnectError {
+    log::warn!("SOCKS proxy connection failed: {}", e.kind());
+    TransportConnectError::ProxyProtocol
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::net::{Ipv6Addr, SocketAddr};
+
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::infra::certs::RootCertificates;
+    use crate::infra::dns::lookup_result::LookupResult;
+    use crate::infra::tcp_ssl::testutil::{
+        localhost_http_server, make_http_request_response_over, SERVER_CERTIFICATE, SERVER_HOSTNAME,
+    };
+    use crate::infra::{DnsSource, HttpRequestDecoratorSeq};
+
+    const PROXY_HOSTNAME: &str = "test-socks-proxy.signal.org.local";
+
+    /// Starts a SOCKS5 proxy that only knows how to reach `upstream_host`, at `upstream_addr`.
+    ///
+    /// If `credentials` are provided, clients have to authenticate with them.
+    fn localhost_socks_proxy(
+        upstream_host: &'static str,
+        upstream_addr: SocketAddr,
+        credentials: Option<(&'static str, &'static str)>,
+    ) -> (SocketAddr, impl Future<Output = ()>) {
+        let listener = std::net::TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).expect("can bind");
+        listener.set_nonblocking(true).expect("can set nonblocking");
+        let listen_addr = listener.local_addr().expect("is bound to local addr");
+        let tcp_listener = tokio::net::TcpListener::from_std(listener).expect("can use std socket");
+
+        let proxy = async move {
+            loop {
+                let (mut stream, _) = tcp_listener.accept().await.expect("incoming connection");
+
+                let [version, method_count] = read_array(&mut stream).await.expect("greeting");
+                assert_eq!(version, SOCKS_VERSION);
+                let mut methods = vec![0; method_count.into()];
+                stream.read_exact(&mut methods).await.expect("methods");
+
+                if let Some((expected_username, expected_password)) = credentials {
+                    if !methods.contains(&METHOD_USERNAME_PASSWORD) {
+                        stream
+                            .write_all(&[SOCKS_VERSION, METHOD_NONE_ACCEPTABLE])
+                            .await
+                            .expect("can reply");
+                        continue;
+                    }
+                    stream
+                        .write_all(&[SOCKS_VERSION, METHOD_USERNAME_PASSWORD])
+                        .await
+                        .expect("can reply");
+                    let [_, username_len] = read_array(&mut stream).await.expect("auth");
+                    let mut username = vec![0; username_len.into()];
+                    stream.read_exact(&mut username).await.expect("username");
+                    let [password_len] = read_array(&mut stream).await.expect("auth");
+                    let mut password = vec![0; password_len.into()];
+                    stream.read_exact(&mut password).await.expect("password");
+                    let accepted = username == expected_username.as_bytes()
+                        && password == expected_password.as_bytes();
+                    stream
+                        .write_all(&[USERNAME_PASSWORD_AUTH_VERSION, u8::from(!accepted)])
+                        .await
+                        .expect("can reply");
+                    if !accepted {
+                        continue;
+                    }
+                } else {
+                    stream
+                        .write_all(&[SOCKS_VERSION, METHOD_NO_AUTH])
+                        .await
+                        .expect("can reply");
+                }
+
+                let [_, command, _, address_type] = read_array(&mut stream).await.expect("request");
+                assert_eq!(command, COMMAND_CONNECT);
+                // The client should leave name resolution to the proxy.
+                assert_eq!(address_type, ADDRESS_TYPE_DOMAIN);
+                let [host_len] = read_array(&mut stream).await.expect("host length");
+                let mut host = vec![0; host_len.into()];
+                stream.read_exact(&mut host).await.expect("host");
+                let [port_hi, port_lo] = read_array(&mut stream).await.expect("port");
+                assert_eq!(host, upstream_host.as_bytes());
+                assert_eq!(u16::from_be_bytes([port_hi, port_lo]), upstream_addr.port());
+
+                let mut upstream = TcpStream::connect(upstream_addr)
+                    .await
+                    .expect("can connect to upstream");
+                let mut reply = vec![SOCKS_VERSION, REPLY_SUCCEEDED, 0x00, ADDRESS_TYPE_IPV6];
+                reply.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+                reply.extend_from_slice(&listen_addr.port().to_be_bytes());
+                stream.write_all(&reply).await.expect("can reply");
+
+                tokio::io::copy_bidirectional(&mut stream, &mut upstream)
+                    .await
+                    .expect("can proxy");
+            }
+        };
+
+        (listen_addr, proxy)
+    }
+
+    fn connector(proxy_addr: SocketAddr, auth: Option<ProxyAuth>) -> SocksConnector {
+        SocksConnector::new(
+            DnsResolver::new_with_static_fallback(HashMap::from([(
+                PROXY_HOSTNAME,
+                LookupResult::localhost(),
+            )])),
+            (PROXY_HOSTNAME, proxy_addr.port().try_into().unwrap()),
+            auth,
+        )
+    }
+
+    fn connection_params(server_addr: SocketAddr) -> ConnectionParams {
+        ConnectionParams {
+            route_type: RouteType::Test,
+            sni: SERVER_HOSTNAME.into(),
+            host: SERVER_HOSTNAME.into(),
+            port: server_addr.port().try_into().expect("bound port"),
+            http_request_decorator: HttpRequestDecoratorSeq::default(),
+            certs: RootCertificates::FromDer(Cow::Borrowed(SERVER_CERTIFICATE.cert.der())),
+            pins: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_through_socks_proxy() {
+        let (addr, server) = localhost_http_server();
+        let _server_handle = tokio::spawn(server);
+
+        let (proxy_addr, proxy) = localhost_socks_proxy(SERVER_HOSTNAME, addr, None);
+        let _proxy_handle = tokio::spawn(proxy);
+
+        let StreamAndInfo(stream, info) = connector(proxy_addr, None)
+            .connect(&connection_params(addr), Alpn::Http1_1)
+            .await
+            .expect("can connect");
+
+        assert_eq!(
+            info,
+            ConnectionInfo {
+                address: url::Host::Ipv6(Ipv6Addr::LOCALHOST),
+                dns_source: DnsSource::Static,
+                route_type: RouteType::SocksProxy,
+            }
+        );
+
+        make_http_request_response_over(stream).await;
+    }
+
+    #[tokio::test]
+    async fn connect_through_socks_proxy_with_auth() {
+        let (addr, server) = localhost_http_server();
+        let _server_handle = tokio::spawn(server);
+
+        let (proxy_addr, proxy) =
+            localhost_socks_proxy(SERVER_HOSTNAME, addr, Some(("user", "secret")));
+        let _proxy_handle = tokio::spawn(proxy);
+
+        let auth = ProxyAuth {
+            username: "user".to_owned(),
+            password: "secret".to_owned(),
+        };
+        let StreamAndInfo(stream, _info) = connector(proxy_addr, Some(auth))
+            .connect(&connection_params(addr), Alpn::Http1_1)
+            .await
+            .expect("can connect");
+
+        make_http_request_response_over(stream).await;
+    }
+
+    #[tokio::test]
+    async fn socks_proxy_rejects_bad_credentials() {
+        let (addr, server) = localhost_http_server();
+        let _server_handle = tokio::spawn(server);
+
+        let (proxy_addr, proxy) =
+            localhost_socks_proxy(SERVER_HOSTNAME, addr, Some(("user", "secret")));
+        let _proxy_handle = tokio::spawn(proxy);
+
+        let wrong_auth = ProxyAuth {
+            username: "user".to_owned(),
+            password: "wrong".to_owned(),
+        };
+        for auth in [None, Some(wrong_auth)] {
+            match connector(proxy_addr, auth)
+                .connect(&connection_params(addr), Alpn::Http1_1)
+                .await
+            {
+                Ok(_) => panic!("should have failed"),
+                Err(e) => assert_matches!(e, TransportConnectError::ProxyAuthenticationFailed),
+            }
+        }
+    }
+}
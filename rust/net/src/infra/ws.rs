@@ -392,7 +392,7 @@ impl<S: AsyncDuplexStream, E> WebSocketClient<S, E>
 where
     WebSocketServiceError: Into<E>,
 {
-    #[cfg(test)]
+    #[cfg(any(test, feature = "fake-cdsi-server", feature = "fake-svr3-server"))]
     pub(crate) fn new_fake(channel: WebSocketStream<S>, connection_info: ConnectionInfo) -> Self {
         const VERY_LARGE_TIMEOUT: Duration = Duration::from_secs(u32::MAX as u64);
         let (client, _service_status) = start_ws_service(
@@ -9,7 +9,9 @@ pub mod chat;
 pub mod enclave;
 pub mod env;
 pub mod infra;
+pub mod metrics;
 pub mod proto;
 pub mod svr;
+pub mod svr2;
 pub mod svr3;
 pub mod utils;
@@ -0,0 +1,809 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Aggregated connection and request metrics.
+//!
+//! [DebugInfo](crate::chat::DebugInfo) describes a single request. [Metrics] instead accumulates
+//! what happened across many connections and requests: how long connecting to each route took, how
+//! long the TLS handshake took, where the DNS answer came from, how long requests to each endpoint
+//! took, and why things failed. Collection happens by wrapping a transport connector in a
+//! [MeteredConnector] and a chat service in a [MeteredChatService].
+//!
+//! Nothing recorded here identifies the user: connections are keyed by route type and server host
+//! name (never by IP address), and request paths are reduced to a template by
+//! [endpoint_label] before being recorded. Apps can read the totals with [Metrics::snapshot], or
+//! forward individual events as they happen by registering a [MetricsSink].
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::time::Instant;
+
+use crate::chat::{
+    ChatService, ChatServiceError, ChatServiceWithDebugInfo, DebugInfo, Request, Response,
+};
+use crate::infra::errors::TransportConnectError;
+use crate::infra::ws::WebSocketServiceError;
+use crate::infra::{
+    Alpn, ConnectionParams, DnsSource, RouteType, StreamAndInfo, TransportConnector,
+};
+
+/// Upper bounds of the [LatencyHistogram] buckets; anything slower goes in a final overflow bucket.
+pub const LATENCY_BUCKET_BOUNDS: [Duration; 10] = [
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+/// Coarse reason for a failed connection attempt or request.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, strum::Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum FailureReason {
+    InvalidConfiguration,
+    Dns,
+    TcpConnect,
+    Tls,
+    Certificate,
+    Proxy,
+    /// The request timed out.
+    Timeout,
+    /// No connection could be established to send the request over.
+    ConnectionUnavailable,
+    /// The connection was closed while the request was in flight.
+    ConnectionClosed,
+    /// The server's response could not be understood.
+    Protocol,
+}
+
+impl From<&TransportConnectError> for FailureReason {
+    fn from(e: &TransportConnectError) -> Self {
+        match e {
+            TransportConnectError::InvalidConfiguration => Self::InvalidConfiguration,
+            TransportConnectError::TcpConnectionFailed => Self::TcpConnect,
+            TransportConnectError::DnsError => Self::Dns,
+            TransportConnectError::SslError(_) | TransportConnectError::SslFailedHandshake(_) => {
+                Self::Tls
+            }
+            TransportConnectError::CertError
+            | TransportConnectError::CertificateChainInvalid(_)
+            | TransportConnectError::CertificatePinMismatch => Self::Certificate,
+            TransportConnectError::ProxyProtocol
+            | TransportConnectError::ProxyAuthenticationFailed => Self::Proxy,
+        }
+    }
+}
+
+impl From<&ChatServiceError> for FailureReason {
+    fn from(e: &ChatServiceError) -> Self {
+        match e {
+            ChatServiceError::Timeout => Self::Timeout,
+            ChatServiceError::TimeoutEstablishingConnection { .. }
+            | ChatServiceError::AllConnectionRoutesFailed { .. }
+            | ChatServiceError::ServiceInactive
+            | ChatServiceError::ServiceUnavailable => Self::ConnectionUnavailable,
+            ChatServiceError::WebSocket(
+                WebSocketServiceError::ChannelClosed
+                | WebSocketServiceError::ChannelIdleTooLong
+                | WebSocketServiceError::Io(_),
+            ) => Self::ConnectionClosed,
+            ChatServiceError::RequestHasInvalidHeader => Self::InvalidConfiguration,
+            ChatServiceError::WebSocket(_)
+            | ChatServiceError::Http(_)
+            | ChatServiceError::UnexpectedFrameReceived
+            | ChatServiceError::ServerRequestMissingId
+            | ChatServiceError::FailedToPassMessageToIncomingChannel
+            | ChatServiceError::IncomingDataInvalid => Self::Protocol,
+        }
+    }
+}
+
+/// Timings for a successfully established transport connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectTimings {
+    /// Time from the start of DNS resolution until the connection was usable.
+    pub total: Duration,
+    /// Time spent in the TLS handshake with the server, if the connector reported one.
+    pub tls_handshake: Option<Duration>,
+    pub dns_source: DnsSource,
+}
+
+/// A single observation, as passed to [MetricsSink::record].
+#[derive(Clone, Debug)]
+pub enum MetricEvent {
+    Connection {
+        route_type: RouteType,
+        host: Arc<str>,
+        outcome: Result<ConnectTimings, FailureReason>,
+    },
+    Request {
+        /// The request's method and path template; see [endpoint_label].
+        endpoint: String,
+        latency: Duration,
+        /// The response status code if a response was received.
+        outcome: Result<u16, FailureReason>,
+    },
+}
+
+/// Receives every event recorded by a [Metrics] instance.
+///
+/// Called synchronously on the task that observed the event, so implementations should hand the
+/// event off rather than doing slow work (such as uploading) inline.
+pub trait MetricsSink: Send + Sync {
+    fn record(&self, event: &MetricEvent);
+}
+
+/// Counts of durations in the buckets given by [LATENCY_BUCKET_BOUNDS].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    counts: [u64; LATENCY_BUCKET_BOUNDS.len() + 1],
+    sum: Duration,
+}
+
+impl LatencyHistogram {
+    fn add(&mut self, value: Duration) {
+        let bucket = LATENCY_BUCKET_BOUNDS.partition_point(|bound| *bound < value);
+        self.counts[bucket] += 1;
+        self.sum = self.sum.saturating_add(value);
+    }
+
+    /// The number of recorded durations.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The sum of all recorded durations.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// Each bucket's inclusive upper bound (`None` for the overflow bucket) and count.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        LATENCY_BUCKET_BOUNDS
+            .iter()
+            .copied()
+            .map(Some)
+            .chain([None])
+            .zip(self.counts.iter().copied())
+    }
+}
+
+/// Totals for connections made over one route to one host.
+#[derive(Clone, Debug, Default)]
+pub struct RouteMetrics {
+    pub successes: u64,
+    pub connect_latency: LatencyHistogram,
+    pub tls_handshake: LatencyHistogram,
+    pub dns_sources: HashMap<DnsSource, u64>,
+    pub failures: HashMap<FailureReason, u64>,
+}
+
+/// Totals for requests to one endpoint.
+#[derive(Clone, Debug, Default)]
+pub struct EndpointMetrics {
+    pub latency: LatencyHistogram,
+    pub statuses: BTreeMap<u16, u64>,
+    pub failures: HashMap<FailureReason, u64>,
+}
+
+/// Everything a [Metrics] instance has recorded, as returned by [Metrics::snapshot].
+#[derive(Clone, Debug, Default)]
+pub struct MetricsSnapshot {
+    pub routes: HashMap<(RouteType, Arc<str>), RouteMetrics>,
+    pub endpoints: HashMap<String, EndpointMetrics>,
+}
+
+impl LatencyHistogram {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "count": self.count(),
+            "sum_ms": self.sum.as_millis() as u64,
+            "buckets": self
+                .buckets()
+                .map(|(bound, count)| (bound.map(|b| b.as_millis() as u64), count))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+fn counts_to_json<'a, K: std::fmt::Display + 'a>(
+    counts: impl IntoIterator<Item = (&'a K, &'a u64)>,
+) -> serde_json::Value {
+    counts
+        .into_iter()
+        .map(|(key, count)| (key.to_string(), (*count).into()))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+impl MetricsSnapshot {
+    /// Serializes the snapshot as JSON, for apps to upload or log.
+    ///
+    /// Routes are listed as objects with `route_type` and `host` fields; endpoints are keyed by
+    /// their label. Latencies are in milliseconds, and each histogram bucket is given as an
+    /// `[upper bound, count]` pair, with a `null` bound for the overflow bucket.
+    pub fn to_json(&self) -> String {
+        let routes = self
+            .routes
+            .iter()
+            .map(|((route_type, host), route)| {
+                serde_json::json!({
+                    "route_type": route_type.to_string(),
+                    "host": &**host,
+                    "successes": route.successes,
+                    "connect_latency": route.connect_latency.to_json(),
+                    "tls_handshake": route.tls_handshake.to_json(),
+                    "dns_sources": counts_to_json(&route.dns_sources),
+                    "failures": counts_to_json(&route.failures),
+                })
+            })
+            .collect::<Vec<_>>();
+        let endpoints = self
+            .endpoints
+            .iter()
+            .map(|(endpoint, metrics)| {
+                let value = serde_json::json!({
+                    "latency": metrics.latency.to_json(),
+                    "statuses": counts_to_json(&metrics.statuses),
+                    "failures": counts_to_json(&metrics.failures),
+                });
+                (endpoint.clone(), value)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        serde_json::json!({ "routes": routes, "endpoints": endpoints }).to_string()
+    }
+}
+
+/// A shared collector for connection and request metrics.
+///
+/// Cloning produces another handle to the same totals and sinks.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    totals: Arc<Mutex<MetricsSnapshot>>,
+    sinks: Arc<Mutex<Vec<Arc<dyn MetricsSink>>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a sink that will see every subsequently recorded event.
+    pub fn add_sink(&self, sink: Arc<dyn MetricsSink>) {
+        self.sinks.lock().expect("not poisoned").push(sink);
+    }
+
+    /// Returns the totals recorded so far.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.totals.lock().expect("not poisoned").clone()
+    }
+
+    /// Returns the totals recorded so far and starts over from zero.
+    ///
+    /// Useful for periodic uploads, so that each upload covers a disjoint interval.
+    pub fn take_snapshot(&self) -> MetricsSnapshot {
+        std::mem::take(&mut *self.totals.lock().expect("not poisoned"))
+    }
+
+    pub fn record(&self, event: MetricEvent) {
+        {
+            let mut totals = self.totals.lock().expect("not poisoned");
+            match &event {
+                MetricEvent::Connection {
+                    route_type,
+                    host,
+                    outcome,
+                } => {
+                    let route = totals
+                        .routes
+                        .entry((*route_type, host.clone()))
+                        .or_default();
+                    match outcome {
+                        Ok(timings) => {
+                            route.successes += 1;
+                            route.connect_latency.add(timings.total);
+                            if let Some(handshake) = timings.tls_handshake {
+                                route.tls_handshake.add(handshake);
+                            }
+                            *route.dns_sources.entry(timings.dns_source).or_default() += 1;
+                        }
+                        Err(reason) => *route.failures.entry(*reason).or_default() += 1,
+                    }
+                }
+                MetricEvent::Request {
+                    endpoint,
+                    latency,
+                    outcome,
+                } => {
+                    let metrics = totals.endpoints.entry(endpoint.clone()).or_default();
+                    metrics.latency.add(*latency);
+                    match outcome {
+                        Ok(status) => *metrics.statuses.entry(*status).or_default() += 1,
+                        Err(reason) => *metrics.failures.entry(*reason).or_default() += 1,
+                    }
+                }
+            }
+        }
+
+        // Copy the list so that sinks can't deadlock by registering other sinks.
+        let sinks = self.sinks.lock().expect("not poisoned").clone();
+        for sink in sinks {
+            sink.record(&event);
+        }
+    }
+}
+
+/// Path segments of the chat server's API that are known not to identify anyone.
+///
+/// Kept sorted so it can be binary-searched.
+const KNOWN_PATH_SEGMENTS: &[&str] = &[
+    "",
+    "accounts",
+    "attachments",
+    "attributes",
+    "backup",
+    "certificate",
+    "challenge",
+    "config",
+    "delivery",
+    "devices",
+    "directory",
+    "echo",
+    "form",
+    "keepalive",
+    "keys",
+    "messages",
+    "multi_recipient",
+    "profile",
+    "receipt",
+    "registration",
+    "signed",
+    "status",
+    "username_hash",
+    "username_link",
+    "uuid",
+    "v1",
+    "v2",
+    "v3",
+    "v4",
+    "websocket",
+];
+
+/// Reduces a request to a label that's safe to report.
+///
+/// The query string is dropped, and any path segment that isn't a known part of the API is
+/// replaced with `*`. For example, `GET /v2/keys/<service ID>/<device ID>` becomes
+/// `GET /v2/keys/*/*`.
+pub fn endpoint_label(method: &::http::Method, path: &str) -> String {
+    let path = path.split_once('?').map_or(path, |(path, _query)| path);
+    let template = path
+        .split('/')
+        .map(|segment| {
+            if KNOWN_PATH_SEGMENTS.binary_search(&segment).is_ok() {
+                segment
+            } else {
+                "*"
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    format!("{method} {template}")
+}
+
+tokio::task_local! {
+    static TLS_HANDSHAKE_TIME: Cell<Option<Duration>>;
+}
+
+/// Reports how long a TLS handshake took to an enclosing [MeteredConnector], if there is one.
+///
+/// When a connection involves several handshakes (say, with a TLS proxy and then with the server),
+/// the last one reported wins, since that's the one with the server.
+pub(crate) fn report_tls_handshake(elapsed: Duration) {
+    // Outside a MeteredConnector there's nobody to report to.
+    let _ = TLS_HANDSHAKE_TIME.try_with(|time| time.set(Some(elapsed)));
+}
+
+/// A [TransportConnector] that records a [MetricEvent::Connection] for every connection attempt.
+#[derive(Clone)]
+pub struct MeteredConnector<C> {
+    inner: C,
+    metrics: Metrics,
+}
+
+impl<C> MeteredConnector<C> {
+    pub fn new(inner: C, metrics: Metrics) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+#[async_trait]
+impl<C: TransportConnector> TransportConnector for MeteredConnector<C> {
+    type Stream = C::Stream;
+
+    async fn connect(
+        &self,
+        connection_params: &ConnectionParams,
+        alpn: Alpn,
+    ) -> Result<StreamAndInfo<Self::Stream>, TransportConnectError> {
+        let start = Instant::now();
+        let (result, tls_handshake) = TLS_HANDSHAKE_TIME
+            .scope(Cell::new(None), async {
+                let result = self.inner.connect(connection_params, alpn).await;
+                (result, TLS_HANDSHAKE_TIME.with(Cell::get))
+            })
+            .await;
+
+        let outcome = match &result {
+            Ok(StreamAndInfo(_, info)) => Ok(ConnectTimings {
+                total: start.elapsed(),
+                tls_handshake,
+                dns_source: info.dns_source,
+            }),
+            Err(e) => Err(e.into()),
+        };
+        self.metrics.record(MetricEvent::Connection {
+            route_type: connection_params.route_type,
+            host: connection_params.host.clone(),
+            outcome,
+        });
+        result
+    }
+}
+
+/// A [ChatService] that records a [MetricEvent::Request] for every request sent.
+#[derive(Clone)]
+pub struct MeteredChatService<T> {
+    inner: T,
+    metrics: Metrics,
+}
+
+impl<T> MeteredChatService<T> {
+    pub fn new(inner: T, metrics: Metrics) -> Self {
+        Self { inner, metrics }
+    }
+
+    fn record_request(
+        &self,
+        endpoint: String,
+        start: Instant,
+        result: &Result<Response, ChatServiceError>,
+    ) {
+        self.metrics.record(MetricEvent::Request {
+            endpoint,
+            latency: start.elapsed(),
+            outcome: result
+                .as_ref()
+                .map(|response| response.status.as_u16())
+                .map_err(FailureReason::from),
+        });
+    }
+}
+
+#[async_trait]
+impl<T> ChatService for MeteredChatService<T>
+where
+    T: ChatService + Send + Sync,
+{
+    async fn send(&self, msg: Request, timeout: Duration) -> Result<Response, ChatServiceError> {
+        let endpoint = endpoint_label(&msg.method, msg.path.as_str());
+        let start = Instant::now();
+        let result = self.inner.send(msg, timeout).await;
+        self.record_request(endpoint, start, &result);
+        result
+    }
+
+    async fn connect(&self) -> Result<(), ChatServiceError> {
+        self.inner.connect().await
+    }
+
+    async fn disconnect(&self) {
+        self.inner.disconnect().await
+    }
+
+    async fn connection_lost(&self) {
+        self.inner.connection_lost().await
+    }
+}
+
+#[async_trait]
+impl<T> ChatServiceWithDebugInfo for MeteredChatService<T>
+where
+    T: ChatServiceWithDebugInfo + Send + Sync,
+{
+    async fn send_and_debug(
+        &self,
+        msg: Request,
+        timeout: Duration,
+    ) -> (Result<Response, ChatServiceError>, DebugInfo) {
+        let endpoint = endpoint_label(&msg.method, msg.path.as_str());
+        let start = Instant::now();
+        let (result, debug_info) = self.inner.send_and_debug(msg, timeout).await;
+        self.record_request(endpoint, start, &result);
+        (result, debug_info)
+    }
+
+    async fn connect_and_debug(&self) -> Result<DebugInfo, ChatServiceError> {
+        self.inner.connect_and_debug().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+
+    use assert_matches::assert_matches;
+    use http::{Method, StatusCode};
+    use nonzero_ext::nonzero;
+
+    use super::*;
+    use crate::chat::fake::FakeChatServer;
+    use crate::chat::test::shared::test_request;
+    use crate::infra::certs::RootCertificates;
+    use crate::infra::dns::lookup_result::LookupResult;
+    use crate::infra::dns::DnsResolver;
+    use crate::infra::tcp_ssl::testutil::{
+        localhost_http_server, SERVER_CERTIFICATE, SERVER_HOSTNAME,
+    };
+    use crate::infra::tcp_ssl::DirectConnector;
+    use crate::infra::HttpRequestDecoratorSeq;
+
+    #[derive(Default)]
+    struct RecordingSink(Mutex<Vec<MetricEvent>>);
+
+    impl MetricsSink for RecordingSink {
+        fn record(&self, event: &MetricEvent) {
+            self.0.lock().expect("not poisoned").push(event.clone())
+        }
+    }
+
+    /// Answers every request with a fixed status, or times out if there is none.
+    struct FixedChatService(Option<StatusCode>);
+
+    #[async_trait]
+    impl ChatService for FixedChatService {
+        async fn send(
+            &self,
+            _msg: Request,
+            _timeout: Duration,
+        ) -> Result<Response, ChatServiceError> {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            self.0.map(response).ok_or(ChatServiceError::Timeout)
+        }
+
+        async fn connect(&self) -> Result<(), ChatServiceError> {
+            Ok(())
+        }
+
+        async fn disconnect(&self) {}
+
+        async fn connection_lost(&self) {}
+    }
+
+    fn response(status: StatusCode) -> Response {
+        Response {
+            status,
+            message: None,
+            body: None,
+            headers: Default::default(),
+        }
+    }
+
+    #[test]
+    fn endpoint_label_hides_identifiers() {
+        for (path, expected) in [
+            ("/v1/config", "GET /v1/config"),
+            (
+                "/v2/keys/PNI:a1b2c3d4-0000-4000-8000-000000000000/2",
+                "GET /v2/keys/*/*",
+            ),
+            (
+                "/v1/messages/multi_recipient?ts=1700000000",
+                "GET /v1/messages/multi_recipient",
+            ),
+            (
+                "/v1/accounts/username_hash/aGVsbG8",
+                "GET /v1/accounts/username_hash/*",
+            ),
+            (
+                "/v1/profile/abcdefghijklmnopqrstuvwxyz",
+                "GET /v1/profile/*",
+            ),
+            ("/v1/profile/alice", "GET /v1/profile/*"),
+            ("/v9/config", "GET /*/config"),
+            ("/", "GET /"),
+        ] {
+            assert_eq!(endpoint_label(&Method::GET, path), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn known_path_segments_are_sorted() {
+        assert!(KNOWN_PATH_SEGMENTS.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn histogram_buckets_by_upper_bound() {
+        let mut histogram = LatencyHistogram::default();
+        for ms in [0, 10, 11, 400, 60_000] {
+            histogram.add(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.sum(), Duration::from_millis(60_421));
+
+        let nonempty = histogram
+            .buckets()
+            .filter(|(_, count)| *count != 0)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            nonempty,
+            [
+                (Some(Duration::from_millis(10)), 2),
+                (Some(Duration::from_millis(25)), 1),
+                (Some(Duration::from_millis(500)), 1),
+                (None, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn snapshot_serializes_to_json() {
+        let metrics = Metrics::new();
+        metrics.record(MetricEvent::Connection {
+            route_type: RouteType::Direct,
+            host: "chat.signal.org".into(),
+            outcome: Ok(ConnectTimings {
+                total: Duration::from_millis(40),
+                tls_handshake: Some(Duration::from_millis(20)),
+                dns_source: DnsSource::Cache,
+            }),
+        });
+        metrics.record(MetricEvent::Request {
+            endpoint: "GET /v1/config".to_owned(),
+            latency: Duration::from_millis(5),
+            outcome: Err(FailureReason::Timeout),
+        });
+
+        let json: serde_json::Value =
+            serde_json::from_str(&metrics.snapshot().to_json()).expect("valid JSON");
+        let route = &json["routes"][0];
+        assert_eq!(route["route_type"], "direct");
+        assert_eq!(route["host"], "chat.signal.org");
+        assert_eq!(route["successes"], 1);
+        assert_eq!(route["connect_latency"]["sum_ms"], 40);
+        assert_eq!(
+            route["tls_handshake"]["buckets"][1],
+            serde_json::json!([25, 1])
+        );
+        assert_eq!(route["dns_sources"], serde_json::json!({ "cache": 1 }));
+
+        let endpoint = &json["endpoints"]["GET /v1/config"];
+        assert_eq!(endpoint["latency"]["count"], 1);
+        assert_eq!(endpoint["statuses"], serde_json::json!({}));
+        assert_eq!(endpoint["failures"], serde_json::json!({ "timeout": 1 }));
+    }
+
+    #[tokio::test]
+    async fn connector_records_connect_and_handshake_times() {
+        let (addr, server) = localhost_http_server();
+        let _server_handle = tokio::spawn(server);
+
+        let metrics = Metrics::new();
+        let connector = MeteredConnector::new(
+            DirectConnector::new(DnsResolver::new_with_static_fallback(HashMap::from([(
+                SERVER_HOSTNAME,
+                LookupResult::localhost(),
+            )]))),
+            metrics.clone(),
+        );
+        let connection_params = ConnectionParams {
+            route_type: RouteType::Test,
+            sni: SERVER_HOSTNAME.into(),
+            host: SERVER_HOSTNAME.into(),
+            port: addr.port().try_into().expect("bound port"),
+            http_request_decorator: HttpRequestDecoratorSeq::default(),
+            certs: RootCertificates::FromDer(Cow::Borrowed(SERVER_CERTIFICATE.cert.der())),
+            pins: None,
+        };
+        connector
+            .connect(&connection_params, Alpn::Http1_1)
+            .await
+            .expect("can connect");
+
+        let snapshot = metrics.snapshot();
+        let route = &snapshot.routes[&(RouteType::Test, Arc::from(SERVER_HOSTNAME))];
+        assert_eq!(route.successes, 1);
+        assert_eq!(route.connect_latency.count(), 1);
+        assert_eq!(route.tls_handshake.count(), 1);
+        assert!(route.tls_handshake.sum() <= route.connect_latency.sum());
+        assert_eq!(route.dns_sources, HashMap::from([(DnsSource::Static, 1)]));
+        assert!(route.failures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn connector_records_failure_reasons() {
+        let server = FakeChatServer::new();
+        server.set_refuse_connections(true);
+        let metrics = Metrics::new();
+        let sink = Arc::new(RecordingSink::default());
+        metrics.add_sink(sink.clone());
+        let connector = MeteredConnector::new(server.connector(), metrics.clone());
+        let connection_params = ConnectionParams::new(
+            RouteType::Test,
+            "chat.signal.org.local",
+            "chat.signal.org.local",
+            nonzero!(443u16),
+            Default::default(),
+            RootCertificates::Signal,
+        );
+
+        for _ in 0..2 {
+            assert_matches!(
+                connector
+                    .connect(&connection_params, Alpn::Http2)
+                    .await
+                    .err(),
+                Some(TransportConnectError::TcpConnectionFailed)
+            );
+        }
+
+        let snapshot = metrics.take_snapshot();
+        let route = &snapshot.routes[&(RouteType::Test, Arc::from("chat.signal.org.local"))];
+        assert_eq!(route.successes, 0);
+        assert_eq!(
+            route.failures,
+            HashMap::from([(FailureReason::TcpConnect, 2)])
+        );
+        assert!(metrics.snapshot().routes.is_empty(), "totals were reset");
+
+        let events = sink.0.lock().expect("not poisoned");
+        assert_eq!(events.len(), 2);
+        assert_matches!(
+            &events[0],
+            MetricEvent::Connection {
+                route_type: RouteType::Test,
+                outcome: Err(FailureReason::TcpConnect),
+                ..
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chat_service_records_request_latency_and_outcome() {
+        let metrics = Metrics::new();
+        let ok = MeteredChatService::new(
+            FixedChatService(Some(StatusCode::NOT_FOUND)),
+            metrics.clone(),
+        );
+        let failing = MeteredChatService::new(FixedChatService(None), metrics.clone());
+
+        for service in [&ok, &ok, &failing] {
+            let _ = service
+                .send(
+                    test_request(Method::GET, "/v1/profile/ACI:1234"),
+                    Duration::from_secs(1),
+                )
+                .await;
+        }
+
+        let snapshot = metrics.snapshot();
+        let endpoint = &snapshot.endpoints["GET /v1/profile/*"];
+        assert_eq!(endpoint.latency.count(), 3);
+        assert_eq!(endpoint.latency.sum(), Duration::from_millis(90));
+        assert_eq!(endpoint.statuses, BTreeMap::from([(404, 2)]));
+        assert_eq!(
+            endpoint.failures,
+            HashMap::from([(FailureReason::Timeout, 1)])
+        );
+    }
+}
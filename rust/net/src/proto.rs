@@ -4,4 +4,5 @@
 //
 
 pub(crate) mod cds2;
+pub mod chat_envelope;
 pub mod chat_websocket;
@@ -0,0 +1,8 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+#![allow(clippy::derive_partial_eq_without_eq)]
+
+include!(concat!(env!("OUT_DIR"), "/signal.proto.chat_envelope.rs"));
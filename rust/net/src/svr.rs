@@ -24,6 +24,16 @@ impl<Flavor: Svr3Flavor, S> From<SvrConnection<Flavor, S>> for AttestedConnectio
     }
 }
 
+#[cfg(any(test, feature = "fake-svr3-server"))]
+impl<Flavor: Svr3Flavor, S> SvrConnection<Flavor, S> {
+    pub(crate) fn new(inner: AttestedConnection<S>) -> Self {
+        Self {
+            inner,
+            witness: PhantomData,
+        }
+    }
+}
+
 impl<Flavor: Svr3Flavor, S: Send> IntoAttestedConnection for SvrConnection<Flavor, S> {
     type Stream = S;
 }
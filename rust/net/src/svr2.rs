@@ -0,0 +1,435 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Client for SVR2, which stores a short secret that can only be restored with the right PIN.
+//!
+//! The PIN is never sent as-is. Instead, it is hashed with [PinHash::create], salted with
+//! [PinHash::make_salt] using the username from the SVR2 credentials and the enclave's
+//! [group ID](crate::enclave::EnclaveEndpointConnection::group_id). The resulting
+//! [access key](PinHash::access_key) is what the enclave checks on restore; the
+//! [encryption key](PinHash::encryption_key) is for the caller to protect the data itself.
+
+use std::num::NonZeroU8;
+
+use attest::proto::svr2::{
+    backup_response, expose_response, request, response, restore_response, BackupRequest,
+    DeleteRequest, ExposeRequest, Request, Response, RestoreRequest,
+};
+use prost::Message as _;
+use signal_pin::PinHash;
+use thiserror::Error;
+
+use crate::enclave::Sgx;
+use crate::infra::errors::LogSafeDisplay;
+use crate::infra::ws::{
+    run_attested_interaction, AttestedConnection, AttestedConnectionError, WebSocketConnectError,
+    WebSocketServiceError,
+};
+use crate::infra::AsyncDuplexStream;
+use crate::svr::SvrConnection;
+
+/// Length limits the enclave imposes on the backed-up data.
+const DATA_LEN: std::ops::RangeInclusive<usize> = 16..=48;
+
+#[derive(Debug, Error, displaydoc::Display)]
+#[ignore_extra_doc_attributes]
+pub enum Error {
+    /// Connection error: {0}
+    Connect(WebSocketConnectError),
+    /// Network error: {0}
+    Service(#[from] WebSocketServiceError),
+    /// Protocol error after establishing a connection: {0}
+    Protocol(String),
+    /// Enclave attestation failed: {0}
+    AttestationError(attest::enclave::Error),
+    /// Request was not correctly specified
+    InvalidRequest,
+    /// PIN did not match, {0} tries remaining
+    ///
+    /// Once no tries remain, the data has been deleted and further attempts will fail with
+    /// [Error::DataMissing].
+    RestoreFailed(u32),
+    /// No data is backed up
+    DataMissing,
+    /// Connect timed out
+    ConnectionTimedOut,
+}
+
+impl LogSafeDisplay for Error {}
+
+impl From<super::svr::Error> for Error {
+    fn from(err: super::svr::Error) -> Self {
+        use super::svr::Error as SvrError;
+        match err {
+            SvrError::WebSocketConnect(inner) => Self::Connect(inner),
+            SvrError::WebSocket(inner) => Self::Service(inner),
+            SvrError::Protocol => Self::Protocol("General SVR protocol error".to_string()),
+            SvrError::AttestationError(inner) => Self::AttestationError(inner),
+            SvrError::ConnectionTimedOut => Self::ConnectionTimedOut,
+        }
+    }
+}
+
+impl From<AttestedConnectionError> for Error {
+    fn from(err: AttestedConnectionError) -> Self {
+        Self::from(super::svr::Error::from(err))
+    }
+}
+
+/// Backs up `data` so that it can be restored with the access key of `pin_hash`, replacing any
+/// previous backup.
+///
+/// `data` must be between 16 and 48 bytes long. After `max_tries` restore attempts with the wrong
+/// PIN, the enclave deletes the data.
+///
+/// The enclave only lets a backup be restored once it has been exposed, so this sends the backup
+/// and then exposes it. If exposing fails, any previous backup has still been replaced.
+pub async fn backup<S: AsyncDuplexStream>(
+    connection: SvrConnection<Sgx, S>,
+    pin_hash: &PinHash,
+    data: &[u8],
+    max_tries: NonZeroU8,
+) -> Result<(), Error> {
+    backup_with(&mut connection.into(), pin_hash, data, max_tries).await
+}
+
+/// Restores the data backed up with the same PIN.
+///
+/// A wrong PIN uses up one of the tries and fails with [Error::RestoreFailed].
+pub async fn restore<S: AsyncDuplexStream>(
+    connection: SvrConnection<Sgx, S>,
+    pin_hash: &PinHash,
+) -> Result<Vec<u8>, Error> {
+    restore_with(&mut connection.into(), pin_hash).await
+}
+
+/// Deletes the backed-up data, if there is any.
+pub async fn delete<S: AsyncDuplexStream>(connection: SvrConnection<Sgx, S>) -> Result<(), Error> {
+    delete_with(&mut connection.into()).await
+}
+
+async fn backup_with<S: AsyncDuplexStream>(
+    connection: &mut AttestedConnection<S>,
+    pin_hash: &PinHash,
+    data: &[u8],
+    max_tries: NonZeroU8,
+) -> Result<(), Error> {
+    if !DATA_LEN.contains(&data.len()) {
+        return Err(Error::InvalidRequest);
+    }
+
+    let request = request::Inner::Backup(BackupRequest {
+        data: data.to_vec(),
+        pin: pin_hash.access_key.to_vec(),
+        max_tries: max_tries.get().into(),
+    });
+    let response::Inner::Backup(response) = run_request(connection, request).await? else {
+        return Err(Error::Protocol("unexpected response to backup".to_string()));
+    };
+
+    match response.status() {
+        backup_response::Status::Ok => {}
+        backup_response::Status::RequestInvalid => return Err(Error::InvalidRequest),
+        backup_response::Status::Unset => {
+            return Err(Error::Protocol("backup status unset".to_string()))
+        }
+    }
+
+    expose_with(connection, data).await
+}
+
+async fn expose_with<S: AsyncDuplexStream>(
+    connection: &mut AttestedConnection<S>,
+    data: &[u8],
+) -> Result<(), Error> {
+    let request = request::Inner::Expose(ExposeRequest {
+        data: data.to_vec(),
+    });
+    let response::Inner::Expose(response) = run_request(connection, request).await? else {
+        return Err(Error::Protocol("unexpected response to expose".to_string()));
+    };
+
+    match response.status() {
+        expose_response::Status::Ok => Ok(()),
+        expose_response::Status::Error => Err(Error::Protocol("expose failed".to_string())),
+        expose_response::Status::Unset => Err(Error::Protocol("expose status unset".to_string())),
+    }
+}
+
+async fn restore_with<S: AsyncDuplexStream>(
+    connection: &mut AttestedConnection<S>,
+    pin_hash: &PinHash,
+) -> Result<Vec<u8>, Error> {
+    let request = request::Inner::Restore(RestoreRequest {
+        pin: pin_hash.access_key.to_vec(),
+    });
+    let response::Inner::Restore(response) = run_request(connection, request).await? else {
+        return Err(Error::Protocol(
+            "unexpected response to restore".to_string(),
+        ));
+    };
+
+    match response.status() {
+        restore_response::Status::Ok => Ok(response.data),
+        restore_response::Status::Missing => Err(Error::DataMissing),
+        restore_response::Status::PinMismatch => Err(Error::RestoreFailed(response.tries)),
+        restore_response::Status::RequestInvalid => Err(Error::InvalidRequest),
+        restore_response::Status::Unset => Err(Error::Protocol("restore status unset".to_string())),
+    }
+}
+
+async fn delete_with<S: AsyncDuplexStream>(
+    connection: &mut AttestedConnection<S>,
+) -> Result<(), Error> {
+    let request = request::Inner::Delete(DeleteRequest {});
+    match run_request(connection, request).await? {
+        response::Inner::Delete(_) => Ok(()),
+        _ => Err(Error::Protocol("unexpected response to delete".to_string())),
+    }
+}
+
+async fn run_request<S: AsyncDuplexStream>(
+    connection: &mut AttestedConnection<S>,
+    request: request::Inner,
+) -> Result<response::Inner, Error> {
+    let request = Request {
+        inner: Some(request),
+    };
+    let response = run_attested_interaction(connection, request.encode_to_vec())
+        .await?
+        .next_or_else(|_| Error::Protocol("no response".to_string()))?;
+    Response::decode(response.as_slice())
+        .map_err(|e| Error::Protocol(format!("failed to decode response: {e}")))?
+        .inner
+        .ok_or_else(|| Error::Protocol("empty response".to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use assert_matches::assert_matches;
+    use attest::proto::svr2::{BackupResponse, ExposeResponse, RestoreResponse};
+    use nonzero_ext::nonzero;
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::infra::ws::testutil::{
+        fake_websocket, mock_connection_info, run_attested_server, AttestedServerOutput,
+        FAKE_ATTESTATION,
+    };
+    use crate::infra::ws::{NextOrClose, WebSocketClient};
+
+    struct StoredBackup {
+        data: Vec<u8>,
+        pin: Vec<u8>,
+        tries: u32,
+        exposed: bool,
+    }
+
+    /// Follows the enclave's rules for a single backup ID.
+    #[derive(Default)]
+    struct FakeEnclave {
+        backup: Option<StoredBackup>,
+    }
+
+    impl FakeEnclave {
+        fn handle(&mut self, request: request::Inner) -> response::Inner {
+            match request {
+                request::Inner::Backup(BackupRequest {
+                    data,
+                    pin,
+                    max_tries,
+                }) => {
+                    let valid = DATA_LEN.contains(&data.len()) && pin.len() == 32 && max_tries > 0;
+                    if valid {
+                        self.backup = Some(StoredBackup {
+                            data,
+                            pin,
+                            tries: max_tries,
+                            exposed: false,
+                        });
+                    }
+                    let mut response = BackupResponse::default();
+                    response.set_status(if valid {
+                        backup_response::Status::Ok
+                    } else {
+                        backup_response::Status::RequestInvalid
+                    });
+                    response::Inner::Backup(response)
+                }
+                request::Inner::Expose(ExposeRequest { data }) => {
+                    let mut response = ExposeResponse::default();
+                    match &mut self.backup {
+                        Some(backup) if backup.data == data => {
+                            backup.exposed = true;
+                            response.set_status(expose_response::Status::Ok);
+                        }
+                        _ => response.set_status(expose_response::Status::Error),
+                    }
+                    response::Inner::Expose(response)
+                }
+                request::Inner::Restore(RestoreRequest { pin }) => {
+                    let mut response = RestoreResponse::default();
+                    match &mut self.backup {
+                        None | Some(StoredBackup { exposed: false, .. }) => {
+                            response.set_status(restore_response::Status::Missing)
+                        }
+                        Some(backup) if backup.pin == pin => {
+                            response.set_status(restore_response::Status::Ok);
+                            response.data = backup.data.clone();
+                            response.tries = backup.tries;
+                        }
+                        Some(backup) => {
+                            backup.tries -= 1;
+                            response.set_status(restore_response::Status::PinMismatch);
+                            response.tries = backup.tries;
+                            if backup.tries == 0 {
+                                self.backup = None;
+                            }
+                        }
+                    }
+                    response::Inner::Restore(response)
+                }
+                request::Inner::Delete(DeleteRequest {}) => {
+                    self.backup = None;
+                    response::Inner::Delete(Default::default())
+                }
+            }
+        }
+    }
+
+    /// Connects to a stand-in enclave that keeps its state in `enclave`.
+    async fn connect(enclave: Arc<Mutex<FakeEnclave>>) -> AttestedConnection<DuplexStream> {
+        let (server, client) = fake_websocket().await;
+
+        let handler = move |frame: NextOrClose<Vec<u8>>| {
+            let NextOrClose::Next(frame) = frame else {
+                return AttestedServerOutput::close(None);
+            };
+            let request = Request::decode(frame.as_slice()).expect("can decode");
+            let response = enclave
+                .lock()
+                .unwrap()
+                .handle(request.inner.expect("has request"));
+            AttestedServerOutput::message(
+                Response {
+                    inner: Some(response),
+                }
+                .encode_to_vec(),
+            )
+        };
+        tokio::spawn(run_attested_server(
+            server,
+            attest::sgx_session::testutil::private_key(),
+            handler,
+        ));
+
+        let ws_client = WebSocketClient::new_fake(client, mock_connection_info());
+        AttestedConnection::connect(ws_client, |fake_attestation| {
+            assert_eq!(fake_attestation, FAKE_ATTESTATION);
+            attest::sgx_session::testutil::handshake_from_tests_data()
+        })
+        .await
+        .expect("handshake failed")
+    }
+
+    fn pin_hash(access_key: u8) -> PinHash {
+        PinHash {
+            encryption_key: [0; 32],
+            access_key: [access_key; 32],
+        }
+    }
+
+    const DATA: &[u8] = &[0xAB; 32];
+
+    #[tokio::test]
+    async fn backup_and_restore() {
+        let mut connection = connect(Arc::default()).await;
+
+        backup_with(&mut connection, &pin_hash(1), DATA, nonzero!(10u8))
+            .await
+            .expect("backed up");
+        let restored = restore_with(&mut connection, &pin_hash(1))
+            .await
+            .expect("restored");
+        assert_eq!(restored, DATA);
+    }
+
+    #[tokio::test]
+    async fn wrong_pin_reports_tries_remaining() {
+        let mut connection = connect(Arc::default()).await;
+
+        backup_with(&mut connection, &pin_hash(1), DATA, nonzero!(2u8))
+            .await
+            .expect("backed up");
+        assert_matches!(
+            restore_with(&mut connection, &pin_hash(2)).await,
+            Err(Error::RestoreFailed(1))
+        );
+        assert_matches!(
+            restore_with(&mut connection, &pin_hash(2)).await,
+            Err(Error::RestoreFailed(0))
+        );
+        assert_matches!(
+            restore_with(&mut connection, &pin_hash(1)).await,
+            Err(Error::DataMissing)
+        );
+    }
+
+    #[tokio::test]
+    async fn restore_after_delete() {
+        let mut connection = connect(Arc::default()).await;
+
+        backup_with(&mut connection, &pin_hash(1), DATA, nonzero!(10u8))
+            .await
+            .expect("backed up");
+        delete_with(&mut connection).await.expect("deleted");
+        assert_matches!(
+            restore_with(&mut connection, &pin_hash(1)).await,
+            Err(Error::DataMissing)
+        );
+    }
+
+    #[tokio::test]
+    async fn backup_rejects_bad_data_length() {
+        let enclave: Arc<Mutex<FakeEnclave>> = Arc::default();
+        let mut connection = connect(Arc::clone(&enclave)).await;
+
+        assert_matches!(
+            backup_with(&mut connection, &pin_hash(1), &[0; 8], nonzero!(10u8)).await,
+            Err(Error::InvalidRequest)
+        );
+        assert!(enclave.lock().unwrap().backup.is_none());
+    }
+    #[tokio::test]
+    async fn unexposed_backup_cannot_be_restored() {
+        let mut connection = connect(Arc::default()).await;
+
+        let request = request::Inner::Backup(BackupRequest {
+            data: DATA.to_vec(),
+            pin: pin_hash(1).access_key.to_vec(),
+            max_tries: 10,
+        });
+        assert_matches!(
+            run_request(&mut connection, request).await,
+            Ok(response::Inner::Backup(_))
+        );
+        assert_matches!(
+            restore_with(&mut connection, &pin_hash(1)).await,
+            Err(Error::DataMissing)
+        );
+        assert_matches!(
+            expose_with(&mut connection, &[0xCD; 32]).await,
+            Err(Error::Protocol(_))
+        );
+
+        expose_with(&mut connection, DATA).await.expect("exposed");
+        let restored = restore_with(&mut connection, &pin_hash(1))
+            .await
+            .expect("restored");
+        assert_eq!(restored, DATA);
+    }
+}
@@ -8,17 +8,23 @@ use thiserror::Error;
 use crate::enclave::{IntoConnections, PpssSetup};
 use crate::infra::errors::LogSafeDisplay;
 use crate::infra::ws::{
-    run_attested_interaction, AttestedConnectionError, WebSocketConnectError, WebSocketServiceError,
+    run_attested_interaction, AttestedConnection, AttestedConnectionError, WebSocketConnectError,
+    WebSocketServiceError,
 };
 use crate::infra::AsyncDuplexStream;
+use crate::svr3::migration::RestoredSecret;
 use async_trait::async_trait;
 use bincode::Options as _;
 use futures_util::future::try_join_all;
-use libsignal_svr3::{Backup, MaskedShareSet, Restore};
+use libsignal_svr3::{Backup, MaskedShareSet, Query, Remove, Restore};
 use rand_core::CryptoRngCore;
 use serde::{Deserialize, Serialize};
 use std::num::NonZeroU32;
 
+#[cfg(any(test, feature = "fake-svr3-server"))]
+pub mod fake;
+pub mod migration;
+
 const MASKED_SHARE_SET_FORMAT: u8 = 0;
 
 #[derive(Clone)]
@@ -135,14 +141,15 @@ pub enum Error {
     AttestationError(attest::enclave::Error),
     /// SVR3 request failed with status {0}
     RequestFailed(libsignal_svr3::ErrorStatus),
-    /// Failure to restore data
+    /// Failure to restore data. {0} tries remaining.
     ///
-    /// This could be caused by an invalid password or share set.
-    RestoreFailed,
-    /// Restore request failed with MISSING status,
+    /// This could be caused by an invalid password or share set. Once no tries remain, the data
+    /// has been deleted and further attempts will fail with [Error::DataMissing].
+    RestoreFailed(u32),
+    /// Request failed with MISSING status,
     ///
-    /// This could mean either the data was never backed-up or we ran out of attempts to restore
-    /// it.
+    /// This could mean the data was never backed-up, was removed, or we ran out of attempts to
+    /// restore it.
     DataMissing,
     /// Connect timed out
     ConnectionTimedOut,
@@ -162,9 +169,9 @@ impl From<attest::enclave::Error> for Error {
 
 impl From<libsignal_svr3::Error> for Error {
     fn from(err: libsignal_svr3::Error) -> Self {
-        use libsignal_svr3::{Error as LogicError, PPSSError};
+        use libsignal_svr3::Error as LogicError;
         match err {
-            LogicError::Ppss(PPSSError::InvalidCommitment) => Self::RestoreFailed,
+            LogicError::RestoreFailed(tries_remaining) => Self::RestoreFailed(tries_remaining),
             LogicError::BadResponseStatus(libsignal_svr3::ErrorStatus::Missing) => {
                 Self::DataMissing
             }
@@ -196,6 +203,23 @@ impl From<AttestedConnectionError> for Error {
     }
 }
 
+/// Why [PpssOps::rotate] failed.
+#[derive(Debug, Error, displaydoc::Display)]
+#[ignore_extra_doc_attributes]
+pub enum RotationError {
+    /// Restoring the secret failed: {0}
+    ///
+    /// Nothing was written, so the share set passed to [PpssOps::rotate] is still the one to keep.
+    Restore(Error),
+    /// Backing up the restored secret failed: {0}
+    ///
+    /// Some servers may already hold the new keys, so neither the old share set nor the new one
+    /// can be relied on. The secret should be backed up again with [PpssOps::backup].
+    Backup(Error, RestoredSecret),
+}
+
+impl LogSafeDisplay for RotationError {}
+
 #[async_trait]
 pub trait PpssOps<S>: PpssSetup<S> {
     async fn backup(
@@ -212,6 +236,34 @@ pub trait PpssOps<S>: PpssSetup<S> {
         share_set: OpaqueMaskedShareSet,
         rng: &mut (impl CryptoRngCore + Send),
     ) -> Result<[u8; 32], Error>;
+
+    /// Deletes the backed-up secret from every server.
+    async fn remove(connections: Self::Connections) -> Result<(), Error>;
+
+    /// Returns how many restore attempts are left before the secret is deleted.
+    async fn query(connections: Self::Connections) -> Result<u32, Error>;
+
+    /// Replaces every server's key with a fresh one, keeping the secret protected by `password`.
+    ///
+    /// This is a [PpssOps::restore] followed by a [PpssOps::backup] of the restored secret over
+    /// the same connections, so it costs one try. On success the remaining tries are reset to
+    /// `max_tries`, and the returned share set replaces `share_set`, which can no longer be used.
+    /// If `password` or `share_set` is wrong, fails with [RotationError::Restore] wrapping
+    /// [Error::RestoreFailed], leaving the keys as they were but with one try fewer.
+    ///
+    /// The two steps are not atomic across the servers. If the backup fails after some servers
+    /// have accepted it, the failure is reported as [RotationError::Backup] with the restored
+    /// secret, which must then be backed up again.
+    ///
+    /// The servers have no rotate request of their own, and `libsignal_svr3` has no rotate
+    /// operation; rotation is built entirely from its existing restore and backup requests.
+    async fn rotate(
+        connections: Self::Connections,
+        password: &str,
+        share_set: OpaqueMaskedShareSet,
+        max_tries: NonZeroU32,
+        rng: &mut (impl CryptoRngCore + Send),
+    ) -> Result<OpaqueMaskedShareSet, RotationError>;
 }
 
 #[async_trait]
@@ -223,27 +275,16 @@ impl<S: AsyncDuplexStream + 'static, Env: PpssSetup<S>> PpssOps<S> for Env {
         max_tries: NonZeroU32,
         rng: &mut (impl CryptoRngCore + Send),
     ) -> Result<OpaqueMaskedShareSet, Error> {
-        let server_ids = Self::server_ids();
-        let backup = Backup::new(server_ids.as_ref(), password, secret, max_tries, rng)?;
         let mut connections = connections.into_connections();
-        let futures = connections
-            .as_mut()
-            .iter_mut()
-            .zip(&backup.requests)
-            .map(|(connection, request)| run_attested_interaction(connection, request));
-        let result = try_join_all(futures).await?;
-        let responses = result
-            .into_iter()
-            .enumerate()
-            .map(|(i, next_or_close)| {
-                let remote_address = connections.as_ref()[i].remote_address();
-                next_or_close.next_or(Error::Protocol(format!(
-                    "no response from {remote_address}"
-                )))
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-        let share_set = backup.finalize(rng, &responses)?;
-        Ok(OpaqueMaskedShareSet::new(share_set))
+        backup_with(
+            connections.as_mut(),
+            Self::server_ids().as_ref(),
+            password,
+            secret,
+            max_tries,
+            rng,
+        )
+        .await
     }
 
     async fn restore(
@@ -252,26 +293,99 @@ impl<S: AsyncDuplexStream + 'static, Env: PpssSetup<S>> PpssOps<S> for Env {
         share_set: OpaqueMaskedShareSet,
         rng: &mut (impl CryptoRngCore + Send),
     ) -> Result<[u8; 32], Error> {
-        let restore = Restore::new(password, share_set.into_inner(), rng)?;
         let mut connections = connections.into_connections();
-        let futures = connections
-            .as_mut()
-            .iter_mut()
-            .zip(&restore.requests)
-            .map(|(connection, request)| run_attested_interaction(connection, request));
-        let result = try_join_all(futures).await?;
-        let responses = result
-            .into_iter()
-            .enumerate()
-            .map(|(i, next_or_close)| {
-                let remote_address = connections.as_ref()[i].remote_address();
-                next_or_close.next_or(Error::Protocol(format!(
-                    "no response from {remote_address}"
-                )))
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-        Ok(restore.finalize(&responses)?)
+        restore_with(connections.as_mut(), password, share_set, rng).await
     }
+
+    async fn remove(connections: Self::Connections) -> Result<(), Error> {
+        let mut connections = connections.into_connections();
+        remove_with(connections.as_mut()).await
+    }
+
+    async fn query(connections: Self::Connections) -> Result<u32, Error> {
+        let query = Query::new(<Self as PpssSetup<S>>::N);
+        let mut connections = connections.into_connections();
+        let responses = run_interactions(connections.as_mut(), &query.requests).await?;
+        Ok(query.finalize(&responses)?)
+    }
+
+    async fn rotate(
+        connections: Self::Connections,
+        password: &str,
+        share_set: OpaqueMaskedShareSet,
+        max_tries: NonZeroU32,
+        rng: &mut (impl CryptoRngCore + Send),
+    ) -> Result<OpaqueMaskedShareSet, RotationError> {
+        let mut connections = connections.into_connections();
+        let secret = restore_with(connections.as_mut(), password, share_set, rng)
+            .await
+            .map_err(RotationError::Restore)?;
+        backup_with(
+            connections.as_mut(),
+            Self::server_ids().as_ref(),
+            password,
+            secret,
+            max_tries,
+            rng,
+        )
+        .await
+        .map_err(|e| RotationError::Backup(e, RestoredSecret(secret)))
+    }
+}
+
+async fn backup_with<S: AsyncDuplexStream>(
+    connections: &mut [AttestedConnection<S>],
+    server_ids: &[u64],
+    password: &str,
+    secret: [u8; 32],
+    max_tries: NonZeroU32,
+    rng: &mut (impl CryptoRngCore + Send),
+) -> Result<OpaqueMaskedShareSet, Error> {
+    let backup = Backup::new(server_ids, password, secret, max_tries, rng)?;
+    let responses = run_interactions(connections, &backup.requests).await?;
+    let share_set = backup.finalize(rng, &responses)?;
+    Ok(OpaqueMaskedShareSet::new(share_set))
+}
+
+async fn restore_with<S: AsyncDuplexStream>(
+    connections: &mut [AttestedConnection<S>],
+    password: &str,
+    share_set: OpaqueMaskedShareSet,
+    rng: &mut (impl CryptoRngCore + Send),
+) -> Result<[u8; 32], Error> {
+    let restore = Restore::new(password, share_set.into_inner(), rng)?;
+    let responses = run_interactions(connections, &restore.requests).await?;
+    Ok(restore.finalize(&responses)?)
+}
+
+async fn remove_with<S: AsyncDuplexStream>(
+    connections: &mut [AttestedConnection<S>],
+) -> Result<(), Error> {
+    let remove = Remove::new(connections.len());
+    let responses = run_interactions(connections, &remove.requests).await?;
+    Ok(remove.finalize(&responses)?)
+}
+
+/// Sends each request over the corresponding connection and collects the responses in order.
+async fn run_interactions<S: AsyncDuplexStream>(
+    connections: &mut [AttestedConnection<S>],
+    requests: &[Vec<u8>],
+) -> Result<Vec<Vec<u8>>, Error> {
+    let futures = connections
+        .iter_mut()
+        .zip(requests)
+        .map(|(connection, request)| run_attested_interaction(connection, request));
+    let result = try_join_all(futures).await?;
+    result
+        .into_iter()
+        .enumerate()
+        .map(|(i, next_or_close)| {
+            let remote_address = connections[i].remote_address();
+            next_or_close.next_or(Error::Protocol(format!(
+                "no response from {remote_address}"
+            )))
+        })
+        .collect()
 }
 
 #[cfg(test)]
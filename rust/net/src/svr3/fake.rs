@@ -0,0 +1,322 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! In-process stand-ins for the SVR3 enclaves, for exercising [PpssOps](crate::svr3::PpssOps)
+//! offline.
+//!
+//! [FakeSvr3Env::connect] opens attested connections to one [FakeSvr3Enclave] for each of the
+//! SGX, Nitro and TPM2-SNP enclaves. Whatever the kind of enclave, the server presents the SGX
+//! test evidence from [attest::sgx_session::testutil] instead of a live attestation. Requests
+//! are handled by [libsignal_svr3::fake::FakeServer], which enforces the limit on tries the way
+//! the real enclaves do. Each enclave can also be made to fail or to respond slowly, to test how
+//! clients cope with one misbehaving server.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use libsignal_svr3::fake::FakeServer;
+use rand::rngs::OsRng;
+use tokio::io::DuplexStream;
+
+use crate::enclave::fake::{self, AttestedServer};
+use crate::enclave::{Nitro, Sgx, Svr3Flavor, Tpm2Snp};
+use crate::svr::SvrConnection;
+use crate::svr3::Error;
+
+/// How a [FakeSvr3Enclave] misbehaves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// Close the connection instead of responding.
+    Disconnect,
+    /// Respond with bytes that aren't a valid response.
+    Malformed,
+}
+
+#[derive(Default)]
+struct State {
+    server: FakeServer,
+    failure: Option<Failure>,
+    /// Requests still to be handled normally before `failure` applies.
+    requests_before_failure: u32,
+    response_delay: Duration,
+}
+
+/// A single fake enclave; see the [module docs](self).
+///
+/// Cloning produces another handle to the same enclave.
+#[derive(Clone, Default)]
+pub struct FakeSvr3Enclave {
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeSvr3Enclave {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The tries left before the stored secret is deleted, or `None` if nothing is stored.
+    pub fn tries_remaining(&self) -> Option<u32> {
+        self.state().server.tries_remaining()
+    }
+
+    /// Makes every request fail in the given way until this is called again with `None`.
+    pub fn set_failure(&self, failure: Option<Failure>) {
+        self.fail_after(0, failure);
+    }
+
+    /// Like [Self::set_failure], but only once `requests` more requests have been handled.
+    pub fn fail_after(&self, requests: u32, failure: Option<Failure>) {
+        let mut state = self.state();
+        state.failure = failure;
+        state.requests_before_failure = requests;
+    }
+
+    /// Delays every response by `delay`.
+    pub fn set_response_delay(&self, delay: Duration) {
+        self.state().response_delay = delay;
+    }
+
+    async fn connect<E: Svr3Flavor>(
+        &self,
+        host: &str,
+    ) -> Result<SvrConnection<E, DuplexStream>, Error> {
+        let enclave = self.clone();
+        let connection = fake::connect(host, |session| enclave.serve(session)).await?;
+        Ok(SvrConnection::new(connection))
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("not poisoned")
+    }
+
+    async fn serve(self, mut session: AttestedServer) {
+        while let Some(request) = session.receive().await {
+            let (response, delay) = {
+                let mut state = self.state();
+                let failure = if state.requests_before_failure > 0 {
+                    state.requests_before_failure -= 1;
+                    None
+                } else {
+                    state.failure
+                };
+                let response = match failure {
+                    None => state.server.handle(&request, &mut OsRng),
+                    Some(Failure::Disconnect) => None,
+                    Some(Failure::Malformed) => Some(b"malformed".to_vec()),
+                };
+                (response, state.response_delay)
+            };
+
+            tokio::time::sleep(delay).await;
+            let Some(response) = response else {
+                session.close(None).await;
+                return;
+            };
+            if session.send(&response).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// One [FakeSvr3Enclave] for each of the enclaves in [Svr3Env](crate::env::Svr3Env).
+///
+/// Cloning produces another handle to the same enclaves.
+#[derive(Clone, Default)]
+pub struct FakeSvr3Env {
+    pub sgx: FakeSvr3Enclave,
+    pub nitro: FakeSvr3Enclave,
+    pub tpm2snp: FakeSvr3Enclave,
+}
+
+impl FakeSvr3Env {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens a connection to each enclave, in the form expected by
+    /// [PpssOps](crate::svr3::PpssOps) for [Svr3Env](crate::env::Svr3Env).
+    pub async fn connect(
+        &self,
+    ) -> Result<
+        (
+            SvrConnection<Sgx, DuplexStream>,
+            SvrConnection<Nitro, DuplexStream>,
+            SvrConnection<Tpm2Snp, DuplexStream>,
+        ),
+        Error,
+    > {
+        Ok((
+            self.sgx.connect("svr3-sgx.local").await?,
+            self.nitro.connect("svr3-nitro.local").await?,
+            self.tpm2snp.connect("svr3-tpm2snp.local").await?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use nonzero_ext::nonzero;
+
+    use super::*;
+    use crate::env::Svr3Env;
+    use crate::svr3::migration::RestoredSecret;
+    use crate::svr3::{OpaqueMaskedShareSet, PpssOps as _, RotationError};
+
+    const SECRET: [u8; 32] = [7; 32];
+
+    async fn backup(env: &FakeSvr3Env) -> Result<OpaqueMaskedShareSet, Error> {
+        Svr3Env::backup(
+            env.connect().await?,
+            "password",
+            SECRET,
+            nonzero!(3u32),
+            &mut OsRng,
+        )
+        .await
+    }
+
+    async fn restore(
+        env: &FakeSvr3Env,
+        password: &str,
+        share_set: OpaqueMaskedShareSet,
+    ) -> Result<[u8; 32], Error> {
+        Svr3Env::restore(env.connect().await?, password, share_set, &mut OsRng).await
+    }
+
+    #[tokio::test]
+    async fn backup_and_restore() {
+        let env = FakeSvr3Env::new();
+        let share_set = backup(&env).await.expect("backed up");
+        assert_matches!(restore(&env, "password", share_set).await, Ok(SECRET));
+        assert_eq!(env.sgx.tries_remaining(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn wrong_password_uses_up_tries() {
+        let env = FakeSvr3Env::new();
+        let share_set = backup(&env).await.expect("backed up");
+
+        assert_matches!(
+            restore(&env, "wrong", share_set).await,
+            Err(Error::RestoreFailed(2))
+        );
+        let tries = Svr3Env::query(env.connect().await.expect("connected")).await;
+        assert_matches!(tries, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn restore_works_after_rotate() {
+        let env = FakeSvr3Env::new();
+        let share_set = backup(&env).await.expect("backed up");
+
+        let rotated = Svr3Env::rotate(
+            env.connect().await.expect("connected"),
+            "password",
+            share_set.clone(),
+            nonzero!(5u32),
+            &mut OsRng,
+        )
+        .await
+        .expect("rotated");
+        assert_eq!(env.tpm2snp.tries_remaining(), Some(5));
+
+        assert_matches!(restore(&env, "password", rotated).await, Ok(SECRET));
+        assert_matches!(
+            restore(&env, "password", share_set).await,
+            Err(Error::RestoreFailed(3))
+        );
+    }
+
+    #[tokio::test]
+    async fn rotate_with_wrong_password_keeps_secret() {
+        let env = FakeSvr3Env::new();
+        let share_set = backup(&env).await.expect("backed up");
+
+        let result = Svr3Env::rotate(
+            env.connect().await.expect("connected"),
+            "wrong",
+            share_set.clone(),
+            nonzero!(5u32),
+            &mut OsRng,
+        )
+        .await;
+        assert_matches!(
+            result.err(),
+            Some(RotationError::Restore(Error::RestoreFailed(2)))
+        );
+        assert_matches!(restore(&env, "password", share_set).await, Ok(SECRET));
+    }
+
+    #[tokio::test]
+    async fn rotate_returns_secret_if_backup_fails() {
+        let env = FakeSvr3Env::new();
+        let share_set = backup(&env).await.expect("backed up");
+        // Let the restore through, then fail the backup on one server only.
+        env.nitro.fail_after(1, Some(Failure::Disconnect));
+
+        let result = Svr3Env::rotate(
+            env.connect().await.expect("connected"),
+            "password",
+            share_set.clone(),
+            nonzero!(5u32),
+            &mut OsRng,
+        )
+        .await;
+        let secret = assert_matches!(
+            result.err(),
+            Some(RotationError::Backup(_, RestoredSecret(secret))) => secret
+        );
+        assert_eq!(secret, SECRET);
+        // The other servers took the new backup, so the old share set is no good any more.
+        env.nitro.set_failure(None);
+        assert_matches!(restore(&env, "password", share_set).await, Err(_));
+    }
+
+    #[tokio::test]
+    async fn restore_fails_after_remove() {
+        let env = FakeSvr3Env::new();
+        let share_set = backup(&env).await.expect("backed up");
+
+        Svr3Env::remove(env.connect().await.expect("connected"))
+            .await
+            .expect("removed");
+        assert_eq!(env.sgx.tries_remaining(), None);
+
+        assert_matches!(
+            restore(&env, "password", share_set).await,
+            Err(Error::DataMissing)
+        );
+        let tries = Svr3Env::query(env.connect().await.expect("connected")).await;
+        assert_matches!(tries, Err(Error::DataMissing));
+    }
+
+    #[tokio::test]
+    async fn failing_enclave_fails_the_operation() {
+        let env = FakeSvr3Env::new();
+
+        env.nitro.set_failure(Some(Failure::Disconnect));
+        assert!(backup(&env).await.is_err());
+
+        env.nitro.set_failure(Some(Failure::Malformed));
+        assert_matches!(backup(&env).await, Err(Error::Protocol(_)));
+
+        env.nitro.set_failure(None);
+        let share_set = backup(&env).await.expect("backed up");
+        assert_matches!(restore(&env, "password", share_set).await, Ok(SECRET));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_enclave_delays_the_operation() {
+        const DELAY: Duration = Duration::from_secs(30);
+        let env = FakeSvr3Env::new();
+        env.tpm2snp.set_response_delay(DELAY);
+
+        let start = tokio::time::Instant::now();
+        backup(&env).await.expect("backed up");
+        assert!(start.elapsed() >= DELAY);
+    }
+}
@@ -0,0 +1,343 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Moving a backup from one set of SVR3 enclaves to another.
+//!
+//! A share set can only be restored from the servers it was backed up to. When an enclave is
+//! replaced, clients holding a share set for the old servers use [migrate] to restore the secret
+//! from them and back it up to the new ones.
+//!
+//! Servers that are in both sets can only hold one backup each, so backing up to them overwrites
+//! the old one. To keep that window small, the new backup goes to the servers only in the new set
+//! first, and the shared servers are only written once that has succeeded. A failure before then
+//! leaves the old share set usable; a failure after then is reported with the restored secret, so
+//! that it can be backed up again.
+
+use std::fmt;
+use std::num::NonZeroU32;
+
+use libsignal_svr3::Backup;
+use rand_core::CryptoRngCore;
+use thiserror::Error;
+
+use crate::enclave::{IntoConnections, PpssSetup};
+use crate::infra::errors::LogSafeDisplay;
+use crate::infra::AsyncDuplexStream;
+use crate::svr3::{remove_with, restore_with, run_interactions, Error, OpaqueMaskedShareSet};
+
+/// Returns whether `share_set` was backed up to servers other than those of `Env`.
+pub fn needs_migration<Env: PpssSetup<S>, S>(share_set: &OpaqueMaskedShareSet) -> bool {
+    share_set.inner.server_ids != Env::server_ids().as_ref()
+}
+
+/// Everything that can stop a migration.
+///
+/// Except for [SharedServersOverwritten](Self::SharedServersOverwritten), the share set passed to
+/// [migrate] is still the one to keep.
+#[derive(Debug, Error, displaydoc::Display)]
+#[ignore_extra_doc_attributes]
+pub enum MigrationError {
+    /// Share set was not backed up to the source servers
+    NotBackedUpToSource,
+    /// Restoring from the source servers failed: {0}
+    Restore(Error),
+    /// Backing up to the destination servers failed: {0}
+    Backup(Error),
+    /// Backing up to the servers shared with the source failed: {0}
+    ///
+    /// Those servers may already have dropped the old backup, so neither share set can be relied
+    /// on. The secret should be backed up to the destination servers again.
+    SharedServersOverwritten(Error, RestoredSecret),
+}
+
+impl LogSafeDisplay for MigrationError {}
+
+/// The secret restored during a migration or [rotation](crate::svr3::PpssOps::rotate) that could
+/// not be finished.
+///
+/// Never printed, including by [Debug].
+pub struct RestoredSecret(pub [u8; 32]);
+
+impl fmt::Debug for RestoredSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RestoredSecret(..)")
+    }
+}
+
+/// The result of a successful migration.
+#[cfg_attr(test, derive(Debug))]
+pub struct Migrated {
+    /// Replaces the share set that was migrated.
+    pub share_set: OpaqueMaskedShareSet,
+    /// Set if the secret couldn't be removed from the source servers.
+    ///
+    /// The migration itself still succeeded. The stale copy can be removed later with
+    /// [PpssOps::remove](crate::svr3::PpssOps::remove).
+    pub cleanup_error: Option<Error>,
+}
+
+/// Restores the secret protected by `password` from the `Old` servers and backs it up to the
+/// `New` ones, then removes it from the `Old` servers that aren't also `New` ones.
+///
+/// Restoring uses up one of the tries on the old servers. The `New` servers that aren't also `Old`
+/// ones are backed up to before the shared ones, so that a failure there leaves `share_set`
+/// usable; see [MigrationError::SharedServersOverwritten] for a failure after that.
+pub async fn migrate<S, Old, New>(
+    old: Old::Connections,
+    new: New::Connections,
+    password: &str,
+    share_set: OpaqueMaskedShareSet,
+    max_tries: NonZeroU32,
+    rng: &mut (impl CryptoRngCore + Send),
+) -> Result<Migrated, MigrationError>
+where
+    S: AsyncDuplexStream + 'static,
+    Old: PpssSetup<S>,
+    New: PpssSetup<S>,
+{
+    if needs_migration::<Old, S>(&share_set) {
+        return Err(MigrationError::NotBackedUpToSource);
+    }
+
+    let mut old = old.into_connections();
+    let mut new = new.into_connections();
+
+    let secret = restore_with(old.as_mut(), password, share_set, rng)
+        .await
+        .map_err(MigrationError::Restore)?;
+    let old_ids = Old::server_ids();
+    let new_ids = New::server_ids();
+    let backup = Backup::new(new_ids.as_ref(), password, secret, max_tries, rng)
+        .map_err(|e| MigrationError::Backup(e.into()))?;
+
+    // Move the connections to servers that aren't shared with the old set to the front, keeping
+    // track of where each one came from so the responses can be put back in server order.
+    let new = new.as_mut();
+    let mut original_index: Vec<usize> = (0..new.len()).collect();
+    let mut new_only_count = 0;
+    for (i, id) in new_ids.as_ref().iter().enumerate() {
+        if !old_ids.as_ref().contains(id) {
+            new.swap(new_only_count, i);
+            original_index.swap(new_only_count, i);
+            new_only_count += 1;
+        }
+    }
+    let requests: Vec<_> = original_index
+        .iter()
+        .map(|&i| backup.requests[i].clone())
+        .collect();
+
+    let mut responses = run_interactions(&mut new[..new_only_count], &requests[..new_only_count])
+        .await
+        .map_err(MigrationError::Backup)?;
+
+    let share_set = async {
+        responses.extend(
+            run_interactions(&mut new[new_only_count..], &requests[new_only_count..]).await?,
+        );
+        let mut ordered = vec![vec![]; responses.len()];
+        for (response, &i) in responses.into_iter().zip(&original_index) {
+            ordered[i] = response;
+        }
+        Ok::<_, Error>(OpaqueMaskedShareSet::new(backup.finalize(rng, &ordered)?))
+    }
+    .await
+    .map_err(|e| MigrationError::SharedServersOverwritten(e, RestoredSecret(secret)))?;
+
+    // Servers in both sets now hold the new backup, so only the others get cleaned up. Move their
+    // connections to the front so they can be passed on as one slice.
+    let old = old.as_mut();
+    let mut stale_count = 0;
+    for (i, id) in old_ids.as_ref().iter().enumerate() {
+        if !new_ids.as_ref().contains(id) {
+            old.swap(stale_count, i);
+            stale_count += 1;
+        }
+    }
+    let cleanup_error = match stale_count {
+        0 => None,
+        _ => remove_with(&mut old[..stale_count]).await.err(),
+    };
+    if let Some(e) = &cleanup_error {
+        log::warn!("migrated SVR3 backup, but failed to remove the old copy: {e}");
+    }
+
+    Ok(Migrated {
+        share_set,
+        cleanup_error,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use nonzero_ext::nonzero;
+    use rand::rngs::OsRng;
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::env::Svr3Env;
+    use crate::svr3::fake::{Failure, FakeSvr3Enclave, FakeSvr3Env};
+    use crate::svr3::{PpssOps as _, SerializableMaskedShareSet};
+
+    const SECRET: [u8; 32] = [7; 32];
+
+    type FakeConnections = <Svr3Env<'static> as PpssSetup<DuplexStream>>::Connections;
+
+    /// The same kinds of enclave as [Svr3Env], but with the third one replaced.
+    struct Overlapping;
+
+    impl PpssSetup<DuplexStream> for Overlapping {
+        type Connections = FakeConnections;
+        type ServerIds = [u64; 3];
+
+        fn server_ids() -> Self::ServerIds {
+            [1, 2, 4]
+        }
+    }
+
+    /// The same kinds of enclave as [Svr3Env], but all of them replaced.
+    struct Disjoint;
+
+    impl PpssSetup<DuplexStream> for Disjoint {
+        type Connections = FakeConnections;
+        type ServerIds = [u64; 3];
+
+        fn server_ids() -> Self::ServerIds {
+            [4, 5, 6]
+        }
+    }
+
+    async fn backup(env: &FakeSvr3Env) -> OpaqueMaskedShareSet {
+        Svr3Env::backup(
+            env.connect().await.expect("connected"),
+            "password",
+            SECRET,
+            nonzero!(3u32),
+            &mut OsRng,
+        )
+        .await
+        .expect("backed up")
+    }
+
+    async fn migrate_to<New: PpssSetup<DuplexStream, Connections = FakeConnections>>(
+        old_env: &FakeSvr3Env,
+        new_env: &FakeSvr3Env,
+        share_set: OpaqueMaskedShareSet,
+    ) -> Migrated {
+        migrate::<_, Svr3Env, New>(
+            old_env.connect().await.expect("connected"),
+            new_env.connect().await.expect("connected"),
+            "password",
+            share_set,
+            nonzero!(5u32),
+            &mut OsRng,
+        )
+        .await
+        .expect("migrated")
+    }
+
+    fn share_set_for(server_ids: Vec<u64>) -> OpaqueMaskedShareSet {
+        OpaqueMaskedShareSet {
+            inner: SerializableMaskedShareSet {
+                masked_shares: vec![[0; 32]; server_ids.len()],
+                server_ids,
+                commitment: [0; 32],
+            },
+        }
+    }
+
+    #[test]
+    fn detects_outdated_server_ids() {
+        let needs_migration =
+            |server_ids| needs_migration::<Svr3Env, DuplexStream>(&share_set_for(server_ids));
+        assert!(!needs_migration(vec![1, 2, 3]));
+        assert!(needs_migration(vec![1, 2, 4]));
+        assert!(needs_migration(vec![1, 2]));
+    }
+
+    #[tokio::test]
+    async fn migrate_to_disjoint_servers() {
+        let old_env = FakeSvr3Env::new();
+        let new_env = FakeSvr3Env::new();
+        let share_set = backup(&old_env).await;
+
+        let migrated = migrate_to::<Disjoint>(&old_env, &new_env, share_set).await;
+        assert_matches!(migrated.cleanup_error, None);
+        for enclave in [&old_env.sgx, &old_env.nitro, &old_env.tpm2snp] {
+            assert_eq!(enclave.tries_remaining(), None);
+        }
+
+        let restored = Disjoint::restore(
+            new_env.connect().await.expect("connected"),
+            "password",
+            migrated.share_set,
+            &mut OsRng,
+        )
+        .await;
+        assert_matches!(restored, Ok(SECRET));
+    }
+
+    #[tokio::test]
+    async fn migrate_to_overlapping_servers() {
+        let old_env = FakeSvr3Env::new();
+        let new_env = FakeSvr3Env {
+            tpm2snp: FakeSvr3Enclave::new(),
+            ..old_env.clone()
+        };
+        let share_set = backup(&old_env).await;
+
+        let migrated = migrate_to::<Overlapping>(&old_env, &new_env, share_set).await;
+        assert_matches!(migrated.cleanup_error, None);
+        // The shared enclaves keep the new backup; only the replaced one is cleaned up.
+        assert_eq!(old_env.sgx.tries_remaining(), Some(5));
+        assert_eq!(old_env.nitro.tries_remaining(), Some(5));
+        assert_eq!(old_env.tpm2snp.tries_remaining(), None);
+
+        let restored = Overlapping::restore(
+            new_env.connect().await.expect("connected"),
+            "password",
+            migrated.share_set,
+            &mut OsRng,
+        )
+        .await;
+        assert_matches!(restored, Ok(SECRET));
+    }
+
+    #[tokio::test]
+    async fn failure_on_replaced_server_keeps_old_backup() {
+        let old_env = FakeSvr3Env::new();
+        let new_env = FakeSvr3Env {
+            tpm2snp: FakeSvr3Enclave::new(),
+            ..old_env.clone()
+        };
+        let share_set = backup(&old_env).await;
+        let serialized = share_set.serialize().expect("can serialize");
+        new_env.tpm2snp.set_failure(Some(Failure::Disconnect));
+
+        let result = migrate::<_, Svr3Env, Overlapping>(
+            old_env.connect().await.expect("connected"),
+            new_env.connect().await.expect("connected"),
+            "password",
+            share_set,
+            nonzero!(5u32),
+            &mut OsRng,
+        )
+        .await;
+        assert_matches!(result, Err(MigrationError::Backup(_)));
+        // The shared enclaves were never asked to back up, so they still have the old backup.
+        assert_eq!(old_env.sgx.tries_remaining(), Some(2));
+        assert_eq!(old_env.nitro.tries_remaining(), Some(2));
+
+        let restored = Svr3Env::restore(
+            old_env.connect().await.expect("connected"),
+            "password",
+            OpaqueMaskedShareSet::deserialize(&serialized).expect("can deserialize"),
+            &mut OsRng,
+        )
+        .await;
+        assert_matches!(restored, Ok(SECRET));
+    }
+}
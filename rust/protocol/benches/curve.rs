@@ -3,8 +3,8 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use criterion::{criterion_group, criterion_main, Criterion};
-use libsignal_protocol::KeyPair;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use libsignal_protocol::{KeyPair, PublicKey};
 use rand::{thread_rng, Rng};
 
 pub fn generation(c: &mut Criterion) {
@@ -44,6 +44,70 @@ pub fn signatures(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, generation, key_agreement, signatures);
+pub fn batch_verification(c: &mut Criterion) {
+    let rng = &mut thread_rng();
+    let mut group = c.benchmark_group("verify signatures");
+
+    for count in [16, 256] {
+        let keys: Vec<_> = (0..count).map(|_| KeyPair::generate(rng)).collect();
+        let messages: Vec<_> = (0..count)
+            .map(|_| {
+                let mut message = [0; 256];
+                rng.fill(&mut message);
+                message
+            })
+            .collect();
+        let signatures: Vec<_> = keys
+            .iter()
+            .zip(&messages)
+            .map(|(key, message)| key.calculate_signature(message, rng).unwrap())
+            .collect();
+        let items: Vec<_> = keys
+            .iter()
+            .zip(&messages)
+            .zip(&signatures)
+            .map(|((key, message), signature)| (&key.public_key, &message[..], &signature[..]))
+            .collect();
+
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(
+            BenchmarkId::new("individually", count),
+            &items,
+            |b, items| {
+                b.iter(|| {
+                    items.iter().all(|(key, message, signature)| {
+                        key.verify_signature(message, signature).unwrap()
+                    })
+                })
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("batch", count), &items, |b, items| {
+            b.iter(|| PublicKey::verify_signatures_batch(items, rng).unwrap())
+        });
+
+        let mut corrupted_signatures = signatures.clone();
+        corrupted_signatures[count / 2][0] ^= 1;
+        let items_with_invalid: Vec<_> = items
+            .iter()
+            .zip(&corrupted_signatures)
+            .map(|(&(key, message, _), signature)| (key, message, &signature[..]))
+            .collect();
+        group.bench_with_input(
+            BenchmarkId::new("batch with one invalid", count),
+            &items_with_invalid,
+            |b, items| b.iter(|| PublicKey::verify_signatures_batch(items, rng).unwrap()),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    generation,
+    key_agreement,
+    signatures,
+    batch_verification
+);
 
 criterion_main!(benches);
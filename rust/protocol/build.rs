@@ -13,6 +13,15 @@ fn main() {
     ];
     let mut prost_build = prost_build::Config::new();
     prost_build.protoc_arg("--experimental_allow_proto3_optional");
+    // Session and sender key state is full of secrets; let the wrapper types wipe it on drop.
+    for secret_type in [
+        ".signal.proto.storage.SessionStructure",
+        ".signal.proto.storage.RecordStructure",
+        ".signal.proto.storage.SenderKeyStateStructure",
+        ".signal.proto.storage.SenderKeyRecordStructure",
+    ] {
+        prost_build.type_attribute(secret_type, "#[derive(zeroize::Zeroize)]");
+    }
     prost_build
         .compile_protos(&protos, &["src"])
         .expect("Protobufs in src are valid");
@@ -23,3 +23,5 @@ pub use v21::LibSignalProtocolV21;
 
 mod v12;
 pub use v12::LibSignalProtocolV12;
+
+pub mod transcript;
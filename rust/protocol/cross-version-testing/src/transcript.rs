@@ -0,0 +1,365 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Recorded protocol sessions that can be replayed against later versions of the library.
+//!
+//! A [`Transcript`] is a list of [`Step`]s. Each step captures one call to a cipher entry point
+//! (processing a pre-key bundle, encrypting, or decrypting) together with every store record the
+//! call read and wrote, the time it was made, and the seed for the RNG it was given. This makes
+//! each step self-contained: a replayer can rebuild exactly the state the call saw without
+//! depending on earlier steps, which keeps old transcripts usable even if later versions consume
+//! randomness or lay out sessions differently.
+//!
+//! Use a [`Recorder`] to capture a transcript and [`replay`] to check one.
+
+use libsignal_protocol_current::{
+    kem, DeviceId, GenericSignedPreKey, IdentityKey, IdentityKeyPair, KyberPreKeyId,
+    KyberPreKeyRecord, PreKeyBundle, PreKeyBundleContent, PreKeyId, PreKeyRecord, ProtocolAddress,
+    PublicKey, SessionRecord, SignalProtocolError, SignedPreKeyRecord,
+};
+use serde::{Deserialize, Serialize};
+
+mod record;
+pub use record::Recorder;
+
+mod replay;
+pub use replay::{replay, replay_with_options, ReplayError, ReplayOptions};
+
+/// Bump this if the JSON representation changes incompatibly.
+pub const TRANSCRIPT_FORMAT_VERSION: u32 = 1;
+
+/// A recorded sequence of protocol operations.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transcript {
+    pub format_version: u32,
+    /// Free-form description of the scenario and the library version that produced it.
+    pub description: String,
+    pub steps: Vec<Step>,
+}
+
+impl Transcript {
+    pub fn new(description: impl Into<String>) -> Self {
+        Self {
+            format_version: TRANSCRIPT_FORMAT_VERSION,
+            description: description.into(),
+            steps: vec![],
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("transcripts can always be serialized")
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+}
+
+/// A single call into the library, along with everything needed to run it again.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Step {
+    /// The party whose store was used. Only used for diagnostics.
+    pub local: Address,
+    /// The address passed to the cipher entry point.
+    pub remote: Address,
+    /// Seed for the [`rand_chacha::ChaCha20Rng`] passed to the operation.
+    pub rng_seed: Bytes,
+    /// The `now` passed to the operation, in milliseconds since the Unix epoch, if it takes one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// Store records the operation read before writing them.
+    pub before: StoreSnapshot,
+    pub operation: Operation,
+    /// Store records the operation wrote, in their final state.
+    pub after: StoreSnapshot,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Operation {
+    ProcessPreKeyBundle {
+        bundle: RecordedPreKeyBundle,
+    },
+    Encrypt {
+        plaintext: Bytes,
+        message_type: u8,
+        ciphertext: Bytes,
+    },
+    Decrypt {
+        message_type: u8,
+        ciphertext: Bytes,
+        plaintext: Bytes,
+    },
+}
+
+/// The subset of a [`ProtocolStore`](libsignal_protocol_current::ProtocolStore) touched by a
+/// single step, as serialized records.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreSnapshot {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_key_pair: Option<Bytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registration_id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub identities: Vec<AddressedRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sessions: Vec<AddressedRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre_keys: Vec<IdRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signed_pre_keys: Vec<IdRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kyber_pre_keys: Vec<IdRecord>,
+    /// One-time pre-keys removed by the operation. Only present in [`Step::after`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_pre_keys: Vec<u32>,
+    /// Kyber pre-keys marked as used by the operation. Only present in [`Step::after`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub used_kyber_pre_keys: Vec<u32>,
+}
+
+impl StoreSnapshot {
+    pub fn identity(&self, address: &Address) -> Option<&Bytes> {
+        find_addressed(&self.identities, address)
+    }
+
+    pub fn session(&self, address: &Address) -> Option<&Bytes> {
+        find_addressed(&self.sessions, address)
+    }
+
+    fn set_identity(&mut self, address: Address, record: Bytes) {
+        set_addressed(&mut self.identities, address, record)
+    }
+
+    fn set_session(&mut self, address: Address, record: Bytes) {
+        set_addressed(&mut self.sessions, address, record)
+    }
+}
+
+fn find_addressed<'a>(records: &'a [AddressedRecord], address: &Address) -> Option<&'a Bytes> {
+    records
+        .iter()
+        .find(|entry| &entry.address == address)
+        .map(|entry| &entry.record)
+}
+
+fn set_addressed(records: &mut Vec<AddressedRecord>, address: Address, record: Bytes) {
+    match records.iter_mut().find(|entry| entry.address == address) {
+        Some(entry) => entry.record = record,
+        None => records.push(AddressedRecord { address, record }),
+    }
+}
+
+fn find_by_id(records: &[IdRecord], id: u32) -> Option<&Bytes> {
+    records
+        .iter()
+        .find(|entry| entry.id == id)
+        .map(|entry| &entry.record)
+}
+
+fn set_by_id(records: &mut Vec<IdRecord>, id: u32, record: Bytes) {
+    match records.iter_mut().find(|entry| entry.id == id) {
+        Some(entry) => entry.record = record,
+        None => records.push(IdRecord { id, record }),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressedRecord {
+    pub address: Address,
+    pub record: Bytes,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdRecord {
+    pub id: u32,
+    pub record: Bytes,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Address {
+    pub name: String,
+    pub device_id: u32,
+}
+
+impl From<&ProtocolAddress> for Address {
+    fn from(address: &ProtocolAddress) -> Self {
+        Self {
+            name: address.name().to_owned(),
+            device_id: address.device_id().into(),
+        }
+    }
+}
+
+impl From<&Address> for ProtocolAddress {
+    fn from(address: &Address) -> Self {
+        ProtocolAddress::new(address.name.clone(), DeviceId::from(address.device_id))
+    }
+}
+
+/// Binary data, hex-encoded in the JSON representation.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Bytes(#[serde(with = "hex")] pub Vec<u8>);
+
+impl std::fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(&self.0))
+    }
+}
+
+impl From<&[u8]> for Bytes {
+    fn from(value: &[u8]) -> Self {
+        Self(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(value: Vec<u8>) -> Self {
+        Self(value)
+    }
+}
+
+impl From<Box<[u8]>> for Bytes {
+    fn from(value: Box<[u8]>) -> Self {
+        Self(value.into_vec())
+    }
+}
+
+/// A [`PreKeyBundle`] in a form that can be stored in a transcript.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedPreKeyBundle {
+    pub registration_id: u32,
+    pub device_id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_key_id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_key_public: Option<Bytes>,
+    pub signed_pre_key_id: u32,
+    pub signed_pre_key_public: Bytes,
+    pub signed_pre_key_signature: Bytes,
+    pub identity_key: Bytes,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kyber_pre_key_id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kyber_pre_key_public: Option<Bytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kyber_pre_key_signature: Option<Bytes>,
+}
+
+impl TryFrom<&PreKeyBundle> for RecordedPreKeyBundle {
+    type Error = SignalProtocolError;
+
+    fn try_from(bundle: &PreKeyBundle) -> Result<Self, Self::Error> {
+        Ok(Self {
+            registration_id: bundle.registration_id()?,
+            device_id: bundle.device_id()?.into(),
+            pre_key_id: bundle.pre_key_id()?.map(u32::from),
+            pre_key_public: bundle.pre_key_public()?.map(|key| key.serialize().into()),
+            signed_pre_key_id: bundle.signed_pre_key_id()?.into(),
+            signed_pre_key_public: bundle.signed_pre_key_public()?.serialize().into(),
+            signed_pre_key_signature: bundle.signed_pre_key_signature()?.into(),
+            identity_key: bundle.identity_key()?.serialize().into(),
+            kyber_pre_key_id: bundle.kyber_pre_key_id()?.map(u32::from),
+            kyber_pre_key_public: bundle
+                .kyber_pre_key_public()?
+                .map(|key| key.serialize().into()),
+            kyber_pre_key_signature: bundle.kyber_pre_key_signature()?.map(Bytes::from),
+        })
+    }
+}
+
+impl TryFrom<&RecordedPreKeyBundle> for PreKeyBundle {
+    type Error = SignalProtocolError;
+
+    fn try_from(recorded: &RecordedPreKeyBundle) -> Result<Self, Self::Error> {
+        PreKeyBundleContent {
+            registration_id: Some(recorded.registration_id),
+            device_id: Some(recorded.device_id.into()),
+            pre_key_id: recorded.pre_key_id.map(PreKeyId::from),
+            pre_key_public: recorded
+                .pre_key_public
+                .as_ref()
+                .map(|key| PublicKey::deserialize(&key.0))
+                .transpose()?,
+            ec_pre_key_id: Some(recorded.signed_pre_key_id.into()),
+            ec_pre_key_public: Some(PublicKey::deserialize(&recorded.signed_pre_key_public.0)?),
+            ec_pre_key_signature: Some(recorded.signed_pre_key_signature.0.clone()),
+            identity_key: Some(IdentityKey::decode(&recorded.identity_key.0)?),
+            kyber_pre_key_id: recorded.kyber_pre_key_id.map(KyberPreKeyId::from),
+            kyber_pre_key_public: recorded
+                .kyber_pre_key_public
+                .as_ref()
+                .map(|key| kem::PublicKey::deserialize(&key.0))
+                .transpose()?,
+            kyber_pre_key_signature: recorded
+                .kyber_pre_key_signature
+                .as_ref()
+                .map(|signature| signature.0.clone()),
+        }
+        .try_into()
+    }
+}
+
+/// Serialization helpers shared by the recorder and the replayer, so both sides agree on how
+/// each record type is represented.
+trait SnapshotRecord: Sized {
+    fn to_snapshot(&self) -> Result<Bytes, SignalProtocolError>;
+    fn from_snapshot(bytes: &Bytes) -> Result<Self, SignalProtocolError>;
+}
+
+impl SnapshotRecord for IdentityKeyPair {
+    fn to_snapshot(&self) -> Result<Bytes, SignalProtocolError> {
+        Ok(self.serialize().into())
+    }
+    fn from_snapshot(bytes: &Bytes) -> Result<Self, SignalProtocolError> {
+        IdentityKeyPair::try_from(&bytes.0[..])
+    }
+}
+
+impl SnapshotRecord for IdentityKey {
+    fn to_snapshot(&self) -> Result<Bytes, SignalProtocolError> {
+        Ok(self.serialize().into())
+    }
+    fn from_snapshot(bytes: &Bytes) -> Result<Self, SignalProtocolError> {
+        IdentityKey::decode(&bytes.0)
+    }
+}
+
+impl SnapshotRecord for SessionRecord {
+    fn to_snapshot(&self) -> Result<Bytes, SignalProtocolError> {
+        Ok(self.serialize()?.into())
+    }
+    fn from_snapshot(bytes: &Bytes) -> Result<Self, SignalProtocolError> {
+        SessionRecord::deserialize(&bytes.0)
+    }
+}
+
+impl SnapshotRecord for PreKeyRecord {
+    fn to_snapshot(&self) -> Result<Bytes, SignalProtocolError> {
+        Ok(self.serialize()?.into())
+    }
+    fn from_snapshot(bytes: &Bytes) -> Result<Self, SignalProtocolError> {
+        PreKeyRecord::deserialize(&bytes.0)
+    }
+}
+
+impl SnapshotRecord for SignedPreKeyRecord {
+    fn to_snapshot(&self) -> Result<Bytes, SignalProtocolError> {
+        Ok(GenericSignedPreKey::serialize(self)?.into())
+    }
+    fn from_snapshot(bytes: &Bytes) -> Result<Self, SignalProtocolError> {
+        <SignedPreKeyRecord as GenericSignedPreKey>::deserialize(&bytes.0)
+    }
+}
+
+impl SnapshotRecord for KyberPreKeyRecord {
+    fn to_snapshot(&self) -> Result<Bytes, SignalProtocolError> {
+        Ok(GenericSignedPreKey::serialize(self)?.into())
+    }
+    fn from_snapshot(bytes: &Bytes) -> Result<Self, SignalProtocolError> {
+        <KyberPreKeyRecord as GenericSignedPreKey>::deserialize(&bytes.0)
+    }
+}
@@ -0,0 +1,482 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::cell::RefCell;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use futures_util::lock::Mutex;
+use libsignal_protocol_current::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+use super::{
+    find_by_id, set_by_id, Address, Bytes, IdRecord, Operation, RecordedPreKeyBundle,
+    SnapshotRecord, Step, StoreSnapshot, Transcript,
+};
+
+type Result<T> = std::result::Result<T, SignalProtocolError>;
+
+/// Captures calls to the cipher entry points as a [`Transcript`].
+///
+/// Any [`ProtocolStore`] can be used; the recorder wraps it for the duration of each call to
+/// observe which records are read and written.
+pub struct Recorder {
+    transcript: Transcript,
+    seeds: ChaCha20Rng,
+}
+
+impl Recorder {
+    /// Creates a recorder whose per-step RNG seeds are chosen randomly.
+    pub fn new(description: impl Into<String>) -> Self {
+        Self::with_seed(description, rand::thread_rng().gen())
+    }
+
+    /// Creates a recorder whose per-step RNG seeds are derived from `seed`.
+    ///
+    /// Recording the same scenario twice with the same seed and the same timestamps produces the
+    /// same transcript.
+    pub fn with_seed(description: impl Into<String>, seed: [u8; 32]) -> Self {
+        Self {
+            transcript: Transcript::new(description),
+            seeds: ChaCha20Rng::from_seed(seed),
+        }
+    }
+
+    pub fn transcript(&self) -> &Transcript {
+        &self.transcript
+    }
+
+    pub fn into_transcript(self) -> Transcript {
+        self.transcript
+    }
+
+    pub async fn process_pre_key_bundle<S: ProtocolStore>(
+        &mut self,
+        store: &mut S,
+        local: &ProtocolAddress,
+        remote: &ProtocolAddress,
+        bundle: &PreKeyBundle,
+        now: SystemTime,
+    ) -> Result<()> {
+        let recorded_bundle = RecordedPreKeyBundle::try_from(bundle)?;
+        let (timestamp, now) = truncate_to_millis(now);
+        let (rng_seed, mut rng) = self.next_rng();
+
+        let recording = RecordingStore::new(store);
+        let (mut session_store, mut identity_store) = (&recording, &recording);
+        process_prekey_bundle(
+            remote,
+            &mut session_store,
+            &mut identity_store,
+            bundle,
+            now,
+            &mut rng,
+        )
+        .await?;
+        let (before, after) = recording.finish();
+
+        self.transcript.steps.push(Step {
+            local: local.into(),
+            remote: remote.into(),
+            rng_seed,
+            timestamp: Some(timestamp),
+            before,
+            operation: Operation::ProcessPreKeyBundle {
+                bundle: recorded_bundle,
+            },
+            after,
+        });
+        Ok(())
+    }
+
+    pub async fn encrypt<S: ProtocolStore>(
+        &mut self,
+        store: &mut S,
+        local: &ProtocolAddress,
+        remote: &ProtocolAddress,
+        ptext: &[u8],
+        now: SystemTime,
+    ) -> Result<CiphertextMessage> {
+        let (timestamp, now) = truncate_to_millis(now);
+        // message_encrypt doesn't take an RNG, but every step gets a seed so that replaying
+        // stays uniform if that ever changes.
+        let (rng_seed, _rng) = self.next_rng();
+
+        let recording = RecordingStore::new(store);
+        let (mut session_store, mut identity_store) = (&recording, &recording);
+        let ciphertext =
+            message_encrypt(ptext, remote, &mut session_store, &mut identity_store, now).await?;
+        let (before, after) = recording.finish();
+
+        self.transcript.steps.push(Step {
+            local: local.into(),
+            remote: remote.into(),
+            rng_seed,
+            timestamp: Some(timestamp),
+            before,
+            operation: Operation::Encrypt {
+                plaintext: ptext.into(),
+                message_type: ciphertext.message_type() as u8,
+                ciphertext: ciphertext.serialize().into(),
+            },
+            after,
+        });
+        Ok(ciphertext)
+    }
+
+    pub async fn decrypt<S: ProtocolStore>(
+        &mut self,
+        store: &mut S,
+        local: &ProtocolAddress,
+        remote: &ProtocolAddress,
+        ciphertext: &CiphertextMessage,
+    ) -> Result<Vec<u8>> {
+        let (rng_seed, mut rng) = self.next_rng();
+
+        let recording = RecordingStore::new(store);
+        let (
+            mut session_store,
+            mut identity_store,
+            mut pre_key_store,
+            signed_pre_key_store,
+            mut kyber_pre_key_store,
+        ) = (&recording, &recording, &recording, &recording, &recording);
+        let ptext = message_decrypt(
+            ciphertext,
+            remote,
+            &mut session_store,
+            &mut identity_store,
+            &mut pre_key_store,
+            &signed_pre_key_store,
+            &mut kyber_pre_key_store,
+            &mut rng,
+        )
+        .await?;
+        let (before, after) = recording.finish();
+
+        self.transcript.steps.push(Step {
+            local: local.into(),
+            remote: remote.into(),
+            rng_seed,
+            timestamp: None,
+            before,
+            operation: Operation::Decrypt {
+                message_type: ciphertext.message_type() as u8,
+                ciphertext: ciphertext.serialize().into(),
+                plaintext: ptext.as_slice().into(),
+            },
+            after,
+        });
+        Ok(ptext)
+    }
+
+    fn next_rng(&mut self) -> (Bytes, ChaCha20Rng) {
+        let seed: [u8; 32] = self.seeds.gen();
+        (seed.as_slice().into(), ChaCha20Rng::from_seed(seed))
+    }
+}
+
+/// Transcripts store timestamps in milliseconds, so make sure the recorded operation sees exactly
+/// what the replayed one will.
+fn truncate_to_millis(now: SystemTime) -> (u64, SystemTime) {
+    let millis = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("after the epoch")
+        .as_millis()
+        .try_into()
+        .expect("representable");
+    (
+        millis,
+        SystemTime::UNIX_EPOCH + Duration::from_millis(millis),
+    )
+}
+
+/// Forwards to a wrapped store, keeping track of the records seen along the way.
+///
+/// The store traits are implemented on `&RecordingStore` rather than `RecordingStore` so that a
+/// single wrapper can be passed as several `&mut dyn` stores to the same cipher call.
+///
+/// The replayer uses this too, to find out which pre-keys an operation removed or marked as used.
+pub(super) struct RecordingStore<'a, S> {
+    // An async lock, because the guard has to be held across the wrapped store's futures. It will
+    // never be contended; cipher operations access their stores one at a time.
+    store: Mutex<&'a mut S>,
+    before: RefCell<StoreSnapshot>,
+    after: RefCell<StoreSnapshot>,
+}
+
+impl<'a, S: ProtocolStore> RecordingStore<'a, S> {
+    pub(super) fn new(store: &'a mut S) -> Self {
+        Self {
+            store: Mutex::new(store),
+            before: Default::default(),
+            after: Default::default(),
+        }
+    }
+
+    pub(super) fn finish(self) -> (StoreSnapshot, StoreSnapshot) {
+        (self.before.into_inner(), self.after.into_inner())
+    }
+
+    /// Records the state of an identity as read, unless this operation has already changed it.
+    fn saw_identity(&self, address: &ProtocolAddress, identity: &IdentityKey) -> Result<()> {
+        let address = Address::from(address);
+        if self.after.borrow().identity(&address).is_some()
+            || self.before.borrow().identity(&address).is_some()
+        {
+            return Ok(());
+        }
+        self.before
+            .borrow_mut()
+            .set_identity(address, identity.to_snapshot()?);
+        Ok(())
+    }
+
+    fn saw_session(&self, address: &ProtocolAddress, record: &SessionRecord) -> Result<()> {
+        let address = Address::from(address);
+        if self.after.borrow().session(&address).is_some()
+            || self.before.borrow().session(&address).is_some()
+        {
+            return Ok(());
+        }
+        self.before
+            .borrow_mut()
+            .set_session(address, record.to_snapshot()?);
+        Ok(())
+    }
+}
+
+/// Records `record` into `before` if neither `before` nor `after` has an entry for `id` yet.
+fn saw_by_id(
+    before: &mut Vec<IdRecord>,
+    after: &[IdRecord],
+    id: u32,
+    record: &impl SnapshotRecord,
+) -> Result<()> {
+    if find_by_id(after, id).is_none() && find_by_id(before, id).is_none() {
+        set_by_id(before, id, record.to_snapshot()?);
+    }
+    Ok(())
+}
+
+#[async_trait(?Send)]
+impl<S: ProtocolStore> IdentityKeyStore for &RecordingStore<'_, S> {
+    async fn get_identity_key_pair(&self) -> Result<IdentityKeyPair> {
+        let key_pair = self.store.lock().await.get_identity_key_pair().await?;
+        self.before
+            .borrow_mut()
+            .identity_key_pair
+            .get_or_insert(key_pair.to_snapshot()?);
+        Ok(key_pair)
+    }
+
+    async fn get_local_registration_id(&self) -> Result<u32> {
+        let registration_id = self.store.lock().await.get_local_registration_id().await?;
+        self.before
+            .borrow_mut()
+            .registration_id
+            .get_or_insert(registration_id);
+        Ok(registration_id)
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+    ) -> Result<bool> {
+        let replaced = self
+            .store
+            .lock()
+            .await
+            .save_identity(address, identity)
+            .await?;
+        self.after
+            .borrow_mut()
+            .set_identity(address.into(), identity.to_snapshot()?);
+        Ok(replaced)
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        direction: Direction,
+    ) -> Result<bool> {
+        let trusted = self
+            .store
+            .lock()
+            .await
+            .is_trusted_identity(address, identity, direction)
+            .await?;
+        if trusted {
+            // Replaying with this identity already saved reproduces the same answer.
+            self.saw_identity(address, identity)?;
+        }
+        Ok(trusted)
+    }
+
+    async fn get_identity(&self, address: &ProtocolAddress) -> Result<Option<IdentityKey>> {
+        let identity = self.store.lock().await.get_identity(address).await?;
+        if let Some(identity) = &identity {
+            self.saw_identity(address, identity)?;
+        }
+        Ok(identity)
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: ProtocolStore> SessionStore for &RecordingStore<'_, S> {
+    async fn load_session(&self, address: &ProtocolAddress) -> Result<Option<SessionRecord>> {
+        let record = self.store.lock().await.load_session(address).await?;
+        if let Some(record) = &record {
+            self.saw_session(address, record)?;
+        }
+        Ok(record)
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+    ) -> Result<()> {
+        self.store
+            .lock()
+            .await
+            .store_session(address, record)
+            .await?;
+        self.after
+            .borrow_mut()
+            .set_session(address.into(), record.to_snapshot()?);
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: ProtocolStore> PreKeyStore for &RecordingStore<'_, S> {
+    async fn get_pre_key(&self, prekey_id: PreKeyId) -> Result<PreKeyRecord> {
+        let record = self.store.lock().await.get_pre_key(prekey_id).await?;
+        saw_by_id(
+            &mut self.before.borrow_mut().pre_keys,
+            &self.after.borrow().pre_keys,
+            prekey_id.into(),
+            &record,
+        )?;
+        Ok(record)
+    }
+
+    async fn save_pre_key(&mut self, prekey_id: PreKeyId, record: &PreKeyRecord) -> Result<()> {
+        self.store
+            .lock()
+            .await
+            .save_pre_key(prekey_id, record)
+            .await?;
+        set_by_id(
+            &mut self.after.borrow_mut().pre_keys,
+            prekey_id.into(),
+            record.to_snapshot()?,
+        );
+        Ok(())
+    }
+
+    async fn remove_pre_key(&mut self, prekey_id: PreKeyId) -> Result<()> {
+        self.store.lock().await.remove_pre_key(prekey_id).await?;
+        self.after
+            .borrow_mut()
+            .removed_pre_keys
+            .push(prekey_id.into());
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: ProtocolStore> SignedPreKeyStore for &RecordingStore<'_, S> {
+    async fn get_signed_pre_key(
+        &self,
+        signed_prekey_id: SignedPreKeyId,
+    ) -> Result<SignedPreKeyRecord> {
+        let record = self
+            .store
+            .lock()
+            .await
+            .get_signed_pre_key(signed_prekey_id)
+            .await?;
+        saw_by_id(
+            &mut self.before.borrow_mut().signed_pre_keys,
+            &self.after.borrow().signed_pre_keys,
+            signed_prekey_id.into(),
+            &record,
+        )?;
+        Ok(record)
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        signed_prekey_id: SignedPreKeyId,
+        record: &SignedPreKeyRecord,
+    ) -> Result<()> {
+        self.store
+            .lock()
+            .await
+            .save_signed_pre_key(signed_prekey_id, record)
+            .await?;
+        set_by_id(
+            &mut self.after.borrow_mut().signed_pre_keys,
+            signed_prekey_id.into(),
+            record.to_snapshot()?,
+        );
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<S: ProtocolStore> KyberPreKeyStore for &RecordingStore<'_, S> {
+    async fn get_kyber_pre_key(&self, kyber_prekey_id: KyberPreKeyId) -> Result<KyberPreKeyRecord> {
+        let record = self
+            .store
+            .lock()
+            .await
+            .get_kyber_pre_key(kyber_prekey_id)
+            .await?;
+        saw_by_id(
+            &mut self.before.borrow_mut().kyber_pre_keys,
+            &self.after.borrow().kyber_pre_keys,
+            kyber_prekey_id.into(),
+            &record,
+        )?;
+        Ok(record)
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: KyberPreKeyId,
+        record: &KyberPreKeyRecord,
+    ) -> Result<()> {
+        self.store
+            .lock()
+            .await
+            .save_kyber_pre_key(kyber_prekey_id, record)
+            .await?;
+        set_by_id(
+            &mut self.after.borrow_mut().kyber_pre_keys,
+            kyber_prekey_id.into(),
+            record.to_snapshot()?,
+        );
+        Ok(())
+    }
+
+    async fn mark_kyber_pre_key_used(&mut self, kyber_prekey_id: KyberPreKeyId) -> Result<()> {
+        self.store
+            .lock()
+            .await
+            .mark_kyber_pre_key_used(kyber_prekey_id)
+            .await?;
+        self.after
+            .borrow_mut()
+            .used_kyber_pre_keys
+            .push(kyber_prekey_id.into());
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::borrow::Cow;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use futures_util::FutureExt;
+use libsignal_protocol_current::*;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+
+use super::record::RecordingStore;
+use super::{Bytes, Operation, SnapshotRecord, Step, StoreSnapshot, Transcript};
+
+/// Controls how strictly [`replay_with_options`] compares against the transcript.
+///
+/// Decrypted plaintexts are always checked. The other checks only hold as long as the library
+/// produces byte-for-byte identical output, which is expected within a version but not across
+/// versions.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReplayOptions {
+    /// Require encryption to produce exactly the recorded ciphertext.
+    pub check_ciphertexts: bool,
+    /// Require every record written during a step to match the recorded one, and the same pre-keys
+    /// to be removed or marked as used.
+    ///
+    /// The session written when processing a bundle with a Kyber pre-key is not compared, because
+    /// Kyber encapsulation draws from the OS RNG rather than the step's RNG.
+    pub check_store_writes: bool,
+}
+
+impl ReplayOptions {
+    pub const STRICT: Self = Self {
+        check_ciphertexts: true,
+        check_store_writes: true,
+    };
+}
+
+#[derive(Debug)]
+pub struct ReplayError {
+    pub step: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step {}: {}", self.step, self.reason)
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// Replays `transcript` against the current library, checking that every recorded message still
+/// decrypts to the recorded plaintext.
+pub fn replay(transcript: &Transcript) -> Result<(), ReplayError> {
+    replay_with_options(transcript, ReplayOptions::default())
+}
+
+pub fn replay_with_options(
+    transcript: &Transcript,
+    options: ReplayOptions,
+) -> Result<(), ReplayError> {
+    if transcript.format_version != super::TRANSCRIPT_FORMAT_VERSION {
+        return Err(ReplayError {
+            step: 0,
+            reason: format!(
+                "unsupported transcript format version {}",
+                transcript.format_version
+            ),
+        });
+    }
+
+    for (index, step) in transcript.steps.iter().enumerate() {
+        log::debug!(
+            "replaying step {}: {} -> {}",
+            index,
+            step.local.name,
+            step.remote.name
+        );
+        replay_step(step, options).map_err(|reason| ReplayError {
+            step: index,
+            reason,
+        })?;
+    }
+    Ok(())
+}
+
+fn replay_step(step: &Step, options: ReplayOptions) -> Result<(), String> {
+    let mut store = restore_store(&step.before).map_err(|e| format!("invalid snapshot: {e}"))?;
+    let remote = ProtocolAddress::from(&step.remote);
+    let mut rng = ChaCha20Rng::from_seed(
+        step.rng_seed
+            .0
+            .as_slice()
+            .try_into()
+            .map_err(|_| "RNG seed must be 32 bytes".to_owned())?,
+    );
+    let now = step
+        .timestamp
+        .map(|millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis));
+
+    let recording = RecordingStore::new(&mut store);
+    let (
+        mut session_store,
+        mut identity_store,
+        mut pre_key_store,
+        signed_pre_key_store,
+        mut kyber_pre_key_store,
+    ) = (&recording, &recording, &recording, &recording, &recording);
+
+    match &step.operation {
+        Operation::ProcessPreKeyBundle { bundle } => {
+            let bundle = PreKeyBundle::try_from(bundle).map_err(|e| format!("bad bundle: {e}"))?;
+            process_prekey_bundle(
+                &remote,
+                &mut session_store,
+                &mut identity_store,
+                &bundle,
+                now.ok_or("missing timestamp")?,
+                &mut rng,
+            )
+            .now_or_never()
+            .expect("synchronous")
+            .map_err(|e| format!("failed to process bundle: {e}"))?;
+        }
+        Operation::Encrypt {
+            plaintext,
+            message_type,
+            ciphertext,
+        } => {
+            let encrypted = message_encrypt(
+                &plaintext.0,
+                &remote,
+                &mut session_store,
+                &mut identity_store,
+                now.ok_or("missing timestamp")?,
+            )
+            .now_or_never()
+            .expect("synchronous")
+            .map_err(|e| format!("failed to encrypt: {e}"))?;
+            if encrypted.message_type() as u8 != *message_type {
+                return Err(format!(
+                    "expected message type {}, got {:?}",
+                    message_type,
+                    encrypted.message_type()
+                ));
+            }
+            if options.check_ciphertexts && encrypted.serialize() != ciphertext.0 {
+                return Err(format!(
+                    "ciphertext mismatch: expected {:?}, got {:?}",
+                    ciphertext,
+                    Bytes::from(encrypted.serialize())
+                ));
+            }
+        }
+        Operation::Decrypt {
+            message_type,
+            ciphertext,
+            plaintext,
+        } => {
+            let message = parse_ciphertext(*message_type, &ciphertext.0)
+                .map_err(|e| format!("failed to parse ciphertext: {e}"))?;
+            let decrypted = message_decrypt(
+                &message,
+                &remote,
+                &mut session_store,
+                &mut identity_store,
+                &mut pre_key_store,
+                &signed_pre_key_store,
+                &mut kyber_pre_key_store,
+                &mut rng,
+            )
+            .now_or_never()
+            .expect("synchronous")
+            .map_err(|e| format!("failed to decrypt: {e}"))?;
+            if decrypted != plaintext.0 {
+                return Err(format!(
+                    "plaintext mismatch: expected {:?}, got {:?}",
+                    plaintext,
+                    Bytes::from(decrypted)
+                ));
+            }
+        }
+    }
+
+    let (_, observed) = recording.finish();
+
+    if options.check_store_writes {
+        let mut expected = Cow::Borrowed(&step.after);
+        if let Operation::ProcessPreKeyBundle { bundle } = &step.operation {
+            if bundle.kyber_pre_key_id.is_some() {
+                expected.to_mut().sessions.clear();
+            }
+        }
+        check_writes(&store, &expected, &observed)?;
+    }
+    Ok(())
+}
+
+fn parse_ciphertext(message_type: u8, bytes: &[u8]) -> Result<CiphertextMessage, String> {
+    match CiphertextMessageType::try_from(message_type) {
+        Ok(CiphertextMessageType::Whisper) => Ok(CiphertextMessage::SignalMessage(
+            SignalMessage::try_from(bytes).map_err(|e| e.to_string())?,
+        )),
+        Ok(CiphertextMessageType::PreKey) => Ok(CiphertextMessage::PreKeySignalMessage(
+            PreKeySignalMessage::try_from(bytes).map_err(|e| e.to_string())?,
+        )),
+        _ => Err(format!("unexpected 1:1 message type {message_type}")),
+    }
+}
+
+/// Builds an in-memory store containing exactly the records in `snapshot`.
+fn restore_store(
+    snapshot: &StoreSnapshot,
+) -> Result<InMemSignalProtocolStore, SignalProtocolError> {
+    let identity_key_pair = match &snapshot.identity_key_pair {
+        Some(bytes) => IdentityKeyPair::from_snapshot(bytes)?,
+        // The operation never asked for it, so any key will do. Don't take it from the step's RNG,
+        // though, or the operation would see different randomness than when it was recorded.
+        None => IdentityKeyPair::generate(&mut ChaCha20Rng::from_seed([0; 32])),
+    };
+    let mut store =
+        InMemSignalProtocolStore::new(identity_key_pair, snapshot.registration_id.unwrap_or(0))?;
+
+    async {
+        for entry in &snapshot.identities {
+            store
+                .save_identity(
+                    &(&entry.address).into(),
+                    &IdentityKey::from_snapshot(&entry.record)?,
+                )
+                .await?;
+        }
+        for entry in &snapshot.sessions {
+            store
+                .store_session(
+                    &(&entry.address).into(),
+                    &SessionRecord::from_snapshot(&entry.record)?,
+                )
+                .await?;
+        }
+        for entry in &snapshot.pre_keys {
+            store
+                .save_pre_key(
+                    entry.id.into(),
+                    &PreKeyRecord::from_snapshot(&entry.record)?,
+                )
+                .await?;
+        }
+        for entry in &snapshot.signed_pre_keys {
+            store
+                .save_signed_pre_key(
+                    entry.id.into(),
+                    &SignedPreKeyRecord::from_snapshot(&entry.record)?,
+                )
+                .await?;
+        }
+        for entry in &snapshot.kyber_pre_keys {
+            store
+                .save_kyber_pre_key(
+                    entry.id.into(),
+                    &KyberPreKeyRecord::from_snapshot(&entry.record)?,
+                )
+                .await?;
+        }
+        Ok::<_, SignalProtocolError>(())
+    }
+    .now_or_never()
+    .expect("synchronous")?;
+
+    Ok(store)
+}
+
+/// Compares the records in `store` with the ones recorded in `expected`, and the pre-keys the
+/// replayed operation removed or marked as used (as `observed` while replaying) with the recorded
+/// ones.
+fn check_writes(
+    store: &InMemSignalProtocolStore,
+    expected: &StoreSnapshot,
+    observed: &StoreSnapshot,
+) -> Result<(), String> {
+    async {
+        for entry in &expected.identities {
+            let actual = store
+                .get_identity(&(&entry.address).into())
+                .await
+                .map_err(|e| e.to_string())?
+                .map(|identity| identity.serialize().into());
+            if actual.as_ref() != Some(&entry.record) {
+                return Err(format!("identity mismatch for {:?}", entry.address));
+            }
+        }
+        for entry in &expected.sessions {
+            let actual = store
+                .load_session(&(&entry.address).into())
+                .await
+                .map_err(|e| e.to_string())?
+                .map(|session| session.to_snapshot())
+                .transpose()
+                .map_err(|e| e.to_string())?;
+            if actual.as_ref() != Some(&entry.record) {
+                return Err(format!("session mismatch for {:?}", entry.address));
+            }
+        }
+        for entry in &expected.pre_keys {
+            // A pre-key saved and then removed in the same step is checked below instead.
+            if expected.removed_pre_keys.contains(&entry.id) {
+                continue;
+            }
+            let actual = store
+                .get_pre_key(entry.id.into())
+                .await
+                .and_then(|record| record.to_snapshot())
+                .map_err(|e| format!("pre-key {}: {e}", entry.id))?;
+            if actual != entry.record {
+                return Err(format!("pre-key mismatch for {}", entry.id));
+            }
+        }
+        for entry in &expected.signed_pre_keys {
+            let actual = store
+                .get_signed_pre_key(entry.id.into())
+                .await
+                .and_then(|record| record.to_snapshot())
+                .map_err(|e| format!("signed pre-key {}: {e}", entry.id))?;
+            if actual != entry.record {
+                return Err(format!("signed pre-key mismatch for {}", entry.id));
+            }
+        }
+        for entry in &expected.kyber_pre_keys {
+            let actual = store
+                .get_kyber_pre_key(entry.id.into())
+                .await
+                .and_then(|record| record.to_snapshot())
+                .map_err(|e| format!("Kyber pre-key {}: {e}", entry.id))?;
+            if actual != entry.record {
+                return Err(format!("Kyber pre-key mismatch for {}", entry.id));
+            }
+        }
+        for id in &expected.removed_pre_keys {
+            if store.get_pre_key((*id).into()).await.is_ok() {
+                return Err(format!("pre-key {id} should have been removed"));
+            }
+        }
+        if observed.removed_pre_keys != expected.removed_pre_keys {
+            return Err(format!(
+                "expected pre-keys {:?} to be removed, got {:?}",
+                expected.removed_pre_keys, observed.removed_pre_keys
+            ));
+        }
+        if observed.used_kyber_pre_keys != expected.used_kyber_pre_keys {
+            return Err(format!(
+                "expected Kyber pre-keys {:?} to be marked used, got {:?}",
+                expected.used_kyber_pre_keys, observed.used_kyber_pre_keys
+            ));
+        }
+        Ok(())
+    }
+    .now_or_never()
+    .expect("synchronous")
+}
@@ -0,0 +1,403 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use futures_util::FutureExt;
+use libsignal_protocol_cross_version_testing::transcript::*;
+use libsignal_protocol_current::*;
+use rand::{CryptoRng, Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+const SEED: [u8; 32] = [0x42; 32];
+
+fn corpus_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/res/transcripts")
+}
+
+fn new_store<R: Rng + CryptoRng>(rng: &mut R) -> InMemSignalProtocolStore {
+    let identity_key = IdentityKeyPair::generate(rng);
+    // Valid registration IDs fit in 14 bits.
+    let registration_id: u8 = rng.gen();
+    InMemSignalProtocolStore::new(identity_key, registration_id.into()).expect("can initialize")
+}
+
+/// Which pre-keys Bob publishes, and therefore which key agreement Alice uses.
+#[derive(Clone, Copy)]
+enum Agreement {
+    X3dh,
+    Pqxdh,
+}
+
+fn create_pre_key_bundle<R: Rng + CryptoRng>(
+    store: &mut InMemSignalProtocolStore,
+    device_id: DeviceId,
+    timestamp: Timestamp,
+    agreement: Agreement,
+    rng: &mut R,
+) -> PreKeyBundle {
+    let pre_key_pair = KeyPair::generate(rng);
+    let signed_pre_key_pair = KeyPair::generate(rng);
+    let identity_key_pair = store
+        .get_identity_key_pair()
+        .now_or_never()
+        .expect("synchronous")
+        .expect("can fetch identity key");
+    let signed_pre_key_signature = identity_key_pair
+        .private_key()
+        .calculate_signature(&signed_pre_key_pair.public_key.serialize(), rng)
+        .expect("can calculate signatures");
+
+    let pre_key_id = PreKeyId::from(rng.gen::<u32>());
+    let signed_pre_key_id = SignedPreKeyId::from(rng.gen::<u32>());
+
+    store
+        .save_pre_key(pre_key_id, &PreKeyRecord::new(pre_key_id, &pre_key_pair))
+        .now_or_never()
+        .expect("synchronous")
+        .expect("can save pre-keys");
+    store
+        .save_signed_pre_key(
+            signed_pre_key_id,
+            &SignedPreKeyRecord::new(
+                signed_pre_key_id,
+                timestamp,
+                &signed_pre_key_pair,
+                &signed_pre_key_signature,
+            ),
+        )
+        .now_or_never()
+        .expect("synchronous")
+        .expect("can save pre-keys");
+
+    let bundle = PreKeyBundle::new(
+        store
+            .get_local_registration_id()
+            .now_or_never()
+            .expect("synchronous")
+            .expect("can fetch registration id"),
+        device_id,
+        Some((pre_key_id, pre_key_pair.public_key)),
+        signed_pre_key_id,
+        signed_pre_key_pair.public_key,
+        signed_pre_key_signature.to_vec(),
+        *identity_key_pair.identity_key(),
+    )
+    .expect("can create pre-key bundles");
+
+    match agreement {
+        Agreement::X3dh => bundle,
+        Agreement::Pqxdh => {
+            // Kyber key generation doesn't take an RNG, so PQXDH sessions can't be recorded
+            // deterministically.
+            let kyber_pre_key_pair = kem::KeyPair::generate(kem::KeyType::Kyber1024);
+            let kyber_pre_key_signature = identity_key_pair
+                .private_key()
+                .calculate_signature(&kyber_pre_key_pair.public_key.serialize(), rng)
+                .expect("can calculate signatures");
+            let kyber_pre_key_id = KyberPreKeyId::from(rng.gen::<u32>());
+            store
+                .save_kyber_pre_key(
+                    kyber_pre_key_id,
+                    &KyberPreKeyRecord::new(
+                        kyber_pre_key_id,
+                        timestamp,
+                        &kyber_pre_key_pair,
+                        &kyber_pre_key_signature,
+                    ),
+                )
+                .now_or_never()
+                .expect("synchronous")
+                .expect("can save pre-keys");
+            bundle.with_kyber_pre_key(
+                kyber_pre_key_id,
+                kyber_pre_key_pair.public_key,
+                kyber_pre_key_signature.to_vec(),
+            )
+        }
+    }
+}
+
+fn send(
+    recorder: &mut Recorder,
+    (from_store, from): (&mut InMemSignalProtocolStore, &ProtocolAddress),
+    to: &ProtocolAddress,
+    ptext: &[u8],
+    now: SystemTime,
+) -> CiphertextMessage {
+    recorder
+        .encrypt(from_store, from, to, ptext, now)
+        .now_or_never()
+        .expect("synchronous")
+        .expect("can encrypt messages")
+}
+
+fn receive(
+    recorder: &mut Recorder,
+    (to_store, to): (&mut InMemSignalProtocolStore, &ProtocolAddress),
+    from: &ProtocolAddress,
+    ctext: &CiphertextMessage,
+    expected: &[u8],
+) {
+    let ptext = recorder
+        .decrypt(to_store, to, from, ctext)
+        .now_or_never()
+        .expect("synchronous")
+        .expect("can decrypt messages");
+    assert_eq!(ptext, expected);
+}
+
+/// Records the same conversation as the live cross-version tests, including out-of-order delivery.
+fn record_basic_session(seed: [u8; 32]) -> Transcript {
+    record_session(seed, Agreement::X3dh)
+}
+
+fn record_pqxdh_session(seed: [u8; 32]) -> Transcript {
+    record_session(seed, Agreement::Pqxdh)
+}
+
+fn record_session(seed: [u8; 32], agreement: Agreement) -> Transcript {
+    let mut rng = ChaCha20Rng::from_seed(seed);
+    let description = match agreement {
+        Agreement::X3dh => "basic session",
+        Agreement::Pqxdh => "PQXDH session",
+    };
+    let mut recorder = Recorder::with_seed(description, rng.gen());
+    // Use a fixed clock so that recording is reproducible.
+    let mut now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let mut tick = || {
+        now += Duration::from_secs(1);
+        now
+    };
+
+    let alice_address = ProtocolAddress::new("alice".to_owned(), 1.into());
+    let bob_address = ProtocolAddress::new("bob".to_owned(), 1.into());
+    let mut alice_store = new_store(&mut rng);
+    let mut bob_store = new_store(&mut rng);
+
+    let bundle = create_pre_key_bundle(
+        &mut bob_store,
+        bob_address.device_id(),
+        Timestamp::from_epoch_millis(1_700_000_000_000),
+        agreement,
+        &mut rng,
+    );
+    recorder
+        .process_pre_key_bundle(
+            &mut alice_store,
+            &alice_address,
+            &bob_address,
+            &bundle,
+            tick(),
+        )
+        .now_or_never()
+        .expect("synchronous")
+        .expect("can process pre-key bundles");
+
+    let ptext = b"L'homme est condamn\xc3\xa9 \xc3\xa0 \xc3\xaatre libre";
+    let ctext = send(
+        &mut recorder,
+        (&mut alice_store, &alice_address),
+        &bob_address,
+        ptext,
+        tick(),
+    );
+    assert_eq!(ctext.message_type(), CiphertextMessageType::PreKey);
+    receive(
+        &mut recorder,
+        (&mut bob_store, &bob_address),
+        &alice_address,
+        &ctext,
+        ptext,
+    );
+
+    let ptext = b"Who watches the watchers?";
+    let ctext = send(
+        &mut recorder,
+        (&mut bob_store, &bob_address),
+        &alice_address,
+        ptext,
+        tick(),
+    );
+    assert_eq!(ctext.message_type(), CiphertextMessageType::Whisper);
+    receive(
+        &mut recorder,
+        (&mut alice_store, &alice_address),
+        &bob_address,
+        &ctext,
+        ptext,
+    );
+
+    let mut delayed = vec![];
+    for i in 0..5 {
+        let ptext = format!("A->B delayed message {}", i);
+        let ctext = send(
+            &mut recorder,
+            (&mut alice_store, &alice_address),
+            &bob_address,
+            ptext.as_bytes(),
+            tick(),
+        );
+        delayed.push((ptext, ctext));
+    }
+
+    for i in 0..5 {
+        let ptext = format!("A->B message {}", i);
+        let ctext = send(
+            &mut recorder,
+            (&mut alice_store, &alice_address),
+            &bob_address,
+            ptext.as_bytes(),
+            tick(),
+        );
+        receive(
+            &mut recorder,
+            (&mut bob_store, &bob_address),
+            &alice_address,
+            &ctext,
+            ptext.as_bytes(),
+        );
+
+        let ptext = format!("B->A message {}", i);
+        let ctext = send(
+            &mut recorder,
+            (&mut bob_store, &bob_address),
+            &alice_address,
+            ptext.as_bytes(),
+            tick(),
+        );
+        receive(
+            &mut recorder,
+            (&mut alice_store, &alice_address),
+            &bob_address,
+            &ctext,
+            ptext.as_bytes(),
+        );
+    }
+
+    for (ptext, ctext) in delayed.iter().rev() {
+        receive(
+            &mut recorder,
+            (&mut bob_store, &bob_address),
+            &alice_address,
+            ctext,
+            ptext.as_bytes(),
+        );
+    }
+
+    recorder.into_transcript()
+}
+
+#[test]
+fn recorded_transcript_replays_exactly() {
+    let transcript = record_basic_session(SEED);
+    replay_with_options(&transcript, ReplayOptions::STRICT).expect("replays");
+}
+
+#[test]
+fn recorded_pqxdh_transcript_replays_exactly() {
+    let transcript = record_pqxdh_session(SEED);
+    let first_decrypt = transcript
+        .steps
+        .iter()
+        .find(|step| matches!(step.operation, Operation::Decrypt { .. }))
+        .expect("has a decryption step");
+    assert_eq!(first_decrypt.before.kyber_pre_keys.len(), 1);
+    assert_eq!(
+        first_decrypt.after.used_kyber_pre_keys,
+        [first_decrypt.before.kyber_pre_keys[0].id]
+    );
+
+    replay_with_options(&transcript, ReplayOptions::STRICT).expect("replays");
+}
+
+#[test]
+fn unused_kyber_pre_key_is_rejected() {
+    let mut transcript = record_pqxdh_session(SEED);
+    let step = transcript
+        .steps
+        .iter_mut()
+        .find(|step| !step.after.used_kyber_pre_keys.is_empty())
+        .expect("has a step that uses a Kyber pre-key");
+    step.after.used_kyber_pre_keys.clear();
+
+    replay(&transcript).expect("only checks plaintexts by default");
+    let err = replay_with_options(&transcript, ReplayOptions::STRICT)
+        .expect_err("should notice the Kyber pre-key was used");
+    assert!(err.reason.contains("Kyber pre-keys"), "{err}");
+}
+
+#[test]
+fn recording_is_deterministic() {
+    assert_eq!(record_basic_session(SEED), record_basic_session(SEED));
+}
+
+#[test]
+fn transcript_json_round_trip() {
+    let transcript = record_basic_session(SEED);
+    let parsed = Transcript::from_json(&transcript.to_json()).expect("valid JSON");
+    assert_eq!(parsed, transcript);
+}
+
+#[test]
+fn tampered_transcript_is_rejected() {
+    let mut transcript = record_basic_session(SEED);
+    let plaintext = transcript
+        .steps
+        .iter_mut()
+        .find_map(|step| match &mut step.operation {
+            Operation::Decrypt { plaintext, .. } => Some(plaintext),
+            _ => None,
+        })
+        .expect("has a decryption step");
+    plaintext.0.push(b'!');
+
+    let err = replay(&transcript).expect_err("should notice the wrong plaintext");
+    assert!(err.reason.contains("plaintext mismatch"), "{err}");
+}
+
+#[test]
+fn corpus_still_decrypts() {
+    let mut paths = std::fs::read_dir(corpus_dir())
+        .expect("corpus directory exists")
+        .map(|entry| entry.expect("can list corpus").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(!paths.is_empty(), "no transcripts found");
+
+    for path in paths {
+        let json = std::fs::read_to_string(&path).expect("can read transcript");
+        let transcript = Transcript::from_json(&json)
+            .unwrap_or_else(|e| panic!("{}: invalid transcript: {e}", path.display()));
+        replay(&transcript).unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+    }
+}
+
+/// Adds transcripts produced by the current version to the corpus, one for each key agreement.
+///
+/// Run this when cutting a release that changes the wire format or session layout:
+///
+/// ```text
+/// TRANSCRIPT_NAME=v0.46.2 cargo test --test transcripts -- --ignored record_corpus_transcript
+/// ```
+#[test]
+#[ignore]
+fn record_corpus_transcript() {
+    let name = std::env::var("TRANSCRIPT_NAME").expect("TRANSCRIPT_NAME must be set");
+    for (agreement, scenario) in [
+        (Agreement::X3dh, "basic-session"),
+        (Agreement::Pqxdh, "pqxdh-session"),
+    ] {
+        let mut transcript = record_session(rand::thread_rng().gen(), agreement);
+        transcript.description = format!("{} recorded with {name}", transcript.description);
+        std::fs::write(
+            corpus_dir().join(format!("{name}-{scenario}.json")),
+            transcript.to_json(),
+        )
+        .expect("can write transcript");
+    }
+}
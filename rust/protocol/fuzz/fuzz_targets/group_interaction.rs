@@ -0,0 +1,187 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+#![no_main]
+
+use futures_util::FutureExt;
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol::*;
+use log::*;
+use rand::prelude::*;
+use uuid::Uuid;
+
+const DISTRIBUTION_ID: Uuid = Uuid::from_u128(0xd1d1d1d1_7000_11eb_b32a_33b8a8a487a6);
+
+struct QueuedMessage {
+    sender: ProtocolAddress,
+    ciphertext: Box<[u8]>,
+    plaintext: Box<[u8]>,
+}
+
+struct Participant {
+    name: &'static str,
+    address: ProtocolAddress,
+    store: InMemSenderKeyStore,
+    has_distributed: bool,
+    /// Sender key messages from other members that haven't been received yet.
+    message_queue: Vec<QueuedMessage>,
+    /// Messages that have already been decrypted, which must not decrypt again.
+    delivered: Vec<(ProtocolAddress, Box<[u8]>)>,
+    /// Skipped message keys stay cached until they're used, so dropped messages count against
+    /// the limit on stored keys forever.
+    dropped_count: usize,
+}
+
+impl Participant {
+    fn new(name: &'static str, phone_number: &str) -> Self {
+        Self {
+            name,
+            address: ProtocolAddress::new(phone_number.to_owned(), 1.into()),
+            store: InMemSenderKeyStore::new(),
+            has_distributed: false,
+            message_queue: Vec::new(),
+            delivered: Vec::new(),
+            dropped_count: 0,
+        }
+    }
+
+    async fn distribute_sender_key(
+        &mut self,
+        others: &mut [&mut Self],
+        rng: &mut (impl Rng + CryptoRng),
+    ) {
+        info!("{}:   distributing sender key", self.name);
+        let distribution_message = create_sender_key_distribution_message(
+            &self.address,
+            DISTRIBUTION_ID,
+            &mut self.store,
+            rng,
+        )
+        .await
+        .unwrap();
+
+        // Test serialization ahead of time.
+        let distribution_message =
+            SenderKeyDistributionMessage::try_from(distribution_message.serialized()).unwrap();
+
+        // Distribution messages go over 1:1 sessions, so they're never lost or reordered.
+        for other in others {
+            process_sender_key_distribution_message(
+                &self.address,
+                &distribution_message,
+                &mut other.store,
+            )
+            .await
+            .unwrap();
+        }
+        self.has_distributed = true;
+    }
+
+    async fn send_message(&mut self, others: &mut [&mut Self], rng: &mut (impl Rng + CryptoRng)) {
+        info!("{}: sending message", self.name);
+        if !self.has_distributed {
+            self.distribute_sender_key(others, rng).await;
+        }
+
+        let length = rng.gen_range(0..140);
+        let mut buffer = vec![0; length];
+        rng.fill_bytes(&mut buffer);
+
+        let outgoing_message = group_encrypt(
+            &mut self.store,
+            &self.address,
+            DISTRIBUTION_ID,
+            &buffer,
+            rng,
+        )
+        .await
+        .unwrap();
+
+        for other in others {
+            other.message_queue.push(QueuedMessage {
+                sender: self.address.clone(),
+                ciphertext: outgoing_message.serialized().into(),
+                plaintext: buffer.clone().into(),
+            });
+        }
+    }
+
+    async fn receive_messages(&mut self) {
+        info!("{}: receiving messages", self.name);
+        for message in std::mem::take(&mut self.message_queue) {
+            let decrypted = group_decrypt(&message.ciphertext, &mut self.store, &message.sender)
+                .await
+                .unwrap();
+            assert_eq!(message.plaintext, decrypted.into());
+            self.delivered.push((message.sender, message.ciphertext));
+        }
+    }
+
+    async fn replay_message(&mut self, rng: &mut (impl Rng + CryptoRng)) {
+        let Some((sender, message)) = self.delivered.choose(rng) else {
+            return;
+        };
+        info!("{}: replaying a delivered message", self.name);
+        let result = group_decrypt(message, &mut self.store, sender).await;
+        assert!(
+            matches!(result, Err(SignalProtocolError::DuplicatedMessage(..))),
+            "{:?}",
+            result
+        );
+    }
+}
+
+fuzz_target!(|data: (u64, &[u8])| {
+    let _ = env_logger::try_init();
+
+    let (seed, actions) = data;
+    async {
+        let mut csprng = StdRng::seed_from_u64(seed);
+
+        let mut participants = [
+            Participant::new("alice", "+14151111111"),
+            Participant::new("bob", "+14151111112"),
+            Participant::new("carol", "+14151111113"),
+        ];
+
+        for action in actions {
+            let (me, mut others) = {
+                let [alice, bob, carol] = &mut participants;
+                match action % 3 {
+                    0 => (alice, [bob, carol]),
+                    1 => (bob, [alice, carol]),
+                    2 => (carol, [alice, bob]),
+                    _ => unreachable!(),
+                }
+            };
+            match action / 3 {
+                0..=24 => me.receive_messages().await,
+                25..=39 => {
+                    info!("{}: drop an incoming message", me.name);
+                    if me.message_queue.pop().is_some() {
+                        me.dropped_count += 1;
+                    }
+                }
+                40..=49 => {
+                    info!("{}: shuffle incoming messages", me.name);
+                    me.message_queue.shuffle(&mut csprng);
+                }
+                50..=54 => me.replay_message(&mut csprng).await,
+                _ => {
+                    // Only send if it can't result in evicting a key that's still needed.
+                    // We're not testing the limit on stored message keys.
+                    if others
+                        .iter()
+                        .all(|other| other.message_queue.len() + other.dropped_count < 1_500)
+                    {
+                        me.send_message(&mut others, &mut csprng).await
+                    }
+                }
+            }
+        }
+    }
+    .now_or_never()
+    .expect("sync");
+});
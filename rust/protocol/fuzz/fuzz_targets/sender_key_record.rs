@@ -0,0 +1,49 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+#![no_main]
+
+use futures_util::FutureExt;
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol::*;
+use rand::prelude::*;
+use uuid::Uuid;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(record) = SenderKeyRecord::deserialize(data) else {
+        return;
+    };
+
+    // Whatever we managed to parse should survive a round trip unchanged.
+    let serialized = record.serialize().expect("can serialize");
+    let reparsed = SenderKeyRecord::deserialize(&serialized).expect("can reparse");
+    assert_eq!(
+        serialized,
+        reparsed.serialize().expect("can serialize again")
+    );
+
+    // Trying to use the record shouldn't panic, no matter how inconsistent its state is.
+    let mut csprng = StdRng::seed_from_u64(0);
+    let mut store = InMemSenderKeyStore::new();
+    let sender = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+    let distribution_id = Uuid::nil();
+    async {
+        store
+            .store_sender_key(&sender, distribution_id, &record)
+            .await
+            .expect("can store sender key");
+        let _: Result<_, _> =
+            group_encrypt(&mut store, &sender, distribution_id, b"hello", &mut csprng).await;
+        let _: Result<_, _> = create_sender_key_distribution_message(
+            &sender,
+            distribution_id,
+            &mut store,
+            &mut csprng,
+        )
+        .await;
+    }
+    .now_or_never()
+    .expect("sync");
+});
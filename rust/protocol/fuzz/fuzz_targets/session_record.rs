@@ -0,0 +1,64 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+#![no_main]
+
+use std::time::SystemTime;
+
+use futures_util::FutureExt;
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol::*;
+use rand::prelude::*;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(mut record) = SessionRecord::deserialize(data) else {
+        return;
+    };
+
+    // Whatever we managed to parse should survive a round trip unchanged.
+    let serialized = record.serialize().expect("can serialize");
+    let reparsed = SessionRecord::deserialize(&serialized).expect("can reparse");
+    assert_eq!(
+        serialized,
+        reparsed.serialize().expect("can serialize again")
+    );
+
+    // None of the accessors should panic, no matter how inconsistent the state is.
+    let _ = record.session_version();
+    let _ = record.local_registration_id();
+    let _ = record.remote_registration_id();
+    let _ = record.local_identity_key_bytes();
+    let _ = record.remote_identity_key_bytes();
+    let _ = record.alice_base_key();
+    let _ = record.get_sender_chain_key_bytes();
+    let _ = record.get_kyber_ciphertext();
+    let _ = record.has_usable_sender_chain(SystemTime::UNIX_EPOCH);
+
+    // Nor should trying to use it.
+    let mut csprng = StdRng::seed_from_u64(0);
+    let mut store = InMemSignalProtocolStore::new(IdentityKeyPair::generate(&mut csprng), 1)
+        .expect("can create store");
+    let address = ProtocolAddress::new("+14151111111".to_owned(), 1.into());
+    async {
+        store
+            .store_session(&address, &record)
+            .await
+            .expect("can store session");
+        let _: Result<_, _> = message_encrypt(
+            b"hello",
+            &address,
+            &mut store.session_store,
+            &mut store.identity_store,
+            SystemTime::UNIX_EPOCH,
+        )
+        .await;
+    }
+    .now_or_never()
+    .expect("sync");
+
+    record.archive_current_state().expect("can archive");
+    SessionRecord::deserialize(&record.serialize().expect("can serialize"))
+        .expect("can reparse after archiving");
+});
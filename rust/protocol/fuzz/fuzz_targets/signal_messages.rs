@@ -0,0 +1,28 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libsignal_protocol::*;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = PreKeySignalMessage::try_from(data) {
+        assert_eq!(message.serialized(), data);
+        let _ = message.message().counter();
+    }
+
+    if let Ok(message) = SenderKeyMessage::try_from(data) {
+        assert_eq!(message.serialized(), data);
+        let _ = message.distribution_id();
+    }
+
+    if let Ok(message) = SenderKeyDistributionMessage::try_from(data) {
+        assert_eq!(message.serialized(), data);
+        let _ = message.distribution_id();
+        let _ = message.chain_key();
+        let _ = message.signing_key();
+    }
+});
@@ -15,6 +15,7 @@ use arrayref::array_ref;
 use curve25519_dalek::scalar;
 use rand::{CryptoRng, Rng};
 use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, ZeroizeOnDrop};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum KeyType {
@@ -131,6 +132,66 @@ impl PublicKey {
         }
     }
 
+    /// Verifies many `(key, message, signature)` triples at once, returning the indices of the
+    /// ones that failed.
+    ///
+    /// When most signatures are valid this is considerably faster than calling
+    /// [`Self::verify_signature`] for each one. The signatures are checked together using random
+    /// weights drawn from `csprng`; if the combined check fails, the batch is split until the
+    /// invalid signatures are isolated.
+    ///
+    /// A signature is reported as failed exactly when [`Self::verify_signature`] would reject it,
+    /// except with negligible probability. Signatures whose key or nonce has a small-order
+    /// component never pass a combined check, so they always end up checked individually.
+    pub fn verify_signatures_batch<R: CryptoRng + Rng>(
+        items: &[(&PublicKey, &[u8], &[u8])],
+        csprng: &mut R,
+    ) -> Result<Vec<usize>> {
+        let mut failed = Vec::new();
+        let mut candidates = Vec::with_capacity(items.len());
+        for (index, (public_key, message, signature)) in items.iter().enumerate() {
+            match (&public_key.key, signature.len()) {
+                (PublicKeyData::DjbPublicKey(pub_key), curve25519::SIGNATURE_LENGTH) => candidates
+                    .push((
+                        index,
+                        (
+                            pub_key,
+                            *message,
+                            array_ref![signature, 0, curve25519::SIGNATURE_LENGTH],
+                        ),
+                    )),
+                _ => failed.push(index),
+            }
+        }
+
+        fn find_invalid<R: CryptoRng + Rng>(
+            candidates: &[(usize, curve25519::BatchItem)],
+            csprng: &mut R,
+            failed: &mut Vec<usize>,
+        ) {
+            match candidates {
+                [] => {}
+                [(index, (pub_key, message, signature))] => {
+                    if !curve25519::PrivateKey::verify_signature(pub_key, &[message], signature) {
+                        failed.push(*index);
+                    }
+                }
+                _ => {
+                    let batch: Vec<_> = candidates.iter().map(|(_, item)| *item).collect();
+                    if !curve25519::PrivateKey::verify_signatures_batch(&batch, csprng) {
+                        let (left, right) = candidates.split_at(candidates.len() / 2);
+                        find_invalid(left, csprng, failed);
+                        find_invalid(right, csprng, failed);
+                    }
+                }
+            }
+        }
+
+        find_invalid(&candidates, csprng, &mut failed);
+        failed.sort_unstable();
+        Ok(failed)
+    }
+
     fn key_data(&self) -> &[u8] {
         match &self.key {
             PublicKeyData::DjbPublicKey(ref k) => k.as_ref(),
@@ -204,12 +265,15 @@ impl fmt::Debug for PublicKey {
     }
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Zeroize)]
 enum PrivateKeyData {
     DjbPrivateKey([u8; curve25519::PRIVATE_KEY_LENGTH]),
 }
 
-#[derive(Clone, Copy, Eq, PartialEq)]
+/// A private key, which is wiped from memory when dropped.
+///
+/// Not `Copy`, since a copy would not be wiped; use [`Clone`] where a second copy is needed.
+#[derive(Clone, Eq, PartialEq, Zeroize, ZeroizeOnDrop)]
 pub struct PrivateKey {
     key: PrivateKeyData,
 }
@@ -219,13 +283,14 @@ impl PrivateKey {
         if value.len() != curve25519::PRIVATE_KEY_LENGTH {
             Err(SignalProtocolError::BadKeyLength(KeyType::Djb, value.len()))
         } else {
-            let mut key = [0u8; curve25519::PRIVATE_KEY_LENGTH];
+            let mut result = Self {
+                key: PrivateKeyData::DjbPrivateKey([0u8; curve25519::PRIVATE_KEY_LENGTH]),
+            };
+            let PrivateKeyData::DjbPrivateKey(key) = &mut result.key;
             key.copy_from_slice(&value[..curve25519::PRIVATE_KEY_LENGTH]);
             // Clamping is not necessary but is kept for backward compatibility
-            key = scalar::clamp_integer(key);
-            Ok(Self {
-                key: PrivateKeyData::DjbPrivateKey(key),
-            })
+            *key = scalar::clamp_integer(*key);
+            Ok(result)
         }
     }
 
@@ -264,19 +329,19 @@ impl PrivateKey {
         message: &[&[u8]],
         csprng: &mut R,
     ) -> Result<Box<[u8]>> {
-        match self.key {
+        match &self.key {
             PrivateKeyData::DjbPrivateKey(k) => {
-                let private_key = curve25519::PrivateKey::from(k);
+                let private_key = curve25519::PrivateKey::from(*k);
                 Ok(Box::new(private_key.calculate_signature(csprng, message)))
             }
         }
     }
 
     pub fn calculate_agreement(&self, their_key: &PublicKey) -> Result<Box<[u8]>> {
-        match (self.key, their_key.key) {
+        match (&self.key, &their_key.key) {
             (PrivateKeyData::DjbPrivateKey(priv_key), PublicKeyData::DjbPublicKey(pub_key)) => {
-                let private_key = curve25519::PrivateKey::from(priv_key);
-                Ok(Box::new(private_key.calculate_agreement(&pub_key)))
+                let private_key = curve25519::PrivateKey::from(*priv_key);
+                Ok(Box::new(private_key.calculate_agreement(pub_key)))
             }
         }
     }
@@ -296,7 +361,8 @@ impl TryFrom<&[u8]> for PrivateKey {
     }
 }
 
-#[derive(Copy, Clone)]
+/// Not `Copy`, because the [`PrivateKey`] it holds isn't.
+#[derive(Clone)]
 pub struct KeyPair {
     pub public_key: PublicKey,
     pub private_key: PrivateKey,
@@ -309,9 +375,9 @@ impl KeyPair {
         let public_key = PublicKey::from(PublicKeyData::DjbPublicKey(
             private_key.derive_public_key_bytes(),
         ));
-        let private_key = PrivateKey::from(PrivateKeyData::DjbPrivateKey(
-            private_key.private_key_bytes(),
-        ));
+        let mut private_key_bytes = private_key.private_key_bytes();
+        let private_key = PrivateKey::from(PrivateKeyData::DjbPrivateKey(private_key_bytes));
+        private_key_bytes.zeroize();
 
         Self {
             public_key,
@@ -393,6 +459,83 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_batch_signatures() -> Result<()> {
+        let mut csprng = OsRng;
+        let key_pairs: Vec<_> = (0..20).map(|_| KeyPair::generate(&mut csprng)).collect();
+        let messages: Vec<_> = (0..20u8).map(|i| vec![i; 100]).collect();
+        let mut signatures = key_pairs
+            .iter()
+            .zip(&messages)
+            .map(|(key_pair, message)| {
+                Ok(key_pair
+                    .calculate_signature(message, &mut csprng)?
+                    .into_vec())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        signatures[3][10] ^= 0x01;
+        signatures[11].truncate(10);
+        signatures[12][63] ^= 0x80;
+        signatures[19] = signatures[18].clone();
+
+        let items: Vec<_> = key_pairs
+            .iter()
+            .zip(&messages)
+            .zip(&signatures)
+            .map(|((key_pair, message), signature)| {
+                (&key_pair.public_key, &message[..], &signature[..])
+            })
+            .collect();
+
+        let failed = PublicKey::verify_signatures_batch(&items, &mut csprng)?;
+        assert_eq!(failed, [3, 11, 12, 19]);
+        for (index, (public_key, message, signature)) in items.iter().enumerate() {
+            assert_eq!(
+                !failed.contains(&index),
+                public_key.verify_signature(message, signature)?
+            );
+        }
+
+        assert!(PublicKey::verify_signatures_batch(&items[13..19], &mut csprng)?.is_empty());
+        assert!(PublicKey::verify_signatures_batch(&[], &mut csprng)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_signatures_with_torsioned_nonce() -> Result<()> {
+        let mut csprng = OsRng;
+        let key_pair = KeyPair::generate(&mut csprng);
+        let message = [0x42; 100];
+        let mut signatures = (0..8)
+            .map(|_| {
+                Ok(key_pair
+                    .calculate_signature(&message, &mut csprng)?
+                    .into_vec())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let PrivateKeyData::DjbPrivateKey(private_key) = &key_pair.private_key.key;
+        signatures[5] = curve25519::PrivateKey::from(*private_key)
+            .calculate_signature_with_torsioned_nonce(&mut csprng, &message)
+            .to_vec();
+
+        assert!(!key_pair
+            .public_key
+            .verify_signature(&message, &signatures[5])?);
+        // The crafted signature fails whether it is checked alone or with valid ones.
+        for (range, crafted_index) in [(5..6, 0), (4..6, 1), (0..8, 5)] {
+            let items: Vec<_> = signatures[range]
+                .iter()
+                .map(|signature| (&key_pair.public_key, &message[..], &signature[..]))
+                .collect();
+            assert_eq!(
+                PublicKey::verify_signatures_batch(&items, &mut csprng)?,
+                [crafted_index]
+            );
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_decode_size() -> Result<()> {
         let mut csprng = OsRng;
@@ -425,4 +568,13 @@ mod tests {
         assert_eq!(&serialized_public[..], &extra_space_decode?.serialize()[..]);
         Ok(())
     }
+
+    #[test]
+    #[allow(unsafe_code)]
+    fn test_private_key_zeroized_on_drop() {
+        let key_pair = KeyPair::generate(&mut OsRng);
+        assert_ne!(key_pair.private_key.serialize(), [0; 32]);
+        // SAFETY: a PrivateKey is just its 32 key bytes, with no padding.
+        unsafe { crate::utils::assert_zeroized_on_drop(key_pair.private_key) };
+    }
 }
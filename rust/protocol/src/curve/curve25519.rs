@@ -3,21 +3,32 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
-use curve25519_dalek::edwards::EdwardsPoint;
+use std::collections::HashSet;
+
+use curve25519_dalek::constants::{ED25519_BASEPOINT_POINT, ED25519_BASEPOINT_TABLE};
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
 use curve25519_dalek::montgomery::MontgomeryPoint;
 use curve25519_dalek::scalar;
 use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::{IsIdentity, VartimeMultiscalarMul};
 use rand::{CryptoRng, Rng};
 use sha2::{Digest, Sha512};
 use subtle::ConstantTimeEq;
 use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{Zeroize, Zeroizing};
 
 const AGREEMENT_LENGTH: usize = 32;
 pub const PRIVATE_KEY_LENGTH: usize = 32;
 pub const PUBLIC_KEY_LENGTH: usize = 32;
 pub const SIGNATURE_LENGTH: usize = 64;
 
+/// A public key, message, and signature to check with [`PrivateKey::verify_signatures_batch`].
+pub type BatchItem<'a> = (
+    &'a [u8; PUBLIC_KEY_LENGTH],
+    &'a [u8],
+    &'a [u8; SIGNATURE_LENGTH],
+);
+
 #[derive(Clone)]
 pub struct PrivateKey {
     secret: StaticSecret,
@@ -29,11 +40,11 @@ impl PrivateKey {
         R: CryptoRng + Rng,
     {
         // This is essentially StaticSecret::random_from_rng only with clamping
-        let mut bytes = [0u8; 32];
-        csprng.fill_bytes(&mut bytes);
-        bytes = scalar::clamp_integer(bytes);
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        csprng.fill_bytes(bytes.as_mut());
+        *bytes = scalar::clamp_integer(*bytes);
 
-        let secret = StaticSecret::from(bytes);
+        let secret = StaticSecret::from(*bytes);
         PrivateKey { secret }
     }
 
@@ -63,11 +74,11 @@ impl PrivateKey {
     where
         R: CryptoRng + Rng,
     {
-        let mut random_bytes = [0u8; 64];
-        csprng.fill_bytes(&mut random_bytes);
+        let mut random_bytes = Zeroizing::new([0u8; 64]);
+        csprng.fill_bytes(random_bytes.as_mut());
 
-        let key_data = self.secret.to_bytes();
-        let a = Scalar::from_bytes_mod_order(key_data);
+        let key_data = Zeroizing::new(self.secret.to_bytes());
+        let mut a = Scalar::from_bytes_mod_order(*key_data);
         let ed_public_key_point = &a * ED25519_BASEPOINT_TABLE;
         let ed_public_key = ed_public_key_point.compress();
         let sign_bit = ed_public_key.as_bytes()[31] & 0b1000_0000_u8;
@@ -86,7 +97,7 @@ impl PrivateKey {
         }
         hash1.update(&random_bytes[..]);
 
-        let r = Scalar::from_hash(hash1);
+        let mut r = Scalar::from_hash(hash1);
         let cap_r = (&r * ED25519_BASEPOINT_TABLE).compress();
 
         let mut hash = Sha512::new();
@@ -98,6 +109,8 @@ impl PrivateKey {
 
         let h = Scalar::from_hash(hash);
         let s = (h * a) + r;
+        a.zeroize();
+        r.zeroize();
 
         let mut result = [0u8; SIGNATURE_LENGTH];
         result[..32].copy_from_slice(cap_r.as_bytes());
@@ -112,12 +125,128 @@ impl PrivateKey {
         message: &[&[u8]],
         signature: &[u8; SIGNATURE_LENGTH],
     ) -> bool {
+        let Some(inputs) = VerificationInputs::new(their_public_key, message, signature) else {
+            return false;
+        };
+
+        let cap_r_check_point = EdwardsPoint::vartime_double_scalar_mul_basepoint(
+            &inputs.h,
+            &inputs.minus_cap_a,
+            &inputs.s,
+        );
+        let cap_r_check = cap_r_check_point.compress();
+
+        bool::from(cap_r_check.as_bytes().ct_eq(&inputs.cap_r))
+    }
+
+    /// Checks several signatures at once using a random linear combination of their verification
+    /// equations.
+    ///
+    /// Returns `true` only if [`Self::verify_signature`] would accept every signature, except with
+    /// negligible probability.
+    ///
+    /// A random linear combination can't tell whether a signature satisfies the verification
+    /// equation exactly or only up to a small-order point, so this returns `false` for any
+    /// signature whose key or nonce has a small-order component, whether or not it is valid.
+    /// Callers should check those individually.
+    pub fn verify_signatures_batch<R>(items: &[BatchItem], csprng: &mut R) -> bool
+    where
+        R: CryptoRng + Rng,
+    {
+        let mut scalars = Vec::with_capacity(2 * items.len() + 1);
+        let mut points = Vec::with_capacity(2 * items.len() + 1);
+        let mut basepoint_scalar = Scalar::ZERO;
+        // Batches usually hold many signatures from a few keys, and checking for torsion costs
+        // about as much as checking a signature.
+        let mut torsion_free_keys = HashSet::new();
+
+        for (their_public_key, message, signature) in items {
+            let Some(inputs) = VerificationInputs::new(their_public_key, &[message], signature)
+            else {
+                return false;
+            };
+            // verify_signature compares encodings, so a non-canonical R can never be accepted.
+            if !is_canonical_encoding(&inputs.cap_r) {
+                return false;
+            }
+            let Some(cap_r) = CompressedEdwardsY(inputs.cap_r).decompress() else {
+                return false;
+            };
+            if !cap_r.is_torsion_free() {
+                return false;
+            }
+            let sign_bit = signature[SIGNATURE_LENGTH - 1] & 0b1000_0000_u8;
+            if !torsion_free_keys.contains(&(**their_public_key, sign_bit)) {
+                if !inputs.minus_cap_a.is_torsion_free() {
+                    return false;
+                }
+                torsion_free_keys.insert((**their_public_key, sign_bit));
+            }
+
+            // Each signature satisfies R = sB - hA, so z(sB - hA - R) sums to the identity for
+            // any choice of z. With every point in the prime-order subgroup, a nonzero term can
+            // only cancel out if z happens to be chosen badly, which is negligibly likely.
+            let z = Scalar::from(csprng.gen::<u128>());
+            basepoint_scalar += z * inputs.s;
+            scalars.push(z * inputs.h);
+            points.push(inputs.minus_cap_a);
+            scalars.push(-z);
+            points.push(cap_r);
+        }
+        scalars.push(basepoint_scalar);
+        points.push(ED25519_BASEPOINT_POINT);
+
+        EdwardsPoint::vartime_multiscalar_mul(scalars, points).is_identity()
+    }
+
+    pub fn derive_public_key_bytes(&self) -> [u8; PUBLIC_KEY_LENGTH] {
+        *PublicKey::from(&self.secret).as_bytes()
+    }
+
+    pub fn private_key_bytes(&self) -> [u8; PRIVATE_KEY_LENGTH] {
+        self.secret.to_bytes()
+    }
+}
+
+/// Returns whether `point` is the encoding that [`EdwardsPoint::compress`] would produce for it.
+///
+/// This is much cheaper than decompressing and compressing again. Decompression reduces the
+/// y-coordinate modulo p and accepts a set sign bit when x is zero; neither can come out of
+/// compression.
+fn is_canonical_encoding(point: &[u8; 32]) -> bool {
+    let sign_bit_set = point[31] & 0b1000_0000 != 0;
+    let mut y = *point;
+    y[31] &= 0b0111_1111;
+
+    // y is at least p = 2^255 - 19 only if every bit from 4 through 254 is set.
+    let upper_bits_set = y[1..31].iter().all(|&b| b == 0xFF) && y[31] == 0x7F;
+    let y_at_least_p = upper_bits_set && y[0] >= 0xED;
+    // x is zero only for y = 1 and y = p - 1.
+    let y_is_one = y[0] == 1 && y[1..].iter().all(|&b| b == 0);
+    let y_is_p_minus_one = upper_bits_set && y[0] == 0xEC;
+
+    !y_at_least_p && !(sign_bit_set && (y_is_one || y_is_p_minus_one))
+}
+
+/// The decoded pieces of an XEdDSA signature check, which passes if `R = hA' + sB`.
+struct VerificationInputs {
+    /// A', the negated Edwards form of the signer's public key.
+    minus_cap_a: EdwardsPoint,
+    /// R, exactly as encoded in the signature.
+    cap_r: [u8; 32],
+    h: Scalar,
+    s: Scalar,
+}
+
+impl VerificationInputs {
+    fn new(
+        their_public_key: &[u8; PUBLIC_KEY_LENGTH],
+        message: &[&[u8]],
+        signature: &[u8; SIGNATURE_LENGTH],
+    ) -> Option<Self> {
         let mont_point = MontgomeryPoint(*their_public_key);
         let ed_pub_key_point =
-            match mont_point.to_edwards((signature[SIGNATURE_LENGTH - 1] & 0b1000_0000_u8) >> 7) {
-                Some(x) => x,
-                None => return false,
-            };
+            mont_point.to_edwards((signature[SIGNATURE_LENGTH - 1] & 0b1000_0000_u8) >> 7)?;
         let cap_a = ed_pub_key_point.compress();
         let mut cap_r = [0u8; 32];
         cap_r.copy_from_slice(&signature[..32]);
@@ -125,7 +254,7 @@ impl PrivateKey {
         s.copy_from_slice(&signature[32..]);
         s[31] &= 0b0111_1111_u8;
         if (s[31] & 0b1110_0000_u8) != 0 {
-            return false;
+            return None;
         }
         let minus_cap_a = -ed_pub_key_point;
 
@@ -138,22 +267,12 @@ impl PrivateKey {
         }
         let h = Scalar::from_hash(hash);
 
-        let cap_r_check_point = EdwardsPoint::vartime_double_scalar_mul_basepoint(
-            &h,
-            &minus_cap_a,
-            &Scalar::from_bytes_mod_order(s),
-        );
-        let cap_r_check = cap_r_check_point.compress();
-
-        bool::from(cap_r_check.as_bytes().ct_eq(&cap_r))
-    }
-
-    pub fn derive_public_key_bytes(&self) -> [u8; PUBLIC_KEY_LENGTH] {
-        *PublicKey::from(&self.secret).as_bytes()
-    }
-
-    pub fn private_key_bytes(&self) -> [u8; PRIVATE_KEY_LENGTH] {
-        self.secret.to_bytes()
+        Some(Self {
+            minus_cap_a,
+            cap_r,
+            h,
+            s: Scalar::from_bytes_mod_order(s),
+        })
     }
 }
 
@@ -164,6 +283,44 @@ impl From<[u8; PRIVATE_KEY_LENGTH]> for PrivateKey {
     }
 }
 
+#[cfg(test)]
+impl PrivateKey {
+    /// Signs like [`Self::calculate_signature`], but adds a small-order point to the nonce.
+    ///
+    /// The result satisfies the cofactored verification equation but not the exact one that
+    /// [`Self::verify_signature`] checks.
+    pub(crate) fn calculate_signature_with_torsioned_nonce<R>(
+        &self,
+        csprng: &mut R,
+        message: &[u8],
+    ) -> [u8; SIGNATURE_LENGTH]
+    where
+        R: CryptoRng + Rng,
+    {
+        let mut random_bytes = [0u8; 64];
+        csprng.fill_bytes(&mut random_bytes);
+        let r = Scalar::from_bytes_mod_order_wide(&random_bytes);
+
+        let a = Scalar::from_bytes_mod_order(self.secret.to_bytes());
+        let ed_public_key = (&a * ED25519_BASEPOINT_TABLE).compress();
+        let sign_bit = ed_public_key.as_bytes()[31] & 0b1000_0000_u8;
+        let cap_r = (&r * ED25519_BASEPOINT_TABLE + curve25519_dalek::constants::EIGHT_TORSION[1])
+            .compress();
+
+        let mut hash = Sha512::new();
+        hash.update(cap_r.as_bytes());
+        hash.update(ed_public_key.as_bytes());
+        hash.update(message);
+        let s = Scalar::from_hash(hash) * a + r;
+
+        let mut result = [0u8; SIGNATURE_LENGTH];
+        result[..32].copy_from_slice(cap_r.as_bytes());
+        result[32..].copy_from_slice(s.as_bytes());
+        result[SIGNATURE_LENGTH - 1] |= sign_bit;
+        result
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use rand::rngs::OsRng;
@@ -285,6 +442,157 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_batch_signatures() {
+        let mut csprng = OsRng;
+        let keys: Vec<_> = (0..8).map(|_| PrivateKey::new(&mut csprng)).collect();
+        let public_keys: Vec<_> = keys.iter().map(|k| k.derive_public_key_bytes()).collect();
+        let messages: Vec<_> = (0..8u8).map(|i| [i; 64]).collect();
+        let mut signatures: Vec<_> = keys
+            .iter()
+            .zip(&messages)
+            .map(|(key, message)| key.calculate_signature(&mut csprng, &[message]))
+            .collect();
+
+        fn items<'a>(
+            public_keys: &'a [[u8; PUBLIC_KEY_LENGTH]],
+            messages: &'a [[u8; 64]],
+            signatures: &'a [[u8; SIGNATURE_LENGTH]],
+        ) -> Vec<BatchItem<'a>> {
+            public_keys
+                .iter()
+                .zip(messages)
+                .zip(signatures)
+                .map(|((key, message), signature)| (key, &message[..], signature))
+                .collect()
+        }
+
+        assert!(PrivateKey::verify_signatures_batch(&[], &mut csprng));
+        assert!(PrivateKey::verify_signatures_batch(
+            &items(&public_keys, &messages, &signatures),
+            &mut csprng
+        ));
+
+        for i in [0, 31, 32, 63] {
+            signatures[3][i] ^= 0x01;
+            assert!(
+                !PrivateKey::verify_signatures_batch(
+                    &items(&public_keys, &messages, &signatures),
+                    &mut csprng
+                ),
+                "batch passed with byte {i} corrupted"
+            );
+            signatures[3][i] ^= 0x01;
+        }
+
+        // Valid signatures checked against the wrong keys.
+        signatures.swap(1, 2);
+        assert!(!PrivateKey::verify_signatures_batch(
+            &items(&public_keys, &messages, &signatures),
+            &mut csprng
+        ));
+    }
+
+    #[test]
+    fn test_batch_rejects_torsioned_nonce() {
+        let mut csprng = OsRng;
+        let key = PrivateKey::new(&mut csprng);
+        let public_key = key.derive_public_key_bytes();
+        let message = [0x42; 64];
+        let honest = key.calculate_signature(&mut csprng, &[&message]);
+        let crafted = key.calculate_signature_with_torsioned_nonce(&mut csprng, &message);
+
+        // The crafted signature is only off by a small-order point.
+        let inputs = VerificationInputs::new(&public_key, &[&message], &crafted)
+            .expect("well-formed signature");
+        let cap_r = CompressedEdwardsY(inputs.cap_r)
+            .decompress()
+            .expect("valid point");
+        let difference = EdwardsPoint::vartime_double_scalar_mul_basepoint(
+            &inputs.h,
+            &inputs.minus_cap_a,
+            &inputs.s,
+        ) - cap_r;
+        assert!(!difference.is_identity());
+        assert!(difference.is_small_order());
+
+        assert!(!PrivateKey::verify_signature(
+            &public_key,
+            &[&message],
+            &crafted
+        ));
+        let crafted_item: BatchItem = (&public_key, &message[..], &crafted);
+        let honest_item: BatchItem = (&public_key, &message[..], &honest);
+        for _ in 0..16 {
+            assert!(!PrivateKey::verify_signatures_batch(
+                &[crafted_item],
+                &mut csprng
+            ));
+            assert!(!PrivateKey::verify_signatures_batch(
+                &[honest_item, crafted_item],
+                &mut csprng
+            ));
+            assert!(PrivateKey::verify_signatures_batch(
+                &[honest_item, honest_item],
+                &mut csprng
+            ));
+        }
+    }
+
+    #[test]
+    fn test_canonical_encoding() {
+        let mut csprng = OsRng;
+        for _ in 0..100 {
+            let mut bytes = [0u8; 32];
+            csprng.fill_bytes(&mut bytes);
+            if let Some(point) = CompressedEdwardsY(bytes).decompress() {
+                assert_eq!(
+                    is_canonical_encoding(&bytes),
+                    point.compress().to_bytes() == bytes
+                );
+            }
+        }
+
+        let mut p = [0xFF; 32];
+        p[0] = 0xED;
+        p[31] = 0x7F;
+        let mut non_canonical = vec![p];
+        // y = p + 1, which decodes like y = 1.
+        non_canonical.push({
+            let mut y = p;
+            y[0] = 0xEE;
+            y
+        });
+        // y = 1 and y = p - 1, with x = 0 marked as negative.
+        non_canonical.push({
+            let mut y = [0; 32];
+            y[0] = 1;
+            y[31] = 0x80;
+            y
+        });
+        non_canonical.push({
+            let mut y = p;
+            y[0] = 0xEC;
+            y[31] |= 0x80;
+            y
+        });
+        for bytes in non_canonical {
+            let point = CompressedEdwardsY(bytes)
+                .decompress()
+                .expect("decodes despite being non-canonical");
+            assert_ne!(point.compress().to_bytes(), bytes);
+            assert!(!is_canonical_encoding(&bytes), "{}", hex::encode(bytes));
+        }
+
+        for bytes in [[0; 32], {
+            let mut y = [0; 32];
+            y[0] = 1;
+            y
+        }] {
+            assert!(is_canonical_encoding(&bytes));
+        }
+    }
+
     #[test]
     fn test_random_signatures() {
         let mut csprng = OsRng;
@@ -87,8 +87,9 @@ impl From<IdentityKey> for PublicKey {
 
 /// The private identity of a user.
 ///
-/// Can be converted to and from [`KeyPair`].
-#[derive(Copy, Clone)]
+/// Can be converted to and from [`KeyPair`]. Not `Copy`, because the [`PrivateKey`] it holds
+/// isn't.
+#[derive(Clone)]
 pub struct IdentityKeyPair {
     identity_key: IdentityKey,
     private_key: PrivateKey,
@@ -64,6 +64,7 @@ use displaydoc::Display;
 use std::marker::PhantomData;
 use std::ops::Deref;
 use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
 
 type SharedSecret = Box<[u8]>;
 
@@ -230,6 +231,13 @@ impl<T: KeyKind> KeyMaterial<T> {
     }
 }
 
+impl<T: KeyKind> Drop for KeyMaterial<T> {
+    fn drop(&mut self) {
+        // Public material doesn't need this, but keeping a single type is simpler.
+        self.data.zeroize();
+    }
+}
+
 impl<T: KeyKind> Deref for KeyMaterial<T> {
     type Target = [u8];
 
@@ -599,4 +607,10 @@ mod tests {
             kyber1024::Parameters.shared_secret_length()
         );
     }
+
+    #[test]
+    fn test_secret_key_zeroized_on_drop() {
+        let kp = KeyPair::generate(KeyType::Kyber1024);
+        crate::utils::assert_heap_zeroized_on_drop(kp.secret_key, |key| vec![&key.key_data[..]]);
+    }
 }
@@ -38,6 +38,8 @@ mod sealed_sender;
 mod sender_keys;
 mod session;
 mod session_cipher;
+#[cfg(feature = "simulator")]
+pub mod simulator;
 mod state;
 mod storage;
 mod timestamp;
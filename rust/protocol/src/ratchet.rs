@@ -12,6 +12,7 @@ use crate::protocol::{CIPHERTEXT_MESSAGE_CURRENT_VERSION, CIPHERTEXT_MESSAGE_PRE
 use crate::state::SessionState;
 use crate::{KeyPair, Result, SessionRecord};
 use rand::{CryptoRng, Rng};
+use zeroize::Zeroizing;
 
 fn derive_keys(has_kyber: bool, secret_input: &[u8]) -> (RootKey, ChainKey) {
     let label = if has_kyber {
@@ -31,9 +32,9 @@ fn message_version(has_kyber: bool) -> u8 {
 }
 
 fn derive_keys_with_label(label: &[u8], secret_input: &[u8]) -> (RootKey, ChainKey) {
-    let mut secrets = [0; 64];
+    let mut secrets = Zeroizing::new([0; 64]);
     hkdf::Hkdf::<sha2::Sha256>::new(None, secret_input)
-        .expand(label, &mut secrets)
+        .expand(label, secrets.as_mut())
         .expect("valid length");
     let (root_key_bytes, chain_key_bytes) = secrets.split_at(32);
 
@@ -51,34 +52,38 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
 
     let sending_ratchet_key = KeyPair::generate(&mut csprng);
 
-    let mut secrets = Vec::with_capacity(32 * 5);
+    // Reserve room for every agreement up front so the buffer is never reallocated, which would
+    // leave an unwiped copy behind.
+    let mut secrets = Zeroizing::new(Vec::with_capacity(32 * 6));
 
     secrets.extend_from_slice(&[0xFFu8; 32]); // "discontinuity bytes"
 
-    let our_base_private_key = parameters.our_base_key_pair().private_key;
+    let our_base_private_key = &parameters.our_base_key_pair().private_key;
 
-    secrets.extend_from_slice(
-        &parameters
+    secrets.extend_from_slice(&Zeroizing::new(
+        parameters
             .our_identity_key_pair()
             .private_key()
             .calculate_agreement(parameters.their_signed_pre_key())?,
-    );
+    ));
 
-    secrets.extend_from_slice(
-        &our_base_private_key.calculate_agreement(parameters.their_identity_key().public_key())?,
-    );
+    secrets.extend_from_slice(&Zeroizing::new(
+        our_base_private_key.calculate_agreement(parameters.their_identity_key().public_key())?,
+    ));
 
-    secrets.extend_from_slice(
-        &our_base_private_key.calculate_agreement(parameters.their_signed_pre_key())?,
-    );
+    secrets.extend_from_slice(&Zeroizing::new(
+        our_base_private_key.calculate_agreement(parameters.their_signed_pre_key())?,
+    ));
 
     if let Some(their_one_time_prekey) = parameters.their_one_time_pre_key() {
-        secrets
-            .extend_from_slice(&our_base_private_key.calculate_agreement(their_one_time_prekey)?);
+        secrets.extend_from_slice(&Zeroizing::new(
+            our_base_private_key.calculate_agreement(their_one_time_prekey)?,
+        ));
     }
 
     let kyber_ciphertext = parameters.their_kyber_pre_key().map(|kyber_public| {
         let (ss, ct) = kyber_public.encapsulate();
+        let ss = Zeroizing::new(ss);
         secrets.extend_from_slice(ss.as_ref());
         ct
     });
@@ -113,37 +118,39 @@ pub(crate) fn initialize_bob_session(
 ) -> Result<SessionState> {
     let local_identity = parameters.our_identity_key_pair().identity_key();
 
-    let mut secrets = Vec::with_capacity(32 * 5);
+    // Reserve room for every agreement up front so the buffer is never reallocated, which would
+    // leave an unwiped copy behind.
+    let mut secrets = Zeroizing::new(Vec::with_capacity(32 * 6));
 
     secrets.extend_from_slice(&[0xFFu8; 32]); // "discontinuity bytes"
 
-    secrets.extend_from_slice(
-        &parameters
+    secrets.extend_from_slice(&Zeroizing::new(
+        parameters
             .our_signed_pre_key_pair()
             .private_key
             .calculate_agreement(parameters.their_identity_key().public_key())?,
-    );
+    ));
 
-    secrets.extend_from_slice(
-        &parameters
+    secrets.extend_from_slice(&Zeroizing::new(
+        parameters
             .our_identity_key_pair()
             .private_key()
             .calculate_agreement(parameters.their_base_key())?,
-    );
+    ));
 
-    secrets.extend_from_slice(
-        &parameters
+    secrets.extend_from_slice(&Zeroizing::new(
+        parameters
             .our_signed_pre_key_pair()
             .private_key
             .calculate_agreement(parameters.their_base_key())?,
-    );
+    ));
 
     if let Some(our_one_time_pre_key_pair) = parameters.our_one_time_pre_key_pair() {
-        secrets.extend_from_slice(
-            &our_one_time_pre_key_pair
+        secrets.extend_from_slice(&Zeroizing::new(
+            our_one_time_pre_key_pair
                 .private_key
                 .calculate_agreement(parameters.their_base_key())?,
-        );
+        ));
     }
 
     match (
@@ -151,7 +158,7 @@ pub(crate) fn initialize_bob_session(
         parameters.their_kyber_ciphertext(),
     ) {
         (Some(key_pair), Some(ciphertext)) => {
-            let ss = key_pair.secret_key.decapsulate(ciphertext)?;
+            let ss = Zeroizing::new(key_pair.secret_key.decapsulate(ciphertext)?);
             secrets.extend_from_slice(ss.as_ref());
         }
         (None, None) => (), // Alice does not support kyber prekeys
@@ -7,7 +7,9 @@ use arrayref::array_ref;
 
 use crate::{crypto, PrivateKey, PublicKey, Result};
 use std::fmt;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
+#[derive(Zeroize, ZeroizeOnDrop)]
 pub(crate) struct MessageKeys {
     cipher_key: [u8; 32],
     mac_key: [u8; 32],
@@ -17,9 +19,9 @@ pub(crate) struct MessageKeys {
 
 impl MessageKeys {
     pub(crate) fn derive_keys(input_key_material: &[u8], counter: u32) -> Self {
-        let mut okm = [0; 80];
+        let mut okm = Zeroizing::new([0; 80]);
         hkdf::Hkdf::<sha2::Sha256>::new(None, input_key_material)
-            .expand(b"WhisperMessageKeys", &mut okm)
+            .expand(b"WhisperMessageKeys", okm.as_mut())
             .expect("valid output length");
 
         MessageKeys {
@@ -60,7 +62,7 @@ impl MessageKeys {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Zeroize, ZeroizeOnDrop)]
 pub(crate) struct ChainKey {
     key: [u8; 32],
     index: u32,
@@ -92,10 +94,8 @@ impl ChainKey {
     }
 
     pub(crate) fn message_keys(&self) -> MessageKeys {
-        MessageKeys::derive_keys(
-            &self.calculate_base_material(Self::MESSAGE_KEY_SEED),
-            self.index,
-        )
+        let base_material = Zeroizing::new(self.calculate_base_material(Self::MESSAGE_KEY_SEED));
+        MessageKeys::derive_keys(base_material.as_ref(), self.index)
     }
 
     fn calculate_base_material(&self, seed: [u8; 1]) -> [u8; 32] {
@@ -103,7 +103,7 @@ impl ChainKey {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Zeroize, ZeroizeOnDrop)]
 pub(crate) struct RootKey {
     key: [u8; 32],
 }
@@ -122,10 +122,10 @@ impl RootKey {
         their_ratchet_key: &PublicKey,
         our_ratchet_key: &PrivateKey,
     ) -> Result<(RootKey, ChainKey)> {
-        let shared_secret = our_ratchet_key.calculate_agreement(their_ratchet_key)?;
-        let mut derived_secret_bytes = [0; 64];
-        hkdf::Hkdf::<sha2::Sha256>::new(Some(&self.key), &shared_secret)
-            .expand(b"WhisperRatchet", &mut derived_secret_bytes)
+        let shared_secret = Zeroizing::new(our_ratchet_key.calculate_agreement(their_ratchet_key)?);
+        let mut derived_secret_bytes = Zeroizing::new([0; 64]);
+        hkdf::Hkdf::<sha2::Sha256>::new(Some(&self.key), shared_secret.as_ref())
+            .expand(b"WhisperRatchet", derived_secret_bytes.as_mut())
             .expect("valid output length");
 
         Ok((
@@ -184,4 +184,20 @@ mod tests {
         assert_eq!(1, chain_key.next_chain_key().message_keys().counter());
         Ok(())
     }
+
+    #[test]
+    #[allow(unsafe_code)]
+    fn test_keys_zeroized_on_drop() {
+        use crate::utils::assert_zeroized_on_drop;
+
+        let chain_key = ChainKey::new([0x55; 32], 7);
+        // SAFETY: these are byte arrays plus at most a u32, and the arrays are multiples of 4 bytes
+        // long, so there is no padding.
+        unsafe {
+            assert_zeroized_on_drop(chain_key.message_keys());
+            assert_zeroized_on_drop(chain_key.next_chain_key());
+            assert_zeroized_on_drop(chain_key);
+            assert_zeroized_on_drop(RootKey::new([0x55; 32]));
+        }
+    }
 }
@@ -748,7 +748,7 @@ mod sealed_sender_v1 {
 
         // The message recipient calculates the ephemeral key and the sender's public key.
         let recipient_eph_keys = EphemeralKeys::calculate(
-            &recipient_identity.into(),
+            &recipient_identity.clone().into(),
             &ephemeral_public,
             Direction::Receiving,
         )?;
@@ -1076,7 +1076,7 @@ mod sealed_sender_v2 {
 
         // The message recipient calculates the original random bytes and authenticates the result.
         let recv_m = apply_agreement_xor(
-            &recipient_identity.into(),
+            &recipient_identity.clone().into(),
             &e.public_key,
             Direction::Receiving,
             &sender_c_0,
@@ -1802,7 +1802,7 @@ pub async fn sealed_sender_decrypt_to_usmc(
             encrypted_message,
         } => {
             let eph_keys = sealed_sender_v1::EphemeralKeys::calculate(
-                &our_identity.into(),
+                &our_identity.clone().into(),
                 &ephemeral_public,
                 Direction::Receiving,
             )?;
@@ -1878,7 +1878,7 @@ pub async fn sealed_sender_decrypt_to_usmc(
                     ))
                 })?;
             let m = sealed_sender_v2::apply_agreement_xor(
-                &our_identity.into(),
+                &our_identity.clone().into(),
                 &ephemeral_public,
                 Direction::Receiving,
                 &encrypted_message_key,
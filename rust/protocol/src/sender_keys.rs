@@ -7,9 +7,11 @@ use std::collections::VecDeque;
 
 use itertools::Itertools;
 use prost::Message;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
 use crate::crypto::hmac_sha256;
 use crate::proto::storage as storage_proto;
+use crate::utils::decode_without_copies;
 use crate::{consts, PrivateKey, PublicKey, SignalProtocolError};
 
 /// A distinct error type to keep from accidentally propagating deserialization errors.
@@ -22,7 +24,7 @@ impl std::fmt::Display for InvalidSessionError {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Zeroize, ZeroizeOnDrop)]
 pub(crate) struct SenderMessageKey {
     iteration: u32,
     iv: Vec<u8>,
@@ -32,9 +34,9 @@ pub(crate) struct SenderMessageKey {
 
 impl SenderMessageKey {
     pub(crate) fn new(iteration: u32, seed: Vec<u8>) -> Self {
-        let mut derived = [0; 48];
+        let mut derived = Zeroizing::new([0; 48]);
         hkdf::Hkdf::<sha2::Sha256>::new(None, &seed)
-            .expand(b"WhisperGroup", &mut derived)
+            .expand(b"WhisperGroup", derived.as_mut())
             .expect("valid output length");
         Self {
             iteration,
@@ -72,7 +74,7 @@ impl SenderMessageKey {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Zeroize, ZeroizeOnDrop)]
 pub(crate) struct SenderChainKey {
     iteration: u32,
     chain_key: Vec<u8>,
@@ -110,7 +112,8 @@ impl SenderChainKey {
 
     fn get_derivative(&self, label: u8) -> Vec<u8> {
         let label = [label];
-        hmac_sha256(&self.chain_key, &label).to_vec()
+        let derivative = Zeroizing::new(hmac_sha256(&self.chain_key, &label));
+        derivative.to_vec()
     }
 
     pub(crate) fn as_protobuf(&self) -> storage_proto::sender_key_state_structure::SenderChainKey {
@@ -180,7 +183,9 @@ impl SenderKeyState {
     }
 
     pub(crate) fn set_sender_chain_key(&mut self, chain_key: SenderChainKey) {
-        self.state.sender_chain_key = Some(chain_key.as_protobuf());
+        if let Some(mut old) = self.state.sender_chain_key.replace(chain_key.as_protobuf()) {
+            old.zeroize();
+        }
     }
 
     pub(crate) fn signing_key_public(&self) -> Result<PublicKey, InvalidSessionError> {
@@ -210,7 +215,7 @@ impl SenderKeyState {
             .sender_message_keys
             .push(sender_message_key.as_protobuf());
         while self.state.sender_message_keys.len() > consts::MAX_MESSAGE_KEYS {
-            self.state.sender_message_keys.remove(0);
+            self.state.sender_message_keys.remove(0).zeroize();
         }
     }
 
@@ -229,6 +234,12 @@ impl SenderKeyState {
     }
 }
 
+impl Drop for SenderKeyState {
+    fn drop(&mut self) {
+        self.state.zeroize();
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SenderKeyRecord {
     states: VecDeque<SenderKeyState>,
@@ -242,7 +253,7 @@ impl SenderKeyRecord {
     }
 
     pub fn deserialize(buf: &[u8]) -> Result<SenderKeyRecord, SignalProtocolError> {
-        let skr = storage_proto::SenderKeyRecordStructure::decode(buf)
+        let skr = decode_without_copies::<storage_proto::SenderKeyRecordStructure>(buf)
             .map_err(|_| SignalProtocolError::InvalidProtobufEncoding)?;
 
         let mut states = VecDeque::with_capacity(skr.sender_key_states.len());
@@ -353,7 +364,76 @@ impl SenderKeyRecord {
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>, SignalProtocolError> {
-        Ok(self.as_protobuf().encode_to_vec())
+        let mut record = self.as_protobuf();
+        let result = record.encode_to_vec();
+        record.zeroize();
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod zeroize_tests {
+    use rand::rngs::OsRng;
+
+    use crate::KeyPair;
+
+    use super::*;
+
+    #[test]
+    fn sender_key_protobufs_are_zeroized() {
+        let signing_key = KeyPair::generate(&mut OsRng);
+        let mut state = SenderKeyState::new(
+            3,
+            1,
+            0,
+            &[0x55; 32],
+            signing_key.public_key,
+            Some(signing_key.private_key),
+        );
+        let chain_key = state.sender_chain_key().expect("has a chain key");
+        state.add_sender_message_key(&chain_key.sender_message_key());
+        let mut record = SenderKeyRecord::new_empty();
+        record.states.push_back(state);
+
+        let mut record_structure = record.as_protobuf();
+        let mut state_structure = record_structure.sender_key_states[0].clone();
+        assert!(state_structure.sender_chain_key.is_some());
+        assert!(state_structure.sender_signing_key.is_some());
+        assert_eq!(state_structure.sender_message_keys.len(), 1);
+
+        state_structure.zeroize();
+        assert_eq!(state_structure, Default::default());
+        record_structure.zeroize();
+        assert_eq!(record_structure, Default::default());
+
+        let round_tripped =
+            SenderKeyRecord::deserialize(&record.serialize().expect("can serialize"))
+                .expect("can deserialize");
+        assert_eq!(round_tripped.as_protobuf(), record.as_protobuf());
+    }
+
+    #[test]
+    fn sender_key_state_heap_buffers_are_wiped_on_drop() {
+        let signing_key = KeyPair::generate(&mut OsRng);
+        let mut state = SenderKeyState::new(
+            3,
+            1,
+            0,
+            &[0x55; 32],
+            signing_key.public_key,
+            Some(signing_key.private_key),
+        );
+        let chain_key = state.sender_chain_key().expect("has a chain key");
+        state.add_sender_message_key(&chain_key.sender_message_key());
+
+        crate::utils::assert_heap_zeroized_on_drop(state, |state| {
+            let state = &state.state;
+            vec![
+                &state.sender_chain_key.as_ref().expect("present").seed[..],
+                &state.sender_signing_key.as_ref().expect("present").private[..],
+                &state.sender_message_keys[0].seed[..],
+            ]
+        });
     }
 }
 
@@ -410,10 +490,9 @@ mod sender_key_record_add_sender_key_state_tests {
                 .sender_key_state_for_chain_id(chain_id)
                 .expect("Expect to find chain id")
                 .sender_chain_key()
-                .expect("Expect to find chain key")
-                .chain_key;
+                .expect("Expect to find chain key");
 
-            assert_eq!(found_chain_key, expected_chain_key);
+            assert_eq!(found_chain_key.seed(), expected_chain_key);
 
             let matching_state = self
                 .sender_key_record
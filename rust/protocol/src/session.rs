@@ -117,7 +117,7 @@ async fn process_prekey_impl(
 
     let parameters = BobSignalProtocolParameters::new(
         identity_store.get_identity_key_pair().await?,
-        our_signed_pre_key_pair, // signed pre key
+        our_signed_pre_key_pair.clone(), // signed pre key
         our_one_time_pre_key_pair,
         our_signed_pre_key_pair, // ratchet key
         our_kyber_pre_key_pair,
@@ -191,7 +191,7 @@ pub async fn process_prekey_bundle<R: Rng + CryptoRng>(
 
     let mut parameters = AliceSignalProtocolParameters::new(
         our_identity_key_pair,
-        our_base_key_pair,
+        our_base_key_pair.clone(),
         *their_identity_key,
         their_signed_prekey,
         their_signed_prekey,
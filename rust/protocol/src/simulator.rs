@@ -0,0 +1,817 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! A deterministic simulator for 1:1 sessions between several devices over an unreliable network.
+//!
+//! A [`Simulation`] runs a number of devices backed by in-memory stores and has them message each
+//! other while the network loses, duplicates, and reorders messages, and while devices reset
+//! sessions or reinstall with new identity keys. Along the way it checks that
+//!
+//! - every message that arrives decrypts to what was sent,
+//! - no message decrypts more than once, and
+//! - a message only fails to decrypt if something happened to invalidate it: a session reset or
+//!   identity change between the two devices, or the sender ratcheting so many times that the
+//!   message's chain has been discarded.
+//!
+//! Once the steps are done, the simulation delivers everything still in flight, has every pair of
+//! devices exchange one more message in each direction, and checks that both sides of each pair
+//! then agree on the current session.
+//!
+//! Every decision is driven by the seed, so a failing run can be reproduced exactly. (Kyber keys
+//! are always generated from the OS RNG, so the ciphertexts themselves differ between runs, but the
+//! sequence of events does not.) This makes it straightforward to drive from proptest:
+//!
+//! ```
+//! use libsignal_protocol::simulator::{Simulation, SimulationConfig};
+//!
+//! let config = SimulationConfig {
+//!     loss_probability: 0.2,
+//!     ..SimulationConfig::default()
+//! };
+//! let report = Simulation::new(config, 42)?.run()?;
+//! assert!(report.messages_lost > 0);
+//! # Ok::<(), libsignal_protocol::simulator::SimulationError>(())
+//! ```
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+use displaydoc::Display;
+use futures_util::FutureExt;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use thiserror::Error;
+
+use crate::state::GenericSignedPreKey;
+use crate::{
+    consts, kem, message_decrypt, message_encrypt, process_prekey_bundle, CiphertextMessage,
+    CiphertextMessageType, DeviceId, IdentityKeyPair, IdentityKeyStore, InMemSignalProtocolStore,
+    KeyPair, KyberPreKeyId, KyberPreKeyRecord, KyberPreKeyStore, PreKeyBundle, PreKeyId,
+    PreKeyRecord, PreKeySignalMessage, PreKeyStore, ProtocolAddress, PublicKey, SessionStore,
+    SignalMessage, SignalProtocolError, SignedPreKeyId, SignedPreKeyRecord, SignedPreKeyStore,
+    Timestamp,
+};
+
+/// Parameters for a [`Simulation`].
+///
+/// Loss and duplication apply to each message sent; the other probabilities apply to each step.
+#[derive(Clone, Debug)]
+pub struct SimulationConfig {
+    /// How many devices take part. Every device may message every other device.
+    pub device_count: usize,
+    /// How many random events to run before settling.
+    pub steps: usize,
+    /// The chance that a message is never delivered.
+    pub loss_probability: f64,
+    /// The chance that a message is delivered twice.
+    pub duplication_probability: f64,
+    /// The chance that a delivery picks a random message in flight rather than the oldest.
+    pub reorder_probability: f64,
+    /// The chance that a step has one device archive its session with another.
+    pub session_reset_probability: f64,
+    /// The chance that a step has one device reinstall with a new identity.
+    pub identity_change_probability: f64,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            device_count: 3,
+            steps: 200,
+            loss_probability: 0.05,
+            duplication_probability: 0.05,
+            reorder_probability: 0.3,
+            session_reset_probability: 0.01,
+            identity_change_probability: 0.005,
+        }
+    }
+}
+
+/// Counts of what happened over the course of a [`Simulation`].
+///
+/// Two runs with the same config and seed produce the same report.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SimulationReport {
+    pub messages_sent: usize,
+    pub messages_lost: usize,
+    pub messages_duplicated: usize,
+    pub messages_delivered: usize,
+    /// Extra copies of already-delivered messages, which were (correctly) rejected.
+    pub duplicates_rejected: usize,
+    /// Messages that legitimately failed to decrypt because of something that happened after
+    /// they were sent.
+    pub messages_invalidated: usize,
+    pub session_resets: usize,
+    pub identity_changes: usize,
+}
+
+/// A broken invariant, or an unexpected failure, found by a [`Simulation`].
+#[derive(Debug, Display, Error)]
+pub enum SimulationError {
+    /// invalid simulation config: {0}
+    InvalidConfig(&'static str),
+    /// step {step}: message {message} from {sender} to {recipient} failed to decrypt: {source}
+    DecryptionFailed {
+        step: usize,
+        message: u64,
+        sender: ProtocolAddress,
+        recipient: ProtocolAddress,
+        source: Box<SignalProtocolError>,
+    },
+    /// step {step}: message {message} from {sender} to {recipient} was decrypted more than once
+    DecryptedTwice {
+        step: usize,
+        message: u64,
+        sender: ProtocolAddress,
+        recipient: ProtocolAddress,
+    },
+    /// step {step}: message {message} from {sender} to {recipient} decrypted to the wrong plaintext
+    WrongPlaintext {
+        step: usize,
+        message: u64,
+        sender: ProtocolAddress,
+        recipient: ProtocolAddress,
+    },
+    /// sessions between {0} and {1} did not converge
+    SessionsDiverged(ProtocolAddress, ProtocolAddress),
+    /// step {step}: unexpected error: {source}
+    Protocol {
+        step: usize,
+        source: SignalProtocolError,
+    },
+}
+
+/// All simulated devices use the same signed pre-key ID; it's replaced when a device reinstalls.
+const SIGNED_PRE_KEY_ID: u32 = 1;
+
+struct Device {
+    address: ProtocolAddress,
+    store: InMemSignalProtocolStore,
+    /// Shared between one-time EC and Kyber pre-keys, and kept across reinstalls so that stale
+    /// pre-key messages never refer to a new pre-key.
+    next_pre_key_id: u32,
+}
+
+impl Device {
+    async fn new(
+        address: ProtocolAddress,
+        next_pre_key_id: u32,
+        now: SystemTime,
+        rng: &mut StdRng,
+    ) -> Result<Self, SignalProtocolError> {
+        let identity_key_pair = IdentityKeyPair::generate(rng);
+        let registration_id = rng.gen_range(1..=0x3FFF);
+        let mut store = InMemSignalProtocolStore::new(identity_key_pair.clone(), registration_id)?;
+
+        let signed_pre_key_pair = KeyPair::generate(rng);
+        let signature = identity_key_pair
+            .private_key()
+            .calculate_signature(&signed_pre_key_pair.public_key.serialize(), rng)?;
+        store
+            .save_signed_pre_key(
+                SIGNED_PRE_KEY_ID.into(),
+                &SignedPreKeyRecord::new(
+                    SIGNED_PRE_KEY_ID.into(),
+                    timestamp(now),
+                    &signed_pre_key_pair,
+                    &signature,
+                ),
+            )
+            .await?;
+
+        Ok(Self {
+            address,
+            store,
+            next_pre_key_id,
+        })
+    }
+
+    /// Plays the part of the server handing out a fresh set of this device's pre-keys.
+    async fn issue_pre_key_bundle(
+        &mut self,
+        now: SystemTime,
+        rng: &mut StdRng,
+    ) -> Result<PreKeyBundle, SignalProtocolError> {
+        let identity_key_pair = self.store.get_identity_key_pair().await?;
+
+        let pre_key_id = PreKeyId::from(self.next_pre_key_id);
+        let kyber_pre_key_id = KyberPreKeyId::from(self.next_pre_key_id);
+        self.next_pre_key_id += 1;
+
+        let pre_key_pair = KeyPair::generate(rng);
+        self.store
+            .save_pre_key(pre_key_id, &PreKeyRecord::new(pre_key_id, &pre_key_pair))
+            .await?;
+
+        let kyber_pre_key_pair = kem::KeyPair::generate(kem::KeyType::Kyber1024);
+        let kyber_signature = identity_key_pair
+            .private_key()
+            .calculate_signature(&kyber_pre_key_pair.public_key.serialize(), rng)?;
+        self.store
+            .save_kyber_pre_key(
+                kyber_pre_key_id,
+                &KyberPreKeyRecord::new(
+                    kyber_pre_key_id,
+                    timestamp(now),
+                    &kyber_pre_key_pair,
+                    &kyber_signature,
+                ),
+            )
+            .await?;
+
+        let signed_pre_key = self
+            .store
+            .get_signed_pre_key(SIGNED_PRE_KEY_ID.into())
+            .await?;
+
+        Ok(PreKeyBundle::new(
+            self.store.get_local_registration_id().await?,
+            self.address.device_id(),
+            Some((pre_key_id, pre_key_pair.public_key)),
+            SignedPreKeyId::from(SIGNED_PRE_KEY_ID),
+            signed_pre_key.public_key()?,
+            signed_pre_key.signature()?,
+            *identity_key_pair.identity_key(),
+        )?
+        .with_kyber_pre_key(
+            kyber_pre_key_id,
+            kyber_pre_key_pair.public_key.clone(),
+            kyber_signature.into_vec(),
+        ))
+    }
+
+    async fn has_usable_session(
+        &self,
+        peer: &ProtocolAddress,
+        now: SystemTime,
+    ) -> Result<bool, SignalProtocolError> {
+        match self.store.load_session(peer).await? {
+            Some(record) => record.has_usable_sender_chain(now),
+            None => Ok(false),
+        }
+    }
+
+    async fn current_ratchet_key(
+        &self,
+        peer: &ProtocolAddress,
+    ) -> Result<Option<PublicKey>, SignalProtocolError> {
+        match self.store.load_session(peer).await? {
+            Some(record) => Ok(record
+                .session_state()
+                .map(|state| state.sender_ratchet_key())
+                .transpose()?),
+            None => Ok(None),
+        }
+    }
+
+    async fn alice_base_key(
+        &self,
+        peer: &ProtocolAddress,
+    ) -> Result<Option<Vec<u8>>, SignalProtocolError> {
+        match self.store.load_session(peer).await? {
+            Some(record) => Ok(Some(record.alice_base_key()?.to_vec())),
+            None => Ok(None),
+        }
+    }
+
+    async fn archive_session(&mut self, peer: &ProtocolAddress) -> Result<(), SignalProtocolError> {
+        if let Some(mut record) = self.store.load_session(peer).await? {
+            record.archive_current_state()?;
+            self.store.store_session(peer, &record).await?;
+        }
+        Ok(())
+    }
+
+    async fn decrypt(
+        &mut self,
+        sender: &ProtocolAddress,
+        message: &InFlightMessage,
+        rng: &mut StdRng,
+    ) -> Result<Vec<u8>, SignalProtocolError> {
+        let ciphertext = match message.message_type {
+            CiphertextMessageType::PreKey => CiphertextMessage::PreKeySignalMessage(
+                PreKeySignalMessage::try_from(&message.ciphertext[..])?,
+            ),
+            CiphertextMessageType::Whisper => {
+                CiphertextMessage::SignalMessage(SignalMessage::try_from(&message.ciphertext[..])?)
+            }
+            CiphertextMessageType::SenderKey | CiphertextMessageType::Plaintext => {
+                unreachable!("only 1:1 messages are simulated")
+            }
+        };
+        message_decrypt(
+            &ciphertext,
+            sender,
+            &mut self.store.session_store,
+            &mut self.store.identity_store,
+            &mut self.store.pre_key_store,
+            &self.store.signed_pre_key_store,
+            &mut self.store.kyber_pre_key_store,
+            rng,
+        )
+        .await
+    }
+}
+
+/// What the simulator tracks about one direction of communication between two devices.
+#[derive(Default)]
+struct Link {
+    /// Bumped (in both directions) whenever either side resets the session or changes identity.
+    epoch: u64,
+    /// Bumped whenever the sender's ratchet key changes.
+    generation: u64,
+    last_ratchet_key: Option<PublicKey>,
+    /// Messages lost in the current generation, whose keys the recipient may have to keep around.
+    lost_in_generation: usize,
+}
+
+#[derive(Clone)]
+struct InFlightMessage {
+    id: u64,
+    sender: usize,
+    recipient: usize,
+    message_type: CiphertextMessageType,
+    ciphertext: Box<[u8]>,
+    plaintext: Box<[u8]>,
+    epoch: u64,
+    generation: u64,
+}
+
+/// A seeded run of several devices messaging each other over an unreliable network.
+///
+/// See the [module-level documentation](self) for what is checked.
+pub struct Simulation {
+    config: SimulationConfig,
+    rng: StdRng,
+    step: usize,
+    now: SystemTime,
+    devices: Vec<Device>,
+    /// Indexed by `[sender][recipient]`.
+    links: Vec<Vec<Link>>,
+    in_flight: Vec<InFlightMessage>,
+    delivered: HashSet<u64>,
+    next_message_id: u64,
+    report: SimulationReport,
+}
+
+impl Simulation {
+    /// Sets up `config.device_count` fresh devices.
+    ///
+    /// Devices don't share any sessions to start with.
+    pub fn new(config: SimulationConfig, seed: u64) -> Result<Self, SimulationError> {
+        if config.device_count < 2 {
+            return Err(SimulationError::InvalidConfig(
+                "at least two devices are required",
+            ));
+        }
+        let probabilities = [
+            config.loss_probability,
+            config.duplication_probability,
+            config.reorder_probability,
+            config.session_reset_probability,
+            config.identity_change_probability,
+        ];
+        if !probabilities.iter().all(|p| (0.0..=1.0).contains(p)) {
+            return Err(SimulationError::InvalidConfig(
+                "probabilities must be between 0 and 1",
+            ));
+        }
+        if config.session_reset_probability + config.identity_change_probability > 1.0 {
+            return Err(SimulationError::InvalidConfig(
+                "session resets and identity changes cannot happen more than once per step",
+            ));
+        }
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let devices = (0..config.device_count)
+            .map(|i| {
+                let address = ProtocolAddress::new(format!("device{i}"), DeviceId::from(1));
+                run_sync(Device::new(address, 1, now, &mut rng))
+            })
+            .collect::<Result<Vec<_>, SignalProtocolError>>()
+            .map_err(|source| SimulationError::Protocol { step: 0, source })?;
+        let links = (0..config.device_count)
+            .map(|_| {
+                std::iter::repeat_with(Link::default)
+                    .take(config.device_count)
+                    .collect()
+            })
+            .collect();
+
+        Ok(Self {
+            config,
+            rng,
+            step: 0,
+            now,
+            devices,
+            links,
+            in_flight: Vec::new(),
+            delivered: HashSet::new(),
+            next_message_id: 0,
+            report: SimulationReport::default(),
+        })
+    }
+
+    /// Runs all the configured steps and then [settles](Self::settle) the network.
+    pub fn run(mut self) -> Result<SimulationReport, SimulationError> {
+        for _ in 0..self.config.steps {
+            self.step()?;
+        }
+        self.settle()?;
+        Ok(self.report)
+    }
+
+    /// Runs a single random event: a send, a delivery, a session reset, or an identity change.
+    pub fn step(&mut self) -> Result<(), SimulationError> {
+        self.step += 1;
+        self.now += Duration::from_secs(1);
+
+        let roll: f64 = self.rng.gen();
+        run_sync(async {
+            if roll < self.config.session_reset_probability {
+                self.reset_session().await
+            } else if roll
+                < self.config.session_reset_probability + self.config.identity_change_probability
+            {
+                self.change_identity().await
+            } else if self.in_flight.is_empty() || self.rng.gen_bool(0.5) {
+                let (sender, recipient) = self.random_pair();
+                self.send(sender, recipient, false).await
+            } else {
+                let index = if self.rng.gen_bool(self.config.reorder_probability) {
+                    self.rng.gen_range(0..self.in_flight.len())
+                } else {
+                    0
+                };
+                self.deliver(index).await
+            }
+        })
+    }
+
+    /// Delivers every message still in flight, then has each pair of devices exchange a message
+    /// in each direction and checks that they end up using the same session.
+    ///
+    /// Nothing is lost or duplicated while settling.
+    pub fn settle(&mut self) -> Result<(), SimulationError> {
+        run_sync(async {
+            while !self.in_flight.is_empty() {
+                self.deliver(0).await?;
+            }
+
+            for a in 0..self.devices.len() {
+                for b in a + 1..self.devices.len() {
+                    self.send(a, b, true).await?;
+                    self.deliver(0).await?;
+                    self.send(b, a, true).await?;
+                    self.deliver(0).await?;
+                    self.check_converged(a, b).await?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// What has happened so far.
+    pub fn report(&self) -> &SimulationReport {
+        &self.report
+    }
+
+    fn random_pair(&mut self) -> (usize, usize) {
+        let count = self.devices.len();
+        let first = self.rng.gen_range(0..count);
+        let second = (first + self.rng.gen_range(1..count)) % count;
+        (first, second)
+    }
+
+    fn unexpected(&self) -> impl FnOnce(SignalProtocolError) -> SimulationError {
+        let step = self.step;
+        move |source| SimulationError::Protocol { step, source }
+    }
+
+    async fn send(
+        &mut self,
+        sender: usize,
+        recipient: usize,
+        reliable: bool,
+    ) -> Result<(), SimulationError> {
+        let recipient_address = self.devices[recipient].address.clone();
+
+        // Lost and delayed messages leave skipped keys behind in the recipient's session. Don't
+        // send more than it can hold; we're not testing that limit here.
+        let link = &self.links[sender][recipient];
+        let outstanding = link.lost_in_generation
+            + self
+                .in_flight
+                .iter()
+                .filter(|m| m.sender == sender && m.recipient == recipient)
+                .count();
+        if !reliable && outstanding >= consts::MAX_MESSAGE_KEYS {
+            log::info!(
+                "step {}: {} has too many messages outstanding to {}",
+                self.step,
+                self.devices[sender].address,
+                recipient_address,
+            );
+            return Ok(());
+        }
+
+        let (message_type, ciphertext, plaintext) = self
+            .encrypt(sender, recipient)
+            .await
+            .map_err(self.unexpected())?;
+        self.observe_ratchet(sender, recipient)
+            .await
+            .map_err(self.unexpected())?;
+
+        let link = &mut self.links[sender][recipient];
+        let message = InFlightMessage {
+            id: self.next_message_id,
+            sender,
+            recipient,
+            message_type,
+            ciphertext,
+            plaintext,
+            epoch: link.epoch,
+            generation: link.generation,
+        };
+        self.next_message_id += 1;
+        self.report.messages_sent += 1;
+        log::info!(
+            "step {}: {} sent message {} to {}",
+            self.step,
+            self.devices[sender].address,
+            message.id,
+            recipient_address,
+        );
+
+        if !reliable && self.rng.gen_bool(self.config.loss_probability) {
+            log::info!("step {}: message {} was lost", self.step, message.id);
+            link.lost_in_generation += 1;
+            self.report.messages_lost += 1;
+            return Ok(());
+        }
+        if !reliable && self.rng.gen_bool(self.config.duplication_probability) {
+            log::info!("step {}: message {} was duplicated", self.step, message.id);
+            self.in_flight.push(message.clone());
+            self.report.messages_duplicated += 1;
+        }
+        self.in_flight.push(message);
+        Ok(())
+    }
+
+    async fn encrypt(
+        &mut self,
+        sender: usize,
+        recipient: usize,
+    ) -> Result<(CiphertextMessageType, Box<[u8]>, Box<[u8]>), SignalProtocolError> {
+        let recipient_address = self.devices[recipient].address.clone();
+
+        if !self.devices[sender]
+            .has_usable_session(&recipient_address, self.now)
+            .await?
+        {
+            let bundle = self.devices[recipient]
+                .issue_pre_key_bundle(self.now, &mut self.rng)
+                .await?;
+            let sender_device = &mut self.devices[sender];
+            process_prekey_bundle(
+                &recipient_address,
+                &mut sender_device.store.session_store,
+                &mut sender_device.store.identity_store,
+                &bundle,
+                self.now,
+                &mut self.rng,
+            )
+            .await?;
+        }
+
+        // Start each plaintext with the message ID so that mix-ups are obvious.
+        let mut plaintext = self.next_message_id.to_be_bytes().to_vec();
+        plaintext.resize(plaintext.len() + self.rng.gen_range(0..64), 0);
+        self.rng.fill(&mut plaintext[8..]);
+
+        let sender_device = &mut self.devices[sender];
+        let ciphertext = message_encrypt(
+            &plaintext,
+            &recipient_address,
+            &mut sender_device.store.session_store,
+            &mut sender_device.store.identity_store,
+            self.now,
+        )
+        .await?;
+
+        Ok((
+            ciphertext.message_type(),
+            ciphertext.serialize().into(),
+            plaintext.into(),
+        ))
+    }
+
+    async fn deliver(&mut self, index: usize) -> Result<(), SimulationError> {
+        let message = self.in_flight.remove(index);
+        let sender = self.devices[message.sender].address.clone();
+        let recipient = self.devices[message.recipient].address.clone();
+
+        // A receiving session only keeps the most recent few chains for each sender.
+        let link = &self.links[message.sender][message.recipient];
+        let chain_discarded =
+            link.generation - message.generation >= consts::MAX_RECEIVER_CHAINS as u64;
+        let invalidated = link.epoch != message.epoch || chain_discarded;
+
+        let result = self.devices[message.recipient]
+            .decrypt(&sender, &message, &mut self.rng)
+            .await;
+
+        match result {
+            Ok(plaintext) => {
+                if !self.delivered.insert(message.id) {
+                    return Err(SimulationError::DecryptedTwice {
+                        step: self.step,
+                        message: message.id,
+                        sender,
+                        recipient,
+                    });
+                }
+                if plaintext[..] != message.plaintext[..] {
+                    return Err(SimulationError::WrongPlaintext {
+                        step: self.step,
+                        message: message.id,
+                        sender,
+                        recipient,
+                    });
+                }
+                log::info!(
+                    "step {}: {} received message {} from {}",
+                    self.step,
+                    recipient,
+                    message.id,
+                    sender,
+                );
+                self.report.messages_delivered += 1;
+                self.observe_ratchet(message.recipient, message.sender)
+                    .await
+                    .map_err(self.unexpected())?;
+            }
+            Err(error) if self.delivered.contains(&message.id) => {
+                log::info!(
+                    "step {}: {} rejected duplicate message {} from {}: {}",
+                    self.step,
+                    recipient,
+                    message.id,
+                    sender,
+                    error,
+                );
+                self.report.duplicates_rejected += 1;
+            }
+            Err(error) if invalidated => {
+                log::info!(
+                    "step {}: {} could not decrypt invalidated message {} from {}: {}",
+                    self.step,
+                    recipient,
+                    message.id,
+                    sender,
+                    error,
+                );
+                self.report.messages_invalidated += 1;
+            }
+            Err(source) => {
+                return Err(SimulationError::DecryptionFailed {
+                    step: self.step,
+                    message: message.id,
+                    sender,
+                    recipient,
+                    source: Box::new(source),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    async fn reset_session(&mut self) -> Result<(), SimulationError> {
+        let (device, peer) = self.random_pair();
+        let peer_address = self.devices[peer].address.clone();
+        log::info!(
+            "step {}: {} reset its session with {}",
+            self.step,
+            self.devices[device].address,
+            peer_address,
+        );
+
+        self.devices[device]
+            .archive_session(&peer_address)
+            .await
+            .map_err(self.unexpected())?;
+        self.bump_epoch(device, peer);
+        self.observe_ratchet(device, peer)
+            .await
+            .map_err(self.unexpected())?;
+        self.report.session_resets += 1;
+        Ok(())
+    }
+
+    async fn change_identity(&mut self) -> Result<(), SimulationError> {
+        let device = self.rng.gen_range(0..self.devices.len());
+        let address = self.devices[device].address.clone();
+        log::info!("step {}: {} reinstalled", self.step, address);
+
+        let next_pre_key_id = self.devices[device].next_pre_key_id;
+        self.devices[device] =
+            Device::new(address.clone(), next_pre_key_id, self.now, &mut self.rng)
+                .await
+                .map_err(self.unexpected())?;
+        let new_identity = *self.devices[device]
+            .store
+            .get_identity_key_pair()
+            .await
+            .map_err(self.unexpected())?
+            .identity_key();
+
+        // Everyone else finds out (say, from the server rejecting their next message), drops their
+        // old session, and accepts the new identity.
+        for peer in 0..self.devices.len() {
+            if peer == device {
+                continue;
+            }
+            self.devices[peer]
+                .archive_session(&address)
+                .await
+                .map_err(self.unexpected())?;
+            self.devices[peer]
+                .store
+                .save_identity(&address, &new_identity)
+                .await
+                .map_err(self.unexpected())?;
+            self.bump_epoch(device, peer);
+            self.observe_ratchet(device, peer)
+                .await
+                .map_err(self.unexpected())?;
+            self.observe_ratchet(peer, device)
+                .await
+                .map_err(self.unexpected())?;
+        }
+        self.report.identity_changes += 1;
+        Ok(())
+    }
+
+    fn bump_epoch(&mut self, a: usize, b: usize) {
+        self.links[a][b].epoch += 1;
+        self.links[b][a].epoch += 1;
+    }
+
+    /// Checks whether `device`'s sending ratchet key for `peer` has changed since last time.
+    async fn observe_ratchet(
+        &mut self,
+        device: usize,
+        peer: usize,
+    ) -> Result<(), SignalProtocolError> {
+        let key = self.devices[device]
+            .current_ratchet_key(&self.devices[peer].address)
+            .await?;
+        let link = &mut self.links[device][peer];
+        if link.last_ratchet_key != key {
+            link.last_ratchet_key = key;
+            link.generation += 1;
+            link.lost_in_generation = 0;
+        }
+        Ok(())
+    }
+
+    async fn check_converged(&self, a: usize, b: usize) -> Result<(), SimulationError> {
+        let a_address = &self.devices[a].address;
+        let b_address = &self.devices[b].address;
+        let a_base_key = self.devices[a]
+            .alice_base_key(b_address)
+            .await
+            .map_err(self.unexpected())?;
+        let b_base_key = self.devices[b]
+            .alice_base_key(a_address)
+            .await
+            .map_err(self.unexpected())?;
+        if a_base_key.is_none() || a_base_key != b_base_key {
+            return Err(SimulationError::SessionsDiverged(
+                a_address.clone(),
+                b_address.clone(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The in-memory stores never actually wait on anything.
+fn run_sync<T>(future: impl Future<Output = T>) -> T {
+    future
+        .now_or_never()
+        .expect("in-memory stores complete synchronously")
+}
+
+fn timestamp(now: SystemTime) -> Timestamp {
+    let millis = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("simulated time is after the epoch")
+        .as_millis();
+    Timestamp::from_epoch_millis(millis.try_into().expect("simulated time fits in 64 bits"))
+}
@@ -8,6 +8,7 @@ use std::time::{Duration, SystemTime};
 
 use prost::Message;
 use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
 
 use crate::ratchet::{ChainKey, MessageKeys, RootKey};
 use crate::{kem, IdentityKey, KeyPair, PrivateKey, PublicKey, SignalProtocolError};
@@ -15,6 +16,7 @@ use crate::{kem, IdentityKey, KeyPair, PrivateKey, PublicKey, SignalProtocolErro
 use crate::consts;
 use crate::proto::storage::{session_structure, RecordStructure, SessionStructure};
 use crate::state::{KyberPreKeyId, PreKeyId, SignedPreKeyId};
+use crate::utils::decode_without_copies;
 
 /// A distinct error type to keep from accidentally propagating deserialization errors.
 #[derive(Debug)]
@@ -563,6 +565,12 @@ impl SessionState {
     }
 }
 
+impl Drop for SessionState {
+    fn drop(&mut self) {
+        self.session.zeroize();
+    }
+}
+
 impl From<SessionStructure> for SessionState {
     fn from(value: SessionStructure) -> SessionState {
         SessionState::from_session_structure(value)
@@ -570,8 +578,8 @@ impl From<SessionStructure> for SessionState {
 }
 
 impl From<SessionState> for SessionStructure {
-    fn from(value: SessionState) -> SessionStructure {
-        value.session
+    fn from(mut value: SessionState) -> SessionStructure {
+        std::mem::take(&mut value.session)
     }
 }
 
@@ -587,6 +595,13 @@ pub struct SessionRecord {
     previous_sessions: Vec<Vec<u8>>,
 }
 
+impl Drop for SessionRecord {
+    fn drop(&mut self) {
+        // The current session wipes itself.
+        self.previous_sessions.zeroize();
+    }
+}
+
 impl SessionRecord {
     pub fn new_fresh() -> Self {
         Self {
@@ -603,7 +618,7 @@ impl SessionRecord {
     }
 
     pub fn deserialize(bytes: &[u8]) -> Result<Self, SignalProtocolError> {
-        let record = RecordStructure::decode(bytes)
+        let record = decode_without_copies::<RecordStructure>(bytes)
             .map_err(|_| InvalidSessionError("failed to decode session record protobuf"))?;
 
         Ok(Self {
@@ -655,7 +670,7 @@ impl SessionRecord {
         &self,
     ) -> impl ExactSizeIterator<Item = Result<SessionState, InvalidSessionError>> + '_ {
         self.previous_sessions.iter().map(|bytes| {
-            Ok(SessionStructure::decode(&bytes[..])
+            Ok(decode_without_copies::<SessionStructure>(bytes)
                 .map_err(|_| InvalidSessionError("failed to decode previous session protobuf"))?
                 .into())
         })
@@ -700,11 +715,13 @@ impl SessionRecord {
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>, SignalProtocolError> {
-        let record = RecordStructure {
+        let mut record = RecordStructure {
             current_session: self.current_session.as_ref().map(|s| s.into()),
             previous_sessions: self.previous_sessions.clone(),
         };
-        Ok(record.encode_to_vec())
+        let result = record.encode_to_vec();
+        record.zeroize();
+        Ok(result)
     }
 
     pub fn remote_registration_id(&self) -> Result<u32, SignalProtocolError> {
@@ -49,7 +49,7 @@ impl InMemIdentityKeyStore {
 #[async_trait(?Send)]
 impl traits::IdentityKeyStore for InMemIdentityKeyStore {
     async fn get_identity_key_pair(&self) -> Result<IdentityKeyPair> {
-        Ok(self.key_pair)
+        Ok(self.key_pair.clone())
     }
 
     async fn get_local_registration_id(&self) -> Result<u32> {
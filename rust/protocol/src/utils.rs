@@ -6,6 +6,9 @@
 use std::cmp::Ordering;
 use std::ops::{Add, Div, Rem};
 
+use prost::Message;
+use zeroize::Zeroizing;
+
 fn expand_top_bit(a: u8) -> u8 {
     //if (a >> 7) == 1 { 0xFF } else { 0 }
     0u8.wrapping_sub(a >> 7)
@@ -100,6 +103,171 @@ pub(crate) fn div_ceil<
     }
 }
 
+/// Decodes `bytes` without leaving unwiped copies of any fields behind.
+///
+/// Decoding directly from a slice copies each field into a temporary buffer first, and those
+/// temporaries are freed without being wiped.
+pub(crate) fn decode_without_copies<M: Message + Default>(
+    bytes: &[u8],
+) -> Result<M, prost::DecodeError> {
+    let shared = bytes::Bytes::from(bytes.to_vec());
+    let result = M::decode(shared.clone());
+    // Every field has been copied out of `shared` by now, so this hands back the original buffer.
+    drop(Zeroizing::new(Vec::from(shared)));
+    result
+}
+
+/// Drops `value` in place and returns the bytes it occupied afterwards.
+///
+/// Only sees the value's inline bytes; heap buffers are freed by the time this returns. Use
+/// [assert_heap_zeroized_on_drop] for those.
+///
+/// # Safety
+///
+/// Every byte of a `T` must be initialized, i.e. `T` (including every field, recursively) must not
+/// have padding bytes or `MaybeUninit` contents.
+#[cfg(test)]
+#[allow(unsafe_code)]
+pub(crate) unsafe fn bytes_after_drop<T>(value: T) -> Vec<u8> {
+    let mut slot = std::mem::ManuallyDrop::new(value);
+    let ptr: *mut T = &mut *slot;
+    // SAFETY: `slot` is never used as a `T` again, and ManuallyDrop keeps its storage alive.
+    unsafe { std::ptr::drop_in_place(ptr) };
+    // SAFETY: the storage is still live and was fully initialized, and the caller promises that
+    // `T` has no padding, so every byte is initialized.
+    unsafe { std::slice::from_raw_parts(ptr as *const u8, std::mem::size_of::<T>()) }.to_vec()
+}
+
+/// Checks that dropping `value` wipes the bytes it occupies.
+///
+/// # Safety
+///
+/// Same as [bytes_after_drop]: `T` must not have padding bytes.
+#[cfg(test)]
+#[allow(unsafe_code)]
+pub(crate) unsafe fn assert_zeroized_on_drop<T>(value: T) {
+    // SAFETY: passed on to the caller.
+    let bytes = unsafe { bytes_after_drop(value) };
+    assert!(
+        bytes.iter().all(|&b| b == 0),
+        "{} was not wiped: {}",
+        std::any::type_name::<T>(),
+        hex::encode(bytes)
+    );
+}
+
+/// Checks that dropping `value` wipes each of the heap buffers picked out by `buffers` before
+/// freeing it.
+///
+/// Each buffer must be owned by `value`, so that dropping `value` frees it.
+#[cfg(test)]
+pub(crate) fn assert_heap_zeroized_on_drop<T>(value: T, buffers: impl FnOnce(&T) -> Vec<&[u8]>) {
+    let watched: Vec<_> = buffers(&value)
+        .into_iter()
+        .map(|buffer| heap_watch::watch(buffer.as_ptr(), buffer.len()))
+        .collect();
+    drop(value);
+    for (index, slot) in watched.into_iter().enumerate() {
+        match heap_watch::finish(slot) {
+            Some(true) => {}
+            Some(false) => panic!(
+                "buffer {index} of {} was freed without being wiped",
+                std::any::type_name::<T>()
+            ),
+            None => panic!(
+                "buffer {index} of {} was not freed",
+                std::any::type_name::<T>()
+            ),
+        }
+    }
+}
+
+/// A global allocator for the unit tests that checks whether watched buffers are all zeros when
+/// they're freed.
+///
+/// Reading a buffer after it's freed isn't allowed, so this is the only place the check can happen.
+#[cfg(test)]
+#[allow(unsafe_code)]
+mod heap_watch {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    #[derive(Clone, Copy)]
+    enum Slot {
+        Empty,
+        Watching { addr: usize, len: usize },
+        Freed { wiped: bool },
+    }
+
+    // A fixed-size table, because the allocator can't allocate to keep track of allocations.
+    static SLOTS: Mutex<[Slot; 64]> = Mutex::new([Slot::Empty; 64]);
+    static WATCHING: AtomicUsize = AtomicUsize::new(0);
+
+    struct WatchingAllocator;
+
+    #[global_allocator]
+    static ALLOCATOR: WatchingAllocator = WatchingAllocator;
+
+    // SAFETY: all allocation is done by System; this only reads buffers before passing them on.
+    unsafe impl GlobalAlloc for WatchingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            // SAFETY: same contract as ours.
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            if WATCHING.load(Ordering::Acquire) != 0 {
+                let mut slots = SLOTS.lock().unwrap_or_else(|e| e.into_inner());
+                for slot in slots.iter_mut() {
+                    if let Slot::Watching { addr, len } = *slot {
+                        if addr == ptr as usize && len <= layout.size() {
+                            // SAFETY: the buffer is still allocated, and its first `len` bytes
+                            // were initialized when the watch started.
+                            let bytes = unsafe { std::slice::from_raw_parts(ptr, len) };
+                            *slot = Slot::Freed {
+                                wiped: bytes.iter().all(|&b| b == 0),
+                            };
+                        }
+                    }
+                }
+            }
+            // SAFETY: same contract as ours.
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    /// Starts watching the `len` bytes at `addr`, returning a slot to pass to [finish].
+    pub(super) fn watch(addr: *const u8, len: usize) -> usize {
+        assert!(len > 0, "can't watch an empty buffer");
+        let mut slots = SLOTS.lock().expect("not poisoned");
+        let index = slots
+            .iter()
+            .position(|slot| matches!(slot, Slot::Empty))
+            .expect("too many buffers watched at once");
+        slots[index] = Slot::Watching {
+            addr: addr as usize,
+            len,
+        };
+        WATCHING.fetch_add(1, Ordering::Release);
+        index
+    }
+
+    /// Stops watching `slot`, returning whether its buffer was wiped, or `None` if it's still
+    /// allocated.
+    pub(super) fn finish(slot: usize) -> Option<bool> {
+        let mut slots = SLOTS.lock().expect("not poisoned");
+        let result = match slots[slot] {
+            Slot::Freed { wiped } => Some(wiped),
+            Slot::Watching { .. } => None,
+            Slot::Empty => unreachable!("slot was never watched"),
+        };
+        slots[slot] = Slot::Empty;
+        WATCHING.fetch_sub(1, Ordering::Release);
+        result
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -183,8 +183,8 @@ fn test_alice_and_bob_agree_on_chain_keys_with_kyber() -> Result<(), SignalProto
     let bob_kyber_pre_key_pair = kem::KeyPair::generate(kem::KeyType::Kyber1024);
 
     let alice_parameters = AliceSignalProtocolParameters::new(
-        alice_identity_key_pair,
-        alice_base_key_pair,
+        alice_identity_key_pair.clone(),
+        alice_base_key_pair.clone(),
         *bob_identity_key_pair.identity_key(),
         bob_signed_pre_key_pair.public_key,
         bob_ephemeral_key_pair.public_key,
@@ -209,7 +209,7 @@ fn test_alice_and_bob_agree_on_chain_keys_with_kyber() -> Result<(), SignalProto
         bob_identity_key_pair,
         bob_signed_pre_key_pair,
         None,
-        bob_ephemeral_key_pair,
+        bob_ephemeral_key_pair.clone(),
         Some(bob_kyber_pre_key_pair),
         *alice_identity_key_pair.identity_key(),
         alice_base_key_pair.public_key,
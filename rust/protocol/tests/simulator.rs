@@ -0,0 +1,115 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use libsignal_protocol::simulator::{Simulation, SimulationConfig, SimulationError};
+use proptest::prelude::*;
+
+fn reliable_config() -> SimulationConfig {
+    SimulationConfig {
+        loss_probability: 0.0,
+        duplication_probability: 0.0,
+        reorder_probability: 0.0,
+        session_reset_probability: 0.0,
+        identity_change_probability: 0.0,
+        ..SimulationConfig::default()
+    }
+}
+
+#[test]
+fn reliable_network_delivers_everything() -> Result<(), SimulationError> {
+    let report = Simulation::new(reliable_config(), 0)?.run()?;
+    assert!(report.messages_sent > 0);
+    assert_eq!(report.messages_delivered, report.messages_sent);
+    assert_eq!(report.messages_invalidated, 0);
+    Ok(())
+}
+
+#[test]
+fn reordering_alone_delivers_everything() -> Result<(), SimulationError> {
+    let config = SimulationConfig {
+        reorder_probability: 1.0,
+        ..reliable_config()
+    };
+    let report = Simulation::new(config, 0)?.run()?;
+    assert_eq!(report.messages_delivered, report.messages_sent);
+    assert_eq!(report.messages_invalidated, 0);
+    Ok(())
+}
+
+#[test]
+fn duplicates_are_rejected() -> Result<(), SimulationError> {
+    let config = SimulationConfig {
+        duplication_probability: 0.5,
+        reorder_probability: 0.5,
+        ..reliable_config()
+    };
+    let report = Simulation::new(config, 0)?.run()?;
+    assert!(report.messages_duplicated > 0);
+    assert_eq!(report.duplicates_rejected, report.messages_duplicated);
+    assert_eq!(report.messages_delivered, report.messages_sent);
+    Ok(())
+}
+
+#[test]
+fn same_seed_same_run() -> Result<(), SimulationError> {
+    let config = SimulationConfig {
+        session_reset_probability: 0.05,
+        identity_change_probability: 0.02,
+        ..SimulationConfig::default()
+    };
+    let first = Simulation::new(config.clone(), 1234)?.run()?;
+    let second = Simulation::new(config, 1234)?.run()?;
+    assert_eq!(first, second);
+    Ok(())
+}
+
+#[test]
+fn invalid_config() {
+    let config = SimulationConfig {
+        device_count: 1,
+        ..SimulationConfig::default()
+    };
+    assert!(matches!(
+        Simulation::new(config, 0),
+        Err(SimulationError::InvalidConfig(_))
+    ));
+
+    let config = SimulationConfig {
+        loss_probability: 1.5,
+        ..SimulationConfig::default()
+    };
+    assert!(matches!(
+        Simulation::new(config, 0),
+        Err(SimulationError::InvalidConfig(_))
+    ));
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn adversarial_network(
+        seed: u64,
+        device_count in 2_usize..5,
+        loss_probability in 0.0..0.3,
+        duplication_probability in 0.0..0.3,
+        reorder_probability in 0.0..=1.0,
+        session_reset_probability in 0.0..0.05,
+        identity_change_probability in 0.0..0.02,
+    ) {
+        let config = SimulationConfig {
+            device_count,
+            steps: 300,
+            loss_probability,
+            duplication_probability,
+            reorder_probability,
+            session_reset_probability,
+            identity_change_probability,
+        };
+        if let Err(e) = Simulation::new(config, seed).and_then(Simulation::run) {
+            return Err(TestCaseError::fail(e.to_string()));
+        }
+    }
+}
@@ -147,11 +147,11 @@ pub fn initialize_sessions_v3() -> Result<(SessionRecord, SessionRecord), Signal
     let alice_base_key = KeyPair::generate(&mut csprng);
 
     let bob_base_key = KeyPair::generate(&mut csprng);
-    let bob_ephemeral_key = bob_base_key;
+    let bob_ephemeral_key = bob_base_key.clone();
 
     let alice_params = AliceSignalProtocolParameters::new(
-        alice_identity,
-        alice_base_key,
+        alice_identity.clone(),
+        alice_base_key.clone(),
         *bob_identity.identity_key(),
         bob_base_key.public_key,
         bob_ephemeral_key.public_key,
@@ -183,13 +183,13 @@ pub fn initialize_sessions_v4() -> Result<(SessionRecord, SessionRecord), Signal
     let alice_base_key = KeyPair::generate(&mut csprng);
 
     let bob_base_key = KeyPair::generate(&mut csprng);
-    let bob_ephemeral_key = bob_base_key;
+    let bob_ephemeral_key = bob_base_key.clone();
 
     let bob_kyber_key = kem::KeyPair::generate(kem::KeyType::Kyber1024);
 
     let alice_params = AliceSignalProtocolParameters::new(
-        alice_identity,
-        alice_base_key,
+        alice_identity.clone(),
+        alice_base_key.clone(),
         *bob_identity.identity_key(),
         bob_base_key.public_key,
         bob_ephemeral_key.public_key,
@@ -0,0 +1,152 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Checks that secrets don't linger in freed heap memory.
+//!
+//! This installs a global allocator that scans every block as it is freed, so it lives in its own
+//! test binary.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use libsignal_protocol::*;
+
+/// A recognizable stand-in for secret key material.
+const MARKER: &[u8; 32] = b"--zeroize-test-secret-marker--!!";
+
+/// Only the middle of the marker is searched for, since private keys get clamped at both ends.
+fn contains_marker(block: &[u8]) -> bool {
+    block.windows(24).any(|window| window == &MARKER[4..28])
+}
+
+static SCAN_LOCK: Mutex<()> = Mutex::new(());
+static SCANNING: AtomicBool = AtomicBool::new(false);
+static FOUND_MARKER: AtomicBool = AtomicBool::new(false);
+
+struct ScanningAllocator;
+
+// SAFETY: defers to System for the actual allocation. The default `realloc` goes through our
+// `dealloc`, so blocks left behind by a reallocation are scanned too.
+unsafe impl GlobalAlloc for ScanningAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if SCANNING.load(Ordering::SeqCst)
+            && contains_marker(std::slice::from_raw_parts(ptr, layout.size()))
+        {
+            FOUND_MARKER.store(true, Ordering::SeqCst);
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: ScanningAllocator = ScanningAllocator;
+
+/// Asserts that no heap block freed while running `f` still held the marker.
+///
+/// `f` must wipe any buffers it creates itself.
+fn assert_no_marker_freed(f: impl FnOnce()) {
+    assert!(!scan(f), "secret material was freed without being wiped");
+}
+
+/// Runs `f` and reports whether any heap block freed along the way still held the marker.
+fn scan(f: impl FnOnce()) -> bool {
+    let _guard = SCAN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    FOUND_MARKER.store(false, Ordering::SeqCst);
+    SCANNING.store(true, Ordering::SeqCst);
+    f();
+    SCANNING.store(false, Ordering::SeqCst);
+    FOUND_MARKER.load(Ordering::SeqCst)
+}
+
+fn wipe(mut buffer: Vec<u8>) {
+    buffer.fill(0);
+    drop(std::hint::black_box(buffer));
+}
+
+/// Concatenates `parts` without ever reallocating, which would free an unwiped copy.
+fn concat(parts: &[&[u8]]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(parts.iter().map(|part| part.len()).sum());
+    for part in parts {
+        result.extend_from_slice(part);
+    }
+    result
+}
+
+/// Hand-encodes a protobuf field with a length prefix. Every field used here fits in one byte.
+fn length_delimited(field_number: u8, contents: &[u8]) -> Vec<u8> {
+    let len = u8::try_from(contents.len()).expect("short field");
+    assert!(len < 0x80);
+    concat(&[&[(field_number << 3) | 2, len], contents])
+}
+
+#[test]
+fn scanner_notices_unwiped_buffers() {
+    assert!(scan(|| drop(std::hint::black_box(MARKER.to_vec()))));
+}
+
+#[test]
+fn kem_secret_key_is_wiped() {
+    assert_no_marker_freed(|| {
+        // Keep the key type prefix of a real key, but replace the key material itself.
+        let mut serialized = kem::KeyPair::generate(kem::KeyType::Kyber1024)
+            .secret_key
+            .serialize()
+            .into_vec();
+        for (byte, marker_byte) in serialized[1..].iter_mut().zip(MARKER.iter().cycle()) {
+            *byte = *marker_byte;
+        }
+
+        let secret_key = kem::SecretKey::deserialize(&serialized).expect("valid key");
+        wipe(serialized);
+        let cloned = secret_key.clone();
+        drop(secret_key);
+        drop(cloned);
+    });
+}
+
+#[test]
+fn session_record_is_wiped() {
+    assert_no_marker_freed(|| {
+        // SessionStructure { root_key: MARKER, sender_chain: { chain_key: { key: MARKER } } }
+        let chain_key = length_delimited(2, MARKER);
+        let sender_chain = length_delimited(3, &chain_key);
+        let root_key_field = length_delimited(4, MARKER);
+        let sender_chain_field = length_delimited(6, &sender_chain);
+        let session = concat(&[&root_key_field, &sender_chain_field]);
+
+        // RecordStructure { current_session: session, previous_sessions: [session] }
+        let current_session_field = length_delimited(1, &session);
+        let previous_session_field = length_delimited(2, &session);
+        let serialized = concat(&[&current_session_field, &previous_session_field]);
+        for buffer in [
+            chain_key,
+            sender_chain,
+            root_key_field,
+            sender_chain_field,
+            session,
+            current_session_field,
+            previous_session_field,
+        ] {
+            wipe(buffer);
+        }
+
+        let record = SessionRecord::deserialize(&serialized).expect("valid record");
+        wipe(serialized);
+
+        let mut reserialized = record.serialize().expect("can serialize");
+        assert!(contains_marker(&reserialized));
+        reserialized.fill(0);
+        wipe(reserialized);
+
+        let cloned = record.clone();
+        drop(record);
+        drop(cloned);
+    });
+}
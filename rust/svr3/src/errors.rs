@@ -18,6 +18,8 @@ pub enum Error {
     BadResponse,
     /// Response status is not OK: {0}
     BadResponseStatus(ErrorStatus),
+    /// Failed to restore the secret, {0} tries remaining
+    RestoreFailed(u32),
 }
 
 /// Represents an erroneous SVR3 response status
@@ -0,0 +1,225 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! The server side of the protocol, for testing clients without a real enclave.
+//!
+//! A [FakeServer] stands in for a single enclave holding a single user's OPRF key. It follows the
+//! same rules as the real servers: every evaluation uses up a try, and the key is deleted once no
+//! tries remain.
+
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use prost::Message;
+use rand_core::CryptoRngCore;
+
+use crate::proto::svr3;
+use crate::proto::svr3::{create_response, evaluate_response, query_response};
+
+/// The largest `max_tries` the real servers accept.
+const MAX_TRIES_LIMIT: u32 = 255;
+
+struct StoredKey {
+    key: Scalar,
+    tries_remaining: u32,
+}
+
+#[derive(Default)]
+pub struct FakeServer {
+    stored: Option<StoredKey>,
+}
+
+impl FakeServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The tries left before the key is deleted, or `None` if nothing is stored.
+    pub fn tries_remaining(&self) -> Option<u32> {
+        self.stored.as_ref().map(|stored| stored.tries_remaining)
+    }
+
+    /// Handles a serialized request and returns the serialized response.
+    ///
+    /// Returns `None` if the request can't be decoded, in which case the real servers drop the
+    /// connection.
+    pub fn handle<R: CryptoRngCore>(&mut self, request: &[u8], rng: &mut R) -> Option<Vec<u8>> {
+        let inner = match svr3::Request::decode(request).ok()?.inner? {
+            svr3::request::Inner::Create(request) => {
+                svr3::response::Inner::Create(self.create(request, rng))
+            }
+            svr3::request::Inner::Evaluate(request) => {
+                svr3::response::Inner::Evaluate(self.evaluate(request))
+            }
+            svr3::request::Inner::Remove(svr3::RemoveRequest {}) => {
+                self.stored = None;
+                svr3::response::Inner::Remove(svr3::RemoveResponse {})
+            }
+            svr3::request::Inner::Query(svr3::QueryRequest {}) => {
+                svr3::response::Inner::Query(self.query())
+            }
+        };
+        Some(svr3::Response { inner: Some(inner) }.encode_to_vec())
+    }
+
+    fn create<R: CryptoRngCore>(
+        &mut self,
+        request: svr3::CreateRequest,
+        rng: &mut R,
+    ) -> svr3::CreateResponse {
+        let svr3::CreateRequest {
+            max_tries,
+            blinded_element,
+        } = request;
+        let Some(blinded_element) = decode_element(&blinded_element) else {
+            return create_error(create_response::Status::InvalidRequest);
+        };
+        if !(1..=MAX_TRIES_LIMIT).contains(&max_tries) {
+            return create_error(create_response::Status::InvalidRequest);
+        }
+
+        let key = Scalar::random(rng);
+        self.stored = Some(StoredKey {
+            key,
+            tries_remaining: max_tries,
+        });
+        svr3::CreateResponse {
+            status: create_response::Status::Ok.into(),
+            evaluated_element: encode_element(key * blinded_element),
+        }
+    }
+
+    fn evaluate(&mut self, request: svr3::EvaluateRequest) -> svr3::EvaluateResponse {
+        let Some(blinded_element) = decode_element(&request.blinded_element) else {
+            return evaluate_error(evaluate_response::Status::InvalidRequest);
+        };
+        let Some(stored) = &mut self.stored else {
+            return evaluate_error(evaluate_response::Status::Missing);
+        };
+
+        stored.tries_remaining -= 1;
+        let response = svr3::EvaluateResponse {
+            status: evaluate_response::Status::Ok.into(),
+            evaluated_element: encode_element(stored.key * blinded_element),
+            tries_remaining: stored.tries_remaining,
+        };
+        if stored.tries_remaining == 0 {
+            self.stored = None;
+        }
+        response
+    }
+
+    fn query(&self) -> svr3::QueryResponse {
+        match self.tries_remaining() {
+            Some(tries_remaining) => svr3::QueryResponse {
+                status: query_response::Status::Ok.into(),
+                tries_remaining,
+            },
+            None => svr3::QueryResponse {
+                status: query_response::Status::Missing.into(),
+                tries_remaining: 0,
+            },
+        }
+    }
+}
+
+fn decode_element(bytes: &[u8]) -> Option<RistrettoPoint> {
+    CompressedRistretto::from_slice(bytes).ok()?.decompress()
+}
+
+fn encode_element(element: RistrettoPoint) -> Vec<u8> {
+    element.compress().to_bytes().into()
+}
+
+fn create_error(status: create_response::Status) -> svr3::CreateResponse {
+    svr3::CreateResponse {
+        status: status.into(),
+        evaluated_element: vec![],
+    }
+}
+
+fn evaluate_error(status: evaluate_response::Status) -> svr3::EvaluateResponse {
+    svr3::EvaluateResponse {
+        status: status.into(),
+        evaluated_element: vec![],
+        tries_remaining: 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use nonzero_ext::nonzero;
+    use rand_core::OsRng;
+
+    use super::*;
+    use crate::{Backup, Error, ErrorStatus, Query, Restore};
+
+    const SECRET: [u8; 32] = [7; 32];
+
+    fn run(servers: &mut [FakeServer], requests: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        servers
+            .iter_mut()
+            .zip(requests)
+            .map(|(server, request)| server.handle(request, &mut OsRng).expect("valid request"))
+            .collect()
+    }
+
+    fn backup(servers: &mut [FakeServer]) -> crate::MaskedShareSet {
+        let backup = Backup::new(&[1, 2, 3], "password", SECRET, nonzero!(2u32), &mut OsRng)
+            .expect("can create backup");
+        let responses = run(servers, &backup.requests);
+        backup
+            .finalize(&mut OsRng, &responses)
+            .expect("can finalize backup")
+    }
+
+    fn restore(
+        servers: &mut [FakeServer],
+        password: &str,
+        share_set: crate::MaskedShareSet,
+    ) -> Result<[u8; 32], Error> {
+        let restore = Restore::new(password, share_set, &mut OsRng).expect("can create restore");
+        let responses = run(servers, &restore.requests);
+        restore.finalize(&responses)
+    }
+
+    #[test]
+    fn backup_and_restore() {
+        let mut servers = [FakeServer::new(), FakeServer::new(), FakeServer::new()];
+        let share_set = backup(&mut servers);
+        assert_matches!(restore(&mut servers, "password", share_set), Ok(SECRET));
+        assert_eq!(servers[0].tries_remaining(), Some(1));
+    }
+
+    #[test]
+    fn tries_run_out() {
+        let mut servers = [FakeServer::new(), FakeServer::new(), FakeServer::new()];
+        let share_set = backup(&mut servers);
+        assert_matches!(
+            restore(&mut servers, "wrong", share_set.clone()),
+            Err(Error::RestoreFailed(1))
+        );
+        assert_matches!(
+            restore(&mut servers, "wrong", share_set.clone()),
+            Err(Error::RestoreFailed(0))
+        );
+        assert_matches!(
+            restore(&mut servers, "password", share_set),
+            Err(Error::BadResponseStatus(ErrorStatus::Missing))
+        );
+
+        let query = Query::new(3);
+        let responses = run(&mut servers, &query.requests);
+        assert_matches!(
+            query.finalize(&responses),
+            Err(Error::BadResponseStatus(ErrorStatus::Missing))
+        );
+    }
+
+    #[test]
+    fn undecodable_request_is_dropped() {
+        assert_eq!(FakeServer::new().handle(&[0xFF; 3], &mut OsRng), None);
+    }
+}
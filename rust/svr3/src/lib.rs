@@ -13,9 +13,11 @@ pub use ppss::{MaskedShareSet, OPRFSession};
 
 mod errors;
 pub use errors::{Error, ErrorStatus, OPRFError, PPSSError};
+#[cfg(any(test, feature = "fake-server"))]
+pub mod fake;
 mod proto;
 use proto::svr3;
-use proto::svr3::{create_response, evaluate_response};
+use proto::svr3::{create_response, evaluate_response, query_response};
 
 const CONTEXT: &str = "Signal_SVR3_20231121_PPSS_Context";
 
@@ -98,14 +100,66 @@ impl<'a> Restore<'a> {
         })
     }
     pub fn finalize(self, responses: &[Vec<u8>]) -> Result<[u8; 32], Error> {
-        let evaluated_elements = responses
+        let (evaluated_elements, tries_remaining): (Vec<_>, Vec<_>) = responses
             .iter()
             .map(|vec| decode_evaluate_response(vec))
-            .collect::<Result<Vec<_>, _>>()?;
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .unzip();
         let outputs = ppss::finalize_oprfs(self.oprfs, &evaluated_elements)?;
-        let (secret, _key) =
-            ppss::restore_secret(CONTEXT, self.password.as_bytes(), outputs, self.share_set)?;
-        Ok(secret)
+        match ppss::restore_secret(CONTEXT, self.password.as_bytes(), outputs, self.share_set) {
+            Ok((secret, _key)) => Ok(secret),
+            Err(PPSSError::InvalidCommitment) => Err(Error::RestoreFailed(
+                tries_remaining.into_iter().min().unwrap_or_default(),
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Deletes the stored secret from every server.
+///
+/// Removing a secret that doesn't exist is not an error.
+pub struct Remove {
+    pub requests: Vec<Vec<u8>>,
+}
+
+impl Remove {
+    pub fn new(server_count: usize) -> Self {
+        let request = make_remove_request().encode_to_vec();
+        Self {
+            requests: vec![request; server_count],
+        }
+    }
+
+    pub fn finalize(self, responses: &[Vec<u8>]) -> Result<(), Error> {
+        responses
+            .iter()
+            .try_for_each(|vec| decode_remove_response(vec))
+    }
+}
+
+/// Asks every server how many restore attempts are left before the secret is deleted.
+pub struct Query {
+    pub requests: Vec<Vec<u8>>,
+}
+
+impl Query {
+    pub fn new(server_count: usize) -> Self {
+        let request = make_query_request().encode_to_vec();
+        Self {
+            requests: vec![request; server_count],
+        }
+    }
+
+    /// Returns the smallest number of tries remaining on any server, since running out on any
+    /// one of them makes the secret unrecoverable.
+    pub fn finalize(self, responses: &[Vec<u8>]) -> Result<u32, Error> {
+        let tries_remaining = responses
+            .iter()
+            .map(|vec| decode_query_response(vec))
+            .collect::<Result<Vec<_>, _>>()?;
+        tries_remaining.into_iter().min().ok_or(Error::BadResponse)
     }
 }
 
@@ -165,14 +219,62 @@ impl From<evaluate_response::Status> for ErrorStatus {
     }
 }
 
-fn decode_evaluate_response(bytes: &[u8]) -> Result<[u8; 32], Error> {
+/// Returns the evaluated element along with the number of tries remaining.
+fn decode_evaluate_response(bytes: &[u8]) -> Result<([u8; 32], u32), Error> {
     let decoded = svr3::Response::decode(bytes)?;
     if let Some(svr3::response::Inner::Evaluate(response)) = decoded.inner {
         if response.status() == evaluate_response::Status::Ok {
-            Ok(response
-                .evaluated_element
-                .try_into()
-                .expect("response should be of right size"))
+            Ok((
+                response
+                    .evaluated_element
+                    .try_into()
+                    .expect("response should be of right size"),
+                response.tries_remaining,
+            ))
+        } else {
+            Err(Error::BadResponseStatus(response.status().into()))
+        }
+    } else {
+        Err(Error::BadResponse)
+    }
+}
+
+fn make_remove_request() -> svr3::Request {
+    svr3::Request {
+        inner: Some(svr3::request::Inner::Remove(svr3::RemoveRequest {})),
+    }
+}
+
+fn decode_remove_response(bytes: &[u8]) -> Result<(), Error> {
+    let decoded = svr3::Response::decode(bytes)?;
+    if let Some(svr3::response::Inner::Remove(svr3::RemoveResponse {})) = decoded.inner {
+        Ok(())
+    } else {
+        Err(Error::BadResponse)
+    }
+}
+
+fn make_query_request() -> svr3::Request {
+    svr3::Request {
+        inner: Some(svr3::request::Inner::Query(svr3::QueryRequest {})),
+    }
+}
+
+impl From<query_response::Status> for ErrorStatus {
+    fn from(status: query_response::Status) -> Self {
+        match status {
+            query_response::Status::Ok => unreachable!(),
+            query_response::Status::Unset => Self::Unset,
+            query_response::Status::Missing => Self::Missing,
+        }
+    }
+}
+
+fn decode_query_response(bytes: &[u8]) -> Result<u32, Error> {
+    let decoded = svr3::Response::decode(bytes)?;
+    if let Some(svr3::response::Inner::Query(response)) = decoded.inner {
+        if response.status() == query_response::Status::Ok {
+            Ok(response.tries_remaining)
         } else {
             Err(Error::BadResponseStatus(response.status().into()))
         }
@@ -326,8 +428,8 @@ mod test {
             .take(3)
             .collect();
         let result = restore.finalize(&responses);
-        let is_ppss_error = matches!(result, Err(Error::Ppss(ppss::PPSSError::InvalidCommitment)));
-        assert_eq!(should_succeed, result.is_ok() || is_ppss_error);
+        let is_restore_error = matches!(result, Err(Error::RestoreFailed(1)));
+        assert_eq!(should_succeed, result.is_ok() || is_restore_error);
     }
 
     #[test_case(vec![1, 2, 3], Error::BadData; "bad_protobuf")]
@@ -341,4 +443,50 @@ mod test {
         let result = restore.finalize(&[response]);
         assert_matches!(result, Err(_expected));
     }
+
+    fn make_query_response(status: svr3::query_response::Status, tries_remaining: u32) -> Vec<u8> {
+        svr3::Response {
+            inner: Some(svr3::response::Inner::Query(svr3::QueryResponse {
+                status: status.into(),
+                tries_remaining,
+            })),
+        }
+        .encode_to_vec()
+    }
+
+    #[test]
+    fn query_finalize_returns_fewest_tries() {
+        let query = Query::new(3);
+        assert_eq!(3, query.requests.len());
+        let responses =
+            [3, 1, 2].map(|tries| make_query_response(svr3::query_response::Status::Ok, tries));
+        assert_matches!(query.finalize(&responses), Ok(1));
+    }
+
+    #[test]
+    fn query_finalize_checks_status() {
+        let responses = [make_query_response(
+            svr3::query_response::Status::Missing,
+            0,
+        )];
+        assert_matches!(
+            Query::new(1).finalize(&responses),
+            Err(Error::BadResponseStatus(ErrorStatus::Missing))
+        );
+    }
+
+    #[test]
+    fn remove_finalize_checks_response_type() {
+        let remove_response = svr3::Response {
+            inner: Some(svr3::response::Inner::Remove(svr3::RemoveResponse {})),
+        }
+        .encode_to_vec();
+        assert_matches!(Remove::new(1).finalize(&[remove_response]), Ok(()));
+
+        let wrong_response = make_query_response(svr3::query_response::Status::Ok, 1);
+        assert_matches!(
+            Remove::new(1).finalize(&[wrong_response]),
+            Err(Error::BadResponse)
+        );
+    }
 }
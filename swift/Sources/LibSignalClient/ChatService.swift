@@ -11,9 +11,32 @@ public enum IpType: UInt8 {
     case unknown, ipv4, ipv6
 }
 
+/// The transport used to send requests to the Chat Service.
+public enum ChatTransport: UInt8 {
+    // Must be kept in sync with the libsignal-bridge Rust enum ChatTransportKind.
+
+    /// A long-lived WebSocket, over which the server can also send requests.
+    case webSocket = 0
+
+    /// One HTTP/2 request per chat request; the server can't send requests.
+    case http2 = 1
+}
+
+/// The state of a connection kept open by ``ChatService/startSupervised(authenticated:)``.
+public enum ChatConnectionState: Int32 {
+    // Must be kept in sync with connection_state_code in libsignal-bridge.
+
+    case disconnected = 0
+    case connecting = 1
+    case connected = 2
+
+    /// Waiting before trying to connect again.
+    case backingOff = 3
+}
+
 /// Represents an API of communication with the Chat Service.
 ///
-/// An instance of this object is obtained via call to ``Net/createChatService(username:password:)``.
+/// An instance of this object is obtained via call to ``Net/createChatService(username:password:transport:)``.
 public class ChatService: NativeHandleOwner {
     public struct Request: Equatable {
         public var method: String
@@ -114,10 +137,10 @@ public class ChatService: NativeHandleOwner {
 
     private let tokioAsyncContext: TokioAsyncContext
 
-    internal init(tokioAsyncContext: TokioAsyncContext, connectionManager: ConnectionManager, username: String, password: String) {
+    internal init(tokioAsyncContext: TokioAsyncContext, connectionManager: ConnectionManager, username: String, password: String, transport: ChatTransport) {
         var handle: OpaquePointer?
         connectionManager.withNativeHandle { connectionManager in
-            failOnError(signal_chat_service_new(&handle, connectionManager, username, password))
+            failOnError(signal_chat_service_new(&handle, connectionManager, username, password, transport.rawValue))
         }
         self.tokioAsyncContext = tokioAsyncContext
         super.init(owned: handle!)
@@ -183,6 +206,53 @@ public class ChatService: NativeHandleOwner {
         }
     }
 
+    /// Keeps a connection to the Chat Service open, reconnecting with backoff whenever it's lost,
+    /// until ``stopSupervised()`` is called.
+    ///
+    /// Calling this again replaces the previous supervision. Returns once supervision has started.
+    public func startSupervised(authenticated: Bool) async throws {
+        _ = try await invokeAsyncFunction(returning: Bool.self) { promise, context in
+            self.tokioAsyncContext.withNativeHandle { tokioAsyncContext in
+                withNativeHandle { chatService in
+                    signal_chat_service_start_supervised(promise, context, tokioAsyncContext, chatService, authenticated)
+                }
+            }
+        }
+    }
+
+    /// Stops the supervision started by ``startSupervised(authenticated:)``.
+    public func stopSupervised() {
+        withNativeHandle { chatService in
+            failOnError(signal_chat_service_stop_supervised(chatService))
+        }
+    }
+
+    /// Tells a supervised connection that the network changed, so that it reconnects right away
+    /// instead of waiting out its backoff.
+    public func networkChanged() {
+        withNativeHandle { chatService in
+            failOnError(signal_chat_service_network_changed(chatService))
+        }
+    }
+
+    /// Waits for the supervised connection's state to be something other than `lastState`.
+    ///
+    /// Returns ``ChatConnectionState/disconnected`` right away if the connection isn't being
+    /// supervised, and once supervision stops.
+    public func nextConnectionState(after lastState: ChatConnectionState) async throws -> ChatConnectionState {
+        let rawState = try await invokeAsyncFunction(returning: Int32.self) { promise, context in
+            self.tokioAsyncContext.withNativeHandle { tokioAsyncContext in
+                withNativeHandle { chatService in
+                    signal_chat_service_next_connection_state(promise, context, tokioAsyncContext, chatService, lastState.rawValue)
+                }
+            }
+        }
+        guard let state = ChatConnectionState(rawValue: rawState) else {
+            fatalError("unknown connection state \(rawState)")
+        }
+        return state
+    }
+
     /// Sends request to the Chat Service over an unauthenticated channel.
     ///
     /// - Throws: ``SignalError/chatServiceInactive(_:)`` if you haven't called ``connectUnauthenticated()``
@@ -32,6 +32,20 @@ public class Net {
         self.svr3 = Svr3Client(self.asyncContext, self.connectionManager)
     }
 
+    /// Creates a new `Net` instance for a deployment other than staging or production.
+    ///
+    /// - Parameters:
+    ///   - config: The deployment's endpoints, in the JSON format described by libsignal-net's `EnvConfig`.
+    ///   - countryCode: The ISO 3166-1 alpha-2 code of the client's country, or `nil` if unknown.
+    ///     Decides whether the deployment's domain fronts are used.
+    ///
+    /// - Throws: if the config is invalid.
+    public init(customEnvironment config: String, countryCode: String?, userAgent: String) throws {
+        self.asyncContext = TokioAsyncContext()
+        self.connectionManager = try ConnectionManager(customEnvironment: config, countryCode: countryCode, userAgent: userAgent)
+        self.svr3 = Svr3Client(self.asyncContext, self.connectionManager)
+    }
+
     /// Sets the proxy host to be used for all new connections (until overridden).
     ///
     /// Sets a domain name and port to be used to proxy all new outgoing connections. The proxy can
@@ -42,14 +56,62 @@ public class Net {
         try self.connectionManager.setProxy(host: host, port: port)
     }
 
+    /// Sets a SOCKS5 proxy to be used for all new connections (until overridden).
+    ///
+    /// The proxy can be overridden by calling this method or ``Net/setProxy(host:port:)`` again,
+    /// or unset by calling ``Net/clearProxy()``.
+    ///
+    /// - Parameters:
+    ///   - username: The username to authenticate with, or `nil` for none.
+    ///   - password: The password to authenticate with, or `nil` for none.
+    ///
+    /// - Throws: if the host or port is not structurally valid, such as a port of 0.
+    public func setSocksProxy(host: String, port: UInt16, username: String? = nil, password: String? = nil) throws {
+        try self.connectionManager.setSocksProxy(host: host, port: port, username: username, password: password)
+    }
+
+    /// Sets an HTTP proxy, used with `CONNECT`, for all new connections (until overridden).
+    ///
+    /// The proxy can be overridden by calling this method or ``Net/setProxy(host:port:)`` again,
+    /// or unset by calling ``Net/clearProxy()``.
+    ///
+    /// - Parameters:
+    ///   - username: The username to authenticate with, or `nil` for none.
+    ///   - password: The password to authenticate with, or `nil` for none.
+    ///
+    /// - Throws: if the host or port is not structurally valid, such as a port of 0.
+    public func setHttpProxy(host: String, port: UInt16, username: String? = nil, password: String? = nil) throws {
+        try self.connectionManager.setHttpProxy(host: host, port: port, username: username, password: password)
+    }
+
     /// Clears the proxy host (if any) so that future connections will be made directly.
     ///
-    /// Clears any proxy configuration set via ``Net/setProxy(host:port:)``. If
-    /// none was set, calling this method is a no-op.
+    /// Clears any proxy configuration set via ``Net/setProxy(host:port:)``,
+    /// ``Net/setSocksProxy(host:port:username:password:)`` or
+    /// ``Net/setHttpProxy(host:port:username:password:)``. If none was set, calling this method is
+    /// a no-op.
     public func clearProxy() {
         self.connectionManager.clearProxy()
     }
 
+    /// Remembers DNS results in a file at `path`, so that later runs can connect without waiting on DNS.
+    public func setDnsCachePath(_ path: String) {
+        self.connectionManager.setDnsCachePath(path)
+    }
+
+    /// Forgets the DNS results remembered since ``Net/setDnsCachePath(_:)`` was called, including in the file.
+    public func clearDnsCache() {
+        self.connectionManager.clearDnsCache()
+    }
+
+    /// Returns the connection and request metrics recorded since the last call, as JSON, and starts
+    /// over from zero.
+    ///
+    /// The JSON has `routes` and `endpoints` fields; see libsignal-net's `MetricsSnapshot::to_json`.
+    public func takeMetricsSnapshot() -> String {
+        self.connectionManager.takeMetricsSnapshot()
+    }
+
     /// Like ``cdsiLookup(auth:request:)`` but with the parameters to ``CdsiLookupRequest`` broken out.
     public func cdsiLookup(
         auth: Auth,
@@ -120,8 +182,9 @@ public class Net {
         return CdsiLookup(native: handle, asyncContext: self.asyncContext)
     }
 
-    public func createChatService(username: String, password: String) -> ChatService {
-        return ChatService(tokioAsyncContext: self.asyncContext, connectionManager: self.connectionManager, username: username, password: password)
+    /// Creates a ``ChatService`` that sends its requests over `transport`.
+    public func createChatService(username: String, password: String, transport: ChatTransport = .webSocket) -> ChatService {
+        return ChatService(tokioAsyncContext: self.asyncContext, connectionManager: self.connectionManager, username: username, password: password, transport: transport)
     }
 
     private var asyncContext: TokioAsyncContext
@@ -381,6 +444,12 @@ internal class ConnectionManager: NativeHandleOwner {
         self.init(owned: handle!)
     }
 
+    convenience init(customEnvironment config: String, countryCode: String?, userAgent: String) throws {
+        var handle: OpaquePointer?
+        try checkError(signal_connection_manager_new_custom(&handle, config, userAgent, countryCode))
+        self.init(owned: handle!)
+    }
+
     internal func setProxy(host: String, port: UInt16) throws {
         try self.withNativeHandle {
             // We have to cast to Int32 because of how the port number is validated...for Java.
@@ -388,12 +457,46 @@ internal class ConnectionManager: NativeHandleOwner {
         }
     }
 
+    internal func setSocksProxy(host: String, port: UInt16, username: String?, password: String?) throws {
+        try self.withNativeHandle {
+            try checkError(signal_connection_manager_set_socks_proxy($0, host, Int32(port), username, password))
+        }
+    }
+
+    internal func setHttpProxy(host: String, port: UInt16, username: String?, password: String?) throws {
+        try self.withNativeHandle {
+            try checkError(signal_connection_manager_set_http_proxy($0, host, Int32(port), username, password))
+        }
+    }
+
     internal func clearProxy() {
         self.withNativeHandle {
             failOnError(signal_connection_manager_clear_proxy($0))
         }
     }
 
+    internal func setDnsCachePath(_ path: String) {
+        self.withNativeHandle {
+            failOnError(signal_connection_manager_set_dns_cache_path($0, path))
+        }
+    }
+
+    internal func clearDnsCache() {
+        self.withNativeHandle {
+            failOnError(signal_connection_manager_clear_dns_cache($0))
+        }
+    }
+
+    internal func takeMetricsSnapshot() -> String {
+        self.withNativeHandle { connectionManager in
+            failOnError {
+                try invokeFnReturningString {
+                    signal_connection_manager_take_metrics_snapshot($0, connectionManager)
+                }
+            }
+        }
+    }
+
     override internal class func destroyNativeHandle(_ handle: OpaquePointer) -> SignalFfiErrorRef? {
         signal_connection_manager_destroy(handle)
     }
@@ -144,7 +144,8 @@ public class Svr3Client {
     ///     restores has been exceeded or the value has never been backed up in
     ///     the first place.
     ///   - `SignalError.svrRestoreFailed` when the restore failed due to a bad
-    ///     combination of password and share set.
+    ///     combination of password and share set, with the number of tries
+    ///     remaining.
     ///
     /// ## Notes:
     ///   - Error messages are expected to be log-safe and not contain any
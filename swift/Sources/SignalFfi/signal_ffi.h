@@ -643,6 +643,8 @@ uint32_t signal_error_get_type(const SignalFfiError *err);
 
 SignalFfiError *signal_error_get_retry_after_seconds(const SignalFfiError *err, uint32_t *out);
 
+SignalFfiError *signal_error_get_tries_remaining(const SignalFfiError *err, uint32_t *out);
+
 void signal_error_free(SignalFfiError *err);
 
 SignalFfiError *signal_identitykeypair_deserialize(SignalPrivateKey **private_key, SignalPublicKey **public_key, SignalBorrowedBuffer input);
@@ -1395,10 +1397,22 @@ SignalFfiError *signal_verify_signature(bool *out, SignalBorrowedBuffer cert_pem
 
 SignalFfiError *signal_connection_manager_new(SignalConnectionManager **out, uint8_t environment, const char *user_agent);
 
+SignalFfiError *signal_connection_manager_new_custom(SignalConnectionManager **out, const char *config, const char *user_agent, const char *country_code);
+
 SignalFfiError *signal_connection_manager_set_proxy(const SignalConnectionManager *connection_manager, const char *host, int32_t port);
 
+SignalFfiError *signal_connection_manager_set_socks_proxy(const SignalConnectionManager *connection_manager, const char *host, int32_t port, const char *username, const char *password);
+
+SignalFfiError *signal_connection_manager_set_http_proxy(const SignalConnectionManager *connection_manager, const char *host, int32_t port, const char *username, const char *password);
+
 SignalFfiError *signal_connection_manager_clear_proxy(const SignalConnectionManager *connection_manager);
 
+SignalFfiError *signal_connection_manager_set_dns_cache_path(const SignalConnectionManager *connection_manager, const char *path);
+
+SignalFfiError *signal_connection_manager_clear_dns_cache(const SignalConnectionManager *connection_manager);
+
+SignalFfiError *signal_connection_manager_take_metrics_snapshot(const char **out, const SignalConnectionManager *connection_manager);
+
 SignalFfiError *signal_connection_manager_destroy(SignalConnectionManager *p);
 
 SignalFfiError *signal_create_otp(const char **out, const char *username, SignalBorrowedBuffer secret);
@@ -1419,10 +1433,18 @@ SignalFfiError *signal_http_request_new_without_body(SignalHttpRequest **out, co
 
 SignalFfiError *signal_http_request_add_header(const SignalHttpRequest *request, const char *name, const char *value);
 
-SignalFfiError *signal_chat_service_new(SignalChat **out, const SignalConnectionManager *connection_manager, const char *username, const char *password);
+SignalFfiError *signal_chat_service_new(SignalChat **out, const SignalConnectionManager *connection_manager, const char *username, const char *password, uint8_t transport);
 
 SignalFfiError *signal_chat_service_disconnect(SignalCPromisebool promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalChat *chat);
 
+SignalFfiError *signal_chat_service_start_supervised(SignalCPromisebool promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalChat *chat, bool authenticated);
+
+SignalFfiError *signal_chat_service_stop_supervised(const SignalChat *chat);
+
+SignalFfiError *signal_chat_service_network_changed(const SignalChat *chat);
+
+SignalFfiError *signal_chat_service_next_connection_state(SignalCPromisei32 promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalChat *chat, int32_t last_state);
+
 SignalFfiError *signal_chat_service_connect_unauth(SignalCPromiseFfiChatServiceDebugInfo promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalChat *chat);
 
 SignalFfiError *signal_chat_service_connect_auth(SignalCPromiseFfiChatServiceDebugInfo promise, const void *promise_context, const SignalTokioAsyncContext *async_runtime, const SignalChat *chat);
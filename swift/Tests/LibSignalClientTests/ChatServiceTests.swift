@@ -123,6 +123,18 @@ final class ChatServiceTests: TestCaseBase {
         try await chat.disconnect()
     }
 
+    func testConnectUnauthOverHttp2() async throws {
+        guard ProcessInfo.processInfo.environment["LIBSIGNAL_TESTING_PROXY_SERVER"] != nil else {
+            throw XCTSkip()
+        }
+
+        let net = Net(env: .staging, userAgent: Self.userAgent)
+        let chat = net.createChatService(username: "", password: "", transport: .http2)
+        // Just make sure we can connect.
+        try await chat.connectUnauthenticated()
+        try await chat.disconnect()
+    }
+
     func testConnectUnauthThroughProxy() async throws {
         guard let PROXY_SERVER = ProcessInfo.processInfo.environment["LIBSIGNAL_TESTING_PROXY_SERVER"] else {
             throw XCTSkip()
@@ -166,4 +178,58 @@ final class ChatServiceTests: TestCaseBase {
             // Okay
         }
     }
+
+    func testSupervisedConnection() async throws {
+        guard ProcessInfo.processInfo.environment["LIBSIGNAL_TESTING_PROXY_SERVER"] != nil else {
+            throw XCTSkip()
+        }
+
+        let net = Net(env: .staging, userAgent: Self.userAgent)
+        let chat = net.createChatService(username: "", password: "")
+        try await chat.startSupervised(authenticated: false)
+
+        var state = ChatConnectionState.disconnected
+        while state != .connected {
+            state = try await chat.nextConnectionState(after: state)
+        }
+        chat.networkChanged()
+
+        chat.stopSupervised()
+        let finalState = try await chat.nextConnectionState(after: state)
+        XCTAssertEqual(finalState, .disconnected)
+    }
+
+    func testConnectFailsWithInvalidSocksOrHttpProxy() async throws {
+        let net = Net(env: .production, userAgent: Self.userAgent)
+        do {
+            try net.setSocksProxy(host: "signalfoundation.org", port: 0)
+            XCTFail("should not allow setting invalid proxy")
+        } catch SignalError.ioError {
+            // Okay
+        }
+        do {
+            try net.setHttpProxy(host: "signalfoundation.org", port: 0, username: "user", password: "pass")
+            XCTFail("should not allow setting invalid proxy")
+        } catch SignalError.ioError {
+            // Okay
+        }
+
+        let chat = net.createChatService(username: "", password: "")
+        // Make sure we *can't* connect.
+        do {
+            try await chat.connectUnauthenticated()
+            XCTFail("should not allow connecting")
+        } catch SignalError.connectionFailed {
+            // Okay
+        }
+    }
+
+    func testNextConnectionStateWithoutSupervision() async throws {
+        let net = Net(env: .staging, userAgent: Self.userAgent)
+        let chat = net.createChatService(username: "", password: "")
+        chat.networkChanged()
+        chat.stopSupervised()
+        let state = try await chat.nextConnectionState(after: .connected)
+        XCTAssertEqual(state, .disconnected)
+    }
 }
@@ -105,6 +105,57 @@ final class NetTests: XCTestCase {
         }
     }
 
+    func testCustomEnvironment() throws {
+        let sgxMeasurement = String(repeating: "00", count: 32)
+        func domain(_ hostname: String) -> [String: Any] {
+            ["hostname": hostname, "proxy_path": "/proxy", "cert": "signal"]
+        }
+        func enclave(_ hostname: String, _ measurement: String) -> [String: Any] {
+            ["domain": domain(hostname), "mr_enclave": measurement]
+        }
+        let config: [String: Any] = [
+            "chat": domain("chat.example.org"),
+            "cdsi": enclave("cdsi.example.org", sgxMeasurement),
+            "svr2": enclave("svr2.example.org", sgxMeasurement),
+            "svr3": [
+                "sgx": enclave("svr3-sgx.example.org", sgxMeasurement),
+                "nitro": enclave("svr3-nitro.example.org", "1.2.3"),
+                "tpm2snp": enclave("svr3-tpm2snp.example.org", "1.2.3"),
+            ],
+            "domain_fronts": [[
+                "sni_list": ["front.example.net"],
+                "host": "signal.cdn.example.net",
+                "enabled_countries": ["AA"],
+            ]],
+        ]
+        let json = String(decoding: try JSONSerialization.data(withJSONObject: config), as: UTF8.self)
+
+        _ = try Net(customEnvironment: json, countryCode: nil, userAgent: userAgent)
+        _ = try Net(customEnvironment: json, countryCode: "AA", userAgent: userAgent)
+
+        do {
+            _ = try Net(customEnvironment: "{}", countryCode: nil, userAgent: userAgent)
+            XCTFail("should not accept an invalid config")
+        } catch SignalError.ioError {
+            // Okay
+        }
+    }
+
+    func testMetricsStartEmpty() throws {
+        let net = Net(env: .staging, userAgent: userAgent)
+        let snapshot = try JSONSerialization.jsonObject(with: Data(net.takeMetricsSnapshot().utf8)) as! [String: Any]
+        XCTAssertEqual((snapshot["routes"] as! [Any]).count, 0)
+        XCTAssertEqual((snapshot["endpoints"] as! [String: Any]).count, 0)
+    }
+
+    func testDnsCache() throws {
+        let net = Net(env: .staging, userAgent: userAgent)
+        let path = FileManager.default.temporaryDirectory.appendingPathComponent("libsignal-dns-\(UUID())").path
+        net.setDnsCachePath(path)
+        net.clearDnsCache()
+        try? FileManager.default.removeItem(atPath: path)
+    }
+
     func testCdsiLookupCompilation() async throws {
         try throwSkipForCompileOnlyTest()
 
@@ -176,7 +227,7 @@ final class Svr3Tests: TestCaseBase {
                 auth: auth
             )
             XCTFail("Should have thrown")
-        } catch SignalError.svrRestoreFailed(_) {
+        } catch SignalError.svrRestoreFailed(_, _) {
             // Success!
         } catch {
             XCTFail("Unexpected exception: '\(error)'")
@@ -203,7 +254,7 @@ final class Svr3Tests: TestCaseBase {
                 auth: auth
             )
             XCTFail("Should have thrown")
-        } catch SignalError.svrRestoreFailed(_) {
+        } catch SignalError.svrRestoreFailed(_, _) {
             // Success!
         } catch {
             XCTFail("Unexpected exception: '\(error)'")
@@ -259,7 +310,7 @@ final class Svr3Tests: TestCaseBase {
                 auth: auth
             )
             XCTFail("Should have thrown")
-        } catch SignalError.svrRestoreFailed(_) {
+        } catch SignalError.svrRestoreFailed(triesRemaining: 0, _) {
             // Success!
         } catch {
             XCTFail("Unexpected exception: '\(error)'")